@@ -0,0 +1,157 @@
+// Name: ref_with_2_flags_derive - #[derive(TagEnum)] for fieldless enums.
+//
+// Description: Implements ref_with_2_flags::TagEnum for an enum without
+//              fields: the tag of a variant is its position, 0, 1, 2, ...,
+//              and BITS is the fewest bits that hold the last position, so
+//              enum Color { White, Gray, Black } takes 2 bits.
+//
+//              #[tag_bits(N)] fixes BITS to N instead, and the derive fails
+//              with a compile error if the variants don't fit in N bits. The
+//              fit with the alignment of the pointee is checked where the
+//              enum is used, by RefWithTag.
+//
+//              It works on the raw proc_macro token stream, the enum grammar
+//              it needs is small enough to not pull in syn and quote.
+
+use proc_macro::{Delimiter, Group, Span, TokenStream, TokenTree};
+
+#[proc_macro_derive(TagEnum, attributes(tag_bits))]
+pub fn derive_tag_enum(input: TokenStream) -> TokenStream {
+    match expand(input) {
+        Ok(output) => output,
+        Err((span, message)) => compile_error(span, &message),
+    }
+}
+
+type Error = (Span, String);
+
+fn expand(input: TokenStream) -> Result<TokenStream, Error> {
+    let mut tokens = input.into_iter();
+    let mut tag_bits = None;
+    let name = loop {
+        match tokens.next() {
+            Some(TokenTree::Punct(p)) if p.as_char() == '#' => {
+                if let Some(TokenTree::Group(attr)) = tokens.next() {
+                    if let Some(bits) = parse_tag_bits(&attr)? {
+                        tag_bits = Some(bits);
+                    }
+                }
+            }
+            Some(TokenTree::Ident(keyword)) if keyword.to_string() == "enum" => match tokens.next() {
+                Some(TokenTree::Ident(name)) => break name,
+                _ => return Err((keyword.span(), "expected the enum name".into())),
+            },
+            Some(TokenTree::Ident(keyword)) if matches!(keyword.to_string().as_str(), "struct" | "union") => {
+                return Err((keyword.span(), "TagEnum can only be derived for enums".into()));
+            }
+            Some(_) => {}
+            None => return Err((Span::call_site(), "expected an enum".into())),
+        }
+    };
+    let body = match tokens.next() {
+        Some(TokenTree::Group(body)) if body.delimiter() == Delimiter::Brace => body,
+        Some(TokenTree::Punct(p)) if p.as_char() == '<' => {
+            return Err((p.span(), "TagEnum can't be derived for a generic enum".into()));
+        }
+        _ => return Err((name.span(), "expected the enum variants".into())),
+    };
+    let variants = parse_variants(&body)?;
+    if variants.is_empty() {
+        return Err((name.span(), "TagEnum needs at least one variant".into()));
+    }
+
+    let needed = bits_for(variants.len() - 1);
+    let bits = match tag_bits {
+        Some((bits, span)) if bits < needed => {
+            let message = format!("{} variants don't fit in {} tag bits, they need {}", variants.len(), bits, needed);
+            return Err((span, message));
+        }
+        Some((bits, _)) => bits,
+        None => needed,
+    };
+
+    let to_bits: String = variants.iter().enumerate().map(|(i, v)| format!("{}::{} => {},", name, v, i)).collect();
+    // The last variant also takes the values to_bits never returns, so the
+    // match is exhaustive without a panic.
+    let from_bits: String = variants
+        .iter()
+        .enumerate()
+        .map(|(i, v)| if i + 1 == variants.len() { format!("_ => {}::{},", name, v) } else { format!("{} => {}::{},", i, name, v) })
+        .collect();
+    let output = format!(
+        "impl ::ref_with_2_flags::TagEnum for {name} {{
+            const BITS: usize = {bits};
+
+            fn to_bits(self) -> usize {{
+                match self {{ {to_bits} }}
+            }}
+
+            fn from_bits(bits: usize) -> {name} {{
+                match bits {{ {from_bits} }}
+            }}
+        }}"
+    );
+    Ok(output.parse().unwrap())
+}
+
+// Some((N, span)) for #[tag_bits(N)], None for the other attributes.
+fn parse_tag_bits(attr: &Group) -> Result<Option<(usize, Span)>, Error> {
+    let mut tokens = attr.stream().into_iter();
+    match tokens.next() {
+        Some(TokenTree::Ident(ident)) if ident.to_string() == "tag_bits" => {}
+        _ => return Ok(None),
+    }
+    let args = match tokens.next() {
+        Some(TokenTree::Group(args)) if args.delimiter() == Delimiter::Parenthesis => args,
+        _ => return Err((attr.span(), "expected #[tag_bits(N)]".into())),
+    };
+    let mut args = args.stream().into_iter();
+    match (args.next(), args.next()) {
+        (Some(TokenTree::Literal(lit)), None) => match lit.to_string().parse() {
+            Ok(bits) => Ok(Some((bits, lit.span()))),
+            Err(_) => Err((lit.span(), "expected a number of bits".into())),
+        },
+        _ => Err((attr.span(), "expected #[tag_bits(N)]".into())),
+    }
+}
+
+// The variant names, attributes and explicit discriminants are skipped.
+fn parse_variants(body: &Group) -> Result<Vec<String>, Error> {
+    let mut variants = Vec::new();
+    let mut tokens = body.stream().into_iter();
+    let (mut expect_name, mut in_discriminant) = (true, false);
+    while let Some(token) = tokens.next() {
+        match token {
+            TokenTree::Punct(p) if p.as_char() == '#' && expect_name => {
+                tokens.next();
+            }
+            TokenTree::Punct(p) if p.as_char() == ',' => (expect_name, in_discriminant) = (true, false),
+            TokenTree::Punct(p) if p.as_char() == '=' => in_discriminant = true,
+            TokenTree::Ident(ident) if expect_name => {
+                variants.push(ident.to_string());
+                expect_name = false;
+            }
+            TokenTree::Group(fields) if !expect_name && !in_discriminant => {
+                return Err((fields.span(), "TagEnum can only be derived for fieldless enums".into()));
+            }
+            _ => {}
+        }
+    }
+    Ok(variants)
+}
+
+// Fewest bits that hold `max`, 0 for a single variant.
+fn bits_for(max: usize) -> usize {
+    (usize::BITS - max.leading_zeros()) as usize
+}
+
+fn compile_error(span: Span, message: &str) -> TokenStream {
+    let output: TokenStream = format!("::core::compile_error!({:?});", message).parse().unwrap();
+    output
+        .into_iter()
+        .map(|mut token| {
+            token.set_span(span);
+            token
+        })
+        .collect()
+}
@@ -0,0 +1,152 @@
+// Name: AlignedAtLeast - the alignment contract of the tagged pointers.
+//
+// Description: A tagged pointer steals the low bits of the address, so it
+//              needs a promise that every address it is given is aligned to
+//              at least N bytes. AlignedAtLeast<N> is that promise, as a
+//              trait bound on the constructors, so an under aligned type is
+//              rejected at compile time instead of by a runtime assert.
+//
+//              It is implemented here for the primitives and the common std
+//              types, each impl checked against align_of at compile time.
+//              Users implement it for their own types with the safe
+//              aligned_at_least! macro, that checks align_of the same way:
+//
+//                 aligned_at_least!(CacheSlot => 8);
+//
+//              implements AlignedAtLeast<2>, <4> and <8> for CacheSlot, and
+//              fails to compile if CacheSlot isn't 8 bytes aligned. A hand
+//              written unsafe impl is only for the values that are always
+//              placed in over-aligned allocations that align_of doesn't know
+//              about.
+//
+//              Every type is aligned to at least 1 byte, so AlignedAtLeast<1>
+//              is implemented for all types.
+//
+//              The impls for String, Vec, Box, Rc and Arc need the std
+//              feature, the others are core only.
+
+/// # Safety
+/// Every reference to a `Self` given to a tagged pointer constructor must be
+/// aligned to at least `N` bytes.
+pub unsafe trait AlignedAtLeast<const N: usize> {}
+
+unsafe impl<T: ?Sized> AlignedAtLeast<1> for T {}
+
+/// Implements `AlignedAtLeast` for a type, from 2 up to `N` bytes, after a
+/// compile time check of its `align_of`. `N` is 2, 4 or 8.
+///
+/// ```
+/// use ref_with_2_flags::{aligned_at_least, RefWith2Flags};
+///
+/// #[repr(align(8))]
+/// struct Slot(u8);
+///
+/// aligned_at_least!(Slot => 8);
+///
+/// let slot = Slot(1);
+/// let tagged = RefWith2Flags::new(&slot, true, false);
+/// assert!(tagged.get_flag_a());
+/// ```
+///
+/// An under aligned type doesn't compile:
+///
+/// ```compile_fail
+/// use ref_with_2_flags::aligned_at_least;
+///
+/// struct Bytes([u8; 4]);
+///
+/// aligned_at_least!(Bytes => 4);
+/// ```
+#[macro_export]
+macro_rules! aligned_at_least {
+    (@impl $t:ty => $($n:literal),*) => {
+        $(
+            const _: () = assert!(::core::mem::align_of::<$t>() >= $n, "the type is under aligned");
+            unsafe impl $crate::AlignedAtLeast<$n> for $t {}
+        )*
+    };
+    ($t:ty => 2) => {
+        $crate::aligned_at_least!(@impl $t => 2);
+    };
+    ($t:ty => 4) => {
+        $crate::aligned_at_least!(@impl $t => 2, 4);
+    };
+    ($t:ty => 8) => {
+        $crate::aligned_at_least!(@impl $t => 2, 4, 8);
+    };
+}
+
+macro_rules! impl_aligned_at_least {
+    ($n:literal => generic $($t:ident),*) => {
+        $(
+            const _: () = assert!(core::mem::align_of::<$t<u8>>() >= $n);
+            unsafe impl<T> AlignedAtLeast<$n> for $t<T> {}
+        )*
+    };
+    ($n:literal => pointers $($t:ident),*) => {
+        $(
+            const _: () = assert!(core::mem::align_of::<$t<u8>>() >= $n);
+            unsafe impl<T: ?Sized> AlignedAtLeast<$n> for $t<T> {}
+        )*
+    };
+    ($n:literal => references) => {
+        const _: () = assert!(core::mem::align_of::<&u8>() >= $n);
+        unsafe impl<T: ?Sized> AlignedAtLeast<$n> for &T {}
+        unsafe impl<T: ?Sized> AlignedAtLeast<$n> for &mut T {}
+        unsafe impl<T: AlignedAtLeast<$n>, const M: usize> AlignedAtLeast<$n> for [T; M] {}
+    };
+    ($n:literal => $($t:ty),*) => {
+        $(
+            const _: () = assert!(core::mem::align_of::<$t>() >= $n);
+            unsafe impl AlignedAtLeast<$n> for $t {}
+        )*
+    };
+}
+
+impl_aligned_at_least!(2 => u16, i16, u32, i32, f32, char, u64, i64, f64, u128, i128);
+impl_aligned_at_least!(4 => u32, i32, f32, char, u64, i64, f64, u128, i128);
+#[cfg(target_pointer_width = "64")]
+impl_aligned_at_least!(8 => u64, i64, f64, u128, i128);
+
+#[cfg(any(target_pointer_width = "32", target_pointer_width = "64"))]
+impl_aligned_at_least!(2 => usize, isize);
+#[cfg(any(target_pointer_width = "32", target_pointer_width = "64"))]
+impl_aligned_at_least!(4 => usize, isize);
+#[cfg(target_pointer_width = "64")]
+impl_aligned_at_least!(8 => usize, isize);
+
+#[cfg(any(target_pointer_width = "32", target_pointer_width = "64"))]
+impl_aligned_at_least!(2 => references);
+#[cfg(any(target_pointer_width = "32", target_pointer_width = "64"))]
+impl_aligned_at_least!(4 => references);
+#[cfg(target_pointer_width = "64")]
+impl_aligned_at_least!(8 => references);
+
+#[cfg(any(feature = "std", test))]
+mod owning {
+    use std::rc::Rc;
+    use std::sync::Arc;
+
+    use super::AlignedAtLeast;
+
+    #[cfg(any(target_pointer_width = "32", target_pointer_width = "64"))]
+    impl_aligned_at_least!(2 => String);
+    #[cfg(any(target_pointer_width = "32", target_pointer_width = "64"))]
+    impl_aligned_at_least!(4 => String);
+    #[cfg(target_pointer_width = "64")]
+    impl_aligned_at_least!(8 => String);
+
+    #[cfg(any(target_pointer_width = "32", target_pointer_width = "64"))]
+    impl_aligned_at_least!(2 => generic Vec);
+    #[cfg(any(target_pointer_width = "32", target_pointer_width = "64"))]
+    impl_aligned_at_least!(4 => generic Vec);
+    #[cfg(target_pointer_width = "64")]
+    impl_aligned_at_least!(8 => generic Vec);
+
+    #[cfg(any(target_pointer_width = "32", target_pointer_width = "64"))]
+    impl_aligned_at_least!(2 => pointers Box, Rc, Arc);
+    #[cfg(any(target_pointer_width = "32", target_pointer_width = "64"))]
+    impl_aligned_at_least!(4 => pointers Box, Rc, Arc);
+    #[cfg(target_pointer_width = "64")]
+    impl_aligned_at_least!(8 => pointers Box, Rc, Arc);
+}
@@ -0,0 +1,139 @@
+// Name: AlignedBox - an owned value over-aligned to A bytes, to make room for
+//       more tag bits.
+//
+// Description: A type only 4 bytes aligned has 2 free low bits. Boxed in an
+//              AlignedBox<T, 64> it lands on a 64 bytes boundary and a
+//              reference to it has 6, at the price of some allocator slack.
+//
+//              The alignment is in the type, not only in the allocation: the
+//              box holds an AlignN<T>, a #[repr(align(N))] shim around the T,
+//              so align_of::<AlignN<T>>() is N and the tagged pointer types
+//              see it. get_aligned() gives the &AlignN<T> to tag, with
+//              TaggedRef<'_, AlignN<T>, BITS> or RefWithTag for all the bits,
+//              or RefWith2Flags / RefWith3Flags, and the shim derefs to the T.
+//
+//              A is one of 8, 16, 32, 64, 128, 256 and 4096, the shims that
+//              exist.
+
+use std::ops::{Deref, DerefMut};
+
+use crate::aligned::AlignedAtLeast;
+
+/// The alignment `A` as a type, to pick its shim.
+pub struct ConstAlign<const A: usize>;
+
+/// The alignments there is a shim for.
+pub trait SupportedAlign {
+    type Shim<T>: Deref<Target = T> + DerefMut;
+
+    fn wrap<T>(value: T) -> Self::Shim<T>;
+
+    fn unwrap<T>(shim: Self::Shim<T>) -> T;
+}
+
+macro_rules! align_shims {
+    ($($shim:ident => $n:literal),*) => {
+        $(
+            #[repr(align($n))]
+            pub struct $shim<T>(pub T);
+
+            impl<T> Deref for $shim<T> {
+                type Target = T;
+
+                fn deref(&self) -> &T {
+                    &self.0
+                }
+            }
+
+            impl<T> DerefMut for $shim<T> {
+                fn deref_mut(&mut self) -> &mut T {
+                    &mut self.0
+                }
+            }
+
+            unsafe impl<T> AlignedAtLeast<2> for $shim<T> {}
+            unsafe impl<T> AlignedAtLeast<4> for $shim<T> {}
+            unsafe impl<T> AlignedAtLeast<8> for $shim<T> {}
+
+            impl SupportedAlign for ConstAlign<$n> {
+                type Shim<T> = $shim<T>;
+
+                fn wrap<T>(value: T) -> $shim<T> {
+                    $shim(value)
+                }
+
+                fn unwrap<T>(shim: $shim<T>) -> T {
+                    shim.0
+                }
+            }
+        )*
+    };
+}
+
+align_shims!(
+    Align8 => 8, Align16 => 16, Align32 => 32, Align64 => 64, Align128 => 128, Align256 => 256, Align4096 => 4096
+);
+
+pub struct AlignedBox<T, const A: usize>
+where
+    ConstAlign<A>: SupportedAlign,
+{
+    shim: Box<<ConstAlign<A> as SupportedAlign>::Shim<T>>,
+}
+
+impl<T, const A: usize> AlignedBox<T, A>
+where
+    ConstAlign<A>: SupportedAlign,
+{
+
+    pub fn new(value: T) -> AlignedBox<T, A> {
+        AlignedBox { shim: Box::new(ConstAlign::<A>::wrap(value)) }
+    }
+
+    /// The shim, aligned to A bytes by its type, to give to a tagged pointer.
+    pub fn get_aligned(&self) -> &<ConstAlign<A> as SupportedAlign>::Shim<T> {
+        &self.shim
+    }
+
+    pub fn into_inner(self) -> T {
+        ConstAlign::<A>::unwrap(*self.shim)
+    }
+
+}
+
+impl<T, const A: usize> Deref for AlignedBox<T, A>
+where
+    ConstAlign<A>: SupportedAlign,
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.shim
+    }
+}
+
+impl<T, const A: usize> DerefMut for AlignedBox<T, A>
+where
+    ConstAlign<A>: SupportedAlign,
+{
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.shim
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tagged_ref::TaggedRef;
+
+    #[test]
+    fn over_alignment_gives_tag_bits() {
+        let mut boxed_counter = AlignedBox::<u32, 64>::new(41);
+        *boxed_counter += 1;
+        let mut six_bits = TaggedRef::<Align64<u32>, 6>::new(boxed_counter.get_aligned(), 0b10_1010);
+        assert_eq!((**six_bits.get_ref(), six_bits.get_tag()), (42, 0b10_1010));
+        six_bits.set_tag(63);
+        assert_eq!(six_bits.get_tag(), 63);
+        assert_eq!(boxed_counter.into_inner(), 42);
+    }
+}
@@ -0,0 +1,259 @@
+// Name: ArcWith2Flags - an Arc<T> and 2 flags in one word.
+//
+// Description: The thread safe counterpart of RcWith2Flags. The Arc is turned
+//              into a raw pointer with Arc::into_raw and the flags go into
+//              its low bits. Clone increments the strong count and Drop
+//              decrements it, so the counts are exactly those of the Arc that
+//              was tagged, and each clone has its own flags.
+//
+//              It is Send and Sync under the same bounds as Arc<T>, T: Send +
+//              Sync, so tagged handles can be shared across threads.
+//
+//              The word is an AtomicPtr to the value in the Arc allocation,
+//              its flags changed with map_addr, so the handle another thread
+//              rebuilds with Arc::from_raw still carries the allocation's
+//              provenance.
+//
+//              With UNIQUE = true, made by with_unique_cache(), flag b isn't
+//              the user's, it caches "known unique": this handle is the only
+//              one. get_mut() and make_mut() set it once Arc::get_mut has
+//              seen a strong count of 1 and no Weak, and then skip that
+//              atomic check as long as it is set. The invalidation rules:
+//
+//                 - Clone clears it, on the clone and on the original, the
+//                   count is 2 from then on. The word is an AtomicPtr only
+//                   for that, clone() has just a &self.
+//                 - No Weak is ever handed out, downgrade() doesn't exist, so
+//                   no Weak can be upgraded behind the flag's back.
+//                 - into_arc() consumes the handle, the flag goes with it.
+//                 - Nothing else makes a strong reference from a handle, so
+//                   a set flag stays true until one of the above.
+
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::Arc;
+
+use crate::aligned::AlignedAtLeast;
+
+const UNIQUE_FLAG: usize = 2;
+
+pub struct ArcWith2Flags<T, const UNIQUE: bool = false> {
+    ptr_and_bit: AtomicPtr<T>,
+    behaves_like: PhantomData<Arc<T>>,
+}
+
+// Send and Sync only if T is Send + Sync, like the Arc.
+unsafe impl<T: Send + Sync, const UNIQUE: bool> Send for ArcWith2Flags<T, UNIQUE> {}
+unsafe impl<T: Send + Sync, const UNIQUE: bool> Sync for ArcWith2Flags<T, UNIQUE> {}
+
+impl<T> ArcWith2Flags<T> {
+
+    pub fn new(arc: Arc<T>, flag_a: bool, flag_b: bool) -> ArcWith2Flags<T>
+    where
+        T: AlignedAtLeast<4>,
+    {
+        Self::from_arc(arc, flag_a as usize | ((flag_b as usize) << 1))
+    }
+
+    pub fn get_flag_b(&self) -> bool {
+        self.word().addr() & 2 != 0
+    }
+
+    pub fn set_flag_b(&mut self, flag: bool) {
+        self.set_bit(2, flag);
+    }
+
+}
+
+impl<T> ArcWith2Flags<T, true> {
+
+    /// Flag b caches "known unique", see the invalidation rules above.
+    pub fn with_unique_cache(arc: Arc<T>, flag_a: bool) -> ArcWith2Flags<T, true>
+    where
+        T: AlignedAtLeast<4>,
+    {
+        Self::from_arc(arc, flag_a as usize)
+    }
+
+    pub fn is_known_unique(&self) -> bool {
+        self.word().addr() & UNIQUE_FLAG != 0
+    }
+
+    /// Like Arc::get_mut, without the atomic check once known unique.
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        if !self.is_known_unique() {
+            Arc::get_mut(&mut self.arc())?;
+            self.set_bit(UNIQUE_FLAG, true);
+        }
+        // Known unique, no other handle can see the value.
+        Some(unsafe { &mut *self.ptr().cast_mut() })
+    }
+
+    /// Like Arc::make_mut: clones the value into a new Arc if it is shared,
+    /// so this handle is unique after it.
+    pub fn make_mut(&mut self) -> &mut T
+    where
+        T: Clone,
+    {
+        if self.get_mut().is_none() {
+            let unique = Arc::into_raw(Arc::new(self.get_ref().clone())).cast_mut();
+            let flags = self.word().addr() & 1;
+            let shared = std::mem::replace(self.ptr_and_bit.get_mut(), unique.map_addr(|addr| addr | flags | UNIQUE_FLAG));
+            drop(unsafe { Arc::from_raw(shared.map_addr(|addr| addr & !3)) });
+        }
+        unsafe { &mut *self.ptr().cast_mut() }
+    }
+
+}
+
+impl<T, const UNIQUE: bool> ArcWith2Flags<T, UNIQUE> {
+
+    pub fn get_ref(&self) -> &T {
+        unsafe { &*self.ptr() }
+    }
+
+    pub fn get_flag_a(&self) -> bool {
+        self.word().addr() & 1 != 0
+    }
+
+    pub fn set_flag_a(&mut self, flag: bool) {
+        self.set_bit(1, flag);
+    }
+
+    /// Number of Arc's, tagged or not, sharing the value.
+    pub fn strong_count(&self) -> usize {
+        Arc::strong_count(&self.arc())
+    }
+
+    /// True if both point to the same allocation, whatever their flags.
+    pub fn ptr_eq(&self, other: &ArcWith2Flags<T, UNIQUE>) -> bool {
+        self.ptr() == other.ptr()
+    }
+
+    /// Gives back the Arc, dropping the flags.
+    pub fn into_arc(self) -> Arc<T> {
+        let this = ManuallyDrop::new(self);
+        unsafe { Arc::from_raw(this.ptr()) }
+    }
+
+    fn from_arc(arc: Arc<T>, bits: usize) -> ArcWith2Flags<T, UNIQUE> {
+        ArcWith2Flags {
+            ptr_and_bit: AtomicPtr::new(Arc::into_raw(arc).cast_mut().map_addr(|addr| addr | bits)),
+            behaves_like: PhantomData,
+        }
+    }
+
+    // Only clone() stores through a &self, and only to clear UNIQUE_FLAG,
+    // so a relaxed load sees the address and the user flag as they were set.
+    fn word(&self) -> *mut T {
+        self.ptr_and_bit.load(Ordering::Relaxed)
+    }
+
+    fn set_bit(&mut self, bit: usize, flag: bool) {
+        let word = self.ptr_and_bit.get_mut();
+        *word = word.map_addr(|addr| if flag { addr | bit } else { addr & !bit });
+    }
+
+    fn ptr(&self) -> *const T {
+        self.word().map_addr(|addr| addr & !3)
+    }
+
+    // The Arc that was tagged, not to be dropped, it doesn't own a count.
+    fn arc(&self) -> ManuallyDrop<Arc<T>> {
+        ManuallyDrop::new(unsafe { Arc::from_raw(self.ptr()) })
+    }
+
+}
+
+impl<T, const UNIQUE: bool> Clone for ArcWith2Flags<T, UNIQUE> {
+    fn clone(&self) -> Self {
+        let mut word = self.word();
+        if UNIQUE {
+            // There are 2 handles from now on.
+            word = self.ptr_and_bit.fetch_and(!UNIQUE_FLAG, Ordering::Relaxed).map_addr(|addr| addr & !UNIQUE_FLAG);
+        }
+        unsafe { Arc::increment_strong_count(self.ptr()) };
+        ArcWith2Flags { ptr_and_bit: AtomicPtr::new(word), behaves_like: PhantomData }
+    }
+}
+
+impl<T, const UNIQUE: bool> Drop for ArcWith2Flags<T, UNIQUE> {
+    fn drop(&mut self) {
+        drop(unsafe { Arc::from_raw(self.ptr()) });
+    }
+}
+
+impl<T, const UNIQUE: bool> Deref for ArcWith2Flags<T, UNIQUE> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.get_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aligned_box::Align16;
+    use std::thread;
+
+    #[test]
+    fn known_unique_is_set_by_get_mut_and_cleared_by_clone() {
+        let mut tagged = ArcWith2Flags::with_unique_cache(Arc::new(vec![1u32]), true);
+        assert!(!tagged.is_known_unique());
+        tagged.get_mut().unwrap().push(2);
+        assert!(tagged.is_known_unique());
+        tagged.make_mut().push(3);
+
+        let copy = tagged.clone();
+        assert!(!tagged.is_known_unique() && !copy.is_known_unique());
+        assert!(tagged.get_mut().is_none());
+        assert!(tagged.get_flag_a() && copy.get_flag_a());
+
+        // Shared, make_mut clones the value and leaves the copy alone.
+        tagged.make_mut().push(4);
+        assert!(tagged.is_known_unique());
+        assert!(!tagged.ptr_eq(&copy));
+        assert_eq!((tagged.get_ref().len(), copy.get_ref().len()), (4, 3));
+        assert!(tagged.get_flag_a());
+
+        drop(copy);
+        assert_eq!(tagged.strong_count(), 1);
+        assert_eq!(tagged.into_arc().as_slice(), &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn each_thread_has_its_flags() {
+        let shared = ArcWith2Flags::new(Arc::new(Align16(11u16)), false, false);
+        let workers: Vec<_> = (0..4)
+            .map(|i| {
+                let mut mine = shared.clone();
+                thread::spawn(move || {
+                    mine.set_flag_a(i % 2 == 0);
+                    (mine.0, mine.get_flag_a())
+                })
+            })
+            .collect();
+        let seen: Vec<(u16, bool)> = workers.into_iter().map(|w| w.join().unwrap()).collect();
+        assert_eq!(seen, vec![(11, true), (11, false), (11, true), (11, false)]);
+        assert_eq!(shared.strong_count(), 1);
+        assert!(!shared.get_flag_a() && shared.ptr_eq(&shared.clone()));
+    }
+
+    #[test]
+    fn counts_settle_after_the_threads() {
+        let value = Arc::new(Align16(5u16));
+        thread::scope(|s| {
+            for i in 0..8 {
+                let mine = ArcWith2Flags::new(value.clone(), i % 2 == 0, false);
+                s.spawn(move || {
+                    let copies: Vec<_> = (0..16).map(|_| mine.clone()).collect();
+                    assert!(copies.iter().all(|c| c.0 == 5 && c.get_flag_a() == (i % 2 == 0)));
+                });
+            }
+        });
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+}
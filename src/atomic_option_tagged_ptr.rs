@@ -0,0 +1,154 @@
+// Name: AtomicOptionTaggedPtr - an atomic Option<RefWith2Flags>.
+//
+// Description: A slot that holds either nothing or a tagged reference, in a
+//              single AtomicPtr. A RefWith2Flags is never null, so the null
+//              pointer is free to mean None, and Some keeps the 2 flags
+//              next to the address.
+//
+//              take() and replace() are one swap, compare_exchange compares
+//              the whole word, address and flags, so a slot can't be claimed
+//              with stale flags. fetch_update runs the CAS loop over the
+//              decoded value, no raw word in user code, like the one of
+//              AtomicTaggedPtr. This is the shape of one shot handoff slots,
+//              e.g. the token of a parked thread.
+
+use core::marker::PhantomData;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::ref_with_2_flags::RefWith2Flags;
+
+pub struct AtomicOptionTaggedPtr<'a, T> {
+    word: AtomicPtr<T>,
+    behaves_like: PhantomData<Option<RefWith2Flags<'a, T>>>,
+}
+
+impl<'a, T: 'a> AtomicOptionTaggedPtr<'a, T> {
+
+    pub fn new(value: Option<RefWith2Flags<'a, T>>) -> AtomicOptionTaggedPtr<'a, T> {
+        AtomicOptionTaggedPtr { word: AtomicPtr::new(Self::pack(value.as_ref())), behaves_like: PhantomData }
+    }
+
+    pub fn none() -> AtomicOptionTaggedPtr<'a, T> {
+        Self::new(None)
+    }
+
+    pub fn load(&self, order: Ordering) -> Option<RefWith2Flags<'a, T>> {
+        Self::unpack(self.word.load(order))
+    }
+
+    pub fn store(&self, value: Option<RefWith2Flags<'a, T>>, order: Ordering) {
+        self.word.store(Self::pack(value.as_ref()), order);
+    }
+
+    /// Empties the slot, returns what was in it.
+    pub fn take(&self, order: Ordering) -> Option<RefWith2Flags<'a, T>> {
+        Self::unpack(self.word.swap(ptr::null_mut(), order))
+    }
+
+    /// Puts `value` in the slot, returns what was in it.
+    pub fn replace(&self, value: Option<RefWith2Flags<'a, T>>, order: Ordering) -> Option<RefWith2Flags<'a, T>> {
+        Self::unpack(self.word.swap(Self::pack(value.as_ref()), order))
+    }
+
+    /// Stores `new` if the slot holds `current`, same address and same flags.
+    /// Returns the previous value on success and the actual one on failure.
+    pub fn compare_exchange(
+        &self,
+        current: Option<&RefWith2Flags<'a, T>>,
+        new: Option<RefWith2Flags<'a, T>>,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<Option<RefWith2Flags<'a, T>>, Option<RefWith2Flags<'a, T>>> {
+        self.word
+            .compare_exchange(Self::pack(current), Self::pack(new.as_ref()), success, failure)
+            .map(Self::unpack)
+            .map_err(Self::unpack)
+    }
+
+    /// Like `AtomicUsize::fetch_update`, on the decoded view: `f` gets the
+    /// current value and returns the new one, or None to give up. Retried
+    /// until the CAS succeeds, returns the previous value, or the last one
+    /// seen if `f` gave up.
+    pub fn fetch_update<F>(
+        &self,
+        set_order: Ordering,
+        fetch_order: Ordering,
+        mut f: F,
+    ) -> Result<Option<RefWith2Flags<'a, T>>, Option<RefWith2Flags<'a, T>>>
+    where
+        F: FnMut(Option<RefWith2Flags<'a, T>>) -> Option<Option<RefWith2Flags<'a, T>>>,
+    {
+        self.word
+            .fetch_update(set_order, fetch_order, |ptr| f(Self::unpack(ptr)).map(|new| Self::pack(new.as_ref())))
+            .map(Self::unpack)
+            .map_err(Self::unpack)
+    }
+
+    fn pack(value: Option<&RefWith2Flags<'a, T>>) -> *mut T {
+        value.map_or(ptr::null_mut(), RefWith2Flags::tagged_ptr)
+    }
+
+    fn unpack(ptr: *mut T) -> Option<RefWith2Flags<'a, T>> {
+        if ptr.is_null() { None } else { Some(unsafe { RefWith2Flags::from_tagged_ptr(ptr) }) }
+    }
+
+}
+
+impl<'a, T: 'a> Default for AtomicOptionTaggedPtr<'a, T> {
+    fn default() -> Self {
+        Self::none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aligned_box::Align16;
+
+    #[test]
+    fn one_shot_handoff_between_threads() {
+        let token = Align16(11u16);
+        let handoff = AtomicOptionTaggedPtr::none();
+        std::thread::scope(|s| {
+            s.spawn(|| handoff.store(Some(RefWith2Flags::new(&token, true, false)), Ordering::Release));
+        });
+        let seen = handoff.load(Ordering::Acquire).unwrap();
+        let stale = RefWith2Flags::new(&token, false, false);
+        assert!(handoff.compare_exchange(Some(&stale), None, Ordering::AcqRel, Ordering::Acquire).is_err());
+        let previous = handoff.compare_exchange(Some(&seen), Some(RefWith2Flags::new(&token, true, true)), Ordering::AcqRel, Ordering::Acquire);
+        assert!(matches!(previous, Ok(Some(ref r)) if r.get_flag_a()));
+        let taken = handoff.take(Ordering::AcqRel).unwrap();
+        assert!(taken.get_flag_b() && taken.get_ref().0 == 11);
+        assert!(handoff.take(Ordering::AcqRel).is_none());
+        assert!(handoff.replace(Some(taken), Ordering::AcqRel).is_none());
+        assert!(AtomicOptionTaggedPtr::<Align16<u16>>::default().load(Ordering::Relaxed).is_none());
+    }
+
+    #[test]
+    fn fetch_update_sees_none() {
+        let token = Align16(11u16);
+        let handoff = AtomicOptionTaggedPtr::none();
+        handoff.store(Some(RefWith2Flags::new(&token, true, true)), Ordering::Release);
+        let toggled = handoff.fetch_update(Ordering::AcqRel, Ordering::Acquire, |current| {
+            current.map(|r| Some(RefWith2Flags::new(r.get_ref(), !r.get_flag_a(), r.get_flag_b())))
+        });
+        assert!(matches!(toggled, Ok(Some(ref r)) if r.get_flag_a()));
+        assert!(!handoff.load(Ordering::Acquire).unwrap().get_flag_a());
+        handoff.take(Ordering::AcqRel);
+        assert!(handoff.fetch_update(Ordering::AcqRel, Ordering::Acquire, |current| current.map(Some)).is_err());
+    }
+
+    #[test]
+    fn an_empty_slot_and_replace_round_trips() {
+        let (first, second) = (Align16(1u16), Align16(2u16));
+        let slot = AtomicOptionTaggedPtr::none();
+        assert!(slot.take(Ordering::AcqRel).is_none());
+        assert!(slot.compare_exchange(None, None, Ordering::AcqRel, Ordering::Acquire).is_ok());
+        assert!(slot.replace(Some(RefWith2Flags::new(&first, false, true)), Ordering::AcqRel).is_none());
+        let old = slot.replace(Some(RefWith2Flags::new(&second, true, false)), Ordering::AcqRel).unwrap();
+        assert!(old.get_ref().0 == 1 && old.get_flag_b() && !old.get_flag_a());
+        let now = slot.load(Ordering::Acquire).unwrap();
+        assert!(now.get_ref().0 == 2 && now.get_flag_a());
+    }
+}
@@ -0,0 +1,170 @@
+// Name: AtomicStampedPtr - an AtomicTaggedPtr with a 16 bit stamp in the
+//       high bits, against ABA.
+//
+// Description: A CAS only compares the word, so it can't tell that the node
+//              it expects was freed, reallocated and pushed back at the same
+//              address in between (the ABA problem). Java's
+//              AtomicStampedReference pairs the reference with a counter;
+//              here the counter is in the 16 high bits of the same word, the
+//              ones a 48 bit canonical address doesn't use (see
+//              HighTaggedRef), so no double word CAS is needed:
+//
+//                 bits 0..2   - flag a, flag b
+//                 bits 2..48  - the address
+//                 bits 48..64 - the stamp
+//
+//              Every successful compare_exchange and every store bumps the
+//              stamp, so a CAS that expects the old stamp fails even if the
+//              address and the flags came back. The stamp wraps at 2^16, the
+//              stalled thread would have to miss exactly 65536 updates to be
+//              fooled. Every address packed into the word is checked: new(),
+//              store() and the compare_exchanges panic on one that doesn't
+//              fit in 48 bits, like HighTaggedRef.
+
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::high_tagged_ref::{canonical, ADDR_MASK, TAG_SHIFT};
+use crate::ref_with_2_flags::RefWith2Flags;
+
+pub struct AtomicStampedPtr<'a, T> {
+    word: AtomicPtr<T>,
+    behaves_like: PhantomData<RefWith2Flags<'a, T>>,
+}
+
+impl<'a, T: 'a> AtomicStampedPtr<'a, T> {
+
+    /// Panics if the address isn't a canonical 48 bit address.
+    pub fn new(value: RefWith2Flags<'a, T>, stamp: u16) -> AtomicStampedPtr<'a, T> {
+        AtomicStampedPtr { word: AtomicPtr::new(Self::pack(value.tagged_ptr(), stamp)), behaves_like: PhantomData }
+    }
+
+    pub fn load(&self, order: Ordering) -> (RefWith2Flags<'a, T>, u16) {
+        Self::unpack(self.word.load(order))
+    }
+
+    /// Stores `value` with the next stamp, returns the stamp it stored.
+    /// Panics like new().
+    pub fn store(&self, value: RefWith2Flags<'a, T>, order: Ordering) -> u16 {
+        let fetch_order = match order {
+            Ordering::Release => Ordering::Relaxed,
+            Ordering::AcqRel => Ordering::Acquire,
+            order => order,
+        };
+        let previous = self
+            .word
+            .fetch_update(order, fetch_order, |word| Some(Self::pack(value.tagged_ptr(), Self::stamp_of(word).wrapping_add(1))))
+            .unwrap();
+        Self::stamp_of(previous).wrapping_add(1)
+    }
+
+    /// Stores `new` with the stamp after `stamp` if the current value is
+    /// `current` with `stamp`: same address, same flags and same stamp.
+    /// Returns the previous value and stamp on success and the actual ones
+    /// on failure. Panics like new().
+    pub fn compare_exchange(
+        &self,
+        current: &RefWith2Flags<'a, T>,
+        stamp: u16,
+        new: RefWith2Flags<'a, T>,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<(RefWith2Flags<'a, T>, u16), (RefWith2Flags<'a, T>, u16)> {
+        self.word
+            .compare_exchange(
+                Self::pack(current.tagged_ptr(), stamp),
+                Self::pack(new.tagged_ptr(), stamp.wrapping_add(1)),
+                success,
+                failure,
+            )
+            .map(Self::unpack)
+            .map_err(Self::unpack)
+    }
+
+    /// Like compare_exchange, but may fail spuriously, for CAS loops.
+    pub fn compare_exchange_weak(
+        &self,
+        current: &RefWith2Flags<'a, T>,
+        stamp: u16,
+        new: RefWith2Flags<'a, T>,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<(RefWith2Flags<'a, T>, u16), (RefWith2Flags<'a, T>, u16)> {
+        self.word
+            .compare_exchange_weak(
+                Self::pack(current.tagged_ptr(), stamp),
+                Self::pack(new.tagged_ptr(), stamp.wrapping_add(1)),
+                success,
+                failure,
+            )
+            .map(Self::unpack)
+            .map_err(Self::unpack)
+    }
+
+    pub fn into_inner(self) -> (RefWith2Flags<'a, T>, u16) {
+        Self::unpack(self.word.into_inner())
+    }
+
+    fn pack(ptr: *mut T, stamp: u16) -> *mut T {
+        assert!(canonical(ptr.addr()) == ptr.addr(), "address doesn't fit in 48 bits");
+        ptr.map_addr(|addr| (addr & ADDR_MASK) | ((stamp as usize) << TAG_SHIFT))
+    }
+
+    fn unpack(word: *mut T) -> (RefWith2Flags<'a, T>, u16) {
+        // The low 48 bits, flags included, back to the canonical form.
+        let ptr = word.map_addr(|addr| canonical(addr & ADDR_MASK));
+        (unsafe { RefWith2Flags::from_tagged_ptr(ptr) }, Self::stamp_of(word))
+    }
+
+    fn stamp_of(word: *mut T) -> u16 {
+        (word.addr() >> TAG_SHIFT) as u16
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aligned_box::Align16;
+
+    #[test]
+    fn the_stamp_catches_aba() {
+        let cells = [Align16(0u16), Align16(1), Align16(2)];
+        let top = AtomicStampedPtr::new(RefWith2Flags::new(&cells[0], false, false), 0);
+        let (seen, stamp) = top.load(Ordering::Acquire);
+        top.store(RefWith2Flags::new(&cells[1], false, false), Ordering::Release);
+        assert_eq!(top.store(seen, Ordering::Release), 2);
+        let stale = top.compare_exchange(&seen, stamp, RefWith2Flags::new(&cells[2], true, false), Ordering::AcqRel, Ordering::Acquire);
+        assert!(matches!(stale, Err((ref actual, 2)) if actual.ptr_and_flags_eq(&seen)));
+        let fresh = top.compare_exchange(&seen, 2, RefWith2Flags::new(&cells[2], true, false), Ordering::AcqRel, Ordering::Acquire);
+        assert!(fresh.is_ok());
+        let (now, stamp) = top.load(Ordering::Acquire);
+        assert!(std::ptr::eq(now.get_ref(), &cells[2]) && now.get_flag_a() && stamp == 3);
+    }
+
+    #[test]
+    fn the_stamp_wraps() {
+        let cell = Align16(7u16);
+        let top = AtomicStampedPtr::new(RefWith2Flags::new(&cell, true, true), u16::MAX);
+        assert_eq!(top.store(RefWith2Flags::new(&cell, false, true), Ordering::Release), 0);
+        let (value, stamp) = top.load(Ordering::Acquire);
+        assert!(std::ptr::eq(value.get_ref(), &cell) && !value.get_flag_a() && value.get_flag_b() && stamp == 0);
+        let weak = loop {
+            if let Ok(previous) = top.compare_exchange_weak(&value, stamp, value, Ordering::AcqRel, Ordering::Acquire) {
+                break previous;
+            }
+        };
+        assert_eq!(weak.1, 0);
+        assert_eq!(top.into_inner().1, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "address doesn't fit in 48 bits")]
+    fn non_canonical_stores_panic() {
+        let cell = Align16(7u16);
+        let top = AtomicStampedPtr::new(RefWith2Flags::new(&cell, false, false), 0);
+        // Never dereferenced, the store must refuse it first.
+        let high = unsafe { RefWith2Flags::<Align16<u16>>::from_tagged_ptr(std::ptr::without_provenance_mut(1 << 60)) };
+        top.store(high, Ordering::Release);
+    }
+}
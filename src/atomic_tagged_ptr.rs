@@ -0,0 +1,141 @@
+// Name: AtomicTaggedPtr - an atomic RefWith2Flags.
+//
+// Description: The (reference, flag_a, flag_b) triple in a single AtomicPtr.
+//              Every operation reads or writes the whole word at once, so a
+//              reader never sees the address of one value with the flags of
+//              another, and compare_exchange only succeeds if both the
+//              address and the flags are the expected ones. That is how lock
+//              free lists mark a node as logically deleted: the CAS that
+//              unlinks it fails if someone marked it in between.
+//
+//              Unlike AtomicOptionTaggedPtr there is always a reference in
+//              it, there is no empty state. Otherwise the 2 APIs are the same,
+//              fetch_update included.
+
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::ref_with_2_flags::RefWith2Flags;
+
+pub struct AtomicTaggedPtr<'a, T> {
+    word: AtomicPtr<T>,
+    behaves_like: PhantomData<RefWith2Flags<'a, T>>,
+}
+
+impl<'a, T: 'a> AtomicTaggedPtr<'a, T> {
+
+    pub fn new(value: RefWith2Flags<'a, T>) -> AtomicTaggedPtr<'a, T> {
+        AtomicTaggedPtr { word: AtomicPtr::new(value.tagged_ptr()), behaves_like: PhantomData }
+    }
+
+    pub fn load(&self, order: Ordering) -> RefWith2Flags<'a, T> {
+        unsafe { RefWith2Flags::from_tagged_ptr(self.word.load(order)) }
+    }
+
+    pub fn store(&self, value: RefWith2Flags<'a, T>, order: Ordering) {
+        self.word.store(value.tagged_ptr(), order);
+    }
+
+    /// Stores `value`, returns the previous one.
+    pub fn swap(&self, value: RefWith2Flags<'a, T>, order: Ordering) -> RefWith2Flags<'a, T> {
+        unsafe { RefWith2Flags::from_tagged_ptr(self.word.swap(value.tagged_ptr(), order)) }
+    }
+
+    /// Stores `new` if the current value is `current`, same address and same
+    /// flags. Returns the previous value on success and the actual one on
+    /// failure.
+    pub fn compare_exchange(
+        &self,
+        current: &RefWith2Flags<'a, T>,
+        new: RefWith2Flags<'a, T>,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<RefWith2Flags<'a, T>, RefWith2Flags<'a, T>> {
+        self.word
+            .compare_exchange(current.tagged_ptr(), new.tagged_ptr(), success, failure)
+            .map(|ptr| unsafe { RefWith2Flags::from_tagged_ptr(ptr) })
+            .map_err(|ptr| unsafe { RefWith2Flags::from_tagged_ptr(ptr) })
+    }
+
+    /// Like compare_exchange, but may fail spuriously, for CAS loops.
+    pub fn compare_exchange_weak(
+        &self,
+        current: &RefWith2Flags<'a, T>,
+        new: RefWith2Flags<'a, T>,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<RefWith2Flags<'a, T>, RefWith2Flags<'a, T>> {
+        self.word
+            .compare_exchange_weak(current.tagged_ptr(), new.tagged_ptr(), success, failure)
+            .map(|ptr| unsafe { RefWith2Flags::from_tagged_ptr(ptr) })
+            .map_err(|ptr| unsafe { RefWith2Flags::from_tagged_ptr(ptr) })
+    }
+
+    /// Like `AtomicUsize::fetch_update`, on the decoded view: `f` gets the
+    /// current value and returns the new one, or None to give up. Retried
+    /// until the CAS succeeds, returns the previous value, or the last one
+    /// seen if `f` gave up.
+    pub fn fetch_update<F>(
+        &self,
+        set_order: Ordering,
+        fetch_order: Ordering,
+        mut f: F,
+    ) -> Result<RefWith2Flags<'a, T>, RefWith2Flags<'a, T>>
+    where
+        F: FnMut(RefWith2Flags<'a, T>) -> Option<RefWith2Flags<'a, T>>,
+    {
+        self.word
+            .fetch_update(set_order, fetch_order, |ptr| f(unsafe { RefWith2Flags::from_tagged_ptr(ptr) }).map(|new| new.tagged_ptr()))
+            .map(|ptr| unsafe { RefWith2Flags::from_tagged_ptr(ptr) })
+            .map_err(|ptr| unsafe { RefWith2Flags::from_tagged_ptr(ptr) })
+    }
+
+    pub fn into_inner(self) -> RefWith2Flags<'a, T> {
+        unsafe { RefWith2Flags::from_tagged_ptr(self.word.into_inner()) }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aligned_box::Align16;
+    use std::thread;
+
+    #[test]
+    fn fetch_update_toggles_the_flags() {
+        let value = 7u32;
+        let slot = AtomicTaggedPtr::new(RefWith2Flags::new(&value, false, true));
+        let toggled = slot.fetch_update(Ordering::AcqRel, Ordering::Acquire, |current| {
+            Some(RefWith2Flags::new(current.get_ref(), !current.get_flag_a(), current.get_flag_b()))
+        });
+        assert!(matches!(toggled, Ok(ref r) if !r.get_flag_a()));
+        assert!(slot.load(Ordering::Acquire).get_flag_a());
+        // Gives up on a marked reference, the slot is left as it was.
+        let refused = slot.fetch_update(Ordering::AcqRel, Ordering::Acquire, |current| (!current.get_flag_a()).then_some(current));
+        assert!(matches!(refused, Err(ref r) if r.get_flag_a() && r.get_flag_b() && *r.get_ref() == 7));
+    }
+
+    #[test]
+    fn one_thread_wins_the_mark() {
+        let cells: Vec<Align16<u16>> = (0..10).map(Align16).collect();
+        let next = AtomicTaggedPtr::new(RefWith2Flags::new(&cells[8], false, false));
+        let winners: usize = thread::scope(|s| {
+            let racers: Vec<_> = (0..4)
+                .map(|_| {
+                    s.spawn(|| {
+                        let seen = next.load(Ordering::Acquire);
+                        let marked = RefWith2Flags::new(seen.get_ref(), true, seen.get_flag_b());
+                        !seen.get_flag_a() && next.compare_exchange(&seen, marked, Ordering::AcqRel, Ordering::Acquire).is_ok()
+                    })
+                })
+                .collect();
+            racers.into_iter().map(|r| r.join().unwrap() as usize).sum()
+        });
+        assert_eq!(winners, 1);
+        let old = next.swap(RefWith2Flags::new(&cells[9], false, true), Ordering::AcqRel);
+        assert!(old.get_flag_a() && old.get_ref().0 == 8);
+        let last = next.into_inner();
+        assert!(last.get_flag_b() && last.get_ref().0 == 9);
+    }
+}
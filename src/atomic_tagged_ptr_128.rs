@@ -0,0 +1,164 @@
+// Name: AtomicTaggedPtr128 - a tagged reference and a 64 bit version in one
+//       128 bit atomic, with cmpxchg16b.
+//
+// Description: AtomicStampedPtr fits its stamp in the 16 spare high bits, and
+//              a 16 bit stamp can wrap while a thread is preempted. x86_64
+//              can compare and swap 2 words at once, cmpxchg16b, so this is
+//              the whole RefWith2Flags word, flags included, next to a full
+//              u64 version, in a 16 bytes aligned pair:
+//
+//                 word 0 - the address and flag a, flag b
+//                 word 1 - the version
+//
+//              Every successful compare_exchange and every store bumps the
+//              version, a wrap takes 2^64 updates. AtomicU128 is not stable,
+//              so the CAS is the instruction itself, in asm, and a load is a
+//              CAS that writes back the value it read. cmpxchg16b is missing
+//              on the very first x86_64 CPUs, new() checks for it at run
+//              time and panics without it.
+//
+//              The address goes through the asm as an integer, so it is
+//              exposed (expose_provenance) on the way in and picked up again
+//              (with_exposed_provenance) on the way out.
+
+use std::arch::asm;
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::sync::atomic::Ordering;
+
+use crate::ref_with_2_flags::RefWith2Flags;
+
+#[repr(C, align(16))]
+struct Pair {
+    word: u64,
+    version: u64,
+}
+
+pub struct AtomicTaggedPtr128<'a, T> {
+    pair: UnsafeCell<Pair>,
+    behaves_like: PhantomData<RefWith2Flags<'a, T>>,
+}
+
+// Every access to the pair is a lock cmpxchg16b, an atomic.
+unsafe impl<'a, T: Sync> Send for AtomicTaggedPtr128<'a, T> {}
+unsafe impl<'a, T: Sync> Sync for AtomicTaggedPtr128<'a, T> {}
+
+impl<'a, T: 'a> AtomicTaggedPtr128<'a, T> {
+
+    /// Panics if the CPU doesn't have cmpxchg16b.
+    pub fn new(value: RefWith2Flags<'a, T>, version: u64) -> AtomicTaggedPtr128<'a, T> {
+        assert!(std::is_x86_feature_detected!("cmpxchg16b"), "cmpxchg16b is not supported by this CPU");
+        let word = value.tagged_ptr().expose_provenance() as u64;
+        AtomicTaggedPtr128 { pair: UnsafeCell::new(Pair { word, version }), behaves_like: PhantomData }
+    }
+
+    /// cmpxchg16b is a full barrier, every ordering is SeqCst.
+    pub fn load(&self, _order: Ordering) -> (RefWith2Flags<'a, T>, u64) {
+        // The word is never 0 (a reference), so the CAS never matches and
+        // only reads.
+        let (word, version) = self.cmpxchg16b((0, 0), (0, 0));
+        Self::unpack(word, version)
+    }
+
+    /// Stores `value` with the next version, returns the version it stored.
+    pub fn store(&self, value: RefWith2Flags<'a, T>, order: Ordering) -> u64 {
+        let (mut current, mut version) = self.load(order);
+        loop {
+            match self.compare_exchange(&current, version, value, order, order) {
+                Ok(_) => return version.wrapping_add(1),
+                Err(actual) => (current, version) = actual,
+            }
+        }
+    }
+
+    /// Stores `new` with the version after `version` if the current value is
+    /// `current` with `version`: same address, same flags and same version.
+    /// Returns the previous value and version on success and the actual ones
+    /// on failure. cmpxchg16b is a full barrier, every ordering is SeqCst.
+    pub fn compare_exchange(
+        &self,
+        current: &RefWith2Flags<'a, T>,
+        version: u64,
+        new: RefWith2Flags<'a, T>,
+        _success: Ordering,
+        _failure: Ordering,
+    ) -> Result<(RefWith2Flags<'a, T>, u64), (RefWith2Flags<'a, T>, u64)> {
+        let expected = (current.tagged_ptr().expose_provenance() as u64, version);
+        let new = (new.tagged_ptr().expose_provenance() as u64, version.wrapping_add(1));
+        let previous = self.cmpxchg16b(expected, new);
+        let unpacked = Self::unpack(previous.0, previous.1);
+        if previous == expected { Ok(unpacked) } else { Err(unpacked) }
+    }
+
+    pub fn into_inner(self) -> (RefWith2Flags<'a, T>, u64) {
+        let pair = self.pair.into_inner();
+        Self::unpack(pair.word, pair.version)
+    }
+
+    // Returns the pair that was in memory, it holds `new` now if that was
+    // `expected`.
+    fn cmpxchg16b(&self, expected: (u64, u64), new: (u64, u64)) -> (u64, u64) {
+        let (previous_word, previous_version);
+        // rbx is reserved by LLVM, so the low half of `new` goes through
+        // another register and is swapped in and out around the instruction.
+        unsafe {
+            asm!(
+                "xchg {new_word}, rbx",
+                "lock cmpxchg16b xmmword ptr [{pair}]",
+                "mov rbx, {new_word}",
+                pair = in(reg) self.pair.get(),
+                new_word = inout(reg) new.0 => _,
+                in("rcx") new.1,
+                inout("rax") expected.0 => previous_word,
+                inout("rdx") expected.1 => previous_version,
+                options(nostack),
+            );
+        }
+        (previous_word, previous_version)
+    }
+
+    fn unpack(word: u64, version: u64) -> (RefWith2Flags<'a, T>, u64) {
+        let ptr = std::ptr::with_exposed_provenance_mut::<T>(word as usize);
+        (unsafe { RefWith2Flags::from_tagged_ptr(ptr) }, version)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aligned_box::Align16;
+    use std::thread;
+
+    #[test]
+    fn concurrent_stores_bump_the_version_once_each() {
+        let slots = [7u32, 8];
+        let ptr = AtomicTaggedPtr128::new(RefWith2Flags::new(&slots[0], false, false), 0);
+        thread::scope(|s| {
+            for t in 0..4 {
+                let (ptr, slots) = (&ptr, &slots);
+                s.spawn(move || {
+                    for i in 0..1000 {
+                        ptr.store(RefWith2Flags::new(&slots[(t + i) % 2], i % 2 == 0, t % 2 == 0), Ordering::SeqCst);
+                    }
+                });
+            }
+        });
+        let (last, version) = ptr.load(Ordering::SeqCst);
+        assert_eq!(version, 4000);
+        assert!(slots.iter().any(|slot| std::ptr::eq(last.get_ref(), slot)));
+    }
+
+    #[test]
+    fn the_version_wraps_without_aba() {
+        let cells = [Align16(0u16), Align16(1)];
+        let free_head = AtomicTaggedPtr128::new(RefWith2Flags::new(&cells[0], false, false), u64::MAX - 1);
+        let (seen, version) = free_head.load(Ordering::Acquire);
+        let swung = free_head.compare_exchange(&seen, version, RefWith2Flags::new(&cells[1], false, true), Ordering::AcqRel, Ordering::Acquire);
+        assert!(swung.is_ok());
+        assert!(free_head.compare_exchange(&seen, version, seen, Ordering::AcqRel, Ordering::Acquire).is_err());
+        assert_eq!(free_head.store(seen, Ordering::Release), 0);
+        let (now, version) = free_head.into_inner();
+        assert!(std::ptr::eq(now.get_ref(), &cells[0]) && version == 0);
+    }
+}
@@ -0,0 +1,411 @@
+// Name: AvlTreeMap - an AVL tree map whose balance factors live in the left
+//       child pointers.
+//
+// Description: The balance factor of an AVL node, the height of its right
+//              subtree minus the height of its left one, is -1, 0 or +1, 3
+//              values, 2 bits. A node is at least 4 bytes aligned, so they
+//              go in the 2 free low bits of its left link, and the node is
+//              its 2 links plus the key and the value, no height or balance
+//              field padded to a word:
+//
+//                 0 - EVEN        : both subtrees have the same height.
+//                 1 - LEFT_HEAVY  : the left one is 1 higher.
+//                 2 - RIGHT_HEAVY : the right one is 1 higher.
+//
+//              Balance implements TagEnum, like gc::Color. Setting the left
+//              link keeps the balance bits, and setting the balance keeps the
+//              link. There are no parent links: insert() and remove() recurse
+//              down and report on the way up whether the subtree grew or
+//              shrank, the rotations happen where a factor would reach +-2.
+
+use std::cmp::Ordering;
+use std::marker::PhantomData;
+use std::mem;
+use std::ptr;
+
+use crate::ref_with_tag::TagEnum;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Balance {
+    Even,
+    LeftHeavy,
+    RightHeavy,
+}
+
+impl TagEnum for Balance {
+    const BITS: usize = 2;
+
+    fn to_bits(self) -> usize {
+        self as usize
+    }
+
+    fn from_bits(bits: usize) -> Balance {
+        match bits {
+            0 => Balance::Even,
+            1 => Balance::LeftHeavy,
+            _ => Balance::RightHeavy,
+        }
+    }
+}
+
+const BITS: usize = 3;
+
+#[repr(align(4))]
+struct Node<K, V> {
+    // Address of the left child, plus the balance.
+    left: *mut Node<K, V>,
+    right: *mut Node<K, V>,
+    key: K,
+    value: V,
+}
+
+type Link<K, V> = *mut Node<K, V>;
+
+pub struct AvlTreeMap<K, V> {
+    root: Link<K, V>,
+    len: usize,
+    owns: PhantomData<Box<Node<K, V>>>,
+}
+
+impl<K: Ord, V> AvlTreeMap<K, V> {
+
+    pub fn new() -> AvlTreeMap<K, V> {
+        AvlTreeMap { root: ptr::null_mut(), len: 0, owns: PhantomData }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        let node = self.find(key)?;
+        Some(unsafe { &(*node).value })
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let node = self.find(key)?;
+        Some(unsafe { &mut (*node).value })
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.find(key).is_some()
+    }
+
+    /// Returns the previous value of `key`, if there was one.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let mut previous = None;
+        self.root = unsafe { insert_at(self.root, key, value, &mut previous).0 };
+        if previous.is_none() {
+            self.len += 1;
+        }
+        previous
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let (root, _, removed) = unsafe { remove_at(self.root, key) };
+        self.root = root;
+        let node = unsafe { Box::from_raw(removed?) };
+        self.len -= 1;
+        Some(node.value)
+    }
+
+    /// The entries in key order.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> + '_ {
+        let mut stack = Vec::new();
+        let mut next = self.root;
+        std::iter::from_fn(move || {
+            while !next.is_null() {
+                stack.push(next);
+                next = left(next);
+            }
+            let node = stack.pop()?;
+            unsafe {
+                next = (*node).right;
+                Some((&(*node).key, &(*node).value))
+            }
+        })
+    }
+
+    fn find(&self, key: &K) -> Option<Link<K, V>> {
+        let mut x = self.root;
+        while !x.is_null() {
+            x = match key.cmp(unsafe { &(*x).key }) {
+                Ordering::Less => left(x),
+                Ordering::Greater => unsafe { (*x).right },
+                Ordering::Equal => return Some(x),
+            };
+        }
+        None
+    }
+
+}
+
+impl<K: Ord, V> Default for AvlTreeMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> Drop for AvlTreeMap<K, V> {
+    fn drop(&mut self) {
+        let mut stack = vec![self.root];
+        while let Some(x) = stack.pop() {
+            if !x.is_null() {
+                stack.push(left(x));
+                let node = unsafe { Box::from_raw(x) };
+                stack.push(node.right);
+            }
+        }
+    }
+}
+
+fn left<K, V>(x: Link<K, V>) -> Link<K, V> {
+    unsafe { (*x).left.map_addr(|addr| addr & !BITS) }
+}
+
+// Keeps the balance.
+unsafe fn set_left<K, V>(x: Link<K, V>, child: Link<K, V>) {
+    let bits = (*x).left.addr() & BITS;
+    (*x).left = child.map_addr(|addr| addr | bits);
+}
+
+fn balance<K, V>(x: Link<K, V>) -> Balance {
+    Balance::from_bits(unsafe { (*x).left.addr() } & BITS)
+}
+
+// Keeps the left child.
+unsafe fn set_balance<K, V>(x: Link<K, V>, balance: Balance) {
+    (*x).left = (*x).left.map_addr(|addr| (addr & !BITS) | balance.to_bits());
+}
+
+// Returns the new root of the subtree and whether it grew.
+unsafe fn insert_at<K: Ord, V>(x: Link<K, V>, key: K, value: V, previous: &mut Option<V>) -> (Link<K, V>, bool) {
+    if x.is_null() {
+        let node = Box::new(Node { left: ptr::null_mut(), right: ptr::null_mut(), key, value });
+        return (Box::into_raw(node), true);
+    }
+    match key.cmp(&(*x).key) {
+        Ordering::Equal => {
+            *previous = Some(mem::replace(&mut (*x).value, value));
+            (x, false)
+        }
+        Ordering::Less => {
+            let (child, grew) = insert_at(left(x), key, value, previous);
+            set_left(x, child);
+            if !grew {
+                return (x, false);
+            }
+            match balance(x) {
+                Balance::RightHeavy => {
+                    set_balance(x, Balance::Even);
+                    (x, false)
+                }
+                Balance::Even => {
+                    set_balance(x, Balance::LeftHeavy);
+                    (x, true)
+                }
+                // An insertion never leaves the child even, the rotation
+                // brings the height back.
+                Balance::LeftHeavy => (fix_left_heavy(x).0, false),
+            }
+        }
+        Ordering::Greater => {
+            let (child, grew) = insert_at((*x).right, key, value, previous);
+            (*x).right = child;
+            if !grew {
+                return (x, false);
+            }
+            match balance(x) {
+                Balance::LeftHeavy => {
+                    set_balance(x, Balance::Even);
+                    (x, false)
+                }
+                Balance::Even => {
+                    set_balance(x, Balance::RightHeavy);
+                    (x, true)
+                }
+                Balance::RightHeavy => (fix_right_heavy(x).0, false),
+            }
+        }
+    }
+}
+
+// Returns the new root of the subtree, whether it shrank and the unlinked
+// node, if `key` was found.
+unsafe fn remove_at<K: Ord, V>(x: Link<K, V>, key: &K) -> (Link<K, V>, bool, Option<Link<K, V>>) {
+    if x.is_null() {
+        return (x, false, None);
+    }
+    match key.cmp(&(*x).key) {
+        Ordering::Less => {
+            let (child, shrank, removed) = remove_at(left(x), key);
+            set_left(x, child);
+            let (x, shrank) = if shrank { left_shrank(x) } else { (x, false) };
+            (x, shrank, removed)
+        }
+        Ordering::Greater => {
+            let (child, shrank, removed) = remove_at((*x).right, key);
+            (*x).right = child;
+            let (x, shrank) = if shrank { right_shrank(x) } else { (x, false) };
+            (x, shrank, removed)
+        }
+        Ordering::Equal if left(x).is_null() => ((*x).right, true, Some(x)),
+        Ordering::Equal if (*x).right.is_null() => (left(x), true, Some(x)),
+        Ordering::Equal => {
+            // The successor takes the place of x, with its balance.
+            let (right, shrank, successor) = remove_min((*x).right);
+            (*successor).left = (*x).left;
+            (*successor).right = right;
+            let (successor, shrank) = if shrank { right_shrank(successor) } else { (successor, false) };
+            (successor, shrank, Some(x))
+        }
+    }
+}
+
+// Returns the new root of the subtree, whether it shrank and the unlinked
+// minimum.
+unsafe fn remove_min<K, V>(x: Link<K, V>) -> (Link<K, V>, bool, Link<K, V>) {
+    if left(x).is_null() {
+        return ((*x).right, true, x);
+    }
+    let (child, shrank, min) = remove_min(left(x));
+    set_left(x, child);
+    let (x, shrank) = if shrank { left_shrank(x) } else { (x, false) };
+    (x, shrank, min)
+}
+
+// The left subtree of x shrank, returns the new root and whether it shrank.
+unsafe fn left_shrank<K, V>(x: Link<K, V>) -> (Link<K, V>, bool) {
+    match balance(x) {
+        Balance::LeftHeavy => {
+            set_balance(x, Balance::Even);
+            (x, true)
+        }
+        Balance::Even => {
+            set_balance(x, Balance::RightHeavy);
+            (x, false)
+        }
+        Balance::RightHeavy => {
+            let (root, same_height) = fix_right_heavy(x);
+            (root, !same_height)
+        }
+    }
+}
+
+unsafe fn right_shrank<K, V>(x: Link<K, V>) -> (Link<K, V>, bool) {
+    match balance(x) {
+        Balance::RightHeavy => {
+            set_balance(x, Balance::Even);
+            (x, true)
+        }
+        Balance::Even => {
+            set_balance(x, Balance::LeftHeavy);
+            (x, false)
+        }
+        Balance::LeftHeavy => {
+            let (root, same_height) = fix_left_heavy(x);
+            (root, !same_height)
+        }
+    }
+}
+
+// x is left heavy and its left subtree just got 1 higher, a factor of -2
+// that the bits can't hold. Rotates, returns the new root of the subtree and
+// whether it kept its height (only when the left child was even, which
+// happens on removals).
+unsafe fn fix_left_heavy<K, V>(x: Link<K, V>) -> (Link<K, V>, bool) {
+    let l = left(x);
+    match balance(l) {
+        Balance::RightHeavy => {
+            let g = (*l).right;
+            let g_balance = balance(g);
+            (*l).right = left(g);
+            set_left(x, (*g).right);
+            set_left(g, l);
+            (*g).right = x;
+            set_balance(x, if g_balance == Balance::LeftHeavy { Balance::RightHeavy } else { Balance::Even });
+            set_balance(l, if g_balance == Balance::RightHeavy { Balance::LeftHeavy } else { Balance::Even });
+            set_balance(g, Balance::Even);
+            (g, false)
+        }
+        l_balance => {
+            set_left(x, (*l).right);
+            (*l).right = x;
+            let even = l_balance == Balance::Even;
+            set_balance(x, if even { Balance::LeftHeavy } else { Balance::Even });
+            set_balance(l, if even { Balance::RightHeavy } else { Balance::Even });
+            (l, even)
+        }
+    }
+}
+
+unsafe fn fix_right_heavy<K, V>(x: Link<K, V>) -> (Link<K, V>, bool) {
+    let r = (*x).right;
+    match balance(r) {
+        Balance::LeftHeavy => {
+            let g = left(r);
+            let g_balance = balance(g);
+            set_left(r, (*g).right);
+            (*x).right = left(g);
+            (*g).right = r;
+            set_left(g, x);
+            set_balance(x, if g_balance == Balance::RightHeavy { Balance::LeftHeavy } else { Balance::Even });
+            set_balance(r, if g_balance == Balance::LeftHeavy { Balance::RightHeavy } else { Balance::Even });
+            set_balance(g, Balance::Even);
+            (g, false)
+        }
+        r_balance => {
+            (*x).right = left(r);
+            set_left(r, x);
+            let even = r_balance == Balance::Even;
+            set_balance(x, if even { Balance::RightHeavy } else { Balance::Even });
+            set_balance(r, if even { Balance::LeftHeavy } else { Balance::Even });
+            (r, even)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    // Height of the subtree, checking every balance factor on the way.
+    fn check(x: Link<u32, u32>) -> isize {
+        if x.is_null() {
+            return 0;
+        }
+        let (left_height, right_height) = (check(left(x)), check(unsafe { (*x).right }));
+        let expected = match right_height - left_height {
+            0 => Balance::Even,
+            -1 => Balance::LeftHeavy,
+            1 => Balance::RightHeavy,
+            factor => panic!("balance factor {factor}"),
+        };
+        assert_eq!(balance(x), expected);
+        1 + left_height.max(right_height)
+    }
+
+    #[test]
+    fn matches_btree_map_and_stays_balanced() {
+        let mut tree = AvlTreeMap::new();
+        let mut model = BTreeMap::new();
+        let mut seed = 54321u32;
+        for _ in 0..5000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let key = (seed >> 16) % 500;
+            if seed & 1 == 0 {
+                assert_eq!(tree.insert(key, seed), model.insert(key, seed));
+            } else {
+                assert_eq!(tree.remove(&key), model.remove(&key));
+            }
+            check(tree.root);
+        }
+        assert_eq!(tree.len(), model.len());
+        assert!(tree.iter().eq(model.iter()));
+    }
+}
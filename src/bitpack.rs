@@ -0,0 +1,140 @@
+// Name: bitpack - the raw arithmetic behind the tagged pointers.
+//
+// Description: Packing a tag in the low bits of an aligned address is only
+//              a mask, an or and an and. These const fns expose just that,
+//              without the lifetime carrying wrappers, for code that wants
+//              to do its own packing of addresses and small tags.
+//
+//              width is the number of low bits used for the tag, an address
+//              aligned to align has log2(align) free low bits.
+//
+//              untag_ptr() is unpack() for a tagged pointer kept as a
+//              pointer: it clears the bits with map_addr, so the pointer
+//              keeps its provenance.
+
+/// Mask with the `width` low bits set.
+pub const fn mask_for(width: u32) -> usize {
+    assert!(width < usize::BITS, "tag width too big");
+    (1 << width) - 1
+}
+
+/// True if an address aligned to `align` has at least `width` free low bits.
+pub const fn align_supports(align: usize, width: u32) -> bool {
+    align.is_power_of_two() && align.trailing_zeros() >= width
+}
+
+/// Number of free low bits in the addresses of a `T`, log2 of its alignment.
+pub const fn spare_bits<T>() -> usize {
+    core::mem::align_of::<T>().trailing_zeros() as usize
+}
+
+/// Puts `bits` in the `width` low bits of `addr`.
+pub const fn pack(addr: usize, bits: usize, width: u32) -> usize {
+    let mask = mask_for(width);
+    assert!(addr & mask == 0, "address not aligned for this tag width");
+    assert!(bits & !mask == 0, "tag doesn't fit in this width");
+    addr | bits
+}
+
+/// Splits a packed word back into (address, bits).
+pub const fn unpack(word: usize, width: u32) -> (usize, usize) {
+    let mask = mask_for(width);
+    (word & !mask, word & mask)
+}
+
+/// `ptr` with its `width` low bits cleared, keeping its provenance.
+pub fn untag_ptr<T>(ptr: *mut T, width: u32) -> *mut T {
+    let mask = mask_for(width);
+    ptr.map_addr(|addr| addr & !mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mask_for_every_width() {
+        for width in 0..usize::BITS {
+            let mask = mask_for(width);
+            assert_eq!(mask.count_ones(), width);
+            assert_eq!(mask.trailing_ones(), width);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn mask_for_full_word_panics() {
+        mask_for(usize::BITS);
+    }
+
+    #[test]
+    fn align_supports_every_power_of_two() {
+        for shift in 0..usize::BITS {
+            let align = 1_usize << shift;
+            for width in 0..usize::BITS {
+                assert_eq!(align_supports(align, width), width <= shift);
+            }
+        }
+        assert!(!align_supports(0, 0));
+        assert!(!align_supports(12, 2));
+    }
+
+    #[test]
+    fn pack_unpack_round_trip() {
+        for width in 0..=8 {
+            let align = 1_usize << width;
+            for addr in (0..16).map(|i| i * align + 0x1000) {
+                for bits in 0..=mask_for(width) {
+                    let word = pack(addr, bits, width);
+                    assert_eq!(unpack(word, width), (addr, bits));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pack_keeps_high_addresses() {
+        let addr = usize::MAX & !mask_for(3);
+        assert_eq!(unpack(pack(addr, 5, 3), 3), (addr, 5));
+    }
+
+    #[test]
+    #[should_panic]
+    fn pack_rejects_unaligned_address() {
+        pack(0x1002, 0, 2);
+    }
+
+    #[test]
+    #[should_panic]
+    fn pack_rejects_wide_tag() {
+        pack(0x1000, 4, 2);
+    }
+
+    #[test]
+    fn usable_in_const_context() {
+        const WORD: usize = pack(0x1000, 3, 2);
+        const PARTS: (usize, usize) = unpack(WORD, 2);
+        assert_eq!(PARTS, (0x1000, 3));
+    }
+
+    #[test]
+    fn spare_bits_follow_alignment() {
+        #[repr(align(64))]
+        struct Line;
+        assert_eq!(spare_bits::<u8>(), 0);
+        assert_eq!(spare_bits::<u32>(), 2);
+        assert_eq!(spare_bits::<Line>(), 6);
+        assert!(align_supports(core::mem::align_of::<Line>(), spare_bits::<Line>() as u32));
+    }
+
+    #[test]
+    fn untag_ptr_clears_only_the_tag() {
+        let mut value = 7_u64;
+        let ptr: *mut u64 = &mut value;
+        let tagged = ptr.map_addr(|addr| addr | 5);
+        assert_eq!(untag_ptr(tagged, 3), ptr);
+        assert_eq!(untag_ptr(ptr, 0), ptr);
+        unsafe { *untag_ptr(tagged, 3) += 1 };
+        assert_eq!(value, 8);
+    }
+}
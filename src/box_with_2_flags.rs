@@ -0,0 +1,124 @@
+// Name: BoxWith2Flags - an owning Box<T> and 2 flags in one word.
+//
+// Description: The owned counterpart of RefWith2Flags. The Box is turned into
+//              a raw pointer with Box::into_raw, the flags go into its low
+//              bits, and Drop rebuilds the Box from the untagged address so
+//              the value is dropped and the allocation freed exactly once.
+//              into_box() gives the Box back without dropping anything.
+//              Box::from_raw gets the very pointer Box::into_raw returned:
+//              the flags are ORed into it and masked off with map_addr, it
+//              never goes through an integer.
+
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+
+use crate::aligned::AlignedAtLeast;
+
+pub struct BoxWith2Flags<T> {
+    ptr_and_bit: *mut T,
+    // Owns a T, for the drop check.
+    behaves_like: PhantomData<Box<T>>,
+}
+
+// The raw pointer opts out of Send and Sync, this is a Box<T> as far as
+// threads go.
+unsafe impl<T: Send> Send for BoxWith2Flags<T> {}
+unsafe impl<T: Sync> Sync for BoxWith2Flags<T> {}
+
+impl<T> BoxWith2Flags<T> {
+
+    pub fn new(boxed: Box<T>, flag_a: bool, flag_b: bool) -> BoxWith2Flags<T>
+    where
+        T: AlignedAtLeast<4>,
+    {
+        BoxWith2Flags {
+            ptr_and_bit: Box::into_raw(boxed).map_addr(|addr| addr | flag_a as usize | ((flag_b as usize) << 1)),
+            behaves_like: PhantomData,
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        unsafe { &*self.ptr() }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.ptr() }
+    }
+
+    pub fn get_flag_a(&self) -> bool {
+        self.ptr_and_bit.addr() & 1 != 0
+    }
+
+    pub fn get_flag_b(&self) -> bool {
+        self.ptr_and_bit.addr() & 2 != 0
+    }
+
+    pub fn set_flag_a(&mut self, flag: bool) {
+        self.ptr_and_bit = self.ptr_and_bit.map_addr(|addr| (addr & !1) | flag as usize);
+    }
+
+    pub fn set_flag_b(&mut self, flag: bool) {
+        self.ptr_and_bit = self.ptr_and_bit.map_addr(|addr| (addr & !2) | ((flag as usize) << 1));
+    }
+
+    /// Gives back the Box, dropping the flags.
+    pub fn into_box(self) -> Box<T> {
+        let this = ManuallyDrop::new(self);
+        unsafe { Box::from_raw(this.ptr()) }
+    }
+
+    fn ptr(&self) -> *mut T {
+        self.ptr_and_bit.map_addr(|addr| addr & !3)
+    }
+
+}
+
+impl<T> Drop for BoxWith2Flags<T> {
+    fn drop(&mut self) {
+        drop(unsafe { Box::from_raw(self.ptr()) });
+    }
+}
+
+impl<T> Deref for BoxWith2Flags<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.get_ref()
+    }
+}
+
+impl<T> DerefMut for BoxWith2Flags<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.get_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aligned_box::Align16;
+    use std::rc::Rc;
+
+    #[test]
+    fn rebuilds_the_box() {
+        let mut owned = BoxWith2Flags::new(Box::new(Align16(3u16)), true, false);
+        owned.0 += 4;
+        owned.set_flag_b(true);
+        assert!(owned.get_flag_a() && owned.get_flag_b());
+        let unboxed = owned.into_box();
+        assert_eq!(unboxed.0, 7);
+        let _dropped = BoxWith2Flags::new(Box::new(String::from("freed on drop")), false, true);
+    }
+
+    #[test]
+    fn dropping_frees_the_value_once() {
+        let counted = Rc::new(());
+        let owned = BoxWith2Flags::new(Box::new(counted.clone()), true, true);
+        assert_eq!(Rc::strong_count(&counted), 2);
+        drop(owned);
+        assert_eq!(Rc::strong_count(&counted), 1);
+        let kept = BoxWith2Flags::new(Box::new(counted.clone()), false, true).into_box();
+        assert_eq!(Rc::strong_count(&kept), 2);
+    }
+}
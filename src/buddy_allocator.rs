@@ -0,0 +1,229 @@
+// Name: BuddyAllocator - a buddy allocator without per block headers.
+//
+// Description: The region is split in blocks of MIN_BLOCK << order bytes.
+//              Every free block keeps, in its own first word, the link to
+//              the next free block of the same order. Blocks are at least
+//              16 bytes aligned, so the link has free low bits and bit 0 is
+//              used as a flag:
+//
+//                 bit 0 - FREE : set on every link of a free list. A link
+//                                without it means that someone wrote into
+//                                a free block (use after free), and it is
+//                                reported instead of followed.
+//
+//              Whether a block was split off a bigger one needs no bit, it
+//              is order < max_order, so every such block has a buddy.
+//
+//              Allocated blocks carry no header at all, the caller gives the
+//              size back on deallocate(), like with std::alloc. The buddy of
+//              a freed block is only looked for in the free list of its
+//              order, so the allocator never reads memory owned by the user.
+//
+//              The links are pointers derived from the region, the flag set
+//              with map_addr, so following a free list never turns an
+//              integer back into a pointer.
+
+use std::alloc::{alloc, dealloc, Layout};
+use std::ptr::{self, NonNull};
+
+pub const MIN_BLOCK: usize = 16;
+
+const FREE: usize = 1;
+
+pub struct BuddyAllocator {
+    base: *mut u8,
+    layout: Layout,
+    max_order: usize,
+    // Untagged first free block of each order, or null.
+    free_heads: Vec<*mut u8>,
+}
+
+impl BuddyAllocator {
+
+    /// Creates an allocator managing one region of `MIN_BLOCK << max_order`
+    /// bytes.
+    pub fn new(max_order: usize) -> BuddyAllocator {
+        let size = MIN_BLOCK << max_order;
+        let layout = Layout::from_size_align(size, size).expect("region too big");
+        let base = unsafe { alloc(layout) };
+        assert!(!base.is_null(), "out of memory");
+        let mut allocator = BuddyAllocator {
+            base,
+            layout,
+            max_order,
+            free_heads: vec![ptr::null_mut(); max_order + 1],
+        };
+        allocator.push(max_order, base);
+        allocator
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.layout.size()
+    }
+
+    /// Number of free blocks of the given order, 0 above max_order.
+    pub fn free_blocks(&self, order: usize) -> usize {
+        let mut count = 0;
+        let mut block = self.free_heads.get(order).copied().unwrap_or(ptr::null_mut());
+        while !block.is_null() {
+            count += 1;
+            block = self.next_of(block);
+        }
+        count
+    }
+
+    pub fn allocate(&mut self, size: usize) -> Option<NonNull<u8>> {
+        let order = self.order_for(size)?;
+        let mut current = (order..=self.max_order).find(|&o| !self.free_heads[o].is_null())?;
+        let block = self.pop(current);
+        while current > order {
+            current -= 1;
+            self.push(current, block.wrapping_add(MIN_BLOCK << current));
+        }
+        NonNull::new(block)
+    }
+
+    /// # Safety
+    /// `ptr` must come from `allocate` on this allocator with the same `size`
+    /// and must not be used after this call.
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, size: usize) {
+        let mut order = self.order_for(size).expect("size bigger than the region");
+        // Offsets from the region, the blocks rebuilt from base keep its
+        // provenance whatever pointer the caller hands back.
+        let mut offset = ptr.as_ptr().offset_from(self.base) as usize;
+        while order < self.max_order {
+            let buddy = offset ^ (MIN_BLOCK << order);
+            if !self.remove(order, self.base.wrapping_add(buddy)) {
+                break;
+            }
+            offset = offset.min(buddy);
+            order += 1;
+        }
+        self.push(order, self.base.wrapping_add(offset));
+    }
+
+    // None for a size whose block would be bigger than the region, including
+    // the ones so big that rounding to a power of two overflows.
+    fn order_for(&self, size: usize) -> Option<usize> {
+        let blocks = size.max(1).div_ceil(MIN_BLOCK).checked_next_power_of_two()?;
+        let order = blocks.trailing_zeros() as usize;
+        if order <= self.max_order { Some(order) } else { None }
+    }
+
+    fn push(&mut self, order: usize, block: *mut u8) {
+        let link = self.free_heads[order].map_addr(|addr| addr | FREE);
+        unsafe { *(block as *mut *mut u8) = link };
+        self.free_heads[order] = block;
+    }
+
+    fn pop(&mut self, order: usize) -> *mut u8 {
+        let block = self.free_heads[order];
+        self.free_heads[order] = self.next_of(block);
+        block
+    }
+
+    // Unlinks `block` from the free list of `order`, if it is there.
+    fn remove(&mut self, order: usize, block: *mut u8) -> bool {
+        let mut prev: *mut u8 = ptr::null_mut();
+        let mut current = self.free_heads[order];
+        while !current.is_null() {
+            let next = self.next_of(current);
+            if current == block {
+                if prev.is_null() {
+                    self.free_heads[order] = next;
+                } else {
+                    unsafe { *(prev as *mut *mut u8) = next.map_addr(|addr| addr | FREE) };
+                }
+                return true;
+            }
+            prev = current;
+            current = next;
+        }
+        false
+    }
+
+    fn next_of(&self, block: *mut u8) -> *mut u8 {
+        let link = unsafe { *(block as *const *mut u8) };
+        assert!(link.addr() & FREE != 0, "free list corrupted at {:p}", block);
+        link.map_addr(|addr| addr & !FREE)
+    }
+
+}
+
+impl Drop for BuddyAllocator {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.base, self.layout) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_and_merges_back() {
+        let mut buddy = BuddyAllocator::new(4);
+        assert_eq!(buddy.capacity(), MIN_BLOCK << 4);
+        let small = buddy.allocate(10).unwrap();
+        let large = buddy.allocate(64).unwrap();
+        assert_eq!(buddy.free_blocks(0), 1);
+        assert_eq!(buddy.free_blocks(1), 1);
+        unsafe {
+            buddy.deallocate(small, 10);
+            buddy.deallocate(large, 64);
+        }
+        assert_eq!(buddy.free_blocks(4), 1);
+        assert!(buddy.allocate(MIN_BLOCK << 5).is_none());
+    }
+
+    #[test]
+    fn orders_out_of_range() {
+        let mut buddy = BuddyAllocator::new(3);
+        assert_eq!(buddy.free_blocks(3), 1);
+        assert_eq!(buddy.free_blocks(4), 0);
+        assert_eq!(buddy.free_blocks(usize::MAX), 0);
+        assert!(buddy.allocate(usize::MAX).is_none());
+        assert!(buddy.allocate(usize::MAX / 2).is_none());
+        let zero = buddy.allocate(0).unwrap();
+        assert_eq!(buddy.free_blocks(0), 1);
+        unsafe { buddy.deallocate(zero, 0) };
+        assert_eq!(buddy.free_blocks(3), 1);
+    }
+
+    #[test]
+    fn random_allocations_never_overlap_and_merge_back() {
+        let max_order = 6;
+        let mut buddy = BuddyAllocator::new(max_order);
+        // The model: every live block, with its size and the byte written in it.
+        let mut live: Vec<(NonNull<u8>, usize, u8)> = Vec::new();
+        let mut seed = 777u32;
+        for step in 0..3000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let size = 1 + ((seed >> 16) as usize % (MIN_BLOCK << 3));
+            if (seed >> 8) % 3 < 2 {
+                match buddy.allocate(size) {
+                    Some(block) => {
+                        let fill = step as u8;
+                        unsafe { block.as_ptr().write_bytes(fill, size) };
+                        live.push((block, size, fill));
+                    }
+                    None => assert!(!live.is_empty()),
+                }
+            } else if !live.is_empty() {
+                let (block, size, fill) = live.swap_remove((seed >> 4) as usize % live.len());
+                // Nothing else wrote into the block while it was handed out.
+                assert!((0..size).all(|i| unsafe { *block.as_ptr().add(i) } == fill));
+                unsafe { buddy.deallocate(block, size) };
+            }
+            let rounded = |size: usize| MIN_BLOCK * size.div_ceil(MIN_BLOCK).next_power_of_two();
+            let used: usize = live.iter().map(|&(_, size, _)| rounded(size)).sum();
+            let free: usize = (0..=max_order).map(|order| buddy.free_blocks(order) * (MIN_BLOCK << order)).sum();
+            assert_eq!(used + free, buddy.capacity());
+        }
+        for (block, size, _) in live.drain(..) {
+            unsafe { buddy.deallocate(block, size) };
+        }
+        assert_eq!(buddy.free_blocks(max_order), 1);
+        assert!((0..max_order).all(|order| buddy.free_blocks(order) == 0));
+    }
+}
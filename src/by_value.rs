@@ -0,0 +1,87 @@
+// Name: ByValue - value semantics for a tagged reference.
+//
+// Description: A RefWith2Flags is a reference, the natural equality for it is
+//              identity, the address. For deduplication, interning or memo
+//              tables that is wrong, 2 equal values at different addresses
+//              must be the same key. ByValue compares, hashes and orders by
+//              the referent value instead, ignoring the flags, or with them
+//              after the value when WITH_FLAGS is true (ByValueAndFlags).
+
+use core::cmp::Ordering;
+use core::hash::{Hash, Hasher};
+
+use crate::ref_with_2_flags::RefWith2Flags;
+
+pub struct ByValue<R, const WITH_FLAGS: bool = false>(pub R);
+
+/// ByValue that also tells apart equal values with different flags.
+pub type ByValueAndFlags<R> = ByValue<R, true>;
+
+impl<'a, T, const WITH_FLAGS: bool> ByValue<RefWith2Flags<'a, T>, WITH_FLAGS> {
+
+    pub fn new(tagged: RefWith2Flags<'a, T>) -> Self {
+        ByValue(tagged)
+    }
+
+    pub fn into_inner(self) -> RefWith2Flags<'a, T> {
+        self.0
+    }
+
+    fn flags(&self) -> (bool, bool) {
+        if WITH_FLAGS { (self.0.get_flag_a(), self.0.get_flag_b()) } else { (false, false) }
+    }
+
+}
+
+impl<'a, T: PartialEq, const WITH_FLAGS: bool> PartialEq for ByValue<RefWith2Flags<'a, T>, WITH_FLAGS> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.get_ref() == other.0.get_ref() && self.flags() == other.flags()
+    }
+}
+
+impl<'a, T: Eq, const WITH_FLAGS: bool> Eq for ByValue<RefWith2Flags<'a, T>, WITH_FLAGS> {}
+
+impl<'a, T: Hash, const WITH_FLAGS: bool> Hash for ByValue<RefWith2Flags<'a, T>, WITH_FLAGS> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.get_ref().hash(state);
+        if WITH_FLAGS {
+            self.flags().hash(state);
+        }
+    }
+}
+
+impl<'a, T: PartialOrd, const WITH_FLAGS: bool> PartialOrd for ByValue<RefWith2Flags<'a, T>, WITH_FLAGS> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match self.0.get_ref().partial_cmp(other.0.get_ref())? {
+            Ordering::Equal => Some(self.flags().cmp(&other.flags())),
+            ordering => Some(ordering),
+        }
+    }
+}
+
+impl<'a, T: Ord, const WITH_FLAGS: bool> Ord for ByValue<RefWith2Flags<'a, T>, WITH_FLAGS> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.get_ref().cmp(other.0.get_ref()).then_with(|| self.flags().cmp(&other.flags()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{BTreeSet, HashSet};
+
+    #[test]
+    fn dedups_equal_values_at_different_addresses() {
+        let words = [String::from("tag"), String::from("bit"), String::from("tag")];
+        let interned: HashSet<ByValue<_>> = words.iter().map(|w| ByValue(RefWith2Flags::new(w, false, false))).collect();
+        assert_eq!(interned.len(), 2);
+        let with_flags: BTreeSet<_> = words
+            .iter()
+            .enumerate()
+            .map(|(i, w)| ByValueAndFlags::new(RefWith2Flags::new(w, i == 0, false)))
+            .collect();
+        assert_eq!(with_flags.len(), 3);
+        let first = with_flags.into_iter().next().unwrap().into_inner();
+        assert_eq!(first.get_ref(), "bit");
+    }
+}
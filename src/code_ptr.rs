@@ -0,0 +1,117 @@
+// Name: CodePtr - the entry word of a function in a tiered VM.
+//
+// Description: A function of a tiered VM starts interpreted, pointing to its
+//              bytecode, and later is compiled, pointing to machine code.
+//              CodePtr keeps that in one word, a reference plus 2 flags:
+//
+//                 bit 0 - COMPILED : the reference is to the native code (N),
+//                                    otherwise to the bytecode (B).
+//                 bit 1 - DEOPT    : a deoptimization was requested, the
+//                                    runtime should go back to the bytecode
+//                                    at the next safe point.
+//
+//              dispatch() decodes the word once and calls the right tier,
+//              instead of ad hoc masks at every call site. The word is a
+//              pointer to whichever tier is installed, the bits set with
+//              map_addr, so the reference handed to the tier keeps the
+//              provenance of the one installed.
+
+use core::marker::PhantomData;
+
+use crate::aligned::AlignedAtLeast;
+
+const COMPILED: usize = 1;
+const DEOPT: usize = 2;
+
+pub struct CodePtr<'a, N, B> {
+    ptr_and_bits: *const (),
+    behaves_like: PhantomData<(&'a N, &'a B)>,
+}
+
+// The raw pointer opts out of Send and Sync, this is a &N or a &B as far as
+// threads go.
+unsafe impl<'a, N: Sync, B: Sync> Send for CodePtr<'a, N, B> {}
+unsafe impl<'a, N: Sync, B: Sync> Sync for CodePtr<'a, N, B> {}
+
+impl<'a, N: AlignedAtLeast<4>, B: AlignedAtLeast<4>> CodePtr<'a, N, B> {
+
+    /// A function that starts interpreted.
+    pub fn interpreted(bytecode: &'a B) -> CodePtr<'a, N, B> {
+        CodePtr {
+            ptr_and_bits: (bytecode as *const B).cast(),
+            behaves_like: PhantomData,
+        }
+    }
+
+    pub fn is_compiled(&self) -> bool {
+        self.ptr_and_bits.addr() & COMPILED != 0
+    }
+
+    pub fn is_deopt_requested(&self) -> bool {
+        self.ptr_and_bits.addr() & DEOPT != 0
+    }
+
+    /// Tiers up to native code, clearing any pending deoptimization request.
+    pub fn install_compiled(&mut self, native: &'a N) {
+        self.ptr_and_bits = (native as *const N).cast::<()>().map_addr(|addr| addr | COMPILED);
+    }
+
+    pub fn request_deopt(&mut self) {
+        self.ptr_and_bits = self.ptr_and_bits.map_addr(|addr| addr | DEOPT);
+    }
+
+    /// Goes back to the bytecode, clearing the deoptimization request.
+    pub fn deoptimize(&mut self, bytecode: &'a B) {
+        self.ptr_and_bits = (bytecode as *const B).cast();
+    }
+
+    /// Calls `native` with the machine code or `interpret` with the bytecode.
+    pub fn dispatch<R>(&self, native: impl FnOnce(&'a N) -> R, interpret: impl FnOnce(&'a B) -> R) -> R {
+        let ptr = self.ptr_and_bits.map_addr(|addr| addr & !3);
+        if self.is_compiled() {
+            native(unsafe { &*ptr.cast::<N>() })
+        } else {
+            interpret(unsafe { &*ptr.cast::<B>() })
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiers_up_and_deoptimizes() {
+        let bytecode: Vec<u32> = vec![1, 2, 3];
+        let native: Box<dyn Fn(u32) -> u32> = Box::new(|x| x * 2);
+        let mut entry: CodePtr<Box<dyn Fn(u32) -> u32>, Vec<u32>> = CodePtr::interpreted(&bytecode);
+        let run = |entry: &CodePtr<Box<dyn Fn(u32) -> u32>, Vec<u32>>| {
+            entry.dispatch(|code| code(21), |ops| ops.iter().sum())
+        };
+        assert_eq!(run(&entry), 6);
+        entry.install_compiled(&native);
+        assert!(entry.is_compiled());
+        assert_eq!(run(&entry), 42);
+        entry.request_deopt();
+        assert!(entry.is_deopt_requested());
+        entry.deoptimize(&bytecode);
+        assert!(!entry.is_compiled() && !entry.is_deopt_requested());
+    }
+
+    #[test]
+    fn deopt_requests_are_cleared_by_either_tier() {
+        let bytecode = 7_u32;
+        let native = 9_u32;
+        let mut entry: CodePtr<u32, u32> = CodePtr::interpreted(&bytecode);
+        entry.request_deopt();
+        assert!(entry.is_deopt_requested() && !entry.is_compiled());
+        assert_eq!(entry.dispatch(|_| 0, |ops| *ops), 7);
+        entry.install_compiled(&native);
+        assert!(entry.is_compiled() && !entry.is_deopt_requested());
+        entry.request_deopt();
+        assert_eq!(entry.dispatch(|code| *code, |_| 0), 9);
+        entry.deoptimize(&bytecode);
+        assert_eq!(entry.dispatch(|_| 0, |ops| *ops), 7);
+    }
+}
@@ -0,0 +1,132 @@
+// Name: codegen_check - asm level proof of the branch free accessors.
+//
+// Description: Built only by `cargo test --release` on x86_64. The probes
+//              below are exported, so the optimizer keeps each one as a
+//              real function with the real calling convention, not inlined
+//              into the test or with its arguments rewritten. The test
+//              builds this same test crate again with --emit asm, into its
+//              own target dir, finds the probes in the listing and checks
+//              their instructions:
+//
+//                 select, get_ref_if - no conditional jump.
+//                 get_all            - a single load, the word, and no
+//                                      call or jump at all.
+//
+//              The listing is in Intel syntax, asked for explicitly, so the
+//              check doesn't depend on the LLVM default.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::OnceLock;
+
+use crate::ref_with_2_flags::RefWith2Flags;
+
+#[no_mangle]
+#[inline(never)]
+pub fn ref_with_2_flags_codegen_select(
+    cond: bool,
+    a: RefWith2Flags<'static, u32>,
+    b: RefWith2Flags<'static, u32>,
+) -> RefWith2Flags<'static, u32> {
+    RefWith2Flags::select(cond, a, b)
+}
+
+#[no_mangle]
+#[inline(never)]
+pub fn ref_with_2_flags_codegen_get_ref_if(r: &RefWith2Flags<'static, u32>, cond: bool) -> Option<&'static u32> {
+    r.get_ref_if(cond)
+}
+
+#[no_mangle]
+#[inline(never)]
+pub fn ref_with_2_flags_codegen_get_all(r: &RefWith2Flags<'static, u32>) -> (&'static u32, bool, bool) {
+    r.get_all()
+}
+
+// The listing, built once for all the tests.
+fn asm() -> &'static str {
+    static ASM: OnceLock<String> = OnceLock::new();
+    ASM.get_or_init(emit_asm)
+}
+
+// Builds the lib tests again with --emit asm, returns the listing.
+fn emit_asm() -> String {
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let target_dir = manifest_dir.join("target").join("asm-check");
+    let status = Command::new(env!("CARGO"))
+        .current_dir(manifest_dir)
+        .args(["rustc", "--release", "--lib", "--target-dir"])
+        .arg(&target_dir)
+        .args(["--", "--test", "--emit", "asm", "-C", "codegen-units=1", "-C", "llvm-args=-x86-asm-syntax=intel"])
+        .status()
+        .expect("cargo rustc didn't start");
+    assert!(status.success(), "cargo rustc --emit asm failed");
+    let listing = newest_listing(&target_dir.join("release").join("deps"));
+    fs::read_to_string(listing).unwrap()
+}
+
+// The build leaves one listing per hash of the flags, the last one written is
+// ours.
+fn newest_listing(deps: &Path) -> PathBuf {
+    fs::read_dir(deps)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "s"))
+        .filter(|path| path.file_name().unwrap().to_string_lossy().starts_with("ref_with_2_flags-"))
+        .max_by_key(|path| fs::metadata(path).unwrap().modified().unwrap())
+        .expect("no asm listing")
+}
+
+// The instructions of the function, directives and labels left out.
+fn instructions<'s>(asm: &'s str, symbol: &str) -> Vec<&'s str> {
+    let start = format!("{symbol}:");
+    let mut lines = asm.lines().skip_while(|line| *line != start).skip(1);
+    let body: Vec<&str> = lines
+        .by_ref()
+        .take_while(|line| !line.starts_with(".Lfunc_end"))
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('.') && !line.ends_with(':'))
+        .collect();
+    assert!(!body.is_empty(), "{symbol} not found in the listing");
+    body
+}
+
+fn is_conditional_jump(instruction: &str) -> bool {
+    let mnemonic = instruction.split_whitespace().next().unwrap();
+    mnemonic.starts_with('j') && mnemonic != "jmp"
+}
+
+// A memory operand as the source, Intel syntax puts it after the comma.
+fn is_load(instruction: &str) -> bool {
+    !instruction.starts_with("lea") && instruction.split_once(',').is_some_and(|(_, source)| source.contains('['))
+}
+
+static X: u32 = 3;
+static Y: u32 = 4;
+
+#[test]
+fn select_and_get_ref_if_have_no_conditional_jump() {
+    let (a, b) = (RefWith2Flags::new(&X, true, false), RefWith2Flags::new(&Y, false, true));
+    assert!(std::ptr::eq(ref_with_2_flags_codegen_select(true, a, b).get_ref(), &X));
+    assert!(ref_with_2_flags_codegen_select(false, a, b).get_flag_b());
+    assert_eq!(ref_with_2_flags_codegen_get_ref_if(&a, true), Some(&3));
+    assert_eq!(ref_with_2_flags_codegen_get_ref_if(&a, false), None);
+
+    for symbol in ["ref_with_2_flags_codegen_select", "ref_with_2_flags_codegen_get_ref_if"] {
+        let body = instructions(asm(), symbol);
+        assert!(!body.iter().any(|i| is_conditional_jump(i)), "{symbol} branches:\n{}", body.join("\n"));
+    }
+}
+
+#[test]
+fn get_all_is_one_load() {
+    let r = RefWith2Flags::new(&Y, false, true);
+    let (value, a, b) = ref_with_2_flags_codegen_get_all(&r);
+    assert!(std::ptr::eq(value, &Y) && !a && b);
+
+    let body = instructions(asm(), "ref_with_2_flags_codegen_get_all");
+    let listing = body.join("\n");
+    assert_eq!(body.iter().filter(|i| is_load(i)).count(), 1, "get_all loads:\n{listing}");
+    assert!(!body.iter().any(|i| i.starts_with('j') || i.starts_with("call")), "get_all jumps:\n{listing}");
+}
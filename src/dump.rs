@@ -0,0 +1,170 @@
+// Name: dump - snapshots of tagged reference collections for offline
+//       analysis.
+//
+// Description: A Dump records, for every element of a collection of tagged
+//              references, its index, the untagged address, both flags and
+//              optionally the Debug text of the referent. It can be written
+//              as JSON or CSV, and 2 dumps of the same collection can be
+//              diffed, e.g. before and after a mark phase, to see which
+//              elements changed their flags or their target.
+
+use std::fmt::{self, Debug, Write};
+
+use crate::ref_with_2_flags::RefWith2Flags;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DumpEntry {
+    pub index: usize,
+    pub addr: usize,
+    pub flag_a: bool,
+    pub flag_b: bool,
+    pub referent: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Dump {
+    pub entries: Vec<DumpEntry>,
+}
+
+/// One difference between 2 dumps, matched by index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DumpChange {
+    /// Same index, different address or flags.
+    Changed { before: DumpEntry, after: DumpEntry },
+    /// Only in the second dump.
+    Added(DumpEntry),
+    /// Only in the first dump.
+    Removed(DumpEntry),
+}
+
+impl Dump {
+
+    /// Addresses and flags only.
+    pub fn capture<'r, 'a: 'r, T: 'a>(tagged: impl IntoIterator<Item = &'r RefWith2Flags<'a, T>>) -> Dump {
+        Self::capture_with(tagged, |_| None)
+    }
+
+    /// Addresses, flags and the Debug text of each referent.
+    pub fn capture_with_debug<'r, 'a: 'r, T: Debug + 'a>(
+        tagged: impl IntoIterator<Item = &'r RefWith2Flags<'a, T>>,
+    ) -> Dump {
+        Self::capture_with(tagged, |value| Some(format!("{:?}", value)))
+    }
+
+    fn capture_with<'r, 'a: 'r, T: 'a>(
+        tagged: impl IntoIterator<Item = &'r RefWith2Flags<'a, T>>,
+        referent: impl Fn(&T) -> Option<String>,
+    ) -> Dump {
+        let entries = tagged
+            .into_iter()
+            .enumerate()
+            .map(|(index, r)| {
+                let (value, flag_a, flag_b) = r.get_all();
+                DumpEntry { index, addr: value as *const T as usize, flag_a, flag_b, referent: referent(value) }
+            })
+            .collect();
+        Dump { entries }
+    }
+
+    /// A JSON array of objects, addresses as hex strings.
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("[");
+        for (i, e) in self.entries.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            write!(out, "{{\"index\":{},\"addr\":\"{:#x}\",\"flag_a\":{},\"flag_b\":{}", e.index, e.addr, e.flag_a, e.flag_b)
+                .unwrap();
+            if let Some(text) = &e.referent {
+                out.push_str(",\"referent\":");
+                json_string(&mut out, text);
+            }
+            out.push('}');
+        }
+        out.push(']');
+        out
+    }
+
+    /// CSV with a header line, the referent column is empty when not
+    /// captured.
+    pub fn to_csv(&self) -> String {
+        let mut out = String::from("index,addr,flag_a,flag_b,referent\n");
+        for e in &self.entries {
+            write!(out, "{},{:#x},{},{},", e.index, e.addr, e.flag_a, e.flag_b).unwrap();
+            if let Some(text) = &e.referent {
+                out.push('"');
+                out.push_str(&text.replace('"', "\"\""));
+                out.push('"');
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    /// What changed from `self` to `after`, in index order.
+    pub fn diff(&self, after: &Dump) -> Vec<DumpChange> {
+        let len = self.entries.len().max(after.entries.len());
+        (0..len)
+            .filter_map(|i| match (self.entries.get(i), after.entries.get(i)) {
+                (Some(b), Some(a)) if (b.addr, b.flag_a, b.flag_b) != (a.addr, a.flag_a, a.flag_b) => {
+                    Some(DumpChange::Changed { before: b.clone(), after: a.clone() })
+                }
+                (None, Some(a)) => Some(DumpChange::Added(a.clone())),
+                (Some(b), None) => Some(DumpChange::Removed(b.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+}
+
+impl fmt::Display for DumpChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flags = |e: &DumpEntry| format!("{}{}", e.flag_b as u8, e.flag_a as u8);
+        match self {
+            DumpChange::Changed { before, after } => write!(
+                f,
+                "[{}] {:#x}_{} -> {:#x}_{}",
+                before.index, before.addr, flags(before), after.addr, flags(after)
+            ),
+            DumpChange::Added(e) => write!(f, "[{}] + {:#x}_{}", e.index, e.addr, flags(e)),
+            DumpChange::Removed(e) => write!(f, "[{}] - {:#x}_{}", e.index, e.addr, flags(e)),
+        }
+    }
+}
+
+fn json_string(out: &mut String, text: &str) {
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diffs_snapshots_around_a_mark_phase() {
+        let words = [String::from("tag"), String::from("bit"), String::from("tag")];
+        let mut heap_refs = RefWith2Flags::tag_vec(words.iter().collect(), false, false);
+        let before = Dump::capture_with_debug(&heap_refs);
+        RefWith2Flags::retag_slice(&mut heap_refs[1..2], true, false);
+        let after = Dump::capture(&heap_refs);
+        let changes = before.diff(&after);
+        assert_eq!(changes.len(), 1);
+        assert!(matches!(&changes[0], DumpChange::Changed { before, after } if before.index == 1 && after.flag_a));
+        assert!(changes[0].to_string().ends_with("_01"));
+        assert!(before.to_json().contains("\"referent\":\"\\\"bit\\\"\""));
+        assert_eq!(after.to_csv().lines().count(), 4);
+        assert_eq!(Dump::capture(&heap_refs[..2]).diff(&after).len(), 1);
+        assert!(matches!(after.diff(&Dump::default())[0], DumpChange::Removed(_)));
+    }
+}
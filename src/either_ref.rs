@@ -0,0 +1,99 @@
+// Name: EitherRef - a &A or a &B in one word.
+//
+// Description: enum Either<'a, A, B> { A(&'a A), B(&'a B) } takes 2 words,
+//              one of them only for the discriminant. A and B aligned to at
+//              least 2 bytes leave bit 0 of the address free, and it is the
+//              discriminant here:
+//
+//                 bit 0 - 0 : a &A
+//                         1 : a &B
+//
+//              as_a() and as_b() give the reference if it is of that side,
+//              match_ref() runs the closure of the side it is.
+
+use core::marker::PhantomData;
+use core::ptr::NonNull;
+
+use crate::aligned::AlignedAtLeast;
+
+const IS_B: usize = 1;
+
+#[repr(transparent)]
+pub struct EitherRef<'a, A, B> {
+    ptr_and_side: NonNull<()>,
+    behaves_like: PhantomData<(&'a A, &'a B)>,
+}
+
+// NonNull opts out of Send and Sync, this is a &A or a &B as far as threads
+// go.
+unsafe impl<'a, A: Sync, B: Sync> Send for EitherRef<'a, A, B> {}
+unsafe impl<'a, A: Sync, B: Sync> Sync for EitherRef<'a, A, B> {}
+
+impl<'a, A: 'a, B: 'a> EitherRef<'a, A, B>
+where
+    A: AlignedAtLeast<2>,
+    B: AlignedAtLeast<2>,
+{
+
+    pub fn new_a(ptr: &'a A) -> EitherRef<'a, A, B> {
+        EitherRef { ptr_and_side: NonNull::from(ptr).cast(), behaves_like: PhantomData }
+    }
+
+    pub fn new_b(ptr: &'a B) -> EitherRef<'a, A, B> {
+        EitherRef { ptr_and_side: NonNull::from(ptr).cast().map_addr(|addr| addr | IS_B), behaves_like: PhantomData }
+    }
+
+    pub fn is_a(&self) -> bool {
+        !self.is_b()
+    }
+
+    pub fn is_b(&self) -> bool {
+        self.ptr_and_side.as_ptr().addr() & IS_B != 0
+    }
+
+    pub fn as_a(&self) -> Option<&'a A> {
+        if self.is_a() { Some(unsafe { self.untagged::<A>() }) } else { None }
+    }
+
+    pub fn as_b(&self) -> Option<&'a B> {
+        if self.is_b() { Some(unsafe { self.untagged::<B>() }) } else { None }
+    }
+
+    /// Calls `on_a` or `on_b` with the reference, by its side.
+    pub fn match_ref<R>(&self, on_a: impl FnOnce(&'a A) -> R, on_b: impl FnOnce(&'a B) -> R) -> R {
+        if self.is_b() { on_b(unsafe { self.untagged::<B>() }) } else { on_a(unsafe { self.untagged::<A>() }) }
+    }
+
+    // The caller checked that the reference is a &U.
+    unsafe fn untagged<U>(&self) -> &'a U {
+        unsafe { &*self.ptr_and_side.as_ptr().map_addr(|addr| addr & !IS_B).cast::<U>() }
+    }
+
+}
+
+impl<'a, A, B> Clone for EitherRef<'a, A, B> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, A, B> Copy for EitherRef<'a, A, B> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aligned_box::Align16;
+
+    #[test]
+    fn the_side_is_bit_0() {
+        let (number, slot) = (30u32, Align16(22u16));
+        let sides = [EitherRef::<u32, Align16<u16>>::new_a(&number), EitherRef::new_b(&slot)];
+        let described: Vec<String> = sides
+            .iter()
+            .map(|side| side.match_ref(|n| format!("number {}", n), |slot| format!("slot with {} hits", slot.0)))
+            .collect();
+        assert_eq!(described, ["number 30", "slot with 22 hits"]);
+        assert!(sides[1].as_a().is_none() && sides[1].as_b().is_some());
+        assert_eq!(std::mem::size_of::<EitherRef<u32, Align16<u16>>>(), std::mem::size_of::<usize>());
+    }
+}
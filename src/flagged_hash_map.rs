@@ -0,0 +1,289 @@
+// Name: FlaggedHashMap - a HashMap whose values carry a tombstone bit and a
+//       user bit in their handle.
+//
+// Description: The values are boxed and the map stores one tagged word per
+//              entry, the address of the box plus 2 bits:
+//
+//                 bit 0 - DEAD : the entry is logically removed.
+//                 bit 1 - USER : free for the user, e.g. "recently used".
+//
+//              mark_dead() only sets the bit, the table is not touched, so a
+//              deletion heavy cache doesn't pay for rehashing and moving
+//              entries on every removal. The dead entries are invisible to
+//              get() and iter(), and purge() drops them all at once when it
+//              suits the caller. Each handle is the *mut from
+//              Box::into_raw with the 2 bits ORed in by map_addr, and
+//              untag_ptr gives that same pointer back for deref and drop.
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+use crate::aligned_box::Align8;
+use crate::bitpack::untag_ptr;
+
+const DEAD: usize = 1;
+const USER: usize = 2;
+const BITS: usize = DEAD | USER;
+
+struct Handle<V> {
+    word: *mut Align8<V>,
+    owns: PhantomData<Box<Align8<V>>>,
+}
+
+// The raw pointer opts out of Send and Sync, this is a Box<V> as far as
+// threads go.
+unsafe impl<V: Send> Send for Handle<V> {}
+unsafe impl<V: Sync> Sync for Handle<V> {}
+
+impl<V> Handle<V> {
+
+    fn new(value: V) -> Handle<V> {
+        let word = Box::into_raw(Box::new(Align8(value)));
+        debug_assert_eq!(word.addr() & BITS, 0);
+        Handle { word, owns: PhantomData }
+    }
+
+    fn is_dead(&self) -> bool {
+        self.word.addr() & DEAD != 0
+    }
+
+    fn value(&self) -> &V {
+        unsafe { &(*self.untagged()).0 }
+    }
+
+    fn value_mut(&mut self) -> &mut V {
+        unsafe { &mut (*self.untagged()).0 }
+    }
+
+    fn into_value(self) -> V {
+        let ptr = self.untagged();
+        std::mem::forget(self);
+        unsafe { Box::from_raw(ptr).0 }
+    }
+
+    fn untagged(&self) -> *mut Align8<V> {
+        untag_ptr(self.word, 2)
+    }
+
+}
+
+impl<V> Drop for Handle<V> {
+    fn drop(&mut self) {
+        drop(unsafe { Box::from_raw(self.untagged()) });
+    }
+}
+
+pub struct FlaggedHashMap<K, V> {
+    entries: HashMap<K, Handle<V>>,
+    dead: usize,
+}
+
+impl<K: Hash + Eq, V> FlaggedHashMap<K, V> {
+
+    pub fn new() -> FlaggedHashMap<K, V> {
+        FlaggedHashMap { entries: HashMap::new(), dead: 0 }
+    }
+
+    /// Number of live entries.
+    pub fn len(&self) -> usize {
+        self.entries.len() - self.dead
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of dead entries waiting for purge().
+    pub fn tombstones(&self) -> usize {
+        self.dead
+    }
+
+    /// Inserts a live value, returns the previous one if it was live. The
+    /// user bit starts cleared.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let old = self.entries.insert(key, Handle::new(value))?;
+        if old.is_dead() {
+            self.dead -= 1;
+            None
+        } else {
+            Some(old.into_value())
+        }
+    }
+
+    pub fn get<Q: Hash + Eq + ?Sized>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+    {
+        self.live(key).map(Handle::value)
+    }
+
+    pub fn get_mut<Q: Hash + Eq + ?Sized>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+    {
+        self.entries.get_mut(key).filter(|h| !h.is_dead()).map(Handle::value_mut)
+    }
+
+    pub fn contains_key<Q: Hash + Eq + ?Sized>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+    {
+        self.live(key).is_some()
+    }
+
+    /// Logically removes the entry, returns false if there was no live one.
+    pub fn mark_dead<Q: Hash + Eq + ?Sized>(&mut self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+    {
+        match self.entries.get_mut(key) {
+            Some(handle) if !handle.is_dead() => {
+                handle.word = handle.word.map_addr(|addr| addr | DEAD);
+                self.dead += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The user bit of a live entry.
+    pub fn user_flag<Q: Hash + Eq + ?Sized>(&self, key: &Q) -> Option<bool>
+    where
+        K: Borrow<Q>,
+    {
+        self.live(key).map(|h| h.word.addr() & USER != 0)
+    }
+
+    /// Sets the user bit of a live entry, returns false if there is none.
+    pub fn set_user_flag<Q: Hash + Eq + ?Sized>(&mut self, key: &Q, flag: bool) -> bool
+    where
+        K: Borrow<Q>,
+    {
+        match self.entries.get_mut(key) {
+            Some(handle) if !handle.is_dead() => {
+                handle.word = handle.word.map_addr(|addr| (addr & !USER) | ((flag as usize) << 1));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Drops the dead entries, returns how many.
+    pub fn purge(&mut self) -> usize {
+        let purged = self.dead;
+        if purged > 0 {
+            self.entries.retain(|_, handle| !handle.is_dead());
+            self.dead = 0;
+        }
+        purged
+    }
+
+    /// The live entries, with their user bit.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V, bool)> + '_ {
+        self.entries
+            .iter()
+            .filter(|(_, h)| !h.is_dead())
+            .map(|(k, h)| (k, h.value(), h.word.addr() & USER != 0))
+    }
+
+    fn live<Q: Hash + Eq + ?Sized>(&self, key: &Q) -> Option<&Handle<V>>
+    where
+        K: Borrow<Q>,
+    {
+        self.entries.get(key).filter(|h| !h.is_dead())
+    }
+
+}
+
+impl<K: Hash + Eq, V> Default for FlaggedHashMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    #[test]
+    fn tombstones_and_user_flags() {
+        let mut cache = FlaggedHashMap::new();
+        for (key, value) in [("a", 1), ("b", 2), ("c", 3)] {
+            cache.insert(key.to_string(), value);
+        }
+        assert!(cache.set_user_flag("a", true));
+        assert_eq!(cache.user_flag("a"), Some(true));
+        assert!(cache.mark_dead("b") && !cache.mark_dead("b"));
+        assert_eq!((cache.len(), cache.tombstones()), (2, 1));
+        assert_eq!(cache.get("b"), None);
+        assert!(!cache.contains_key("b") && cache.contains_key("c"));
+        *cache.get_mut("c").unwrap() += 10;
+        assert_eq!(cache.insert("c".to_string(), 30), Some(13));
+        let used: Vec<&String> = cache.iter().filter(|(_, _, used)| *used).map(|(k, _, _)| k).collect();
+        assert_eq!(used, vec!["a"]);
+        assert_eq!(cache.purge(), 1);
+        assert_eq!(cache.tombstones(), 0);
+        assert!(!cache.is_empty() && FlaggedHashMap::<u8, u8>::default().is_empty());
+    }
+
+    #[test]
+    fn random_operations_match_a_hash_map() {
+        // The model keeps the live entries and their user bit.
+        let mut map = FlaggedHashMap::new();
+        let mut model: HashMap<u32, (u32, bool)> = HashMap::new();
+        let mut seed = 2828u32;
+        for _ in 0..3000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let key = (seed >> 16) % 32;
+            match (seed >> 8) % 6 {
+                0 | 1 => {
+                    assert_eq!(map.insert(key, seed), model.insert(key, (seed, false)).map(|entry| entry.0));
+                }
+                2 => {
+                    assert_eq!(map.mark_dead(&key), model.remove(&key).is_some());
+                }
+                3 => {
+                    let flag = seed & 1 == 0;
+                    let live = model.get_mut(&key).map(|entry| entry.1 = flag).is_some();
+                    assert_eq!(map.set_user_flag(&key, flag), live);
+                }
+                4 => {
+                    let dead = map.tombstones();
+                    assert_eq!(map.purge(), dead);
+                    assert_eq!(map.tombstones(), 0);
+                }
+                _ => {
+                    assert_eq!(map.get(&key), model.get(&key).map(|entry| &entry.0));
+                    assert_eq!(map.user_flag(&key), model.get(&key).map(|entry| entry.1));
+                }
+            }
+            assert_eq!(map.len(), model.len());
+        }
+        let mut live: Vec<(u32, u32, bool)> = map.iter().map(|(k, v, used)| (*k, *v, used)).collect();
+        let mut expected: Vec<(u32, u32, bool)> = model.iter().map(|(k, &(v, used))| (*k, v, used)).collect();
+        live.sort();
+        expected.sort();
+        assert_eq!(live, expected);
+    }
+
+    #[test]
+    fn an_empty_map_and_dropped_tombstones() {
+        let counted = Rc::new(());
+        let mut map: FlaggedHashMap<&str, Rc<()>> = FlaggedHashMap::new();
+        assert!(!map.mark_dead("a") && !map.set_user_flag("a", true));
+        assert_eq!((map.purge(), map.iter().count()), (0, 0));
+        map.insert("a", counted.clone());
+        map.insert("b", counted.clone());
+        assert!(map.mark_dead("a"));
+        assert_eq!(map.insert("a", counted.clone()), None);
+        assert_eq!(map.tombstones(), 0);
+        assert!(map.mark_dead("b"));
+        assert_eq!(Rc::strong_count(&counted), 3);
+        drop(map);
+        assert_eq!(Rc::strong_count(&counted), 1);
+    }
+}
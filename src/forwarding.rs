@@ -0,0 +1,142 @@
+// Name: Forwardable - an object with a forwarding pointer header, for a
+//       copying or compacting collector.
+//
+// Description: When a copying collector moves an object, it leaves in the
+//              old copy's header the address of the new one, so every
+//              reference still pointing to the old copy can be redirected.
+//              Here the header is one atomic word:
+//
+//                 bit 0 - FORWARDED : the object moved, the rest of the word
+//                                     is the address of the new copy.
+//                 bit 1 - MARKED    : a free bit for the collector, e.g. the
+//                                     mark of a mark-compact.
+//
+//              forward_to() swings the header from "here" to the new address
+//              and sets FORWARDED in the same CAS, MARKED is carried over.
+//              With several GC threads copying in parallel, 2 of them may
+//              copy the same object: only one CAS wins, the loser gets the
+//              winner's copy back and drops its own. resolve() chases the
+//              forwarding pointers to the current copy.
+
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+const FORWARDED: usize = 1;
+const MARKED: usize = 2;
+
+#[repr(align(4))]
+pub struct Forwardable<'a, T> {
+    // Null with the bits while not forwarded, the new copy with FORWARDED
+    // once forwarded.
+    header: AtomicPtr<Forwardable<'a, T>>,
+    value: T,
+}
+
+impl<'a, T> Forwardable<'a, T> {
+
+    pub fn new(value: T) -> Forwardable<'a, T> {
+        Forwardable { header: AtomicPtr::new(ptr::null_mut()), value }
+    }
+
+    /// The value of this copy, which may be a stale one.
+    pub fn get(&self) -> &T {
+        &self.value
+    }
+
+    pub fn is_forwarded(&self) -> bool {
+        self.header.load(Ordering::Acquire).addr() & FORWARDED != 0
+    }
+
+    /// The copy this one was forwarded to, one step.
+    pub fn forwardee(&self) -> Option<&'a Forwardable<'a, T>> {
+        let word = self.header.load(Ordering::Acquire);
+        if word.addr() & FORWARDED == 0 {
+            return None;
+        }
+        // Set by forward_to() from a &'a Forwardable, 4 aligned.
+        Some(unsafe { &*word.map_addr(|addr| addr & !(FORWARDED | MARKED)) })
+    }
+
+    /// Forwards this object to `new`, keeping MARKED. If another thread
+    /// forwarded it first, returns that copy, to use instead of `new`.
+    /// Panics if `new` is this object, a copy can't forward to itself.
+    pub fn forward_to(&self, new: &'a Forwardable<'a, T>) -> Result<(), &'a Forwardable<'a, T>> {
+        assert!(!ptr::eq(self, new), "an object can't be forwarded to itself");
+        let target = ptr::from_ref(new).cast_mut();
+        let mut word = self.header.load(Ordering::Acquire);
+        loop {
+            if word.addr() & FORWARDED != 0 {
+                return Err(self.forwardee().unwrap());
+            }
+            let forwarded = target.map_addr(|addr| addr | FORWARDED | (word.addr() & MARKED));
+            match self.header.compare_exchange_weak(word, forwarded, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return Ok(()),
+                Err(actual) => word = actual,
+            }
+        }
+    }
+
+    /// The current copy: this one if it wasn't forwarded, else the end of
+    /// the chain of forwarding pointers.
+    pub fn resolve(&'a self) -> &'a Forwardable<'a, T> {
+        let mut current = self;
+        while let Some(next) = current.forwardee() {
+            current = next;
+        }
+        current
+    }
+
+    pub fn is_marked(&self) -> bool {
+        self.header.load(Ordering::Acquire).addr() & MARKED != 0
+    }
+
+    /// Sets or clears MARKED, keeps the forwarding pointer.
+    pub fn set_marked(&self, marked: bool) {
+        if marked {
+            self.header.fetch_or(MARKED, Ordering::AcqRel);
+        } else {
+            self.header.fetch_and(!MARKED, Ordering::AcqRel);
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aligned_box::Align16;
+    use std::thread;
+
+    #[test]
+    fn parallel_copies_agree_on_one_winner() {
+        let old = Forwardable::new(7u32);
+        old.set_marked(true);
+        let copies: Vec<Forwardable<u32>> = (0..4).map(|_| Forwardable::new(7)).collect();
+        let chosen: Vec<*const Forwardable<u32>> = thread::scope(|s| {
+            let handles: Vec<_> = copies
+                .iter()
+                .map(|copy| {
+                    let old = &old;
+                    s.spawn(move || ptr::from_ref(old.forward_to(copy).err().unwrap_or(copy)) as usize)
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap() as *const Forwardable<u32>).collect()
+        });
+        assert!(chosen.iter().all(|&winner| ptr::eq(winner, old.resolve())));
+        assert!(old.is_forwarded() && old.is_marked());
+    }
+
+    #[test]
+    fn resolves_through_a_chain_of_moves() {
+        let moved_twice = Forwardable::new(Align16(3u16));
+        let moved_once = Forwardable::new(Align16(3u16));
+        let original = Forwardable::new(Align16(3u16));
+        original.set_marked(true);
+        assert!(original.forward_to(&moved_once).is_ok());
+        assert!(moved_once.forward_to(&moved_twice).is_ok());
+        assert!(std::ptr::eq(original.resolve(), &moved_twice) && original.is_marked());
+        let late = Forwardable::new(Align16(3u16));
+        assert!(matches!(original.forward_to(&late), Err(winner) if std::ptr::eq(winner, &moved_once)));
+        assert_eq!(original.resolve().get().0, 3);
+    }
+}
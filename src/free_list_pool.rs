@@ -0,0 +1,224 @@
+// Name: FreeListPool - a fixed size object pool with poisoned free links.
+//
+// Description: The pool has a fixed number of slots. Every slot has a link
+//              word next to the object: a free slot stores there the pointer
+//              to the next free slot, and the low bit of that word is the
+//              POISON bit, set on every free slot and clear on a live one.
+//
+//              This gives a cheap double free check. The link word is the
+//              pool's, never the object's bytes, so when a slot is freed the
+//              POISON bit tells exactly whether it is already free, with a
+//              single bit test and no walk of the free list. Detected double
+//              frees are counted in PoolStats and reported as an error,
+//              instead of corrupting the list.
+//
+//              The slots are one allocation, reached only through raw
+//              pointers derived from it, so the pointers handed out stay
+//              valid while the pool works on the other slots.
+
+use std::mem::{offset_of, MaybeUninit};
+use std::ptr::{self, NonNull};
+
+const POISON: usize = 1;
+
+struct Slot<T> {
+    // The next free slot tagged with POISON, or null while the slot is live.
+    link: *mut Slot<T>,
+    value: MaybeUninit<T>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    pub capacity: usize,
+    pub in_use: usize,
+    pub allocations: usize,
+    pub frees: usize,
+    pub double_frees: usize,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum FreeError {
+    /// The pointer doesn't point to a slot of this pool.
+    ForeignPointer,
+    /// The slot was already free.
+    DoubleFree,
+}
+
+pub struct FreeListPool<T> {
+    slots: *mut Slot<T>,
+    // Untagged first free slot, or null.
+    free_head: *mut Slot<T>,
+    stats: PoolStats,
+}
+
+// The raw pointers opt out of Send and Sync, this is a Box<[T]> as far as
+// threads go.
+unsafe impl<T: Send> Send for FreeListPool<T> {}
+unsafe impl<T: Sync> Sync for FreeListPool<T> {}
+
+impl<T> FreeListPool<T> {
+
+    pub fn new(capacity: usize) -> FreeListPool<T> {
+        let slots: Box<[Slot<T>]> = (0..capacity)
+            .map(|_| Slot { link: ptr::null_mut(), value: MaybeUninit::uninit() })
+            .collect();
+        let mut pool = FreeListPool {
+            slots: Box::into_raw(slots) as *mut Slot<T>,
+            free_head: ptr::null_mut(),
+            stats: PoolStats { capacity, ..PoolStats::default() },
+        };
+        for index in (0..capacity).rev() {
+            pool.push_free(pool.slots.wrapping_add(index));
+        }
+        pool
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        self.stats
+    }
+
+    /// Moves `value` into a free slot, or gives it back if the pool is full.
+    pub fn alloc(&mut self, value: T) -> Result<NonNull<T>, T> {
+        if self.free_head.is_null() {
+            return Err(value);
+        }
+        let slot = self.free_head;
+        let value_ptr = unsafe {
+            self.free_head = (*slot).link.map_addr(|addr| addr & !POISON);
+            (*slot).link = ptr::null_mut();
+            let value_ptr = ptr::addr_of_mut!((*slot).value).cast::<T>();
+            value_ptr.write(value);
+            value_ptr
+        };
+        self.stats.allocations += 1;
+        self.stats.in_use += 1;
+        Ok(unsafe { NonNull::new_unchecked(value_ptr) })
+    }
+
+    /// Drops the object and returns its slot to the pool.
+    pub fn free(&mut self, ptr: NonNull<T>) -> Result<(), FreeError> {
+        let index = self.index_of(ptr.as_ptr()).ok_or(FreeError::ForeignPointer)?;
+        let slot = self.slots.wrapping_add(index);
+        if unsafe { (*slot).link }.addr() & POISON != 0 {
+            self.stats.double_frees += 1;
+            return Err(FreeError::DoubleFree);
+        }
+        unsafe { ptr::addr_of_mut!((*slot).value).cast::<T>().drop_in_place() };
+        self.push_free(slot);
+        self.stats.frees += 1;
+        self.stats.in_use -= 1;
+        Ok(())
+    }
+
+    // The slot whose object is at `value`, from the addresses alone.
+    fn index_of(&self, value: *mut T) -> Option<usize> {
+        let start = self.slots.addr() + offset_of!(Slot<T>, value);
+        let size = std::mem::size_of::<Slot<T>>();
+        let offset = value.addr().checked_sub(start)?;
+        if offset % size != 0 || offset / size >= self.stats.capacity {
+            return None;
+        }
+        Some(offset / size)
+    }
+
+    fn push_free(&mut self, slot: *mut Slot<T>) {
+        unsafe { (*slot).link = self.free_head.map_addr(|addr| addr | POISON) };
+        self.free_head = slot;
+    }
+
+}
+
+impl<T> Drop for FreeListPool<T> {
+    fn drop(&mut self) {
+        let mut slots = unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(self.slots, self.stats.capacity)) };
+        for slot in slots.iter_mut() {
+            if slot.link.addr() & POISON == 0 {
+                unsafe { slot.value.assume_init_drop() };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    #[test]
+    fn catches_double_frees() {
+        let mut pool = FreeListPool::new(2);
+        let first = pool.alloc(String::from("first")).unwrap();
+        let _second = pool.alloc(String::from("second")).unwrap();
+        assert!(pool.alloc(String::from("third")).is_err());
+        assert_eq!(unsafe { first.as_ref() }, "first");
+        assert_eq!(pool.free(first), Ok(()));
+        assert_eq!(pool.free(first), Err(FreeError::DoubleFree));
+        assert_eq!(pool.free(NonNull::dangling()), Err(FreeError::ForeignPointer));
+        let stats = pool.stats();
+        assert_eq!((stats.capacity, stats.in_use, stats.double_frees), (2, 1, 1));
+        assert_eq!((stats.allocations, stats.frees), (2, 1));
+    }
+
+    #[test]
+    fn odd_first_words_are_not_double_frees() {
+        // A live object whose first word is odd looks like a poisoned link to a
+        // check that reads the object, the link word next to it doesn't.
+        let mut pool = FreeListPool::new(1);
+        let odd = pool.alloc(usize::MAX).unwrap();
+        assert_eq!(pool.free(odd), Ok(()));
+        let again = pool.alloc(1_usize).unwrap();
+        assert_eq!(again, odd);
+        assert_eq!(pool.free(again), Ok(()));
+        assert_eq!(pool.stats().double_frees, 0);
+    }
+
+    #[test]
+    fn empty_pools_and_the_live_objects_left_on_drop() {
+        let mut empty: FreeListPool<u8> = FreeListPool::new(0);
+        assert_eq!(empty.alloc(7), Err(7));
+        assert_eq!(empty.free(NonNull::dangling()), Err(FreeError::ForeignPointer));
+        let counted = Rc::new(());
+        let mut pool = FreeListPool::new(3);
+        let kept = pool.alloc(counted.clone()).unwrap();
+        let freed = pool.alloc(counted.clone()).unwrap();
+        assert_eq!(pool.free(freed), Ok(()));
+        assert_eq!(Rc::strong_count(&counted), 2);
+        assert_eq!(Rc::strong_count(unsafe { kept.as_ref() }), 2);
+        drop(pool);
+        assert_eq!(Rc::strong_count(&counted), 1);
+    }
+
+    #[test]
+    fn random_alloc_and_free_against_a_model() {
+        let mut pool = FreeListPool::new(32);
+        let mut live: Vec<(NonNull<u32>, u32)> = Vec::new();
+        let mut freed: Vec<NonNull<u32>> = Vec::new();
+        let mut seed = 99u32;
+        for _ in 0..4000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            match (seed >> 8) % 4 {
+                0 | 1 => match pool.alloc(seed) {
+                    Ok(ptr) => {
+                        freed.retain(|&f| f != ptr);
+                        live.push((ptr, seed));
+                    }
+                    Err(value) => assert_eq!((value, live.len()), (seed, 32)),
+                },
+                2 if !live.is_empty() => {
+                    let (ptr, value) = live.swap_remove((seed >> 16) as usize % live.len());
+                    assert_eq!(unsafe { *ptr.as_ref() }, value);
+                    assert_eq!(pool.free(ptr), Ok(()));
+                    freed.push(ptr);
+                }
+                _ if !freed.is_empty() => {
+                    let ptr = freed[(seed >> 16) as usize % freed.len()];
+                    assert_eq!(pool.free(ptr), Err(FreeError::DoubleFree));
+                }
+                _ => {}
+            }
+            assert_eq!(pool.stats().in_use, live.len());
+        }
+        let stats = pool.stats();
+        assert_eq!(stats.allocations - stats.frees, live.len());
+    }
+}
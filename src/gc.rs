@@ -0,0 +1,341 @@
+// Name: Gc - a tri-color mark and sweep heap, the color of each object in the
+//       pointer bits of its handle table entry.
+//
+// Description: The GC use case of the module docs, made concrete. Every
+//              object is boxed in the Align8 shim of aligned_box, and its
+//              entry in the handle table is one word, the address of the box
+//              plus its color in the 2 low bits:
+//
+//                 0 - WHITE : not reached (yet), freed by the sweep.
+//                 1 - GRAY  : reached, its children not scanned yet. Gray
+//                             objects are the ones on the worklist.
+//                 2 - BLACK : reached and scanned.
+//
+//              A collection is mark_gray() on every root, blacken() until it
+//              returns false, then sweep_white(). blacken() takes one gray
+//              object, marks gray its white children, reported by
+//              Trace::trace, and turns it black, so the worklist can be
+//              drained a step at a time, e.g. between the instructions of an
+//              interpreter. The sweep frees the white objects and turns the
+//              black ones white again for the next cycle.
+//
+//              Color implements TagEnum, the colors are read and written
+//              through to_bits() and from_bits(). A GcRef is an index in the
+//              table. Once its object is swept the handle is dead, and the
+//              next alloc() may reuse the slot: there is no generation as in
+//              a PoolHandle, so a GcRef kept past the sweep of its object
+//              reads the new one. Only keep GcRefs that are traced or roots.
+//
+//              An entry is the pointer Box::into_raw returned with the color
+//              ORed in by map_addr, untag_ptr takes it off for every read and
+//              for the free.
+
+use std::marker::PhantomData;
+use std::ptr;
+
+use crate::aligned_box::Align8;
+use crate::bitpack::untag_ptr;
+use crate::ref_with_tag::TagEnum;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Color {
+    White,
+    Gray,
+    Black,
+}
+
+impl TagEnum for Color {
+    const BITS: usize = 2;
+
+    fn to_bits(self) -> usize {
+        self as usize
+    }
+
+    fn from_bits(bits: usize) -> Color {
+        match bits {
+            0 => Color::White,
+            1 => Color::Gray,
+            _ => Color::Black,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct GcRef(usize);
+
+/// Reports the GcRefs held by an object, its edges in the object graph.
+pub trait Trace {
+    fn trace(&self, edges: &mut dyn FnMut(GcRef));
+}
+
+pub struct Heap<T: Trace> {
+    // Tagged Box<Align8<T>> pointers, null for an empty slot.
+    objects: Vec<*mut Align8<T>>,
+    gray: Vec<GcRef>,
+    owns: PhantomData<Box<T>>,
+}
+
+// The raw pointers opt out of Send and Sync, this is a Vec<Box<T>> as far as
+// threads go.
+unsafe impl<T: Trace + Send> Send for Heap<T> {}
+unsafe impl<T: Trace + Sync> Sync for Heap<T> {}
+
+impl<T: Trace> Heap<T> {
+
+    pub fn new() -> Heap<T> {
+        Heap { objects: Vec::new(), gray: Vec::new(), owns: PhantomData }
+    }
+
+    /// Number of live objects.
+    pub fn len(&self) -> usize {
+        self.objects.iter().filter(|word| !word.is_null()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Adds a white object. Allocating during a mark phase is allowed, the
+    /// object is swept in this cycle unless it gets reached.
+    pub fn alloc(&mut self, value: T) -> GcRef {
+        let word = Box::into_raw(Box::new(Align8(value))).map_addr(|addr| addr | Color::White.to_bits());
+        match self.objects.iter().position(|word| word.is_null()) {
+            Some(index) => {
+                self.objects[index] = word;
+                GcRef(index)
+            }
+            None => {
+                self.objects.push(word);
+                GcRef(self.objects.len() - 1)
+            }
+        }
+    }
+
+    pub fn get(&self, object: GcRef) -> Option<&T> {
+        let word = self.word(object)?;
+        Some(unsafe { &(*untag_ptr(word, 2)).0 })
+    }
+
+    pub fn get_mut(&mut self, object: GcRef) -> Option<&mut T> {
+        let word = self.word(object)?;
+        Some(unsafe { &mut (*untag_ptr(word, 2)).0 })
+    }
+
+    pub fn color(&self, object: GcRef) -> Option<Color> {
+        self.word(object).map(|word| Color::from_bits(word.addr() & 3))
+    }
+
+    /// Marks a white object gray and puts it on the worklist, gray and black
+    /// objects are left as they are. Called on the roots, and by blacken()
+    /// on the children.
+    pub fn mark_gray(&mut self, object: GcRef) {
+        if self.color(object) == Some(Color::White) {
+            self.set_color(object, Color::Gray);
+            self.gray.push(object);
+        }
+    }
+
+    /// Scans one gray object: its white children turn gray, it turns black.
+    /// Returns false once the worklist is empty, the mark phase is over.
+    pub fn blacken(&mut self) -> bool {
+        let Some(object) = self.gray.pop() else {
+            return false;
+        };
+        let mut children = Vec::new();
+        if let Some(value) = self.get(object) {
+            value.trace(&mut |child| children.push(child));
+        }
+        for child in children {
+            self.mark_gray(child);
+        }
+        self.set_color(object, Color::Black);
+        true
+    }
+
+    /// Marks gray the roots and blackens until the worklist is empty.
+    pub fn mark(&mut self, roots: impl IntoIterator<Item = GcRef>) {
+        for root in roots {
+            self.mark_gray(root);
+        }
+        while self.blacken() {}
+    }
+
+    /// Frees the white objects and turns the black ones white, returns how
+    /// many were freed. Panics if the mark phase isn't over.
+    pub fn sweep_white(&mut self) -> usize {
+        assert!(self.gray.is_empty(), "sweep with gray objects left, blacken() first");
+        let mut freed = 0;
+        for word in self.objects.iter_mut().filter(|word| !word.is_null()) {
+            if Color::from_bits(word.addr() & 3) == Color::White {
+                drop(unsafe { Box::from_raw(untag_ptr(*word, 2)) });
+                *word = ptr::null_mut();
+                freed += 1;
+            } else {
+                *word = word.map_addr(|addr| (addr & !3) | Color::White.to_bits());
+            }
+        }
+        freed
+    }
+
+    fn word(&self, object: GcRef) -> Option<*mut Align8<T>> {
+        self.objects.get(object.0).copied().filter(|word| !word.is_null())
+    }
+
+    fn set_color(&mut self, object: GcRef, color: Color) {
+        let word = &mut self.objects[object.0];
+        *word = word.map_addr(|addr| (addr & !3) | color.to_bits());
+    }
+
+}
+
+impl<T: Trace> Default for Heap<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Trace> Drop for Heap<T> {
+    fn drop(&mut self) {
+        for &word in self.objects.iter().filter(|word| !word.is_null()) {
+            drop(unsafe { Box::from_raw(untag_ptr(word, 2)) });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{HashMap, HashSet};
+    use std::rc::Rc;
+
+    #[test]
+    fn marks_and_sweeps_a_cons_list() {
+        // A value of a toy language: an integer or a cons cell on the heap.
+        enum Value {
+            Int(i64),
+            Pair(GcRef, GcRef),
+        }
+
+        impl Trace for Value {
+            fn trace(&self, edges: &mut dyn FnMut(GcRef)) {
+                if let Value::Pair(head, tail) = *self {
+                    edges(head);
+                    edges(tail);
+                }
+            }
+        }
+
+        let mut heap = Heap::new();
+        let one = heap.alloc(Value::Int(1));
+        let two = heap.alloc(Value::Int(2));
+        let list = heap.alloc(Value::Pair(one, two));
+        let garbage = heap.alloc(Value::Int(99));
+        heap.mark_gray(list);
+        assert_eq!(heap.color(list), Some(Color::Gray));
+        assert!(heap.blacken());
+        assert_eq!((heap.color(list), heap.color(one), heap.color(garbage)), (Some(Color::Black), Some(Color::Gray), Some(Color::White)));
+        while heap.blacken() {}
+        assert_eq!(heap.sweep_white(), 1);
+        assert!(heap.get(garbage).is_none() && matches!(heap.get(two), Some(Value::Int(2))));
+        assert_eq!(heap.color(list), Some(Color::White));
+        heap.mark([one]);
+        assert_eq!((heap.sweep_white(), heap.len()), (2, 1));
+    }
+
+    #[test]
+    fn random_graphs_keep_exactly_the_reachable_objects() {
+        struct Node {
+            children: Vec<GcRef>,
+        }
+
+        impl Trace for Node {
+            fn trace(&self, edges: &mut dyn FnMut(GcRef)) {
+                self.children.iter().for_each(|&child| edges(child));
+            }
+        }
+
+        // The model: the edges of every live object, and the roots.
+        let mut heap = Heap::new();
+        let mut model: HashMap<GcRef, Vec<GcRef>> = HashMap::new();
+        let mut roots: Vec<GcRef> = Vec::new();
+        let mut seed = 2900u32;
+        for _ in 0..2000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let live: Vec<GcRef> = model.keys().copied().collect();
+            match (seed >> 8) % 8 {
+                0..=2 => {
+                    let object = heap.alloc(Node { children: Vec::new() });
+                    assert!(!model.contains_key(&object));
+                    model.insert(object, Vec::new());
+                    if seed & 0x1000 != 0 {
+                        roots.push(object);
+                    }
+                }
+                3..=5 if live.len() >= 2 => {
+                    let from = live[(seed >> 16) as usize % live.len()];
+                    let to = live[(seed >> 20) as usize % live.len()];
+                    heap.get_mut(from).unwrap().children.push(to);
+                    model.get_mut(&from).unwrap().push(to);
+                }
+                6 if !roots.is_empty() => {
+                    roots.swap_remove((seed >> 16) as usize % roots.len());
+                }
+                _ => {
+                    let mut reached: HashSet<GcRef> = HashSet::new();
+                    let mut stack = roots.clone();
+                    while let Some(object) = stack.pop() {
+                        if reached.insert(object) {
+                            stack.extend(&model[&object]);
+                        }
+                    }
+                    heap.mark(roots.iter().copied());
+                    assert!(model.keys().all(|o| heap.color(*o) == Some(if reached.contains(o) { Color::Black } else { Color::White })));
+                    assert_eq!(heap.sweep_white(), model.len() - reached.len());
+                    model.retain(|object, _| reached.contains(object));
+                    assert!(model.keys().all(|o| heap.color(*o) == Some(Color::White)));
+                }
+            }
+            assert_eq!(heap.len(), model.len());
+        }
+    }
+
+    #[test]
+    fn an_empty_heap_reused_slots_and_drop() {
+        struct Leaf(Rc<()>);
+
+        impl Trace for Leaf {
+            fn trace(&self, _: &mut dyn FnMut(GcRef)) {}
+        }
+
+        let counted = Rc::new(());
+        let mut heap = Heap::new();
+        assert_eq!((heap.sweep_white(), heap.len()), (0, 0));
+        assert!(!heap.blacken() && heap.is_empty());
+        let first = heap.alloc(Leaf(counted.clone()));
+        let kept = heap.alloc(Leaf(counted.clone()));
+        heap.mark([kept]);
+        assert_eq!(heap.sweep_white(), 1);
+        assert!(heap.get(first).is_none() && heap.color(first).is_none());
+        assert!(Rc::ptr_eq(&heap.get(kept).unwrap().0, &counted));
+        assert_eq!(Rc::strong_count(&counted), 2);
+        // The freed slot is the next one handed out.
+        assert_eq!(heap.alloc(Leaf(counted.clone())), first);
+        drop(heap);
+        assert_eq!(Rc::strong_count(&counted), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "sweep with gray objects left")]
+    fn sweeping_before_the_mark_ends_panics() {
+        struct Leaf;
+
+        impl Trace for Leaf {
+            fn trace(&self, _: &mut dyn FnMut(GcRef)) {}
+        }
+
+        let mut heap = Heap::new();
+        let root = heap.alloc(Leaf);
+        heap.mark_gray(root);
+        heap.sweep_white();
+    }
+}
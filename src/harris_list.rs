@@ -0,0 +1,294 @@
+// Name: HarrisList - Harris' lock free sorted linked list, the mark bit in
+//       the next pointers.
+//
+// Description: A sorted set where every next pointer is an AtomicTaggedPtr
+//              and flag a of a node's next is its deletion mark:
+//
+//                 flag a - MARKED : the node is logically deleted.
+//
+//              remove() first marks the node, one CAS on its next, and only
+//              then unlinks it, a second CAS on the next of its predecessor.
+//              The mark is the point where the value is gone: an insert
+//              after the node, a CAS on that same word, fails from then on,
+//              so nothing can be linked behind a node being removed. If the
+//              unlink CAS loses a race, the next search() that walks over
+//              the node unlinks it, every operation helps.
+//
+//              An AtomicTaggedPtr always holds a reference, so the list has
+//              a head and a tail sentinel without value, and the next of
+//              the last node points to the tail, never to nothing (a null
+//              next couldn't carry the mark).
+//
+//              Unlinked nodes are not freed while the list is shared, they
+//              go on a retired list freed by collect() and by Drop, which
+//              take &mut self, as in TaggedStack. So a traversal never reads
+//              freed memory and a node address never comes back (no ABA),
+//              and iter() can hand out references for as long as the list
+//              is borrowed. The nodes are &'static to the AtomicTaggedPtr,
+//              so T must be 'static.
+
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::aligned::AlignedAtLeast;
+use crate::atomic_tagged_ptr::AtomicTaggedPtr;
+use crate::ref_with_2_flags::RefWith2Flags;
+
+#[repr(align(4))]
+struct Node<T: 'static> {
+    // Uninit in the sentinels, never written once the node is linked.
+    value: MaybeUninit<T>,
+    next: AtomicTaggedPtr<'static, Node<T>>,
+    // Link of the retired list, written by the one CAS that unlinked it.
+    retired_next: AtomicPtr<Node<T>>,
+    // The pointer from Box::into_raw, to free the node with its own
+    // provenance, not the one of a shared reference.
+    raw: *mut Node<T>,
+}
+
+const _: () = assert!(core::mem::align_of::<Node<()>>() >= 4);
+unsafe impl<T: 'static> AlignedAtLeast<4> for Node<T> {}
+
+pub struct HarrisList<T: 'static> {
+    head: *mut Node<T>,
+    tail: *mut Node<T>,
+    retired: AtomicPtr<Node<T>>,
+}
+
+// The values are shared between the threads that read the list and moved
+// in by the ones that insert.
+unsafe impl<T: Send + Sync> Send for HarrisList<T> {}
+unsafe impl<T: Send + Sync> Sync for HarrisList<T> {}
+
+impl<T: Ord + 'static> HarrisList<T> {
+
+    pub fn new() -> HarrisList<T> {
+        // The next of the tail is never followed, it points to the tail
+        // itself, so there is a reference to put in it.
+        let tail = Self::alloc_node(MaybeUninit::uninit(), None);
+        let head = Self::alloc_node(MaybeUninit::uninit(), Some(tail));
+        HarrisList { head, tail, retired: AtomicPtr::new(ptr::null_mut()) }
+    }
+
+    /// Returns false, and drops `value`, if it was already in the list.
+    pub fn insert(&self, value: T) -> bool {
+        let node = Self::alloc_node(MaybeUninit::new(value), Some(self.tail));
+        let new = unsafe { &*node };
+        let value = unsafe { new.value.assume_init_ref() };
+        loop {
+            let (prev, curr) = self.search(value);
+            if !self.is_tail(curr) && unsafe { curr.value.assume_init_ref() } == value {
+                drop(unsafe { Box::from_raw(node).value.assume_init() });
+                return false;
+            }
+            // The node isn't shared until the CAS publishes it.
+            new.next.store(RefWith2Flags::new(curr, false, false), Ordering::Relaxed);
+            let expected = RefWith2Flags::new(curr, false, false);
+            let linked = RefWith2Flags::new(new, false, false);
+            if prev.next.compare_exchange(&expected, linked, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+                return true;
+            }
+        }
+    }
+
+    /// Returns false if `value` wasn't in the list, or another remove() got
+    /// it first.
+    pub fn remove(&self, value: &T) -> bool {
+        loop {
+            let (prev, curr) = self.search(value);
+            if self.is_tail(curr) || unsafe { curr.value.assume_init_ref() } != value {
+                return false;
+            }
+            let next = curr.next.load(Ordering::Acquire);
+            if next.get_flag_a() {
+                return false;
+            }
+            let mut marked = next;
+            marked.set_flag_a(true);
+            if curr.next.compare_exchange(&next, marked, Ordering::AcqRel, Ordering::Acquire).is_err() {
+                // An insert after it or a concurrent mark, look again.
+                continue;
+            }
+            let expected = RefWith2Flags::new(curr, false, false);
+            let succ = RefWith2Flags::new(next.get_ref(), false, false);
+            if prev.next.compare_exchange(&expected, succ, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+                self.retire(curr);
+            } else {
+                self.search(value);
+            }
+            return true;
+        }
+    }
+
+    /// Wait free, it doesn't help unlink.
+    pub fn contains(&self, value: &T) -> bool {
+        let mut node = self.head_node().next.load(Ordering::Acquire).get_ref();
+        while !self.is_tail(node) && unsafe { node.value.assume_init_ref() } < value {
+            node = node.next.load(Ordering::Acquire).get_ref();
+        }
+        !self.is_tail(node)
+            && unsafe { node.value.assume_init_ref() } == value
+            && !node.next.load(Ordering::Acquire).get_flag_a()
+    }
+
+    /// The values not marked for deletion, in order. Under concurrent
+    /// updates it is not a snapshot, each node is seen as it is when the
+    /// iterator gets to it.
+    pub fn iter(&self) -> impl Iterator<Item = &T> + '_ {
+        let mut node = self.head_node();
+        std::iter::from_fn(move || loop {
+            node = node.next.load(Ordering::Acquire).get_ref();
+            if self.is_tail(node) {
+                return None;
+            }
+            if !node.next.load(Ordering::Acquire).get_flag_a() {
+                return Some(unsafe { node.value.assume_init_ref() });
+            }
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    // The first node not below `value`, or the tail, and the node before it,
+    // unlinking the marked nodes on the way.
+    fn search(&self, value: &T) -> (&'static Node<T>, &'static Node<T>) {
+        'retry: loop {
+            let mut prev = self.head_node();
+            let mut curr = prev.next.load(Ordering::Acquire).get_ref();
+            loop {
+                if self.is_tail(curr) {
+                    return (prev, curr);
+                }
+                let next = curr.next.load(Ordering::Acquire);
+                if next.get_flag_a() {
+                    let expected = RefWith2Flags::new(curr, false, false);
+                    let succ = RefWith2Flags::new(next.get_ref(), false, false);
+                    if prev.next.compare_exchange(&expected, succ, Ordering::AcqRel, Ordering::Acquire).is_err() {
+                        // prev is marked itself, or changed.
+                        continue 'retry;
+                    }
+                    self.retire(curr);
+                    curr = next.get_ref();
+                    continue;
+                }
+                if unsafe { curr.value.assume_init_ref() } >= value {
+                    return (prev, curr);
+                }
+                prev = curr;
+                curr = next.get_ref();
+            }
+        }
+    }
+
+    fn head_node(&self) -> &'static Node<T> {
+        unsafe { &*self.head }
+    }
+
+    fn is_tail(&self, node: &Node<T>) -> bool {
+        ptr::eq(node, self.tail)
+    }
+
+    // `next` None makes the node point to itself.
+    fn alloc_node(value: MaybeUninit<T>, next: Option<*mut Node<T>>) -> *mut Node<T> {
+        let raw = Box::into_raw(Box::<Node<T>>::new_uninit()).cast::<Node<T>>();
+        let next = unsafe { RefWith2Flags::from_tagged_ptr(next.unwrap_or(raw)) };
+        let node = Node { value, next: AtomicTaggedPtr::new(next), retired_next: AtomicPtr::new(ptr::null_mut()), raw };
+        unsafe { raw.write(node) };
+        raw
+    }
+
+    fn retire(&self, node: &Node<T>) {
+        let mut top = self.retired.load(Ordering::Relaxed);
+        loop {
+            node.retired_next.store(top, Ordering::Relaxed);
+            match self.retired.compare_exchange_weak(top, node.raw, Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => return,
+                Err(actual) => top = actual,
+            }
+        }
+    }
+
+}
+
+impl<T: 'static> HarrisList<T> {
+
+    /// Frees the nodes unlinked so far.
+    pub fn collect(&mut self) {
+        let mut node = self.retired.swap(ptr::null_mut(), Ordering::Acquire);
+        while !node.is_null() {
+            let boxed = unsafe { Box::from_raw(node) };
+            node = boxed.retired_next.load(Ordering::Relaxed);
+            drop(unsafe { boxed.value.assume_init() });
+        }
+    }
+
+}
+
+impl<T: Ord + 'static> Default for HarrisList<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: 'static> Drop for HarrisList<T> {
+    fn drop(&mut self) {
+        self.collect();
+        // The linked nodes, marked or not, between the sentinels.
+        let mut node = unsafe { (*self.head).next.load(Ordering::Relaxed).get_ref().raw };
+        while node != self.tail {
+            let boxed = unsafe { Box::from_raw(node) };
+            node = boxed.next.load(Ordering::Relaxed).get_ref().raw;
+            drop(unsafe { boxed.value.assume_init() });
+        }
+        drop(unsafe { Box::from_raw(self.head) });
+        drop(unsafe { Box::from_raw(self.tail) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn concurrent_inserts_and_removes_keep_the_set() {
+        let list = HarrisList::new();
+        thread::scope(|s| {
+            for t in 0..4 {
+                let list = &list;
+                s.spawn(move || {
+                    for i in (t..400).step_by(4) {
+                        assert!(list.insert(i));
+                    }
+                    for i in (t..400).step_by(4).filter(|i| i % 3 == 0) {
+                        assert!(list.remove(&i));
+                    }
+                });
+            }
+        });
+        let expected: Vec<u32> = (0..400).filter(|i| i % 3 != 0).collect();
+        assert_eq!(list.iter().copied().collect::<Vec<_>>(), expected);
+        assert!(list.contains(&1) && !list.contains(&3));
+        assert!(!list.insert(1) && !list.remove(&3));
+    }
+
+    #[test]
+    fn racing_removes_have_one_winner() {
+        let list = HarrisList::new();
+        for _ in 0..100 {
+            list.insert(7);
+            let winners = thread::scope(|s| {
+                let handles: Vec<_> = (0..4).map(|_| s.spawn(|| list.remove(&7))).collect();
+                handles.into_iter().map(|h| h.join().unwrap()).filter(|&won| won).count()
+            });
+            assert_eq!(winners, 1);
+        }
+    }
+}
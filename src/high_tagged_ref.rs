@@ -0,0 +1,171 @@
+// Name: HighTaggedRef, ByteTaggedRef - a reference and a payload in the high
+//       bits, or in the high and the low bits.
+//
+// Description: x86_64 and aarch64 use 48 bit virtual addresses (4 level page
+//              tables), the 16 high bits of a user space address are copies
+//              of bit 47, the canonical form. They carry no information, so
+//              they can hold a u16 payload, far more than the 2 or 3 bits the
+//              alignment frees, and whatever the alignment of T.
+//
+//              get_ref() restores the canonical form by sign extending bit
+//              47, the tagged word itself is never dereferenced (it would
+//              fault, the CPU checks the canonical form). new() panics on an
+//              address that doesn't fit in 48 bits, e.g. with 5 level paging
+//              and a kernel that hands out addresses above 2^47.
+//
+//              ByteTaggedRef layers both kinds of tagging: the 2 low bits the
+//              4 bytes alignment frees hold the low 2 bits of a u8 payload
+//              and the high bits hold the other 6, so a whole byte, e.g. a
+//              small object header, fits in the reference.
+
+use crate::aligned::AlignedAtLeast;
+
+use core::marker::PhantomData;
+use core::ptr::NonNull;
+
+pub(crate) const ADDR_BITS: u32 = 48;
+pub(crate) const TAG_SHIFT: u32 = ADDR_BITS;
+pub(crate) const ADDR_MASK: usize = (1 << ADDR_BITS) - 1;
+
+#[repr(transparent)]
+pub struct HighTaggedRef<'a, T> {
+    ptr_and_tag: NonNull<T>,
+    behaves_like: PhantomData<&'a T>,
+}
+
+// NonNull opts out of Send and Sync, this is a &T as far as threads go.
+unsafe impl<'a, T: Sync> Send for HighTaggedRef<'a, T> {}
+unsafe impl<'a, T: Sync> Sync for HighTaggedRef<'a, T> {}
+
+impl<'a, T: 'a> HighTaggedRef<'a, T> {
+
+    /// Panics if the address isn't a canonical 48 bit address.
+    pub fn new(ptr: &'a T, tag: u16) -> HighTaggedRef<'a, T> {
+        let ptr = NonNull::from(ptr);
+        assert!(canonical(ptr.as_ptr().addr()) == ptr.as_ptr().addr(), "address doesn't fit in 48 bits");
+        // The low 48 bits are the non null address, so the word isn't null.
+        let tagged = ptr.as_ptr().map_addr(|addr| (addr & ADDR_MASK) | ((tag as usize) << TAG_SHIFT));
+        HighTaggedRef { ptr_and_tag: unsafe { NonNull::new_unchecked(tagged) }, behaves_like: PhantomData }
+    }
+
+    pub fn get_ref(&self) -> &'a T {
+        unsafe { &*self.ptr_and_tag.as_ptr().map_addr(canonical) }
+    }
+
+    pub fn get_tag(&self) -> u16 {
+        (self.ptr_and_tag.as_ptr().addr() >> TAG_SHIFT) as u16
+    }
+
+    pub fn set_tag(&mut self, tag: u16) {
+        let tagged = self.ptr_and_tag.as_ptr().map_addr(|addr| (addr & ADDR_MASK) | ((tag as usize) << TAG_SHIFT));
+        self.ptr_and_tag = unsafe { NonNull::new_unchecked(tagged) };
+    }
+
+}
+
+#[repr(transparent)]
+pub struct ByteTaggedRef<'a, T> {
+    ptr_and_tag: NonNull<T>,
+    behaves_like: PhantomData<&'a T>,
+}
+
+unsafe impl<'a, T: Sync> Send for ByteTaggedRef<'a, T> {}
+unsafe impl<'a, T: Sync> Sync for ByteTaggedRef<'a, T> {}
+
+impl<'a, T: 'a> ByteTaggedRef<'a, T> {
+
+    /// Panics if the address isn't a canonical 48 bit address.
+    pub fn new(ptr: &'a T, payload: u8) -> ByteTaggedRef<'a, T>
+    where
+        T: AlignedAtLeast<4>,
+    {
+        let ptr = NonNull::from(ptr);
+        assert!(canonical(ptr.as_ptr().addr()) == ptr.as_ptr().addr(), "address doesn't fit in 48 bits");
+        let tagged = ptr.as_ptr().map_addr(|addr| (addr & ADDR_MASK) | spread(payload));
+        ByteTaggedRef { ptr_and_tag: unsafe { NonNull::new_unchecked(tagged) }, behaves_like: PhantomData }
+    }
+
+    pub fn get_ref(&self) -> &'a T {
+        unsafe { &*self.ptr_and_tag.as_ptr().map_addr(|addr| canonical(addr & !3)) }
+    }
+
+    pub fn get_payload(&self) -> u8 {
+        let word = self.ptr_and_tag.as_ptr().addr();
+        ((word & 3) | ((word >> TAG_SHIFT) << 2)) as u8
+    }
+
+    pub fn set_payload(&mut self, payload: u8) {
+        let tagged = self.ptr_and_tag.as_ptr().map_addr(|addr| (addr & ADDR_MASK & !3) | spread(payload));
+        // The address part, non null, is kept.
+        self.ptr_and_tag = unsafe { NonNull::new_unchecked(tagged) };
+    }
+
+}
+
+impl<'a, T> Clone for ByteTaggedRef<'a, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, T> Copy for ByteTaggedRef<'a, T> {}
+
+impl<'a, T> Clone for HighTaggedRef<'a, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, T> Copy for HighTaggedRef<'a, T> {}
+
+// The 2 low bits of the payload stay low, the other 6 go above bit 47.
+fn spread(payload: u8) -> usize {
+    (payload as usize & 3) | ((payload as usize >> 2) << TAG_SHIFT)
+}
+
+// Bit 47 copied into the 16 high bits.
+pub(crate) fn canonical(addr: usize) -> usize {
+    (((addr << (usize::BITS - ADDR_BITS)) as isize) >> (usize::BITS - ADDR_BITS)) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aligned_box::Align16;
+
+    #[test]
+    fn canonical_sign_extends_bit_47() {
+        let low = 0x0000_7fff_dead_beef_usize;
+        let high = 0xffff_8000_0000_1000_usize;
+        assert_eq!(canonical(low | 0xabcd << TAG_SHIFT), low);
+        assert_eq!(canonical((high & ADDR_MASK) | 0x1234 << TAG_SHIFT), high);
+        assert_eq!(canonical(high & ADDR_MASK), high);
+    }
+
+    #[test]
+    fn byte_payload_round_trips() {
+        let value = 5u32;
+        let mut r = ByteTaggedRef::new(&value, 0);
+        for payload in 0..=u8::MAX {
+            r.set_payload(payload);
+            assert_eq!(r.get_payload(), payload);
+            assert!(core::ptr::eq(r.get_ref(), &value));
+        }
+    }
+
+    #[test]
+    fn sixteen_bits_above_the_address() {
+        let byte = 0xABu8;
+        let mut high = HighTaggedRef::new(&byte, 0xFFFF);
+        assert_eq!((*high.get_ref(), high.get_tag()), (0xAB, 0xFFFF));
+        high.set_tag(0x1234);
+        assert_eq!((*high.get_ref(), high.get_tag()), (0xAB, 0x1234));
+        let slot = Align16(21u16);
+        let on_stack = HighTaggedRef::new(&slot, 7);
+        assert!(std::ptr::eq(on_stack.get_ref(), &slot));
+        let mut header = ByteTaggedRef::new(&slot, 0b1011_0110);
+        assert_eq!((header.get_payload(), header.get_ref().0), (0b1011_0110, 21));
+        header.set_payload(u8::MAX);
+        assert_eq!((header.get_payload(), header.get_ref().0), (u8::MAX, 21));
+    }
+}
@@ -0,0 +1,121 @@
+// Name: InlineCache - a one word inline cache entry for an interpreter.
+//
+// Description: A call site of an interpreter caches the target it resolved
+//              the last time, so the next execution skips the lookup. The
+//              entry is a single word, the cached target plus 2 flags:
+//
+//                 bit 0 - VALID       : the cached target can be used.
+//                 bit 1 - MEGAMORPHIC : the site saw more than one target,
+//                                       stop caching and always do the full
+//                                       lookup.
+//
+//              probe(), fill() and invalidate() are each a single load or a
+//              single store of that word. The word is in a Cell, so the cache
+//              can be updated through the shared reference the interpreter
+//              holds to its code. It is a Cell of a pointer, the bits set
+//              with map_addr, so the target probe() hands back has the
+//              provenance of the one filled in.
+
+use core::cell::Cell;
+use core::marker::PhantomData;
+use core::ptr;
+
+use crate::aligned::AlignedAtLeast;
+
+const VALID: usize = 1;
+const MEGAMORPHIC: usize = 2;
+
+pub struct InlineCache<'a, T> {
+    ptr_and_bits: Cell<*const T>,
+    behaves_like: PhantomData<&'a T>,
+}
+
+// The raw pointer opts out of Send, this is a Cell<Option<&T>> as far as
+// threads go. The Cell already keeps it from being Sync.
+unsafe impl<'a, T: Sync> Send for InlineCache<'a, T> {}
+
+impl<'a, T: AlignedAtLeast<4>> InlineCache<'a, T> {
+
+    pub fn new() -> InlineCache<'a, T> {
+        InlineCache { ptr_and_bits: Cell::new(ptr::null()), behaves_like: PhantomData }
+    }
+
+    /// The cached target, if the entry is valid and the site is monomorphic.
+    pub fn probe(&self) -> Option<&'a T> {
+        let word = self.ptr_and_bits.get();
+        if word.addr() & (VALID | MEGAMORPHIC) == VALID {
+            Some(unsafe { &*word.map_addr(|addr| addr & !3) })
+        } else {
+            None
+        }
+    }
+
+    /// Caches the target resolved by the slow path. A second, different,
+    /// target makes the site megamorphic and it is never cached again.
+    pub fn fill(&self, target: &'a T) {
+        let word = self.ptr_and_bits.get();
+        let target = target as *const T;
+        let new_word = if word.addr() & MEGAMORPHIC != 0 {
+            word
+        } else if word.addr() & VALID != 0 && word.addr() & !3 != target.addr() {
+            ptr::without_provenance(MEGAMORPHIC)
+        } else {
+            target.map_addr(|addr| addr | VALID)
+        };
+        self.ptr_and_bits.set(new_word);
+    }
+
+    /// Drops the cached target, for example after the target was redefined.
+    pub fn invalidate(&self) {
+        self.ptr_and_bits.set(self.ptr_and_bits.get().map_addr(|addr| addr & !VALID));
+    }
+
+    pub fn is_megamorphic(&self) -> bool {
+        self.ptr_and_bits.get().addr() & MEGAMORPHIC != 0
+    }
+
+}
+
+impl<'a, T: AlignedAtLeast<4>> Default for InlineCache<'a, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn goes_megamorphic_on_a_second_target() {
+        let (method_a, method_b) = (1_u32, 2_u32);
+        let cache = InlineCache::new();
+        assert!(cache.probe().is_none());
+        cache.fill(&method_a);
+        assert_eq!(cache.probe(), Some(&1));
+        cache.invalidate();
+        assert!(cache.probe().is_none());
+        cache.fill(&method_a);
+        cache.fill(&method_b);
+        assert!(cache.is_megamorphic() && cache.probe().is_none());
+    }
+
+    #[test]
+    fn refilling_the_same_target_stays_monomorphic() {
+        let target = 5_u32;
+        let cache = InlineCache::default();
+        cache.fill(&target);
+        cache.fill(&target);
+        assert_eq!(cache.probe(), Some(&5));
+        // An invalidated entry takes any target, it doesn't count as a second one.
+        cache.invalidate();
+        let other = 6_u32;
+        cache.fill(&other);
+        assert!(!cache.is_megamorphic());
+        assert_eq!(cache.probe(), Some(&6));
+        cache.fill(&target);
+        cache.invalidate();
+        cache.fill(&other);
+        assert!(cache.is_megamorphic() && cache.probe().is_none());
+    }
+}
@@ -0,0 +1,239 @@
+//! Tagged pointers: references, raw pointers and atomics that keep 2 flags
+//! (or more, with enough alignment) in the low bits of the address, and the
+//! data structures and synchronization primitives built on them.
+//!
+//! The main types are re-exported at the root, the modules keep the rest of
+//! each API (error types, handles, guards, aliases). The one exception is the
+//! module of RefWith2Flags, it would have the name of the crate and make
+//! `use ref_with_2_flags::*` ambiguous, so it is private and its error types
+//! are at the root too:
+//!
+//! ```
+//! use ref_with_2_flags::*;
+//!
+//! let value = 7_u32;
+//! let tagged = RefWith2Flags::new(&value, true, false);
+//! assert_eq!(tagged.validate(), Ok::<(), TagCorruption>(()));
+//! assert_eq!(*tagged.get_ref(), 7);
+//! ```
+//!
+//! The crate is `no_std` by default: the tagged references, the atomics and
+//! the alignment contract only need `core`. The `std` feature adds the owning
+//! pointers and the data structures and locks built on them, the `derive`
+//! feature adds `#[derive(TagEnum)]` and the `demo` feature builds the demo
+//! binary.
+
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+pub mod aligned;
+#[cfg(any(feature = "std", test))]
+pub mod aligned_box;
+#[cfg(any(feature = "std", test))]
+pub mod arc_with_2_flags;
+pub mod atomic_option_tagged_ptr;
+#[cfg(target_pointer_width = "64")]
+pub mod atomic_stamped_ptr;
+pub mod atomic_tagged_ptr;
+#[cfg(all(target_arch = "x86_64", any(feature = "std", test)))]
+pub mod atomic_tagged_ptr_128;
+#[cfg(any(feature = "std", test))]
+pub mod avl_tree;
+pub mod bitpack;
+#[cfg(any(feature = "std", test))]
+pub mod box_with_2_flags;
+#[cfg(any(feature = "std", test))]
+pub mod buddy_allocator;
+pub mod by_value;
+pub mod code_ptr;
+#[cfg(all(test, not(debug_assertions), target_arch = "x86_64"))]
+mod codegen_check;
+#[cfg(any(feature = "std", test))]
+pub mod dump;
+pub mod either_ref;
+#[cfg(any(feature = "std", test))]
+pub mod flagged_hash_map;
+pub mod forwarding;
+#[cfg(any(feature = "std", test))]
+pub mod free_list_pool;
+#[cfg(any(feature = "std", test))]
+pub mod gc;
+#[cfg(any(feature = "std", test))]
+pub mod harris_list;
+#[cfg(target_pointer_width = "64")]
+pub mod high_tagged_ref;
+pub mod inline_cache;
+pub mod lru_list;
+#[cfg(any(feature = "std", test))]
+pub mod mangled_ref_with_2_flags;
+#[cfg(any(feature = "std", test))]
+pub mod maybe_owned_with_flag;
+#[cfg(any(feature = "std", test))]
+pub mod ms_queue;
+#[cfg(target_pointer_width = "64")]
+pub mod nan_box;
+#[cfg(all(test, not(debug_assertions)))]
+mod no_panic;
+#[cfg(any(feature = "std", test))]
+pub mod object_pool;
+pub mod one_of_4_ref;
+pub mod option_ref_with_2_flags;
+#[cfg(any(feature = "std", test))]
+pub mod pairing_heap;
+#[cfg(any(feature = "std", test))]
+pub mod parking_tagged_ptr;
+#[cfg(any(feature = "std", test))]
+pub mod persistent_map;
+#[cfg(any(feature = "std", test))]
+pub mod radix_trie;
+#[cfg(any(feature = "std", test))]
+pub mod rb_tree;
+#[cfg(any(feature = "std", test))]
+pub mod rc_with_2_flags;
+pub mod ref_mut_with_2_flags;
+pub mod ref_with_1_flag;
+mod ref_with_2_flags;
+pub mod ref_with_3_flags;
+pub mod ref_with_tag;
+#[cfg(any(feature = "std", test))]
+pub mod rrb_vector;
+#[cfg(any(feature = "std", test))]
+pub mod scene_graph;
+pub mod scheduled_task;
+pub mod scoped_tag;
+#[cfg(any(feature = "std", test))]
+pub mod shared_slice_with_2_flags;
+#[cfg(any(feature = "std", test))]
+pub mod skip_list;
+pub mod smi_or_ref;
+#[cfg(any(feature = "std", test))]
+pub mod sorted_tombstone_vec;
+#[cfg(any(feature = "std", test))]
+pub mod tagged_arena;
+pub mod tagged_list;
+#[cfg(any(feature = "std", test))]
+pub mod tagged_mutex;
+pub mod tagged_non_null;
+pub mod tagged_ref;
+pub mod tagged_result;
+#[cfg(any(feature = "std", test))]
+pub mod tagged_small_string;
+#[cfg(any(feature = "std", test))]
+pub mod tagged_stack;
+#[cfg(any(feature = "std", test))]
+pub mod tagged_vec;
+#[cfg(any(feature = "std", test))]
+pub mod task_queue;
+#[cfg(any(feature = "std", test))]
+pub mod timer_wheel;
+#[cfg(any(feature = "std", test))]
+pub mod tiny_slice;
+#[cfg(all(target_pointer_width = "64", any(feature = "std", test)))]
+pub mod toy_vm;
+pub mod union_find;
+#[cfg(any(feature = "std", test))]
+pub mod word_mutex;
+#[cfg(any(feature = "std", test))]
+pub mod xor_list;
+
+pub use aligned::AlignedAtLeast;
+#[cfg(any(feature = "std", test))]
+pub use aligned_box::AlignedBox;
+#[cfg(any(feature = "std", test))]
+pub use arc_with_2_flags::ArcWith2Flags;
+pub use atomic_option_tagged_ptr::AtomicOptionTaggedPtr;
+#[cfg(target_pointer_width = "64")]
+pub use atomic_stamped_ptr::AtomicStampedPtr;
+pub use atomic_tagged_ptr::AtomicTaggedPtr;
+#[cfg(all(target_arch = "x86_64", any(feature = "std", test)))]
+pub use atomic_tagged_ptr_128::AtomicTaggedPtr128;
+#[cfg(any(feature = "std", test))]
+pub use avl_tree::AvlTreeMap;
+#[cfg(any(feature = "std", test))]
+pub use box_with_2_flags::BoxWith2Flags;
+#[cfg(any(feature = "std", test))]
+pub use buddy_allocator::BuddyAllocator;
+pub use by_value::{ByValue, ByValueAndFlags};
+pub use code_ptr::CodePtr;
+#[cfg(any(feature = "std", test))]
+pub use dump::Dump;
+pub use either_ref::EitherRef;
+#[cfg(any(feature = "std", test))]
+pub use flagged_hash_map::FlaggedHashMap;
+pub use forwarding::Forwardable;
+#[cfg(any(feature = "std", test))]
+pub use free_list_pool::FreeListPool;
+#[cfg(any(feature = "std", test))]
+pub use harris_list::HarrisList;
+#[cfg(target_pointer_width = "64")]
+pub use high_tagged_ref::{ByteTaggedRef, HighTaggedRef};
+pub use inline_cache::InlineCache;
+#[cfg(any(feature = "std", test))]
+pub use mangled_ref_with_2_flags::MangledRefWith2Flags;
+#[cfg(any(feature = "std", test))]
+pub use maybe_owned_with_flag::MaybeOwnedWithFlag;
+#[cfg(any(feature = "std", test))]
+pub use ms_queue::MsQueue;
+#[cfg(any(feature = "std", test))]
+pub use object_pool::ObjectPool;
+#[cfg(target_pointer_width = "64")]
+pub use nan_box::NanBox;
+pub use one_of_4_ref::{OneOf4, OneOf4Ref};
+pub use option_ref_with_2_flags::OptionRefWith2Flags;
+#[cfg(any(feature = "std", test))]
+pub use pairing_heap::PairingHeap;
+#[cfg(any(feature = "std", test))]
+pub use parking_tagged_ptr::ParkingTaggedPtr;
+#[cfg(any(feature = "std", test))]
+pub use persistent_map::PersistentMap;
+#[cfg(any(feature = "std", test))]
+pub use radix_trie::RadixTrie;
+#[cfg(any(feature = "std", test))]
+pub use rb_tree::RbTreeMap;
+#[cfg(any(feature = "std", test))]
+pub use rc_with_2_flags::RcWith2Flags;
+pub use ref_mut_with_2_flags::RefMutWith2Flags;
+pub use ref_with_1_flag::RefWith1Flag;
+pub use ref_with_2_flags::{RefWith2Flags, TagCorruption, UserDataError};
+pub use ref_with_3_flags::RefWith3Flags;
+pub use ref_with_tag::{RefWithTag, TagEnum};
+#[cfg(feature = "derive")]
+pub use ref_with_2_flags_derive::TagEnum;
+#[cfg(any(feature = "std", test))]
+pub use rrb_vector::RrbVector;
+#[cfg(any(feature = "std", test))]
+pub use scene_graph::SceneGraph;
+pub use scheduled_task::AtomicTaskPtr;
+pub use scoped_tag::ScopedTag;
+#[cfg(any(feature = "std", test))]
+pub use shared_slice_with_2_flags::{ArcSliceWith2Flags, ArcStrWith2Flags, RcSliceWith2Flags, RcStrWith2Flags};
+#[cfg(any(feature = "std", test))]
+pub use skip_list::SkipList;
+pub use smi_or_ref::SmiOrRef;
+#[cfg(any(feature = "std", test))]
+pub use sorted_tombstone_vec::SortedTombstoneVec;
+#[cfg(any(feature = "std", test))]
+pub use tagged_arena::TaggedArena;
+#[cfg(any(feature = "std", test))]
+pub use tagged_mutex::TaggedMutex;
+pub use tagged_non_null::TaggedNonNull;
+pub use tagged_ref::TaggedRef;
+pub use tagged_result::TaggedResult;
+#[cfg(any(feature = "std", test))]
+pub use tagged_small_string::TaggedSmallString;
+#[cfg(any(feature = "std", test))]
+pub use tagged_stack::TaggedStack;
+#[cfg(any(feature = "std", test))]
+pub use tagged_vec::TaggedVec;
+#[cfg(any(feature = "std", test))]
+pub use task_queue::TaskQueue;
+#[cfg(any(feature = "std", test))]
+pub use timer_wheel::TimerWheel;
+#[cfg(any(feature = "std", test))]
+pub use tiny_slice::TinySlice;
+#[cfg(all(target_pointer_width = "64", any(feature = "std", test)))]
+pub use toy_vm::ToyVm;
+pub use union_find::UnionFindNode;
+#[cfg(any(feature = "std", test))]
+pub use word_mutex::WordMutex;
+#[cfg(any(feature = "std", test))]
+pub use xor_list::XorList;
@@ -0,0 +1,340 @@
+// Name: LruList - an intrusive doubly linked LRU list, the "pinned" and
+//       "referenced" bits of each entry in its next link.
+//
+// Description: The doubly linked sibling of tagged_list::List, for caches.
+//              Each entry embeds an LruLink, a prev and a next address, and
+//              the 2 free low bits of the next one are the cache bits of the
+//              entry:
+//
+//                 bit 0 - PINNED     : never evicted, e.g. while in use.
+//                 bit 1 - REFERENCED : used since the clock hand last passed.
+//
+//              The list is ordered from the most recently inserted (front)
+//              to the oldest (back). A hit only sets REFERENCED, no relinking
+//              on the hot path, and evict() is the CLOCK sweep: from the
+//              back, a pinned entry is skipped, a referenced one loses the
+//              bit and gets a second chance at the front, and the first one
+//              with neither is unlinked and returned. move_to_front() is
+//              there for a strict LRU instead.
+//
+//              remove() and move_to_front() unlink in O(1) through the prev
+//              link. The entry must be in this list, as with
+//              tagged_list::List an entry is in at most one list at a time.
+//
+//              Both links are pointers made from the &'a T of the neighbour,
+//              the bits ORed into next by map_addr, so prev() and next() hand
+//              back references with the neighbour's provenance. At an end of
+//              the list, next holds only the bits, a pointer without
+//              provenance.
+
+use core::cell::Cell;
+use core::marker::PhantomData;
+use core::ptr;
+
+use crate::aligned::AlignedAtLeast;
+
+const PINNED: usize = 1;
+const REFERENCED: usize = 2;
+
+pub struct LruLink<'a, T> {
+    prev: Cell<*const T>,
+    // The next entry, plus PINNED and REFERENCED.
+    next: Cell<*const T>,
+    behaves_like: PhantomData<Cell<Option<&'a T>>>,
+}
+
+// The raw pointers opt out of Send, this is a Cell<Option<&T>> as far as
+// threads go.
+unsafe impl<'a, T: Sync> Send for LruLink<'a, T> {}
+
+/// An entry type that embeds an LruLink to its own type.
+pub trait LruLinked<'a>: AlignedAtLeast<4> + Sized + 'a {
+    fn lru_link(&self) -> &LruLink<'a, Self>;
+}
+
+impl<'a, T> LruLink<'a, T> {
+
+    pub const fn new() -> LruLink<'a, T> {
+        LruLink { prev: Cell::new(ptr::null()), next: Cell::new(ptr::null()), behaves_like: PhantomData }
+    }
+
+    pub fn is_pinned(&self) -> bool {
+        self.next.get().addr() & PINNED != 0
+    }
+
+    pub fn set_pinned(&self, pinned: bool) {
+        self.next.set(self.next.get().map_addr(|addr| (addr & !PINNED) | pinned as usize));
+    }
+
+    pub fn is_referenced(&self) -> bool {
+        self.next.get().addr() & REFERENCED != 0
+    }
+
+    pub fn set_referenced(&self, referenced: bool) {
+        self.next.set(self.next.get().map_addr(|addr| (addr & !REFERENCED) | ((referenced as usize) << 1)));
+    }
+
+    fn prev(&self) -> Option<&'a T> {
+        // Only ever set by set_prev() from a &'a T.
+        unsafe { self.prev.get().as_ref() }
+    }
+
+    fn next(&self) -> Option<&'a T> {
+        // Only ever set by set_next() from a &'a T.
+        unsafe { self.next.get().map_addr(|addr| addr & !3).as_ref() }
+    }
+
+    fn set_prev(&self, prev: Option<&'a T>) {
+        self.prev.set(prev.map_or(ptr::null(), ptr::from_ref));
+    }
+
+    // Keeps the bits.
+    fn set_next(&self, next: Option<&'a T>) {
+        let bits = self.next.get().addr() & 3;
+        self.next.set(next.map_or(ptr::null(), ptr::from_ref).map_addr(|addr| addr | bits));
+    }
+
+}
+
+impl<'a, T> Default for LruLink<'a, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct LruList<'a, T> {
+    front: Option<&'a T>,
+    back: Option<&'a T>,
+    len: usize,
+}
+
+impl<'a, T: LruLinked<'a>> LruList<'a, T> {
+
+    pub const fn new() -> LruList<'a, T> {
+        LruList { front: None, back: None, len: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.front.is_none()
+    }
+
+    /// Links a new entry at the front, its bits are kept.
+    pub fn push_front(&mut self, entry: &'a T) {
+        let link = entry.lru_link();
+        link.set_prev(None);
+        link.set_next(self.front);
+        match self.front {
+            Some(front) => front.lru_link().set_prev(Some(entry)),
+            None => self.back = Some(entry),
+        }
+        self.front = Some(entry);
+        self.len += 1;
+    }
+
+    /// Unlinks an entry of this list, its bits are kept.
+    pub fn remove(&mut self, entry: &'a T) {
+        let link = entry.lru_link();
+        match link.prev() {
+            Some(prev) => prev.lru_link().set_next(link.next()),
+            None => self.front = link.next(),
+        }
+        match link.next() {
+            Some(next) => next.lru_link().set_prev(link.prev()),
+            None => self.back = link.prev(),
+        }
+        link.set_prev(None);
+        link.set_next(None);
+        self.len -= 1;
+    }
+
+    /// Strict LRU: moves an entry of this list to the front.
+    pub fn move_to_front(&mut self, entry: &'a T) {
+        self.remove(entry);
+        self.push_front(entry);
+    }
+
+    /// A cache hit, sets REFERENCED. Doesn't relink.
+    pub fn touch(&self, entry: &T) {
+        entry.lru_link().set_referenced(true);
+    }
+
+    /// The CLOCK sweep: unlinks and returns the oldest entry that is
+    /// neither pinned nor referenced, the referenced ones it passes lose the
+    /// bit and move to the front. None if every entry is pinned.
+    pub fn evict(&mut self) -> Option<&'a T> {
+        // A referenced entry moves to the front without the bit, so after
+        // one full turn only the pinned ones are left to skip.
+        let mut hand = self.back;
+        for _ in 0..2 * self.len {
+            let entry = hand?;
+            let link = entry.lru_link();
+            let older = link.prev();
+            if !link.is_pinned() && !link.is_referenced() {
+                self.remove(entry);
+                return Some(entry);
+            }
+            if !link.is_pinned() {
+                link.set_referenced(false);
+                self.move_to_front(entry);
+            }
+            // Past the front, the hand goes around to the back.
+            hand = older.or(self.back);
+        }
+        None
+    }
+
+    /// From the front, the most recent entries first.
+    pub fn iter(&self) -> impl Iterator<Item = &'a T> + '_ {
+        let mut next = self.front;
+        core::iter::from_fn(move || {
+            let entry = next?;
+            next = entry.lru_link().next();
+            Some(entry)
+        })
+    }
+
+}
+
+impl<'a, T: LruLinked<'a>> Default for LruList<'a, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    // A cache entry, in the list through its own link.
+    #[repr(align(4))]
+    struct Entry<'a> {
+        lru: LruLink<'a, Entry<'a>>,
+        key: u32,
+    }
+
+    crate::aligned_at_least!(Entry<'_> => 4);
+
+    impl<'a> LruLinked<'a> for Entry<'a> {
+        fn lru_link(&self) -> &LruLink<'a, Entry<'a>> {
+            &self.lru
+        }
+    }
+
+    fn entries<'a>(n: u32) -> Vec<Entry<'a>> {
+        (0..n).map(|key| Entry { lru: LruLink::new(), key }).collect()
+    }
+
+    fn keys<'a>(lru: &LruList<'a, Entry<'a>>) -> Vec<u32> {
+        lru.iter().map(|entry| entry.key).collect()
+    }
+
+    #[test]
+    fn clock_eviction_gives_a_second_chance() {
+        let entries = entries(4);
+        let mut lru = LruList::new();
+        for entry in &entries {
+            lru.push_front(entry);
+        }
+        assert_eq!(keys(&lru), [3, 2, 1, 0]);
+        entries[0].lru.set_pinned(true);
+        lru.touch(&entries[1]);
+        assert_eq!(lru.evict().map(|entry| entry.key), Some(2));
+        assert!(keys(&lru) == [1, 3, 0] && !entries[1].lru.is_referenced());
+        lru.move_to_front(&entries[3]);
+        assert_eq!(lru.evict().map(|entry| entry.key), Some(1));
+        assert_eq!(lru.evict().map(|entry| entry.key), Some(3));
+        assert!(lru.evict().is_none() && lru.len() == 1);
+    }
+
+    // The CLOCK sweep on the model, the hand an index from the front.
+    fn model_evict(model: &mut VecDeque<u32>, bits: &mut [(bool, bool)]) -> Option<u32> {
+        let mut hand = model.len().checked_sub(1)?;
+        for _ in 0..2 * model.len() {
+            let key = model[hand];
+            let (pinned, referenced) = bits[key as usize];
+            if !pinned && !referenced {
+                return model.remove(hand);
+            }
+            let mut older = hand.checked_sub(1);
+            if !pinned {
+                bits[key as usize].1 = false;
+                model.remove(hand);
+                model.push_front(key);
+                older = older.map(|older| older + 1);
+            }
+            hand = older.unwrap_or(model.len() - 1);
+        }
+        None
+    }
+
+    #[test]
+    fn random_ops_match_a_deque() {
+        let entries = entries(24);
+        let mut lru = LruList::new();
+        // The model: the keys from the front, and every entry's bits.
+        let mut model: VecDeque<u32> = VecDeque::new();
+        let mut bits = [(false, false); 24];
+        let mut seed: u32 = 7;
+        for _ in 0..5000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let key = (seed >> 16) % 24;
+            let entry = &entries[key as usize];
+            let linked = model.contains(&key);
+            match (seed >> 8) % 6 {
+                0 if !linked => {
+                    lru.push_front(entry);
+                    model.push_front(key);
+                }
+                1 if linked => {
+                    lru.remove(entry);
+                    model.retain(|&k| k != key);
+                }
+                2 if linked => {
+                    lru.move_to_front(entry);
+                    model.retain(|&k| k != key);
+                    model.push_front(key);
+                }
+                3 => {
+                    lru.touch(entry);
+                    bits[key as usize].1 = true;
+                }
+                4 => {
+                    let pinned = seed & 1 != 0;
+                    entry.lru.set_pinned(pinned);
+                    bits[key as usize].0 = pinned;
+                }
+                5 => assert_eq!(lru.evict().map(|entry| entry.key), model_evict(&mut model, &mut bits)),
+                _ => {}
+            }
+            assert!(lru.iter().map(|entry| entry.key).eq(model.iter().copied()));
+            assert!(lru.len() == model.len() && lru.is_empty() == model.is_empty());
+            for (entry, &(pinned, referenced)) in entries.iter().zip(&bits) {
+                assert_eq!((entry.lru.is_pinned(), entry.lru.is_referenced()), (pinned, referenced));
+            }
+        }
+    }
+
+    #[test]
+    fn an_empty_list_and_a_single_entry() {
+        let entries = entries(1);
+        let mut lru = LruList::new();
+        assert!(lru.is_empty() && lru.evict().is_none() && lru.iter().next().is_none());
+        lru.push_front(&entries[0]);
+        lru.touch(&entries[0]);
+        lru.move_to_front(&entries[0]);
+        assert!(keys(&lru) == [0] && entries[0].lru.is_referenced());
+        // Loses the bit on the first pass, goes on the second.
+        assert_eq!(lru.evict().map(|entry| entry.key), Some(0));
+        assert!(lru.is_empty() && !entries[0].lru.is_referenced());
+        entries[0].lru.set_pinned(true);
+        lru.push_front(&entries[0]);
+        assert!(lru.evict().is_none() && lru.len() == 1);
+        lru.remove(&entries[0]);
+        assert!(lru.is_empty() && lru.iter().next().is_none() && entries[0].lru.is_pinned());
+    }
+}
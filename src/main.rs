@@ -74,9 +74,26 @@
 //
 // Because this is a derived work the license is the same as the original code.                                 
 
-mod ref_with_2_flags;
+use ref_with_2_flags::{aligned_at_least, RefWith2Flags, RefWithTag, TagEnum, TaggedRef, TaggedVec};
 
-use ref_with_2_flags::RefWith2Flags;
+// A user type declares its alignment contract, checked at compile time.
+#[repr(align(16))]
+struct CacheSlot {
+    hits: u16,
+}
+
+aligned_at_least!(CacheSlot => 8);
+
+// The tag of a depth first search, derived. #[tag_bits(2)] keeps room for a
+// 4th state later.
+#[derive(Clone, Copy, Debug, PartialEq, TagEnum)]
+#[tag_bits(2)]
+enum Visit {
+    Unseen,
+    /// On the stack.
+    Open,
+    Done = 7,
+}
 
 fn main() {
     println!("************************");
@@ -85,8 +102,28 @@ fn main() {
 
     let vec = vec![10, 20, 30];
     let flagged = RefWith2Flags::new(&vec, true, false);
-    assert_eq!(flagged.get_ref()[1], 20);
-    assert_eq!(flagged.get_flag_a(), true);
-    assert_eq!(flagged.get_flag_b(), false);
-    assert_eq!(flagged.get_ref()[2], 30);
+    println!("{:?}", flagged);
+    println!("{}", flagged.fmt_bits());
+    println!("vec[1] = {}, flag_a = {}, flag_b = {}", flagged.get_ref()[1], flagged.get_flag_a(), flagged.get_flag_b());
+
+    // The alignment is checked by the AlignedAtLeast<4> bound at compile time,
+    // RefWith2Flags::new(&1_u16, ..) doesn't compile.
+    let slots: Vec<CacheSlot> = (0..4).map(|hits| CacheSlot { hits }).collect();
+    let mut list: TaggedVec<CacheSlot> = slots.iter().map(|slot| (slot, slot.hits % 2 == 0, false)).collect();
+    list.push(&slots[3], false, true);
+    for tagged in list.iter() {
+        println!("slot {} a = {} b = {}", tagged.get_ref().hits, tagged.get_flag_a(), tagged.get_flag_b());
+    }
+
+    // All 4 spare bits of a 16 aligned type as a small integer.
+    let mut counter = TaggedRef::<CacheSlot, 4>::new(&slots[1], 0);
+    counter.set_tag(TaggedRef::<CacheSlot, 4>::MASK);
+    println!("slot {} with tag {}", counter.get_ref().hits, counter.get_tag());
+
+    // A derived TagEnum, the variants are numbered by position.
+    let mut visit = RefWithTag::new(&slots[2], Visit::Unseen);
+    for state in [Visit::Open, Visit::Done] {
+        visit.set_tag(state);
+        println!("slot {} is {:?}, {} of {} bits", visit.get_ref().hits, visit.get_tag(), state.to_bits(), <Visit as TagEnum>::BITS);
+    }
 }
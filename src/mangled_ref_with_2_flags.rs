@@ -0,0 +1,187 @@
+// Name: MangledRefWith2Flags - a RefWith2Flags stored XORed with a secret.
+//
+// Description: Opt-in hardening in the style of the glibc PTR_MANGLE. The
+//              packed word (address plus 2 flags) is XORed with a random
+//              cookie chosen once per process, so what sits in memory is not
+//              a usable address. An attacker that can overwrite the word
+//              can't point it somewhere chosen without knowing the cookie,
+//              and a leaked word doesn't reveal the address.
+//
+//              The word is kept as a pointer whose address is mangled with
+//              map_addr, so decoding gives back the provenance of the
+//              reference without an integer to pointer cast.
+//
+//              The accessors decode transparently. The flag getters, setters
+//              and toggles, get_all(), Clone, Copy and Deref are the ones of
+//              RefWith2Flags. Debug shows the flags and the value but not the
+//              address, that would undo the mangling in a log. The rest of
+//              the RefWith2Flags API is behind unmangle().
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::OnceLock;
+
+use crate::aligned::AlignedAtLeast;
+use crate::ref_with_2_flags::RefWith2Flags;
+
+static COOKIE: OnceLock<usize> = OnceLock::new();
+
+// RandomState is seeded from the OS random source, once per process.
+fn cookie() -> usize {
+    *COOKIE.get_or_init(|| {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_usize(&COOKIE as *const _ as usize);
+        (hasher.finish() as usize) | 1 << (usize::BITS - 1)
+    })
+}
+
+pub struct MangledRefWith2Flags<'a, T> {
+    mangled: *const T,
+    behaves_like: PhantomData<&'a T>,
+}
+
+// The raw pointer opts out of Send and Sync, this is a &T as far as threads
+// go.
+unsafe impl<'a, T: Sync> Send for MangledRefWith2Flags<'a, T> {}
+unsafe impl<'a, T: Sync> Sync for MangledRefWith2Flags<'a, T> {}
+
+impl<'a, T: AlignedAtLeast<4>> MangledRefWith2Flags<'a, T> {
+
+    pub fn new(ptr: &'a T, flag_a: bool, flag_b: bool) -> MangledRefWith2Flags<'a, T> {
+        let flags = flag_a as usize | ((flag_b as usize) << 1);
+        MangledRefWith2Flags {
+            mangled: (ptr as *const T).map_addr(|addr| (addr | flags) ^ cookie()),
+            behaves_like: PhantomData,
+        }
+    }
+
+    pub fn get_ref(&self) -> &'a T {
+        unsafe { &*self.demangled().map_addr(|addr| addr & !3) }
+    }
+
+    pub fn get_flag_a(&self) -> bool {
+        self.demangled().addr() & 1 != 0
+    }
+
+    pub fn get_flag_b(&self) -> bool {
+        self.demangled().addr() & 2 != 0
+    }
+
+    pub fn set_flag_a(&mut self, flag: bool) {
+        self.set_bit(1, flag);
+    }
+
+    pub fn set_flag_b(&mut self, flag: bool) {
+        self.set_bit(2, flag);
+    }
+
+    // The cookie changes every bit the same way, so a flag is toggled
+    // without decoding.
+    pub fn toggle_flag_a(&mut self) {
+        self.mangled = self.mangled.map_addr(|addr| addr ^ 1);
+    }
+
+    pub fn toggle_flag_b(&mut self) {
+        self.mangled = self.mangled.map_addr(|addr| addr ^ 2);
+    }
+
+    pub fn set_flags(&mut self, flag_a: bool, flag_b: bool) {
+        self.set_flag_a(flag_a);
+        self.set_flag_b(flag_b);
+    }
+
+    /// The reference and both flags from a single decode.
+    pub fn get_all(&self) -> (&'a T, bool, bool) {
+        let word = self.demangled();
+        let value = unsafe { &*word.map_addr(|addr| addr & !3) };
+        (value, word.addr() & 1 != 0, word.addr() & 2 != 0)
+    }
+
+    /// The word as stored in memory, mangled.
+    pub fn raw_word(&self) -> usize {
+        self.mangled.addr()
+    }
+
+    pub fn unmangle(self) -> RefWith2Flags<'a, T> {
+        let (value, flag_a, flag_b) = self.get_all();
+        RefWith2Flags::new(value, flag_a, flag_b)
+    }
+
+    fn demangled(&self) -> *const T {
+        self.mangled.map_addr(|addr| addr ^ cookie())
+    }
+
+    fn set_bit(&mut self, bit: usize, flag: bool) {
+        let cookie_bit = cookie() & bit;
+        let stored = if flag { bit ^ cookie_bit } else { cookie_bit };
+        self.mangled = self.mangled.map_addr(|addr| (addr & !bit) | stored);
+    }
+
+}
+
+// A pointer and a PhantomData<&T>, it copies like the &T whatever T is.
+impl<'a, T> Clone for MangledRefWith2Flags<'a, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, T> Copy for MangledRefWith2Flags<'a, T> {}
+
+impl<'a, T: AlignedAtLeast<4>> Deref for MangledRefWith2Flags<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.get_ref()
+    }
+}
+
+impl<'a, T: AlignedAtLeast<4> + fmt::Debug> fmt::Debug for MangledRefWith2Flags<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (value, flag_a, flag_b) = self.get_all();
+        f.debug_struct("MangledRefWith2Flags")
+            .field("flag_a", &flag_a)
+            .field("flag_b", &flag_b)
+            .field("value", value)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_stored_word_is_mangled() {
+        let secret = 1234_u32;
+        let mangled = MangledRefWith2Flags::new(&secret, false, true);
+        assert_ne!(mangled.raw_word() & !3, &secret as *const u32 as usize);
+        assert_eq!(*mangled.get_ref(), 1234);
+        assert!(!mangled.get_flag_a() && mangled.get_flag_b());
+        assert!(mangled.unmangle().get_flag_b());
+    }
+
+    #[test]
+    fn flags_change_without_unmangling() {
+        let value = 42_u64;
+        let mut mangled = MangledRefWith2Flags::new(&value, false, false);
+        for (flag_a, flag_b) in [(true, false), (false, true), (true, true), (false, false)] {
+            mangled.set_flags(flag_a, flag_b);
+            assert_eq!(mangled.get_all(), (&42, flag_a, flag_b));
+            mangled.toggle_flag_a();
+            mangled.toggle_flag_b();
+            assert_eq!((mangled.get_flag_a(), mangled.get_flag_b()), (!flag_a, !flag_b));
+            assert!(std::ptr::eq(mangled.get_ref(), &value));
+        }
+        mangled.set_flags(false, false);
+        let copy = mangled;
+        mangled.set_flag_a(true);
+        assert!(mangled.get_flag_a() && !copy.get_flag_a());
+        assert_eq!(*copy + 1, 43);
+        let shown = format!("{:?}", copy);
+        assert!(shown.contains("value: 42") && !shown.contains(&format!("{:x}", &value as *const u64 as usize)));
+    }
+}
@@ -0,0 +1,142 @@
+// Name: MaybeOwnedWithFlag - a Cow<'a, T> of a borrowed &'a T or an owned
+//       Box<T> in one word.
+//
+// Description: Cow keeps a discriminant next to the reference or the Box.
+//              Here it is bit 0 of the address, free for a T aligned to at
+//              least 2 bytes:
+//
+//                 bit 0 - OWNED : the word is a Box<T> to drop, not a &'a T.
+//
+//              Drop frees the Box only when OWNED is set. to_mut() clones a
+//              borrowed value into a Box on the first write, like
+//              Cow::to_mut, and into_owned() gives a Box whatever the side.
+//              The word is kept as a pointer, with the provenance of the
+//              reference or of the Box.
+
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+use std::ptr;
+
+use crate::aligned::AlignedAtLeast;
+
+const OWNED: usize = 1;
+
+pub struct MaybeOwnedWithFlag<'a, T> {
+    ptr_and_bit: *mut T,
+    // Borrows a T or owns one, for the drop check.
+    behaves_like: PhantomData<(&'a T, Box<T>)>,
+}
+
+// The raw pointer opts out of Send and Sync, this is a &'a T or a Box<T> as
+// far as threads go.
+unsafe impl<'a, T: Send + Sync> Send for MaybeOwnedWithFlag<'a, T> {}
+unsafe impl<'a, T: Sync> Sync for MaybeOwnedWithFlag<'a, T> {}
+
+impl<'a, T: 'a> MaybeOwnedWithFlag<'a, T>
+where
+    T: AlignedAtLeast<2>,
+{
+
+    pub fn borrowed(ptr: &'a T) -> MaybeOwnedWithFlag<'a, T> {
+        // Only written through once OWNED, with the provenance of a Box.
+        MaybeOwnedWithFlag { ptr_and_bit: ptr::from_ref(ptr).cast_mut(), behaves_like: PhantomData }
+    }
+
+    pub fn owned(boxed: Box<T>) -> MaybeOwnedWithFlag<'a, T> {
+        MaybeOwnedWithFlag { ptr_and_bit: Box::into_raw(boxed).map_addr(|addr| addr | OWNED), behaves_like: PhantomData }
+    }
+
+    pub fn is_owned(&self) -> bool {
+        self.ptr_and_bit.addr() & OWNED != 0
+    }
+
+    pub fn get_ref(&self) -> &T {
+        unsafe { &*self.ptr() }
+    }
+
+    /// Clones a borrowed value into a Box first.
+    pub fn to_mut(&mut self) -> &mut T
+    where
+        T: Clone,
+    {
+        if !self.is_owned() {
+            let boxed = Box::new(self.get_ref().clone());
+            self.ptr_and_bit = Box::into_raw(boxed).map_addr(|addr| addr | OWNED);
+        }
+        unsafe { &mut *self.ptr() }
+    }
+
+    /// The Box if owned, a clone of the borrowed value in a new Box if not.
+    pub fn into_owned(self) -> Box<T>
+    where
+        T: Clone,
+    {
+        let this = ManuallyDrop::new(self);
+        if this.is_owned() {
+            unsafe { Box::from_raw(this.ptr()) }
+        } else {
+            Box::new(this.get_ref().clone())
+        }
+    }
+
+}
+
+impl<'a, T> MaybeOwnedWithFlag<'a, T> {
+
+    fn ptr(&self) -> *mut T {
+        self.ptr_and_bit.map_addr(|addr| addr & !OWNED)
+    }
+
+}
+
+impl<'a, T> Drop for MaybeOwnedWithFlag<'a, T> {
+    fn drop(&mut self) {
+        if self.ptr_and_bit.addr() & OWNED != 0 {
+            drop(unsafe { Box::from_raw(self.ptr()) });
+        }
+    }
+}
+
+impl<'a, T> Deref for MaybeOwnedWithFlag<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.ptr() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    #[test]
+    fn copies_on_the_first_write() {
+        let defaults = vec![1u32, 2, 3];
+        let mut settings = MaybeOwnedWithFlag::borrowed(&defaults);
+        assert!(!settings.is_owned() && std::ptr::eq(settings.get_ref(), &defaults));
+        settings.to_mut().push(4);
+        assert!(settings.is_owned());
+        assert_eq!((defaults.len(), settings.len()), (3, 4));
+        assert_eq!(*settings.into_owned(), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn owned_values_drop_once_and_copy_never() {
+        let counted = Rc::new(());
+        let mut owned = MaybeOwnedWithFlag::owned(Box::new(vec![counted.clone()]));
+        let before = std::ptr::from_ref(owned.get_ref());
+        owned.to_mut().push(counted.clone());
+        assert!(owned.is_owned() && Rc::strong_count(&counted) == 3);
+        assert!(std::ptr::eq(owned.get_ref(), before));
+        drop(owned);
+        assert_eq!(Rc::strong_count(&counted), 1);
+        let shared = vec![counted.clone()];
+        let borrowed = MaybeOwnedWithFlag::borrowed(&shared);
+        drop(borrowed);
+        assert_eq!(Rc::strong_count(&counted), 2);
+        let boxed = MaybeOwnedWithFlag::borrowed(&shared).into_owned();
+        assert_eq!((boxed.len(), Rc::strong_count(&counted)), (1, 3));
+    }
+}
@@ -0,0 +1,258 @@
+// Name: MsQueue - the Michael-Scott lock free MPMC queue, with a sealed bit
+//       in the last link.
+//
+// Description: A linked list with a dummy node at the front: head points to
+//              the dummy, tail to the last node or one behind it, and the
+//              next links are AtomicOptionTaggedPtr. enqueue() CASes the
+//              next of the last node from None to the new node, then swings
+//              tail, dequeue() CASes head from the dummy to its next, which
+//              becomes the new dummy, and takes the value out of it. Each
+//              side helps the other by moving a lagging tail forward.
+//
+//              seal() shuts the producers down without any extra state: it
+//              CASes the None of the last link into a link with flag a set,
+//
+//                 flag a - SEALED : nothing can be linked after this node.
+//
+//              pointing to a sentinel that holds no value. The enqueue CAS
+//              expects None there, so from then on it fails and gives the
+//              value back, while consumers still drain the queue up to the
+//              seal.
+//
+//              Like in TaggedStack, the dequeued dummies are not freed while
+//              the queue is shared, they go on a retired list freed by
+//              collect() and by Drop, which take &mut self. tail never falls
+//              behind head (dequeue() moves it first), so it never points to
+//              a retired node.
+
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::aligned::AlignedAtLeast;
+use crate::atomic_option_tagged_ptr::AtomicOptionTaggedPtr;
+use crate::atomic_tagged_ptr::AtomicTaggedPtr;
+use crate::ref_with_2_flags::RefWith2Flags;
+
+#[repr(align(4))]
+struct Node<T: 'static> {
+    // Init from the enqueue to the dequeue that makes the node the dummy.
+    value: UnsafeCell<MaybeUninit<T>>,
+    next: AtomicOptionTaggedPtr<'static, Node<T>>,
+    // Link of the retired list, written by the one dequeue that retired it.
+    retired_next: AtomicPtr<Node<T>>,
+    // The pointer from Box::into_raw, to free the node with its own
+    // provenance, not the one of a shared reference.
+    raw: *mut Node<T>,
+}
+
+const _: () = assert!(core::mem::align_of::<Node<()>>() >= 4);
+unsafe impl<T: 'static> AlignedAtLeast<4> for Node<T> {}
+
+pub struct MsQueue<T: 'static> {
+    head: AtomicTaggedPtr<'static, Node<T>>,
+    tail: AtomicTaggedPtr<'static, Node<T>>,
+    // The target of the SEALED link.
+    seal: *mut Node<T>,
+    retired: AtomicPtr<Node<T>>,
+}
+
+// The values are only moved in and out, never shared.
+unsafe impl<T: Send> Send for MsQueue<T> {}
+unsafe impl<T: Send> Sync for MsQueue<T> {}
+
+impl<T: 'static> MsQueue<T> {
+
+    pub fn new() -> MsQueue<T> {
+        let dummy = unsafe { &*Self::alloc_node(MaybeUninit::uninit()) };
+        MsQueue {
+            head: AtomicTaggedPtr::new(RefWith2Flags::new(dummy, false, false)),
+            tail: AtomicTaggedPtr::new(RefWith2Flags::new(dummy, false, false)),
+            seal: Self::alloc_node(MaybeUninit::uninit()),
+            retired: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Gives the value back if the queue is sealed.
+    pub fn enqueue(&self, value: T) -> Result<(), T> {
+        let node = Self::alloc_node(MaybeUninit::new(value));
+        let new = RefWith2Flags::new(unsafe { &*node }, false, false);
+        loop {
+            let tail = self.tail.load(Ordering::Acquire);
+            let last = tail.get_ref();
+            match last.next.load(Ordering::Acquire) {
+                Some(next) if next.get_flag_a() => {
+                    let node = unsafe { Box::from_raw(node) };
+                    return Err(unsafe { node.value.into_inner().assume_init() });
+                }
+                // tail is lagging, help move it.
+                Some(next) => self.advance_tail(&tail, next),
+                None => {
+                    if last.next.compare_exchange(None, Some(new), Ordering::AcqRel, Ordering::Acquire).is_ok() {
+                        self.advance_tail(&tail, new);
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn dequeue(&self) -> Option<T> {
+        loop {
+            let head = self.head.load(Ordering::Acquire);
+            let tail = self.tail.load(Ordering::Acquire);
+            let dummy = head.get_ref();
+            let next = match dummy.next.load(Ordering::Acquire) {
+                Some(next) if !next.get_flag_a() => next,
+                _ => return None,
+            };
+            if ptr::eq(dummy, tail.get_ref()) {
+                // Keep tail from falling behind head.
+                self.advance_tail(&tail, next);
+                continue;
+            }
+            if self.head.compare_exchange(&head, next, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+                // Only the dequeue whose CAS made it the dummy gets here.
+                let value = unsafe { (*next.get_ref().value.get()).assume_init_read() };
+                self.retire(dummy);
+                return Some(value);
+            }
+        }
+    }
+
+    /// Makes every later enqueue fail. Returns false if it was already
+    /// sealed.
+    pub fn seal(&self) -> bool {
+        let sealed = RefWith2Flags::new(unsafe { &*self.seal }, true, false);
+        loop {
+            let tail = self.tail.load(Ordering::Acquire);
+            let last = tail.get_ref();
+            match last.next.load(Ordering::Acquire) {
+                Some(next) if next.get_flag_a() => return false,
+                Some(next) => self.advance_tail(&tail, next),
+                None => {
+                    if last.next.compare_exchange(None, Some(sealed), Ordering::AcqRel, Ordering::Acquire).is_ok() {
+                        return true;
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn is_sealed(&self) -> bool {
+        loop {
+            let tail = self.tail.load(Ordering::Acquire);
+            match tail.get_ref().next.load(Ordering::Acquire) {
+                Some(next) if next.get_flag_a() => return true,
+                Some(next) => self.advance_tail(&tail, next),
+                None => return false,
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        let dummy = self.head.load(Ordering::Acquire).get_ref();
+        dummy.next.load(Ordering::Acquire).is_none_or(|next| next.get_flag_a())
+    }
+
+    /// Frees the dummies dequeued so far.
+    pub fn collect(&mut self) {
+        let mut node = self.retired.swap(ptr::null_mut(), Ordering::Acquire);
+        while !node.is_null() {
+            let boxed = unsafe { Box::from_raw(node) };
+            node = boxed.retired_next.load(Ordering::Relaxed);
+        }
+    }
+
+    fn advance_tail(&self, tail: &RefWith2Flags<'static, Node<T>>, next: RefWith2Flags<'static, Node<T>>) {
+        let _ = self.tail.compare_exchange(tail, next, Ordering::AcqRel, Ordering::Acquire);
+    }
+
+    fn alloc_node(value: MaybeUninit<T>) -> *mut Node<T> {
+        let raw = Box::into_raw(Box::<Node<T>>::new_uninit()).cast::<Node<T>>();
+        let node = Node {
+            value: UnsafeCell::new(value),
+            next: AtomicOptionTaggedPtr::none(),
+            retired_next: AtomicPtr::new(ptr::null_mut()),
+            raw,
+        };
+        unsafe { raw.write(node) };
+        raw
+    }
+
+    fn retire(&self, node: &Node<T>) {
+        let mut top = self.retired.load(Ordering::Relaxed);
+        loop {
+            node.retired_next.store(top, Ordering::Relaxed);
+            match self.retired.compare_exchange_weak(top, node.raw, Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => return,
+                Err(actual) => top = actual,
+            }
+        }
+    }
+
+}
+
+impl<T: 'static> Default for MsQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: 'static> Drop for MsQueue<T> {
+    fn drop(&mut self) {
+        while self.dequeue().is_some() {}
+        self.collect();
+        drop(unsafe { Box::from_raw(self.head.load(Ordering::Relaxed).get_ref().raw) });
+        drop(unsafe { Box::from_raw(self.seal) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::thread;
+
+    #[test]
+    fn concurrent_producers_and_consumers_keep_fifo_per_producer() {
+        let queue = MsQueue::new();
+        let received = Mutex::new(Vec::new());
+        thread::scope(|s| {
+            for p in 0..3u32 {
+                let queue = &queue;
+                s.spawn(move || (0..1000).for_each(|i| queue.enqueue((p, i)).unwrap()));
+            }
+            for _ in 0..3 {
+                s.spawn(|| {
+                    let mut mine = Vec::new();
+                    for _ in 0..1000 {
+                        mine.extend(queue.dequeue());
+                    }
+                    // Each consumer sees the items of a producer in order.
+                    for p in 0..3 {
+                        let seq: Vec<u32> = mine.iter().filter(|&&(q, _)| q == p).map(|&(_, i)| i).collect();
+                        assert!(seq.windows(2).all(|w| w[0] < w[1]));
+                    }
+                    received.lock().unwrap().extend(mine);
+                });
+            }
+        });
+        let mut all = received.into_inner().unwrap();
+        all.extend(std::iter::from_fn(|| queue.dequeue()));
+        all.sort();
+        let expected: Vec<(u32, u32)> = (0..3).flat_map(|p| (0..1000).map(move |i| (p, i))).collect();
+        assert_eq!(all, expected);
+    }
+
+    #[test]
+    fn sealed_queue_drains_but_refuses() {
+        let queue = MsQueue::new();
+        queue.enqueue(1).unwrap();
+        assert!(queue.seal() && !queue.seal() && queue.is_sealed());
+        assert_eq!(queue.enqueue(2), Err(2));
+        assert_eq!((queue.dequeue(), queue.dequeue()), (Some(1), None));
+        assert!(queue.is_empty());
+    }
+}
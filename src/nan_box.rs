@@ -0,0 +1,203 @@
+// Name: NanBox - a float, an integer, a boolean, null or a reference in one
+//       64 bit word, NaN boxing.
+//
+// Description: The value word of interpreters whose numbers are doubles
+//              (JavaScript, Lua), and of ToyVm. A f64 NaN has an all ones
+//              exponent and any non zero mantissa, 2^52 ways to say "not a
+//              number" where one is enough. So every NaN is stored as the
+//              one canonical quiet NaN, and the other values hide in the
+//              negative quiet NaNs, sign, exponent and quiet bit all set, a
+//              type tag in bits 48..51 and a 48 bit payload below:
+//
+//                 any other word          - a f64, as is.
+//                 0xFFF9 << 48            - null.
+//                 0xFFFA << 48 | b        - a boolean, b is the value.
+//                 0xFFFB << 48 | i as u32 - an i32.
+//                 0xFFFC << 48 | address  - a &T, its 48 bit address.
+//
+//              So doubles need no boxing at all and the other values no
+//              allocation. The word is a pointer, whose provenance is the
+//              one of the reference it holds, or none for the other values.
+//              from_ref() panics on an address that doesn't fit in 48 bits,
+//              like HighTaggedRef, and get() restores its canonical form.
+//
+//              get() unpacks the word into the Value enum, to match on, and
+//              as_f64(), as_int(), as_bool(), is_null() and as_ref() read one
+//              type each.
+
+use core::marker::PhantomData;
+use core::ptr;
+
+use crate::high_tagged_ref::{canonical, ADDR_MASK, TAG_SHIFT};
+
+// Sign, exponent and quiet bit: the negative quiet NaNs.
+const BOXED: usize = 0xFFF8 << TAG_SHIFT;
+const TYPE_MASK: usize = 0xFFFF << TAG_SHIFT;
+const NULL: usize = 0xFFF9 << TAG_SHIFT;
+const BOOL: usize = 0xFFFA << TAG_SHIFT;
+const INT: usize = 0xFFFB << TAG_SHIFT;
+const REF: usize = 0xFFFC << TAG_SHIFT;
+const CANONICAL_NAN: u64 = 0x7FF8_0000_0000_0000;
+
+/// The unpacked form of a `NanBox`.
+#[derive(Debug, PartialEq)]
+pub enum Value<'a, T> {
+    Float(f64),
+    Int(i32),
+    Bool(bool),
+    Null,
+    Ref(&'a T),
+}
+
+#[repr(transparent)]
+pub struct NanBox<'a, T> {
+    word: *const T,
+    behaves_like: PhantomData<Value<'a, T>>,
+}
+
+// The raw pointer opts out of Send and Sync, this is a f64 or a &T as far
+// as threads go.
+unsafe impl<'a, T: Sync> Send for NanBox<'a, T> {}
+unsafe impl<'a, T: Sync> Sync for NanBox<'a, T> {}
+
+impl<'a, T: 'a> NanBox<'a, T> {
+
+    /// Panics on a reference whose address doesn't fit in 48 bits.
+    pub fn new(value: Value<'a, T>) -> NanBox<'a, T> {
+        match value {
+            Value::Float(float) => Self::from_f64(float),
+            Value::Int(int) => Self::int(int),
+            Value::Bool(boolean) => Self::bool(boolean),
+            Value::Null => Self::null(),
+            Value::Ref(reference) => Self::from_ref(reference),
+        }
+    }
+
+    /// Every NaN becomes the canonical one.
+    pub fn from_f64(float: f64) -> NanBox<'a, T> {
+        let bits = if float.is_nan() { CANONICAL_NAN } else { float.to_bits() };
+        Self::immediate(bits as usize)
+    }
+
+    pub fn int(int: i32) -> NanBox<'a, T> {
+        Self::immediate(INT | int as u32 as usize)
+    }
+
+    pub fn bool(boolean: bool) -> NanBox<'a, T> {
+        Self::immediate(BOOL | boolean as usize)
+    }
+
+    pub fn null() -> NanBox<'a, T> {
+        Self::immediate(NULL)
+    }
+
+    /// Panics if the address isn't a canonical 48 bit address.
+    pub fn from_ref(reference: &'a T) -> NanBox<'a, T> {
+        let ptr = ptr::from_ref(reference);
+        assert!(canonical(ptr.addr()) == ptr.addr(), "address doesn't fit in 48 bits");
+        NanBox { word: ptr.map_addr(|addr| REF | (addr & ADDR_MASK)), behaves_like: PhantomData }
+    }
+
+    pub fn get(&self) -> Value<'a, T> {
+        let word = self.word.addr();
+        if word & BOXED != BOXED {
+            return Value::Float(f64::from_bits(word as u64));
+        }
+        match word & TYPE_MASK {
+            NULL => Value::Null,
+            BOOL => Value::Bool(word & 1 != 0),
+            INT => Value::Int(word as u32 as i32),
+            // Only from_ref() writes REF, with the address of a &'a T.
+            _ => Value::Ref(unsafe { &*self.word.map_addr(|addr| canonical(addr & ADDR_MASK)) }),
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self.get() {
+            Value::Float(float) => Some(float),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i32> {
+        match self.get() {
+            Value::Int(int) => Some(int),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self.get() {
+            Value::Bool(boolean) => Some(boolean),
+            _ => None,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.word.addr() == NULL
+    }
+
+    pub fn as_ref(&self) -> Option<&'a T> {
+        match self.get() {
+            Value::Ref(reference) => Some(reference),
+            _ => None,
+        }
+    }
+
+    /// The word, e.g. to hash or to compare by identity.
+    pub fn to_bits(&self) -> u64 {
+        self.word.addr() as u64
+    }
+
+    fn immediate(word: usize) -> NanBox<'a, T> {
+        NanBox { word: ptr::without_provenance(word), behaves_like: PhantomData }
+    }
+
+}
+
+impl<'a, T> Clone for Value<'a, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, T> Copy for Value<'a, T> {}
+
+impl<'a, T> Clone for NanBox<'a, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, T> Copy for NanBox<'a, T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_kind_of_value_round_trips() {
+        let name = String::from("answer");
+        let values = [NanBox::from_f64(0.5), NanBox::int(-42), NanBox::bool(true), NanBox::null(), NanBox::from_ref(&name), NanBox::from_f64(f64::NAN)];
+        assert_eq!(values[0].as_f64(), Some(0.5));
+        assert_eq!(values[1].get(), Value::Int(-42));
+        assert!(values[2].as_bool() == Some(true) && values[3].is_null());
+        assert!(matches!(values[4].get(), Value::Ref(string) if std::ptr::eq(string, &name)));
+        assert!(values[5].as_f64().unwrap().is_nan() && values[1].as_f64().is_none());
+        assert_eq!(values.iter().filter(|value| value.as_f64().is_some()).count(), 2);
+        assert_eq!(NanBox::new(Value::<String>::Float(-f64::INFINITY)).as_f64(), Some(-f64::INFINITY));
+    }
+
+    #[test]
+    fn integer_and_float_edges() {
+        for int in [0, 1, -1, i32::MAX, i32::MIN] {
+            assert_eq!(NanBox::<String>::int(int).get(), Value::Int(int));
+        }
+        for float in [0.0, -0.0, f64::MIN_POSITIVE, f64::MAX, f64::INFINITY] {
+            let boxed = NanBox::<String>::from_f64(float);
+            assert_eq!(boxed.as_f64().map(f64::to_bits), Some(float.to_bits()));
+        }
+        assert_eq!(NanBox::<String>::bool(false).as_bool(), Some(false));
+        assert!(NanBox::<String>::null().as_bool().is_none());
+    }
+}
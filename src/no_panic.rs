@@ -0,0 +1,96 @@
+// Name: no_panic - link time proof that the hot paths can't panic.
+//
+// Description: Built only by `cargo test --release`. Each probe calls a hot
+//              path method while holding a Guard whose drop calls a symbol
+//              that doesn't exist. The drop only runs if the call unwinds, so
+//              if the optimizer can prove the call never panics the landing
+//              pad and the reference to the symbol are removed. Otherwise
+//              the link fails with the message of the symbol name.
+//
+//              The same trick as the no-panic crate, without the dependency.
+
+use std::hint::black_box;
+use std::mem::forget;
+use std::ptr::NonNull;
+use std::sync::atomic::Ordering;
+
+use crate::atomic_option_tagged_ptr::AtomicOptionTaggedPtr;
+use crate::atomic_tagged_ptr::AtomicTaggedPtr;
+use crate::ref_with_2_flags::RefWith2Flags;
+use crate::tagged_non_null::TaggedNonNull;
+
+struct Guard;
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        extern "C" {
+            #[link_name = "\n\nERROR: a hot path method of ref_with_2_flags may panic\n\n"]
+            fn hot_path_may_panic() -> !;
+        }
+        unsafe { hot_path_may_panic() }
+    }
+}
+
+macro_rules! no_panic_probe {
+    ($name:ident ( $($arg:ident : $ty:ty),* ) -> $ret:ty $body:block) => {
+        #[inline(never)]
+        fn $name($($arg: $ty),*) -> $ret {
+            // Hides the arguments from constant propagation, so the call is
+            // checked for any input.
+            $(let $arg = black_box($arg);)*
+            let guard = Guard;
+            let result = $body;
+            forget(guard);
+            result
+        }
+    };
+}
+
+no_panic_probe!(get_ref(r: &RefWith2Flags<'static, u32>) -> &'static u32 { r.get_ref() });
+no_panic_probe!(get_flags(r: &RefWith2Flags<'static, u32>) -> (bool, bool) { (r.get_flag_a(), r.get_flag_b()) });
+no_panic_probe!(get_all(r: &RefWith2Flags<'static, u32>) -> (&'static u32, bool, bool) { r.get_all() });
+no_panic_probe!(set_flags(r: &mut RefWith2Flags<'static, u32>, a: bool, b: bool) -> () {
+    r.set_flag_a(a);
+    r.set_flag_b(b);
+});
+no_panic_probe!(toggle_flags(r: &mut RefWith2Flags<'static, u32>) -> () {
+    r.toggle_flag_a();
+    r.toggle_flag_b();
+});
+no_panic_probe!(get_ref_if(r: &RefWith2Flags<'static, u32>, cond: bool) -> Option<&'static u32> { r.get_ref_if(cond) });
+no_panic_probe!(raw_as_ptr(p: TaggedNonNull<u32>) -> NonNull<u32> { p.as_ptr() });
+no_panic_probe!(raw_set_flags(p: &mut TaggedNonNull<u32>, a: bool, b: bool) -> () {
+    p.set_flag_a(a);
+    p.set_flag_b(b);
+});
+no_panic_probe!(atomic_load(slot: &AtomicTaggedPtr<'static, u32>) -> bool {
+    slot.load(Ordering::Acquire).get_flag_a()
+});
+no_panic_probe!(atomic_option_load(slot: &AtomicOptionTaggedPtr<'static, u32>) -> Option<bool> {
+    slot.load(Ordering::Acquire).map(|r| r.get_flag_a())
+});
+no_panic_probe!(atomic_take(slot: &AtomicOptionTaggedPtr<'static, u32>) -> bool {
+    slot.take(Ordering::AcqRel).is_some()
+});
+
+static VALUE: u32 = 5;
+
+#[test]
+fn hot_paths_link_without_panic_paths() {
+    let r = RefWith2Flags::new(&VALUE, true, false);
+    assert_eq!(*get_ref(&r), 5);
+    assert_eq!(get_flags(&r), (true, false));
+    assert!(get_all(&r).1);
+    assert_eq!(get_ref_if(&r, false), None);
+    let mut flipped = r;
+    set_flags(&mut flipped, false, true);
+    toggle_flags(&mut flipped);
+    assert_eq!(get_flags(&flipped), (true, false));
+    let mut raw = TaggedNonNull::new(NonNull::from(&VALUE), false, true);
+    raw_set_flags(&mut raw, true, false);
+    assert_eq!(raw_as_ptr(raw), NonNull::from(&VALUE));
+    assert!(atomic_load(&AtomicTaggedPtr::new(r)));
+    let slot = AtomicOptionTaggedPtr::new(Some(r));
+    assert_eq!(atomic_option_load(&slot), Some(true));
+    assert!(atomic_take(&slot) && !atomic_take(&slot));
+}
@@ -0,0 +1,260 @@
+// Name: ObjectPool - an object pool whose handle table carries the object
+//       state in the pointer bits.
+//
+// Description: Each entry of the handle table keeps in one word the address
+//              of the boxed object plus 2 flags,
+//
+//                 bit 0 - IN_USE : the object was acquired and not released.
+//                 bit 1 - PINNED : the object must stay alive even if unused.
+//
+//              There is no parallel Vec<bool> that can drift out of sync,
+//              the state lives in the same word as the pointer it describes.
+//              try_reclaim() scans the table and drops every object that is
+//              neither in use nor pinned, freeing its slot for reuse.
+//
+//              The objects are boxed inside an Align8 shim, so the 2 low
+//              bits are always free, whatever the alignment of T, and the
+//              flags are set and cleared with map_addr, so each Box rebuilt
+//              is the one that was allocated.
+//
+//              A handle is an index in the table plus the generation of the
+//              slot when the object was inserted. Reclaiming an object bumps
+//              the generation of its slot, so a handle kept past that, even
+//              once the slot holds a new object, finds nothing.
+
+use std::marker::PhantomData;
+use std::ptr;
+
+use crate::aligned_box::Align8;
+use crate::bitpack::untag_ptr;
+
+const IN_USE: usize = 1;
+const PINNED: usize = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PoolHandle {
+    index: usize,
+    generation: u32,
+}
+
+struct Slot<T> {
+    // Tagged Box<Align8<T>> pointer, null for an empty slot.
+    word: *mut Align8<T>,
+    generation: u32,
+}
+
+pub struct ObjectPool<T> {
+    handles: Vec<Slot<T>>,
+    owns: PhantomData<Box<T>>,
+}
+
+// The raw pointers opt out of Send and Sync, this is a Vec<Box<T>> as far as
+// threads go.
+unsafe impl<T: Send> Send for ObjectPool<T> {}
+unsafe impl<T: Sync> Sync for ObjectPool<T> {}
+
+impl<T> ObjectPool<T> {
+
+    pub fn new() -> ObjectPool<T> {
+        ObjectPool { handles: Vec::new(), owns: PhantomData }
+    }
+
+    /// Number of live objects.
+    pub fn len(&self) -> usize {
+        self.handles.iter().filter(|slot| !slot.word.is_null()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Adds an object, not in use and not pinned.
+    pub fn insert(&mut self, value: T) -> PoolHandle {
+        let word = Box::into_raw(Box::new(Align8(value)));
+        let index = match self.handles.iter().position(|slot| slot.word.is_null()) {
+            Some(index) => index,
+            None => {
+                self.handles.push(Slot { word: ptr::null_mut(), generation: 0 });
+                self.handles.len() - 1
+            }
+        };
+        self.handles[index].word = word;
+        PoolHandle { index, generation: self.handles[index].generation }
+    }
+
+    pub fn get(&self, handle: PoolHandle) -> Option<&T> {
+        let word = self.word(handle)?;
+        Some(unsafe { &(*untag_ptr(word, 2)).0 })
+    }
+
+    /// Marks the object as in use and gives access to it.
+    pub fn acquire(&mut self, handle: PoolHandle) -> Option<&mut T> {
+        let word = self.word(handle)?;
+        self.handles[handle.index].word = word.map_addr(|addr| addr | IN_USE);
+        Some(unsafe { &mut (*untag_ptr(word, 2)).0 })
+    }
+
+    pub fn release(&mut self, handle: PoolHandle) {
+        self.update(handle, IN_USE, false);
+    }
+
+    pub fn pin(&mut self, handle: PoolHandle) {
+        self.update(handle, PINNED, true);
+    }
+
+    pub fn unpin(&mut self, handle: PoolHandle) {
+        self.update(handle, PINNED, false);
+    }
+
+    pub fn is_in_use(&self, handle: PoolHandle) -> bool {
+        self.word(handle).is_some_and(|word| word.addr() & IN_USE != 0)
+    }
+
+    pub fn is_pinned(&self, handle: PoolHandle) -> bool {
+        self.word(handle).is_some_and(|word| word.addr() & PINNED != 0)
+    }
+
+    /// Drops every object that is neither in use nor pinned, returns how
+    /// many were reclaimed.
+    pub fn try_reclaim(&mut self) -> usize {
+        let mut reclaimed = 0;
+        for slot in self.handles.iter_mut() {
+            if !slot.word.is_null() && slot.word.addr() & (IN_USE | PINNED) == 0 {
+                drop(unsafe { Box::from_raw(slot.word) });
+                slot.word = ptr::null_mut();
+                slot.generation = slot.generation.wrapping_add(1);
+                reclaimed += 1;
+            }
+        }
+        reclaimed
+    }
+
+    // The tagged word of a live object of the handle's generation.
+    fn word(&self, handle: PoolHandle) -> Option<*mut Align8<T>> {
+        self.handles
+            .get(handle.index)
+            .filter(|slot| !slot.word.is_null() && slot.generation == handle.generation)
+            .map(|slot| slot.word)
+    }
+
+    fn update(&mut self, handle: PoolHandle, bit: usize, value: bool) {
+        if let Some(word) = self.word(handle) {
+            self.handles[handle.index].word = word.map_addr(|addr| if value { addr | bit } else { addr & !bit });
+        }
+    }
+
+}
+
+impl<T> Default for ObjectPool<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for ObjectPool<T> {
+    fn drop(&mut self) {
+        for slot in self.handles.iter().filter(|slot| !slot.word.is_null()) {
+            drop(unsafe { Box::from_raw(untag_ptr(slot.word, 2)) });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn reclaims_only_idle_objects() {
+        let mut objects = ObjectPool::new();
+        let busy = objects.insert(vec![1_u8]);
+        let pinned = objects.insert(vec![2_u8]);
+        let idle = objects.insert(vec![3_u8]);
+        objects.acquire(busy).unwrap().push(10);
+        objects.pin(pinned);
+        assert!(objects.is_in_use(busy) && objects.is_pinned(pinned));
+        assert_eq!(objects.try_reclaim(), 1);
+        assert!(objects.get(idle).is_none());
+        objects.release(busy);
+        objects.unpin(pinned);
+        assert_eq!(objects.get(busy), Some(&vec![1, 10]));
+        assert_eq!(objects.len(), 2);
+        assert_eq!(objects.try_reclaim(), 2);
+        assert!(objects.is_empty());
+    }
+
+    #[test]
+    fn stale_handles_miss_the_reused_slot() {
+        let mut objects = ObjectPool::new();
+        let old = objects.insert(String::from("old"));
+        assert_eq!(objects.try_reclaim(), 1);
+        let new = objects.insert(String::from("new"));
+        assert_ne!(old, new);
+        assert!(objects.get(old).is_none() && objects.acquire(old).is_none());
+        objects.pin(old);
+        assert!(!objects.is_pinned(new) && !objects.is_pinned(old));
+        assert_eq!(objects.get(new).map(String::as_str), Some("new"));
+    }
+
+    #[test]
+    fn empty_pool_and_over_aligned_objects() {
+        let mut empty: ObjectPool<u8> = ObjectPool::default();
+        assert!(empty.is_empty());
+        assert_eq!(empty.try_reclaim(), 0);
+        #[repr(align(64))]
+        struct Line(u8);
+        let mut lines = ObjectPool::new();
+        let line = lines.insert(Line(3));
+        lines.acquire(line).unwrap().0 += 1;
+        lines.pin(line);
+        assert!(lines.is_in_use(line) && lines.is_pinned(line));
+        assert_eq!(lines.get(line).unwrap().0, 4);
+        assert_eq!(lines.get(line).unwrap() as *const Line as usize % 64, 0);
+    }
+
+    #[test]
+    fn random_operations_against_a_model() {
+        // The model: value, in use and pinned of every live handle.
+        let mut objects = ObjectPool::new();
+        let mut model: BTreeMap<usize, (PoolHandle, u32, bool, bool)> = BTreeMap::new();
+        let mut dead: Vec<PoolHandle> = Vec::new();
+        let mut seed = 4242u32;
+        for step in 0..3000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let pick = model.keys().nth((seed >> 16) as usize % model.len().max(1)).copied();
+            match ((seed >> 8) % 6, pick) {
+                (0, _) | (_, None) => {
+                    model.insert(step, (objects.insert(seed), seed, false, false));
+                }
+                (1, Some(key)) => {
+                    let entry = model.get_mut(&key).unwrap();
+                    *objects.acquire(entry.0).unwrap() += 1;
+                    entry.1 += 1;
+                    entry.2 = true;
+                }
+                (2, Some(key)) => {
+                    let entry = model.get_mut(&key).unwrap();
+                    objects.release(entry.0);
+                    entry.2 = false;
+                }
+                (3, Some(key)) => {
+                    let entry = model.get_mut(&key).unwrap();
+                    entry.3 = (seed >> 4) & 1 == 0;
+                    if entry.3 { objects.pin(entry.0) } else { objects.unpin(entry.0) }
+                }
+                (4, _) => {
+                    let idle: Vec<usize> = model.iter().filter(|(_, e)| !e.2 && !e.3).map(|(k, _)| *k).collect();
+                    assert_eq!(objects.try_reclaim(), idle.len());
+                    dead.extend(idle.iter().map(|k| model.remove(k).unwrap().0));
+                }
+                _ => {}
+            }
+            assert_eq!(objects.len(), model.len());
+            for (handle, value, in_use, pinned) in model.values() {
+                assert_eq!(objects.get(*handle), Some(value));
+                assert_eq!((objects.is_in_use(*handle), objects.is_pinned(*handle)), (*in_use, *pinned));
+            }
+            assert!(dead.iter().all(|handle| objects.get(*handle).is_none()));
+        }
+    }
+}
@@ -0,0 +1,134 @@
+// Name: OneOf4Ref - a reference to one of 4 types in one word.
+//
+// Description: EitherRef with both spare bits: types aligned to at least 4
+//              bytes leave bits 0 and 1 of the address free, and they say
+//              which of the 4 types the reference points to,
+//
+//                 0 - a &A
+//                 1 - a &B
+//                 2 - a &C
+//                 3 - a &D
+//
+//              so an enum of 4 references, 2 words, fits in 1. get() unpacks
+//              it into the OneOf4 enum, to match on it like on that enum, and
+//              match_ref() runs the closure of its type.
+
+use core::marker::PhantomData;
+use core::ptr::NonNull;
+
+use crate::aligned::AlignedAtLeast;
+
+const KIND_MASK: usize = 3;
+
+/// The unpacked form of a `OneOf4Ref`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum OneOf4<'a, A, B, C, D> {
+    A(&'a A),
+    B(&'a B),
+    C(&'a C),
+    D(&'a D),
+}
+
+#[repr(transparent)]
+pub struct OneOf4Ref<'a, A, B, C, D> {
+    ptr_and_kind: NonNull<()>,
+    behaves_like: PhantomData<OneOf4<'a, A, B, C, D>>,
+}
+
+// NonNull opts out of Send and Sync, this is one of 4 references as far as
+// threads go.
+unsafe impl<'a, A: Sync, B: Sync, C: Sync, D: Sync> Send for OneOf4Ref<'a, A, B, C, D> {}
+unsafe impl<'a, A: Sync, B: Sync, C: Sync, D: Sync> Sync for OneOf4Ref<'a, A, B, C, D> {}
+
+impl<'a, A: 'a, B: 'a, C: 'a, D: 'a> OneOf4Ref<'a, A, B, C, D>
+where
+    A: AlignedAtLeast<4>,
+    B: AlignedAtLeast<4>,
+    C: AlignedAtLeast<4>,
+    D: AlignedAtLeast<4>,
+{
+
+    pub fn new(one_of: OneOf4<'a, A, B, C, D>) -> OneOf4Ref<'a, A, B, C, D> {
+        let (ptr, kind) = match one_of {
+            OneOf4::A(a) => (NonNull::from(a).cast(), 0),
+            OneOf4::B(b) => (NonNull::from(b).cast(), 1),
+            OneOf4::C(c) => (NonNull::from(c).cast(), 2),
+            OneOf4::D(d) => (NonNull::from(d).cast(), 3),
+        };
+        OneOf4Ref { ptr_and_kind: ptr.map_addr(|addr| addr | kind), behaves_like: PhantomData }
+    }
+
+    /// 0 for a &A up to 3 for a &D.
+    pub fn kind(&self) -> usize {
+        self.ptr_and_kind.as_ptr().addr() & KIND_MASK
+    }
+
+    pub fn get(&self) -> OneOf4<'a, A, B, C, D> {
+        let ptr = self.ptr_and_kind.as_ptr().map_addr(|addr| addr & !KIND_MASK);
+        // The kind bits say which type the address was taken from.
+        unsafe {
+            match self.kind() {
+                0 => OneOf4::A(&*ptr.cast::<A>()),
+                1 => OneOf4::B(&*ptr.cast::<B>()),
+                2 => OneOf4::C(&*ptr.cast::<C>()),
+                _ => OneOf4::D(&*ptr.cast::<D>()),
+            }
+        }
+    }
+
+    /// Calls the closure of the type the reference points to.
+    pub fn match_ref<R>(
+        &self,
+        on_a: impl FnOnce(&'a A) -> R,
+        on_b: impl FnOnce(&'a B) -> R,
+        on_c: impl FnOnce(&'a C) -> R,
+        on_d: impl FnOnce(&'a D) -> R,
+    ) -> R {
+        match self.get() {
+            OneOf4::A(a) => on_a(a),
+            OneOf4::B(b) => on_b(b),
+            OneOf4::C(c) => on_c(c),
+            OneOf4::D(d) => on_d(d),
+        }
+    }
+
+}
+
+impl<'a, A, B, C, D> Clone for OneOf4<'a, A, B, C, D> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, A, B, C, D> Copy for OneOf4<'a, A, B, C, D> {}
+
+impl<'a, A, B, C, D> Clone for OneOf4Ref<'a, A, B, C, D> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, A, B, C, D> Copy for OneOf4Ref<'a, A, B, C, D> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aligned_box::Align16;
+
+    #[test]
+    fn one_of_4_node_types_in_a_word() {
+        let (number, slot, word_node) = (10u32, Align16(21u16), 7u64);
+        let edges: [OneOf4Ref<u32, Align16<u16>, u64, char>; 4] = [
+            OneOf4Ref::new(OneOf4::A(&number)),
+            OneOf4Ref::new(OneOf4::B(&slot)),
+            OneOf4Ref::new(OneOf4::C(&word_node)),
+            OneOf4Ref::new(OneOf4::D(&'x')),
+        ];
+        let kinds: Vec<usize> = edges.iter().map(|edge| edge.kind()).collect();
+        assert_eq!(kinds, [0, 1, 2, 3]);
+        assert!(matches!(edges[2].get(), OneOf4::C(&7)));
+        let weight = edges[3].match_ref(|&n| n as u64, |slot| slot.0 as u64, |&w| w, |&c| c as u64);
+        assert_eq!(weight, 'x' as u64);
+        assert_eq!(std::mem::size_of::<OneOf4Ref<u32, Align16<u16>, u64, char>>(), std::mem::size_of::<usize>());
+    }
+}
@@ -0,0 +1,115 @@
+// Name: OptionRefWith2Flags - maybe a reference, and 2 flags, in one word.
+//
+// Description: The address part of the word is 0 for None, and the flags
+//              live in the low bits as usual, so a None still has its 2
+//              flags, unlike Option<RefWith2Flags> that has none.
+//
+//              Null never collides with a real reference: a &T is never
+//              null, Rust guarantees it (that is also what lets Option<&T>
+//              be a single word). And the flags only use the 2 low bits, so
+//              the words of a None are 0 to 3, and the address of a Some is
+//              a non null multiple of 4, they can't be confused.
+//
+//              The word is kept as a pointer, with the provenance of the
+//              reference for a Some, and none for the words of a None.
+
+use core::marker::PhantomData;
+use core::ptr;
+
+use crate::aligned::AlignedAtLeast;
+
+#[repr(transparent)]
+pub struct OptionRefWith2Flags<'a, T> {
+    ptr_and_bit: *const T,
+    behaves_like: PhantomData<Option<&'a T>>,
+}
+
+// The raw pointer opts out of Send and Sync, this is an Option<&T> as far
+// as threads go.
+unsafe impl<'a, T: Sync> Send for OptionRefWith2Flags<'a, T> {}
+unsafe impl<'a, T: Sync> Sync for OptionRefWith2Flags<'a, T> {}
+
+impl<'a, T: 'a> OptionRefWith2Flags<'a, T> {
+
+    pub fn none(flag_a: bool, flag_b: bool) -> OptionRefWith2Flags<'a, T> {
+        OptionRefWith2Flags {
+            ptr_and_bit: ptr::without_provenance(flag_a as usize | ((flag_b as usize) << 1)),
+            behaves_like: PhantomData,
+        }
+    }
+
+    pub fn some(ptr: &'a T, flag_a: bool, flag_b: bool) -> OptionRefWith2Flags<'a, T>
+    where
+        T: AlignedAtLeast<4>,
+    {
+        OptionRefWith2Flags {
+            ptr_and_bit: ptr::from_ref(ptr).map_addr(|addr| addr | flag_a as usize | ((flag_b as usize) << 1)),
+            behaves_like: PhantomData,
+        }
+    }
+
+    pub fn new(ptr: Option<&'a T>, flag_a: bool, flag_b: bool) -> OptionRefWith2Flags<'a, T>
+    where
+        T: AlignedAtLeast<4>,
+    {
+        match ptr {
+            Some(ptr) => Self::some(ptr, flag_a, flag_b),
+            None => Self::none(flag_a, flag_b),
+        }
+    }
+
+    pub fn get_ref(&self) -> Option<&'a T> {
+        unsafe { self.ptr_and_bit.map_addr(|addr| addr & !3).as_ref() }
+    }
+
+    pub fn is_none(&self) -> bool {
+        self.ptr_and_bit.addr() & !3 == 0
+    }
+
+    pub fn is_some(&self) -> bool {
+        !self.is_none()
+    }
+
+    pub fn get_flag_a(&self) -> bool {
+        self.ptr_and_bit.addr() & 1 != 0
+    }
+
+    pub fn get_flag_b(&self) -> bool {
+        self.ptr_and_bit.addr() & 2 != 0
+    }
+
+    pub fn set_flag_a(&mut self, flag: bool) {
+        self.ptr_and_bit = self.ptr_and_bit.map_addr(|addr| (addr & !1) | flag as usize);
+    }
+
+    pub fn set_flag_b(&mut self, flag: bool) {
+        self.ptr_and_bit = self.ptr_and_bit.map_addr(|addr| (addr & !2) | ((flag as usize) << 1));
+    }
+
+    /// Empties it, keeps the flags, returns the reference it had.
+    pub fn take(&mut self) -> Option<&'a T> {
+        let taken = self.get_ref();
+        self.ptr_and_bit = ptr::without_provenance(self.ptr_and_bit.addr() & 3);
+        taken
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aligned_box::Align16;
+
+    #[test]
+    fn keeps_the_flags_when_none() {
+        let cells: Vec<Align16<u16>> = (0..12).map(Align16).collect();
+        let mut parent = OptionRefWith2Flags::none(false, true);
+        assert!(parent.is_none() && parent.get_ref().is_none() && parent.get_flag_b());
+        parent = OptionRefWith2Flags::some(&cells[10], true, true);
+        assert_eq!(parent.get_ref().map(|c| c.0), Some(10));
+        assert_eq!(parent.take().map(|c| c.0), Some(10));
+        assert!(parent.is_none() && parent.get_flag_a() && parent.get_flag_b());
+        assert!(OptionRefWith2Flags::new(cells.get(11), false, false).is_some());
+        assert_eq!(std::mem::size_of::<OptionRefWith2Flags<Align16<u16>>>(), std::mem::size_of::<usize>());
+    }
+}
@@ -0,0 +1,270 @@
+// Name: PairingHeap - a min pairing heap with lazy deletion through a
+//       "detached" bit in the node links.
+//
+// Description: A pairing heap is a tree where every node is smaller than its
+//              children, kept as a first child link and a next sibling link
+//              per node. push and merge are O(1), pop is O(log n) amortized.
+//
+//              Removing an arbitrary node (a cancelled timer, a dropped job)
+//              would need parent links and a re-merge. Instead remove() just
+//              sets a bit in the sibling link word of the node:
+//
+//                 bit 0 - DETACHED : the node was removed, skip it.
+//
+//              O(1), and the node is freed when it reaches the top, pop()
+//              and peek() drop detached roots as they find them. The bit is
+//              kept through all the link updates of the merges.
+//
+//              The links are the raw pointers of the boxes, the bit set and
+//              masked off with map_addr, so every node is freed through the
+//              pointer Box::into_raw returned for it.
+
+use std::marker::PhantomData;
+use std::ptr::{self, NonNull};
+
+const DETACHED: usize = 1;
+
+type Link<K, V> = *mut Node<K, V>;
+
+struct Node<K, V> {
+    key: K,
+    value: V,
+    child: Link<K, V>,
+    // The next sibling, plus the DETACHED bit of this node.
+    sibling: Link<K, V>,
+}
+
+/// Identifies a pushed node, for remove().
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct HeapHandle(NonNull<()>);
+
+pub struct PairingHeap<K, V> {
+    root: Link<K, V>,
+    len: usize,
+    detached: usize,
+    owns: PhantomData<Box<Node<K, V>>>,
+}
+
+// The raw pointers opt out of Send and Sync, this is a tree of boxed nodes
+// as far as threads go.
+unsafe impl<K: Send, V: Send> Send for PairingHeap<K, V> {}
+unsafe impl<K: Sync, V: Sync> Sync for PairingHeap<K, V> {}
+
+impl<K: Ord, V> PairingHeap<K, V> {
+
+    pub fn new() -> PairingHeap<K, V> {
+        PairingHeap { root: ptr::null_mut(), len: 0, detached: 0, owns: PhantomData }
+    }
+
+    /// Number of live (not removed) entries.
+    pub fn len(&self) -> usize {
+        self.len - self.detached
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn push(&mut self, key: K, value: V) -> HeapHandle {
+        let node = Box::into_raw(Box::new(Node { key, value, child: ptr::null_mut(), sibling: ptr::null_mut() }));
+        debug_assert_eq!(node.addr() & DETACHED, 0);
+        self.root = unsafe { Self::merge(self.root, node) };
+        self.len += 1;
+        HeapHandle(NonNull::new(node).unwrap().cast())
+    }
+
+    /// Marks the entry removed, in O(1). Returns false if it already was.
+    ///
+    /// # Safety
+    /// The entry of `handle` must still be in this heap: not popped, and the
+    /// heap not dropped. Removed entries stay in the heap until they reach
+    /// the top, so removing twice is fine.
+    pub unsafe fn remove(&mut self, handle: HeapHandle) -> bool {
+        let node = &mut *handle.0.as_ptr().cast::<Node<K, V>>();
+        if is_detached(node.sibling) {
+            return false;
+        }
+        node.sibling = node.sibling.map_addr(|addr| addr | DETACHED);
+        self.detached += 1;
+        true
+    }
+
+    /// The smallest live entry.
+    pub fn peek(&mut self) -> Option<(&K, &V)> {
+        self.drop_detached_roots();
+        if self.root.is_null() {
+            return None;
+        }
+        let node = unsafe { &*self.root };
+        Some((&node.key, &node.value))
+    }
+
+    /// Removes and returns the smallest live entry.
+    pub fn pop(&mut self) -> Option<(K, V)> {
+        self.drop_detached_roots();
+        if self.root.is_null() {
+            return None;
+        }
+        let node = unsafe { self.take_root() };
+        Some((node.key, node.value))
+    }
+
+    fn drop_detached_roots(&mut self) {
+        while !self.root.is_null() && is_detached(unsafe { (*self.root).sibling }) {
+            drop(unsafe { self.take_root() });
+            self.detached -= 1;
+        }
+    }
+
+    // Unlinks the root, merging its children into the new root.
+    unsafe fn take_root(&mut self) -> Box<Node<K, V>> {
+        let node = Box::from_raw(self.root);
+        self.root = Self::merge_pairs(node.child);
+        self.len -= 1;
+        node
+    }
+
+    // Merges 2 roots (sibling links ignored), the larger becomes the first
+    // child of the smaller.
+    unsafe fn merge(a: Link<K, V>, b: Link<K, V>) -> Link<K, V> {
+        if a.is_null() {
+            return b;
+        }
+        if b.is_null() {
+            return a;
+        }
+        let (parent, child) = if (*a).key <= (*b).key { (a, b) } else { (b, a) };
+        let bit = (*child).sibling.addr() & DETACHED;
+        (*child).sibling = (*parent).child.map_addr(|addr| addr | bit);
+        (*parent).child = child;
+        parent
+    }
+
+    // The 2 pass merge of a sibling list: pairs left to right, then the
+    // pairs right to left.
+    unsafe fn merge_pairs(first: Link<K, V>) -> Link<K, V> {
+        let mut pairs = Vec::new();
+        let mut next = first;
+        while !next.is_null() {
+            let a = next;
+            let b = Self::detach_sibling(a);
+            next = if b.is_null() { b } else { Self::detach_sibling(b) };
+            pairs.push(Self::merge(a, b));
+        }
+        pairs.into_iter().rev().fold(ptr::null_mut(), |root, pair| Self::merge(pair, root))
+    }
+
+    // Clears the sibling address of a node, keeping its DETACHED bit, and
+    // returns the old sibling.
+    unsafe fn detach_sibling(node: Link<K, V>) -> Link<K, V> {
+        let node = &mut *node;
+        let sibling = untagged(node.sibling);
+        node.sibling = ptr::without_provenance_mut(node.sibling.addr() & DETACHED);
+        sibling
+    }
+
+}
+
+fn is_detached<K, V>(link: Link<K, V>) -> bool {
+    link.addr() & DETACHED != 0
+}
+
+fn untagged<K, V>(link: Link<K, V>) -> Link<K, V> {
+    link.map_addr(|addr| addr & !DETACHED)
+}
+
+impl<K: Ord, V> Default for PairingHeap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> Drop for PairingHeap<K, V> {
+    fn drop(&mut self) {
+        let mut stack = vec![self.root];
+        while let Some(link) = stack.pop() {
+            if !link.is_null() {
+                let node = unsafe { Box::from_raw(link) };
+                stack.push(node.child);
+                stack.push(untagged(node.sibling));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+    use std::rc::Rc;
+
+    #[test]
+    fn removed_nodes_are_skipped() {
+        let mut deadlines = PairingHeap::new();
+        let keys = [50, 10, 40, 30, 20, 60, 5];
+        let handles: Vec<_> = keys.iter().map(|&k| deadlines.push(k, format!("job {}", k))).collect();
+        assert!(unsafe { deadlines.remove(handles[6]) });
+        assert!(!unsafe { deadlines.remove(handles[6]) });
+        assert!(unsafe { deadlines.remove(handles[3]) });
+        assert_eq!(deadlines.len(), 5);
+        assert_eq!(deadlines.peek().map(|(k, _)| *k), Some(10));
+        let order: Vec<i32> = std::iter::from_fn(|| deadlines.pop().map(|(k, _)| k)).collect();
+        assert_eq!(order, vec![10, 20, 40, 50, 60]);
+        assert!(deadlines.is_empty() && deadlines.pop().is_none());
+        let mut leftovers = PairingHeap::default();
+        leftovers.push(1, String::from("dropped with the heap"));
+    }
+
+    #[test]
+    fn random_operations_match_a_btree_set() {
+        // Keys are (priority, id), unique, so the model's order is the heap's.
+        let mut heap = PairingHeap::new();
+        let mut model = BTreeSet::new();
+        let mut handles: Vec<(HeapHandle, (u32, u32))> = Vec::new();
+        let mut seed = 5050u32;
+        for id in 0..4000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            match (seed >> 8) % 5 {
+                0 | 1 => {
+                    let key = ((seed >> 16) % 100, id);
+                    handles.push((heap.push(key, id), key));
+                    model.insert(key);
+                }
+                2 if !handles.is_empty() => {
+                    let (handle, key) = handles.swap_remove((seed >> 16) as usize % handles.len());
+                    assert!(unsafe { heap.remove(handle) });
+                    assert!(model.remove(&key));
+                }
+                3 => {
+                    assert_eq!(heap.peek().map(|(k, _)| *k), model.first().copied());
+                }
+                _ => {
+                    let popped = heap.pop().map(|(k, _)| k);
+                    assert_eq!(popped, model.pop_first());
+                    handles.retain(|&(_, key)| Some(key) != popped);
+                }
+            }
+            assert_eq!(heap.len(), model.len());
+        }
+        let rest: Vec<(u32, u32)> = std::iter::from_fn(|| heap.pop().map(|(k, _)| k)).collect();
+        assert_eq!(rest, model.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn a_single_entry_and_dropping_removed_nodes() {
+        let counted = Rc::new(());
+        let mut heap = PairingHeap::new();
+        assert!(heap.peek().is_none() && heap.pop().is_none());
+        let only = heap.push(1, counted.clone());
+        assert!(unsafe { heap.remove(only) });
+        assert!(heap.is_empty() && heap.peek().is_none());
+        assert_eq!(Rc::strong_count(&counted), 1);
+        let root = heap.push(1, counted.clone());
+        let child = heap.push(2, counted.clone());
+        heap.push(3, counted.clone());
+        assert!(unsafe { heap.remove(child) } && unsafe { heap.remove(root) });
+        assert_eq!(heap.len(), 1);
+        drop(heap);
+        assert_eq!(Rc::strong_count(&counted), 1);
+    }
+}
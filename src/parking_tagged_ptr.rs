@@ -0,0 +1,153 @@
+// Name: ParkingTaggedPtr - an atomic tagged reference that threads can block
+//       on until its flag is cleared.
+//
+// Description: A blocking flag next to a reference, for custom blocking state
+//              machines. The word is the address of the referent plus 2 bits:
+//
+//                 bit 0 - FLAG    : the user state, e.g. "busy", "not ready".
+//                 bit 1 - WAITERS : threads may be parked until FLAG clears.
+//
+//              wait_until_flag_clear() sets WAITERS and parks. Clearing the
+//              flag clears WAITERS too and, only if it was set, wakes all the
+//              parked threads, so while nobody waits the clear is a single
+//              fetch_and.
+//
+//              It is one word: the parked threads aren't kept in it but in a
+//              global table keyed by the address of the word, as in
+//              parking_lot, or as the kernel keys the futex queues by the
+//              futex address. WordMutex parks its waiters in the same table.
+
+use std::marker::PhantomData;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::thread::{self, Thread};
+
+use crate::aligned::AlignedAtLeast;
+
+const FLAG: usize = 1;
+const WAITERS: usize = 2;
+const BITS: usize = FLAG | WAITERS;
+
+// The parking table, the parked threads with the address they wait on,
+// hashed into a few buckets.
+const BUCKETS: usize = 64;
+static PARKED: [Mutex<Vec<(usize, Thread)>>; BUCKETS] = [const { Mutex::new(Vec::new()) }; BUCKETS];
+
+pub struct ParkingTaggedPtr<'a, T> {
+    word: AtomicPtr<T>,
+    behaves_like: PhantomData<&'a T>,
+}
+
+impl<'a, T: AlignedAtLeast<4> + Sync> ParkingTaggedPtr<'a, T> {
+
+    pub fn new(value: &'a T, flag: bool) -> ParkingTaggedPtr<'a, T> {
+        let tagged = ptr::from_ref(value).cast_mut().map_addr(|addr| addr | flag as usize);
+        ParkingTaggedPtr { word: AtomicPtr::new(tagged), behaves_like: PhantomData }
+    }
+
+    pub fn get_ref(&self) -> &'a T {
+        Self::referent(self.word.load(Ordering::Acquire))
+    }
+
+    pub fn is_flag_set(&self) -> bool {
+        self.word.load(Ordering::Acquire).addr() & FLAG != 0
+    }
+
+    /// Sets the flag, returns its previous value.
+    pub fn set_flag(&self) -> bool {
+        self.word.fetch_or(FLAG, Ordering::AcqRel).addr() & FLAG != 0
+    }
+
+    /// Clears the flag and wakes the threads waiting for it, if any.
+    pub fn clear_flag(&self) {
+        let word = self.word.fetch_and(!BITS, Ordering::AcqRel);
+        if word.addr() & WAITERS != 0 {
+            self.wake_all();
+        }
+    }
+
+    /// Blocks until the flag is clear, then returns the referent.
+    pub fn wait_until_flag_clear(&self) -> &'a T {
+        loop {
+            let word = self.word.load(Ordering::Acquire);
+            if word.addr() & FLAG == 0 {
+                return Self::referent(word);
+            }
+            // WAITERS is set with the bucket locked, so the clear that sees it
+            // finds this thread parked. The CAS also fails if the flag was
+            // cleared since the load.
+            park(ptr::from_ref(&self.word).addr(), || {
+                let waiting = word.map_addr(|addr| addr | WAITERS);
+                self.word.compare_exchange(word, waiting, Ordering::AcqRel, Ordering::Acquire).is_ok()
+            });
+        }
+    }
+
+    /// Unparks every waiting thread, they check the flag again.
+    pub fn wake_all(&self) {
+        unpark_all(ptr::from_ref(&self.word).addr());
+    }
+
+    fn referent(word: *mut T) -> &'a T {
+        unsafe { &*word.map_addr(|addr| addr & !BITS) }
+    }
+
+}
+
+fn bucket(key: usize) -> MutexGuard<'static, Vec<(usize, Thread)>> {
+    PARKED[(key / align_of::<usize>()) % BUCKETS].lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Parks the current thread on `key`, the address of a word, if `validate`
+/// returns true. It runs with the bucket locked, so an unpark_all(key) that
+/// follows a successful validate finds the thread.
+pub(crate) fn park(key: usize, validate: impl FnOnce() -> bool) {
+    let mut parked = bucket(key);
+    if !validate() {
+        return;
+    }
+    let current = thread::current();
+    parked.push((key, current.clone()));
+    drop(parked);
+    thread::park();
+    // Only left behind by a spurious wake up.
+    bucket(key).retain(|(parked_key, thread)| *parked_key != key || thread.id() != current.id());
+}
+
+/// Unparks every thread parked on `key`.
+pub(crate) fn unpark_all(key: usize) {
+    bucket(key).retain(|(parked_key, thread)| {
+        if *parked_key != key {
+            return true;
+        }
+        thread.unpark();
+        false
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU32;
+
+    #[test]
+    fn is_one_word_and_wakes_the_waiters() {
+        assert_eq!(size_of::<ParkingTaggedPtr<'_, u32>>(), size_of::<usize>());
+        let config = 7u32;
+        let not_ready = ParkingTaggedPtr::new(&config, true);
+        let woken = AtomicU32::new(0);
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    assert_eq!(*not_ready.wait_until_flag_clear(), 7);
+                    woken.fetch_add(1, Ordering::Relaxed);
+                });
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+            not_ready.clear_flag();
+        });
+        assert_eq!(woken.load(Ordering::Relaxed), 4);
+        assert!(!not_ready.is_flag_set());
+    }
+}
@@ -0,0 +1,281 @@
+// Name: PersistentMap - a path copying ordered map with a "shared" bit on
+//       the node links.
+//
+// Description: A binary search tree whose versions share nodes. Cloning the
+//              map is O(1), both versions point to the same root. An insert
+//              copies only the nodes that are shared with another version,
+//              and mutates in place the ones that are not.
+//
+//              Which is which is decided by a bit in the link to the node,
+//              not by looking at a reference count:
+//
+//                 bit 0 - SHARED : the node may be reachable from another
+//                                  version, copy it before changing it.
+//
+//              A link without the bit is the only way to reach the node, so
+//              in place mutation is safe. Cloning the map sets the bit on
+//              both roots, and copying a node sets it on all the links of the
+//              copy, since the children now have 2 parents. This gives the
+//              performance of transients (a chain of inserts on a version
+//              that was never cloned copies nothing) with no extra field per
+//              node. The bit is conservative: a node can stay marked shared
+//              after the other version is dropped, it is then just copied
+//              once more than needed.
+//
+//              The nodes are Rc allocations, the counts only decide when a
+//              node is freed. The links are the pointers from Rc::into_raw,
+//              the bit set and cleared with map_addr, so each count goes
+//              back to the allocation it came from. The tree is not
+//              balanced.
+
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::marker::PhantomData;
+use std::ptr;
+use std::rc::Rc;
+
+const SHARED: usize = 1;
+
+// A tagged Rc<Node> pointer, null for no child.
+type Link<K, V> = *const Node<K, V>;
+
+struct Node<K, V> {
+    key: K,
+    value: V,
+    left: Link<K, V>,
+    right: Link<K, V>,
+}
+
+impl<K, V> Drop for Node<K, V> {
+    fn drop(&mut self) {
+        release(self.left);
+        release(self.right);
+    }
+}
+
+fn untagged<K, V>(link: Link<K, V>) -> Link<K, V> {
+    link.map_addr(|addr| addr & !SHARED)
+}
+
+fn release<K, V>(link: Link<K, V>) {
+    if !link.is_null() {
+        unsafe { Rc::decrement_strong_count(untagged(link)) };
+    }
+}
+
+fn retain_shared<K, V>(link: Link<K, V>) -> Link<K, V> {
+    if link.is_null() {
+        return link;
+    }
+    unsafe { Rc::increment_strong_count(untagged(link)) };
+    link.map_addr(|addr| addr | SHARED)
+}
+
+pub struct PersistentMap<K, V> {
+    // In a Cell because clone(), through &self, marks the root shared.
+    root: Cell<Link<K, V>>,
+    len: usize,
+    owns: PhantomData<Rc<Node<K, V>>>,
+}
+
+impl<K: Ord + Clone, V: Clone> PersistentMap<K, V> {
+
+    pub fn new() -> PersistentMap<K, V> {
+        PersistentMap { root: Cell::new(ptr::null()), len: 0, owns: PhantomData }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// True if the root may be shared with another version, the next insert
+    /// will copy the path it touches.
+    pub fn is_root_shared(&self) -> bool {
+        self.root.get().addr() & SHARED != 0
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        let mut link = self.root.get();
+        while !link.is_null() {
+            let node = unsafe { &*untagged(link) };
+            link = match key.cmp(&node.key) {
+                Ordering::Less => node.left,
+                Ordering::Greater => node.right,
+                Ordering::Equal => return Some(&node.value),
+            };
+        }
+        None
+    }
+
+    /// Inserts or replaces, returns the previous value.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let mut link: *mut Link<K, V> = self.root.as_ptr();
+        unsafe {
+            loop {
+                if (*link).is_null() {
+                    let node = Rc::new(Node { key, value, left: ptr::null(), right: ptr::null() });
+                    *link = Rc::into_raw(node);
+                    self.len += 1;
+                    return None;
+                }
+                let node = &mut *Self::make_unshared(link).cast_mut();
+                link = match key.cmp(&node.key) {
+                    Ordering::Less => &mut node.left,
+                    Ordering::Greater => &mut node.right,
+                    Ordering::Equal => return Some(std::mem::replace(&mut node.value, value)),
+                };
+            }
+        }
+    }
+
+    /// Iterates in key order.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> + '_ {
+        let mut stack: Vec<&Node<K, V>> = Vec::new();
+        let mut link = self.root.get();
+        std::iter::from_fn(move || {
+            while !link.is_null() {
+                let node = unsafe { &*untagged(link) };
+                stack.push(node);
+                link = node.left;
+            }
+            let node = stack.pop()?;
+            link = node.right;
+            Some((&node.key, &node.value))
+        })
+    }
+
+    // Makes the node behind `link` exclusively owned by that link, copying
+    // it if it is shared, and returns it.
+    unsafe fn make_unshared(link: *mut Link<K, V>) -> Link<K, V> {
+        let word = *link;
+        if word.addr() & SHARED == 0 {
+            return word;
+        }
+        let old = &*untagged(word);
+        let copy = Rc::new(Node {
+            key: old.key.clone(),
+            value: old.value.clone(),
+            left: retain_shared(old.left),
+            right: retain_shared(old.right),
+        });
+        release(word);
+        let copy = Rc::into_raw(copy);
+        *link = copy;
+        copy
+    }
+
+}
+
+impl<K, V> Clone for PersistentMap<K, V> {
+    fn clone(&self) -> Self {
+        let root = retain_shared(self.root.get());
+        self.root.set(root);
+        PersistentMap { root: Cell::new(root), len: self.len, owns: PhantomData }
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> Default for PersistentMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> Drop for PersistentMap<K, V> {
+    fn drop(&mut self) {
+        release(self.root.get());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::rc::Rc;
+
+    #[test]
+    fn shared_roots_are_copied_on_write() {
+        let mut v1 = PersistentMap::new();
+        for key in [50, 20, 80, 10, 30] {
+            v1.insert(key, key * 10);
+        }
+        assert!(!v1.is_root_shared());
+        let mut v2 = v1.clone();
+        assert!(v1.is_root_shared() && v2.is_root_shared());
+        assert_eq!(v2.insert(30, 333), Some(300));
+        v2.insert(90, 900);
+        assert!(!v2.is_root_shared());
+        assert_eq!(v1.get(&30), Some(&300));
+        assert_eq!(v2.get(&30), Some(&333));
+        assert_eq!(v1.get(&90), None);
+        assert_eq!((v1.len(), v2.len()), (5, 6));
+        let keys: Vec<i32> = v2.iter().map(|(k, _)| *k).collect();
+        assert_eq!(keys, vec![10, 20, 30, 50, 80, 90]);
+        drop(v1);
+        assert_eq!(v2.get(&10), Some(&100));
+        assert!(!v2.is_empty() && PersistentMap::<i32, i32>::default().is_empty());
+    }
+
+    #[test]
+    fn random_versions_match_btree_maps() {
+        // The model: a BTreeMap for every version kept.
+        let mut versions = vec![(PersistentMap::new(), BTreeMap::new())];
+        let mut seed = 1999u32;
+        for _ in 0..3000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let pick = (seed >> 20) as usize % versions.len();
+            let key = (seed >> 16) % 200;
+            if (seed >> 8).is_multiple_of(4) {
+                let (map, model) = &versions[pick];
+                versions.push((map.clone(), model.clone()));
+            } else {
+                let (map, model) = &mut versions[pick];
+                assert_eq!(map.insert(key, seed), model.insert(key, seed));
+                assert_eq!(map.len(), model.len());
+            }
+            if versions.len() > 20 {
+                versions.swap_remove((seed >> 4) as usize % versions.len());
+            }
+        }
+        // Inserting into one version never showed through another.
+        for (map, model) in &versions {
+            assert!(map.iter().eq(model.iter()));
+            assert!((0..200).all(|key| map.get(&key) == model.get(&key)));
+        }
+    }
+
+    #[test]
+    fn an_empty_map_and_a_single_key() {
+        let mut map: PersistentMap<u8, u8> = PersistentMap::new();
+        assert_eq!(map.get(&1), None);
+        assert_eq!(map.iter().next(), None);
+        let empty = map.clone();
+        assert!(!map.is_root_shared());
+        assert_eq!(map.insert(1, 10), None);
+        assert_eq!(map.insert(1, 11), Some(10));
+        assert_eq!(map.len(), 1);
+        assert!(empty.is_empty() && empty.get(&1).is_none());
+    }
+
+    #[test]
+    fn dropping_every_version_frees_every_node() {
+        let counted = Rc::new(());
+        let mut first = PersistentMap::new();
+        for key in 0..50 {
+            first.insert((key * 7) % 50, counted.clone());
+        }
+        let mut second = first.clone();
+        for key in 0..25 {
+            second.insert(key * 2, counted.clone());
+        }
+        let third = second.clone();
+        drop(first);
+        assert_eq!(third.len(), 50);
+        drop(second);
+        drop(third);
+        assert_eq!(Rc::strong_count(&counted), 1);
+    }
+}
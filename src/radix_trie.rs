@@ -0,0 +1,286 @@
+// Name: RadixTrie - a radix trie on u64 keys, the leaf bit in the child
+//       pointers.
+//
+// Description: A trie of 16 way nodes, one level per nibble of the key, the
+//              most significant first, so the walk is in key order. A child
+//              is one word, and bit 0 tells the 2 kinds of boxes it can point
+//              to apart, with no enum wrapper around each child:
+//
+//                 0               : no child.
+//                 bit 0 = 0       : an inner node, 16 more children.
+//                 bit 0 = 1, LEAF : a leaf, a whole key and its value.
+//
+//              As in an adaptive radix tree (ART), a subtree with a single
+//              key is just its leaf, at the level where its key stopped
+//              sharing a prefix with the others (lazy expansion): the path
+//              below is only built when a second key needs it, and remove()
+//              collapses an inner node left with a single leaf back into the
+//              leaf. So a lookup is at most 16 levels, and usually far less.
+//
+//              A child is a *mut () straight from Box::into_raw, LEAF ORed in
+//              by map_addr, so the box is reached with the provenance it was
+//              allocated with and "no child" is the null pointer.
+
+use std::marker::PhantomData;
+use std::mem;
+use std::ptr;
+
+const LEAF: usize = 1;
+const FANOUT: usize = 16;
+
+struct Inner {
+    children: [*mut (); FANOUT],
+}
+
+struct Leaf<V> {
+    key: u64,
+    value: V,
+}
+
+pub struct RadixTrie<V> {
+    root: *mut (),
+    len: usize,
+    owns: PhantomData<Box<Leaf<V>>>,
+}
+
+// The raw pointers opt out of Send and Sync, this is a tree of Box<Leaf<V>>
+// as far as threads go.
+unsafe impl<V: Send> Send for RadixTrie<V> {}
+unsafe impl<V: Sync> Sync for RadixTrie<V> {}
+
+impl<V> RadixTrie<V> {
+
+    pub fn new() -> RadixTrie<V> {
+        RadixTrie { root: ptr::null_mut(), len: 0, owns: PhantomData }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, key: u64) -> Option<&V> {
+        let leaf = self.find(key)?;
+        Some(unsafe { &(*leaf).value })
+    }
+
+    pub fn get_mut(&mut self, key: u64) -> Option<&mut V> {
+        let leaf = self.find(key)?;
+        Some(unsafe { &mut (*leaf).value })
+    }
+
+    pub fn contains_key(&self, key: u64) -> bool {
+        self.find(key).is_some()
+    }
+
+    /// Returns the previous value of `key`, if there was one.
+    pub fn insert(&mut self, key: u64, value: V) -> Option<V> {
+        let mut slot: *mut *mut () = &mut self.root;
+        let mut depth = 0;
+        unsafe {
+            loop {
+                let word = *slot;
+                if word.is_null() {
+                    *slot = new_leaf(key, value);
+                    self.len += 1;
+                    return None;
+                }
+                if word.addr() & LEAF == 0 {
+                    slot = &mut (*word.cast::<Inner>()).children[nibble(key, depth)];
+                    depth += 1;
+                    continue;
+                }
+                let leaf = leaf_of::<V>(word);
+                if (*leaf).key == key {
+                    return Some(mem::replace(&mut (*leaf).value, value));
+                }
+                // Expands the path down to the first nibble that differs.
+                let other = (*leaf).key;
+                loop {
+                    let inner = Box::into_raw(Box::new(Inner { children: [ptr::null_mut(); FANOUT] }));
+                    *slot = inner.cast();
+                    let (mine, theirs) = (nibble(key, depth), nibble(other, depth));
+                    if mine != theirs {
+                        (*inner).children[theirs] = word;
+                        (*inner).children[mine] = new_leaf(key, value);
+                        self.len += 1;
+                        return None;
+                    }
+                    slot = &mut (*inner).children[mine];
+                    depth += 1;
+                }
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: u64) -> Option<V> {
+        let value = unsafe { remove_at(&mut self.root, key, 0) }?;
+        self.len -= 1;
+        Some(value)
+    }
+
+    /// The entries in key order.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &V)> + '_ {
+        let mut stack = vec![self.root];
+        std::iter::from_fn(move || loop {
+            let word = stack.pop()?;
+            if word.is_null() {
+                continue;
+            }
+            if word.addr() & LEAF != 0 {
+                let leaf = unsafe { &*leaf_of::<V>(word) };
+                return Some((leaf.key, &leaf.value));
+            }
+            stack.extend(unsafe { (*word.cast::<Inner>()).children.iter().rev() });
+        })
+    }
+
+    fn find(&self, key: u64) -> Option<*mut Leaf<V>> {
+        let mut word = self.root;
+        let mut depth = 0;
+        while !word.is_null() && word.addr() & LEAF == 0 {
+            word = unsafe { (*word.cast::<Inner>()).children[nibble(key, depth)] };
+            depth += 1;
+        }
+        let leaf = leaf_of::<V>(word);
+        (!word.is_null() && unsafe { (*leaf).key } == key).then_some(leaf)
+    }
+
+}
+
+impl<V> Default for RadixTrie<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V> Drop for RadixTrie<V> {
+    fn drop(&mut self) {
+        let mut stack = vec![self.root];
+        while let Some(word) = stack.pop() {
+            if word.addr() & LEAF != 0 {
+                drop(unsafe { Box::from_raw(leaf_of::<V>(word)) });
+            } else if !word.is_null() {
+                let inner = unsafe { Box::from_raw(word.cast::<Inner>()) };
+                stack.extend(inner.children);
+            }
+        }
+    }
+}
+
+fn nibble(key: u64, depth: usize) -> usize {
+    (key >> (60 - 4 * depth)) as usize & (FANOUT - 1)
+}
+
+fn new_leaf<V>(key: u64, value: V) -> *mut () {
+    // A Leaf holds a u64, so its address is even.
+    Box::into_raw(Box::new(Leaf { key, value })).cast::<()>().map_addr(|addr| addr | LEAF)
+}
+
+fn leaf_of<V>(word: *mut ()) -> *mut Leaf<V> {
+    word.map_addr(|addr| addr & !LEAF).cast()
+}
+
+// Removes `key` under `slot`, and collapses the inner nodes left with a
+// single leaf on the way back up.
+unsafe fn remove_at<V>(slot: &mut *mut (), key: u64, depth: usize) -> Option<V> {
+    let word = *slot;
+    if word.is_null() {
+        return None;
+    }
+    if word.addr() & LEAF != 0 {
+        let leaf = leaf_of::<V>(word);
+        if (*leaf).key != key {
+            return None;
+        }
+        *slot = ptr::null_mut();
+        return Some(Box::from_raw(leaf).value);
+    }
+    let inner = word.cast::<Inner>();
+    let value = remove_at(&mut (*inner).children[nibble(key, depth)], key, depth + 1)?;
+    let mut children = (*inner).children.iter().copied().filter(|child| !child.is_null());
+    match (children.next(), children.next()) {
+        (None, _) => *slot = ptr::null_mut(),
+        (Some(only), None) if only.addr() & LEAF != 0 => *slot = only,
+        _ => return Some(value),
+    }
+    drop(Box::from_raw(inner));
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::rc::Rc;
+
+    // Number of keys under `word`, checking that no inner node is left with
+    // a single leaf, or nothing.
+    fn check(word: *mut ()) -> usize {
+        if word.addr() & LEAF != 0 {
+            return 1;
+        }
+        let children = unsafe { (*word.cast::<Inner>()).children };
+        let live: Vec<*mut ()> = children.into_iter().filter(|child| !child.is_null()).collect();
+        assert!(live.len() > 1 || live.first().is_some_and(|only| only.addr() & LEAF == 0), "uncollapsed inner node");
+        live.into_iter().map(check).sum()
+    }
+
+    #[test]
+    fn matches_btree_map_and_collapses() {
+        let mut trie = RadixTrie::new();
+        let mut model = BTreeMap::new();
+        let mut seed = 777u64;
+        for _ in 0..5000 {
+            seed = seed.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+            // Keys sharing long prefixes, to build deep paths.
+            let key = ((seed >> 60) << 40) | ((seed >> 40) % 64);
+            if (seed >> 33) & 1 == 0 {
+                assert_eq!(trie.insert(key, seed), model.insert(key, seed));
+            } else {
+                assert_eq!(trie.remove(key), model.remove(&key));
+            }
+            if !trie.root.is_null() {
+                assert_eq!(check(trie.root), model.len());
+            }
+        }
+        assert_eq!(trie.len(), model.len());
+        assert!(trie.iter().eq(model.iter().map(|(&key, value)| (key, value))));
+    }
+
+    #[test]
+    fn edge_keys_and_an_empty_trie() {
+        let mut trie = RadixTrie::new();
+        assert!(trie.is_empty() && trie.get(0).is_none() && trie.remove(0).is_none() && trie.iter().next().is_none());
+        // 0 and u64::MAX differ in the first nibble, 1 and 0 only in the last.
+        for key in [u64::MAX, 0, 1] {
+            assert_eq!(trie.insert(key, key.to_string()), None);
+        }
+        assert_eq!(check(trie.root), 3);
+        assert!(trie.iter().map(|(key, _)| key).eq([0, 1, u64::MAX]));
+        trie.get_mut(1).unwrap().push('!');
+        assert_eq!(trie.insert(1, "one".to_string()).as_deref(), Some("1!"));
+        assert_eq!(trie.remove(0).as_deref(), Some("0"));
+        assert_eq!(trie.remove(1).as_deref(), Some("one"));
+        // Back to a single leaf at the root.
+        assert!(trie.root.addr() & LEAF != 0 && trie.len() == 1 && trie.contains_key(u64::MAX));
+        assert_eq!(trie.remove(u64::MAX).as_deref(), Some(&*u64::MAX.to_string()));
+        assert!(trie.root.is_null() && trie.is_empty());
+    }
+
+    #[test]
+    fn drops_every_value() {
+        let counter = Rc::new(());
+        let mut trie = RadixTrie::new();
+        for key in 0..100u64 {
+            trie.insert(key * 0x0101_0101, counter.clone());
+        }
+        trie.remove(0);
+        assert_eq!(Rc::strong_count(&counter), 100);
+        drop(trie);
+        assert_eq!(Rc::strong_count(&counter), 1);
+    }
+}
@@ -0,0 +1,437 @@
+// Name: RbTreeMap - a red-black tree map whose node color lives in the parent
+//       pointer.
+//
+// Description: The classic trick of the Linux rbtree: a node is at least 4
+//              bytes aligned, so its parent pointer has 2 free low bits, and
+//              the color doesn't need its own field (a whole word, once
+//              padded). Each node is 3 links plus the key and the value:
+//
+//                 bit 0 - RED      : the node is red, black without it.
+//                 bit 1 - SENTINEL : the node is the nil sentinel.
+//
+//              The tree follows Cormen et al. (CLRS): every missing child and
+//              the parent of the root point to one black nil node per tree,
+//              which makes the rotation and the fix-up code uniform. The nil
+//              node carries SENTINEL in its own parent word, so telling it
+//              apart needs only the node, not the tree. Setting a parent
+//              keeps the bits of the node, and recoloring keeps the parent.
+
+use std::cmp::Ordering;
+use std::marker::PhantomData;
+use std::mem::{self, MaybeUninit};
+use std::ptr;
+
+const RED: usize = 1;
+const SENTINEL: usize = 2;
+const BITS: usize = RED | SENTINEL;
+
+#[repr(align(4))]
+struct Node<K, V> {
+    // Address of the parent, plus RED and SENTINEL.
+    parent: *mut Node<K, V>,
+    left: *mut Node<K, V>,
+    right: *mut Node<K, V>,
+    // Uninit in the nil sentinel only.
+    key: MaybeUninit<K>,
+    value: MaybeUninit<V>,
+}
+
+type Link<K, V> = *mut Node<K, V>;
+
+pub struct RbTreeMap<K, V> {
+    root: Link<K, V>,
+    nil: Link<K, V>,
+    len: usize,
+    owns: PhantomData<Box<Node<K, V>>>,
+}
+
+impl<K: Ord, V> RbTreeMap<K, V> {
+
+    pub fn new() -> RbTreeMap<K, V> {
+        let nil = Box::into_raw(Box::new(Node {
+            parent: ptr::null_mut::<Node<K, V>>().map_addr(|_| SENTINEL),
+            left: ptr::null_mut(),
+            right: ptr::null_mut(),
+            key: MaybeUninit::uninit(),
+            value: MaybeUninit::uninit(),
+        }));
+        RbTreeMap { root: nil, nil, len: 0, owns: PhantomData }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        let node = self.find(key)?;
+        Some(unsafe { (*node).value.assume_init_ref() })
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let node = self.find(key)?;
+        Some(unsafe { (*node).value.assume_init_mut() })
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.find(key).is_some()
+    }
+
+    /// Returns the previous value of `key`, if there was one.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        unsafe {
+            let mut parent = self.nil;
+            let mut x = self.root;
+            while !is_nil(x) {
+                parent = x;
+                x = match key.cmp(key_of(x)) {
+                    Ordering::Less => (*x).left,
+                    Ordering::Greater => (*x).right,
+                    Ordering::Equal => return Some(mem::replace((*x).value.assume_init_mut(), value)),
+                };
+            }
+            let z = Box::into_raw(Box::new(Node {
+                parent: parent.map_addr(|addr| addr | RED),
+                left: self.nil,
+                right: self.nil,
+                key: MaybeUninit::new(key),
+                value: MaybeUninit::new(value),
+            }));
+            if is_nil(parent) {
+                self.root = z;
+            } else if key_of(z) < key_of(parent) {
+                (*parent).left = z;
+            } else {
+                (*parent).right = z;
+            }
+            self.len += 1;
+            self.insert_fixup(z);
+        }
+        None
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let z = self.find(key)?;
+        unsafe {
+            self.delete(z);
+            self.len -= 1;
+            let node = Box::from_raw(z);
+            drop(node.key.assume_init());
+            Some(node.value.assume_init())
+        }
+    }
+
+    /// The entries in key order.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> + '_ {
+        let mut next = if is_nil(self.root) { self.root } else { minimum(self.root) };
+        std::iter::from_fn(move || {
+            if is_nil(next) {
+                return None;
+            }
+            let node = next;
+            next = successor(node);
+            Some(unsafe { (key_of(node), (*node).value.assume_init_ref()) })
+        })
+    }
+
+    fn find(&self, key: &K) -> Option<Link<K, V>> {
+        let mut x = self.root;
+        while !is_nil(x) {
+            x = match key.cmp(unsafe { key_of(x) }) {
+                Ordering::Less => unsafe { (*x).left },
+                Ordering::Greater => unsafe { (*x).right },
+                Ordering::Equal => return Some(x),
+            };
+        }
+        None
+    }
+
+    unsafe fn insert_fixup(&mut self, mut z: Link<K, V>) {
+        while is_red(parent(z)) {
+            let p = parent(z);
+            let g = parent(p);
+            if p == (*g).left {
+                let uncle = (*g).right;
+                if is_red(uncle) {
+                    set_red(p, false);
+                    set_red(uncle, false);
+                    set_red(g, true);
+                    z = g;
+                } else {
+                    if z == (*p).right {
+                        z = p;
+                        self.left_rotate(z);
+                    }
+                    let p = parent(z);
+                    set_red(p, false);
+                    set_red(parent(p), true);
+                    self.right_rotate(parent(p));
+                }
+            } else {
+                let uncle = (*g).left;
+                if is_red(uncle) {
+                    set_red(p, false);
+                    set_red(uncle, false);
+                    set_red(g, true);
+                    z = g;
+                } else {
+                    if z == (*p).left {
+                        z = p;
+                        self.right_rotate(z);
+                    }
+                    let p = parent(z);
+                    set_red(p, false);
+                    set_red(parent(p), true);
+                    self.left_rotate(parent(p));
+                }
+            }
+        }
+        set_red(self.root, false);
+    }
+
+    unsafe fn delete(&mut self, z: Link<K, V>) {
+        let mut y = z;
+        let mut y_was_red = is_red(y);
+        let x;
+        if is_nil((*z).left) {
+            x = (*z).right;
+            self.transplant(z, x);
+        } else if is_nil((*z).right) {
+            x = (*z).left;
+            self.transplant(z, x);
+        } else {
+            y = minimum((*z).right);
+            y_was_red = is_red(y);
+            x = (*y).right;
+            if parent(y) == z {
+                // x may be the nil node, its parent is needed by the fix-up.
+                set_parent(x, y);
+            } else {
+                self.transplant(y, x);
+                (*y).right = (*z).right;
+                set_parent((*y).right, y);
+            }
+            self.transplant(z, y);
+            (*y).left = (*z).left;
+            set_parent((*y).left, y);
+            set_red(y, is_red(z));
+        }
+        if !y_was_red {
+            self.delete_fixup(x);
+        }
+    }
+
+    unsafe fn delete_fixup(&mut self, mut x: Link<K, V>) {
+        while x != self.root && !is_red(x) {
+            let p = parent(x);
+            if x == (*p).left {
+                let mut w = (*p).right;
+                if is_red(w) {
+                    set_red(w, false);
+                    set_red(p, true);
+                    self.left_rotate(p);
+                    w = (*parent(x)).right;
+                }
+                if !is_red((*w).left) && !is_red((*w).right) {
+                    set_red(w, true);
+                    x = parent(x);
+                } else {
+                    if !is_red((*w).right) {
+                        set_red((*w).left, false);
+                        set_red(w, true);
+                        self.right_rotate(w);
+                        w = (*parent(x)).right;
+                    }
+                    set_red(w, is_red(parent(x)));
+                    set_red(parent(x), false);
+                    set_red((*w).right, false);
+                    self.left_rotate(parent(x));
+                    x = self.root;
+                }
+            } else {
+                let mut w = (*p).left;
+                if is_red(w) {
+                    set_red(w, false);
+                    set_red(p, true);
+                    self.right_rotate(p);
+                    w = (*parent(x)).left;
+                }
+                if !is_red((*w).right) && !is_red((*w).left) {
+                    set_red(w, true);
+                    x = parent(x);
+                } else {
+                    if !is_red((*w).left) {
+                        set_red((*w).right, false);
+                        set_red(w, true);
+                        self.left_rotate(w);
+                        w = (*parent(x)).left;
+                    }
+                    set_red(w, is_red(parent(x)));
+                    set_red(parent(x), false);
+                    set_red((*w).left, false);
+                    self.right_rotate(parent(x));
+                    x = self.root;
+                }
+            }
+        }
+        set_red(x, false);
+    }
+
+    // Puts v where u was, under the parent of u.
+    unsafe fn transplant(&mut self, u: Link<K, V>, v: Link<K, V>) {
+        let p = parent(u);
+        if is_nil(p) {
+            self.root = v;
+        } else if u == (*p).left {
+            (*p).left = v;
+        } else {
+            (*p).right = v;
+        }
+        set_parent(v, p);
+    }
+
+    unsafe fn left_rotate(&mut self, x: Link<K, V>) {
+        let y = (*x).right;
+        (*x).right = (*y).left;
+        if !is_nil((*y).left) {
+            set_parent((*y).left, x);
+        }
+        self.transplant(x, y);
+        (*y).left = x;
+        set_parent(x, y);
+    }
+
+    unsafe fn right_rotate(&mut self, x: Link<K, V>) {
+        let y = (*x).left;
+        (*x).left = (*y).right;
+        if !is_nil((*y).right) {
+            set_parent((*y).right, x);
+        }
+        self.transplant(x, y);
+        (*y).right = x;
+        set_parent(x, y);
+    }
+
+}
+
+impl<K: Ord, V> Default for RbTreeMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> Drop for RbTreeMap<K, V> {
+    fn drop(&mut self) {
+        let mut stack = vec![self.root];
+        while let Some(x) = stack.pop() {
+            if is_nil(x) {
+                continue;
+            }
+            let mut node = unsafe { Box::from_raw(x) };
+            stack.push(node.left);
+            stack.push(node.right);
+            unsafe {
+                node.key.assume_init_drop();
+                node.value.assume_init_drop();
+            }
+        }
+        drop(unsafe { Box::from_raw(self.nil) });
+    }
+}
+
+fn is_nil<K, V>(x: Link<K, V>) -> bool {
+    unsafe { (*x).parent.addr() & SENTINEL != 0 }
+}
+
+fn is_red<K, V>(x: Link<K, V>) -> bool {
+    unsafe { (*x).parent.addr() & RED != 0 }
+}
+
+fn parent<K, V>(x: Link<K, V>) -> Link<K, V> {
+    unsafe { (*x).parent.map_addr(|addr| addr & !BITS) }
+}
+
+// Keeps RED and SENTINEL.
+unsafe fn set_parent<K, V>(x: Link<K, V>, parent: Link<K, V>) {
+    let bits = (*x).parent.addr() & BITS;
+    (*x).parent = parent.map_addr(|addr| addr | bits);
+}
+
+// Keeps the parent and SENTINEL.
+fn set_red<K, V>(x: Link<K, V>, red: bool) {
+    unsafe { (*x).parent = (*x).parent.map_addr(|addr| (addr & !RED) | red as usize) };
+}
+
+unsafe fn key_of<'k, K, V>(x: Link<K, V>) -> &'k K {
+    (*x).key.assume_init_ref()
+}
+
+fn minimum<K, V>(mut x: Link<K, V>) -> Link<K, V> {
+    while !is_nil(unsafe { (*x).left }) {
+        x = unsafe { (*x).left };
+    }
+    x
+}
+
+fn successor<K, V>(mut x: Link<K, V>) -> Link<K, V> {
+    unsafe {
+        if !is_nil((*x).right) {
+            return minimum((*x).right);
+        }
+        let mut y = parent(x);
+        while !is_nil(y) && x == (*y).right {
+            x = y;
+            y = parent(y);
+        }
+        y
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    // Black height of the subtree, checking the red-black rules on the way.
+    fn check(x: Link<u32, u32>, nil: Link<u32, u32>) -> usize {
+        if is_nil(x) {
+            assert_eq!(x, nil);
+            assert!(!is_red(x));
+            return 1;
+        }
+        unsafe {
+            for child in [(*x).left, (*x).right] {
+                assert!(!(is_red(x) && is_red(child)), "red node with a red child");
+                if !is_nil(child) {
+                    assert_eq!(parent(child), x);
+                }
+            }
+            let (left, right) = (check((*x).left, nil), check((*x).right, nil));
+            assert_eq!(left, right, "unequal black heights");
+            left + !is_red(x) as usize
+        }
+    }
+
+    #[test]
+    fn matches_btree_map_and_keeps_the_invariants() {
+        let mut tree = RbTreeMap::new();
+        let mut model = BTreeMap::new();
+        let mut seed = 12345u32;
+        for _ in 0..5000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let key = (seed >> 16) % 500;
+            if seed & 1 == 0 {
+                assert_eq!(tree.insert(key, seed), model.insert(key, seed));
+            } else {
+                assert_eq!(tree.remove(&key), model.remove(&key));
+            }
+            assert!(!is_red(tree.root));
+            check(tree.root, tree.nil);
+        }
+        assert_eq!(tree.len(), model.len());
+        assert!(tree.iter().eq(model.iter()));
+    }
+}
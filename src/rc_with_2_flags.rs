@@ -0,0 +1,140 @@
+// Name: RcWith2Flags - an Rc<T> and 2 flags in one word.
+//
+// Description: The Rc is turned into a raw pointer with Rc::into_raw, that
+//              points to the value inside the reference counted allocation,
+//              and the flags go into its low bits. Clone rebuilds the Rc to
+//              increment the strong count and Drop rebuilds it to decrement
+//              it, so the counts are exactly those of the Rc that was tagged.
+//
+//              Each clone has its own flags, only the value is shared. That
+//              is what a graph with shared nodes wants, the flags describe
+//              the edge, e.g. "visited from here" or "weak edge", and not the
+//              node.
+//
+//              Clone, Drop and into_rc() strip the flags with map_addr
+//              before calling Rc::from_raw, so the count is always reached
+//              through the pointer Rc::into_raw handed out.
+
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+use std::rc::Rc;
+
+use crate::aligned::AlignedAtLeast;
+
+pub struct RcWith2Flags<T> {
+    ptr_and_bit: *const T,
+    // Not Send nor Sync, like the Rc.
+    behaves_like: PhantomData<Rc<T>>,
+}
+
+impl<T> RcWith2Flags<T> {
+
+    pub fn new(rc: Rc<T>, flag_a: bool, flag_b: bool) -> RcWith2Flags<T>
+    where
+        T: AlignedAtLeast<4>,
+    {
+        RcWith2Flags {
+            ptr_and_bit: Rc::into_raw(rc).map_addr(|addr| addr | flag_a as usize | ((flag_b as usize) << 1)),
+            behaves_like: PhantomData,
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        unsafe { &*self.ptr() }
+    }
+
+    pub fn get_flag_a(&self) -> bool {
+        self.ptr_and_bit.addr() & 1 != 0
+    }
+
+    pub fn get_flag_b(&self) -> bool {
+        self.ptr_and_bit.addr() & 2 != 0
+    }
+
+    pub fn set_flag_a(&mut self, flag: bool) {
+        self.ptr_and_bit = self.ptr_and_bit.map_addr(|addr| (addr & !1) | flag as usize);
+    }
+
+    pub fn set_flag_b(&mut self, flag: bool) {
+        self.ptr_and_bit = self.ptr_and_bit.map_addr(|addr| (addr & !2) | ((flag as usize) << 1));
+    }
+
+    /// Number of Rc's, tagged or not, sharing the value.
+    pub fn strong_count(&self) -> usize {
+        Rc::strong_count(&self.rc())
+    }
+
+    /// True if both point to the same allocation, whatever their flags.
+    pub fn ptr_eq(&self, other: &RcWith2Flags<T>) -> bool {
+        self.ptr() == other.ptr()
+    }
+
+    /// Gives back the Rc, dropping the flags.
+    pub fn into_rc(self) -> Rc<T> {
+        let this = ManuallyDrop::new(self);
+        unsafe { Rc::from_raw(this.ptr()) }
+    }
+
+    fn ptr(&self) -> *const T {
+        self.ptr_and_bit.map_addr(|addr| addr & !3)
+    }
+
+    // The Rc that was tagged, not to be dropped, it doesn't own a count.
+    fn rc(&self) -> ManuallyDrop<Rc<T>> {
+        ManuallyDrop::new(unsafe { Rc::from_raw(self.ptr()) })
+    }
+
+}
+
+impl<T> Clone for RcWith2Flags<T> {
+    fn clone(&self) -> Self {
+        unsafe { Rc::increment_strong_count(self.ptr()) };
+        RcWith2Flags { ptr_and_bit: self.ptr_and_bit, behaves_like: PhantomData }
+    }
+}
+
+impl<T> Drop for RcWith2Flags<T> {
+    fn drop(&mut self) {
+        drop(unsafe { Rc::from_raw(self.ptr()) });
+    }
+}
+
+impl<T> Deref for RcWith2Flags<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.get_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aligned_box::Align16;
+
+    #[test]
+    fn each_edge_has_its_flags_and_one_count() {
+        let node = Rc::new(Align16(9u16));
+        let edge = RcWith2Flags::new(Rc::clone(&node), true, false);
+        let mut back_edge = edge.clone();
+        back_edge.set_flag_b(true);
+        assert_eq!(edge.strong_count(), 3);
+        assert!(edge.ptr_eq(&back_edge) && edge.get_flag_a() && !edge.get_flag_b());
+        assert!(back_edge.get_flag_b() && back_edge.0 == 9);
+        drop(edge);
+        assert!(Rc::ptr_eq(&back_edge.into_rc(), &node));
+        assert_eq!(Rc::strong_count(&node), 1);
+    }
+
+    #[test]
+    fn clones_and_drops_move_the_count() {
+        let node = Rc::new(Align16(1u16));
+        let edges: Vec<RcWith2Flags<Align16<u16>>> = (0..4).map(|i| RcWith2Flags::new(node.clone(), i % 2 == 0, i > 1)).collect();
+        assert_eq!(Rc::strong_count(&node), 5);
+        let flags: Vec<(bool, bool)> = edges.iter().map(|e| (e.get_flag_a(), e.get_flag_b())).collect();
+        assert_eq!(flags, vec![(true, false), (false, false), (true, true), (false, true)]);
+        drop(edges);
+        assert_eq!(Rc::strong_count(&node), 1);
+    }
+}
@@ -0,0 +1,129 @@
+// Name: RefMutWith2Flags - a &mut T and 2 flags in one word.
+//
+// Description: The exclusive counterpart of RefWith2Flags. It behaves like a
+//              &'a mut T for the borrow checker (PhantomData<&'a mut T>), so
+//              it is invariant in T and the referent can't be aliased while
+//              it lives, and it hands out &mut T through get_mut().
+//
+//              Like RefWith2Flags the word is kept as a pointer and the flags
+//              are set with map_addr, so it keeps the provenance of the
+//              reference it was made from.
+
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+
+use crate::aligned::AlignedAtLeast;
+
+#[repr(transparent)]
+pub struct RefMutWith2Flags<'a, T> {
+    ptr_and_bit: *mut T,
+    behaves_like: PhantomData<&'a mut T>,
+}
+
+// The raw pointer opts out of Send and Sync, this is a &mut T as far as
+// threads go.
+unsafe impl<'a, T: Send> Send for RefMutWith2Flags<'a, T> {}
+unsafe impl<'a, T: Sync> Sync for RefMutWith2Flags<'a, T> {}
+
+impl<'a, T: 'a> RefMutWith2Flags<'a, T> {
+
+    /// Like RefWith2Flags::new, an under aligned type doesn't compile:
+    ///
+    /// ```compile_fail
+    /// let mut half = 7u16;
+    /// let _ = ref_with_2_flags::RefMutWith2Flags::new(&mut half, true, false);
+    /// ```
+    pub fn new(ptr: &'a mut T, flag_a: bool, flag_b: bool) -> RefMutWith2Flags<'a, T>
+    where
+        T: AlignedAtLeast<4>,
+    {
+        RefMutWith2Flags {
+            ptr_and_bit: ptr::from_mut(ptr).map_addr(|addr| addr | flag_a as usize | ((flag_b as usize) << 1)),
+            behaves_like: PhantomData,
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        unsafe { &*self.untagged() }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.untagged() }
+    }
+
+    /// Gives back the exclusive reference, for the whole lifetime 'a.
+    pub fn into_mut(self) -> &'a mut T {
+        unsafe { &mut *self.untagged() }
+    }
+
+    pub fn get_flag_a(&self) -> bool {
+        self.ptr_and_bit.addr() & 1 != 0
+    }
+
+    pub fn get_flag_b(&self) -> bool {
+        self.ptr_and_bit.addr() & 2 != 0
+    }
+
+    pub fn set_flag_a(&mut self, flag: bool) {
+        self.ptr_and_bit = self.ptr_and_bit.map_addr(|addr| (addr & !1) | flag as usize);
+    }
+
+    pub fn set_flag_b(&mut self, flag: bool) {
+        self.ptr_and_bit = self.ptr_and_bit.map_addr(|addr| (addr & !2) | ((flag as usize) << 1));
+    }
+
+    fn untagged(&self) -> *mut T {
+        self.ptr_and_bit.map_addr(|addr| addr & !3)
+    }
+
+}
+
+impl<'a, T> Deref for RefMutWith2Flags<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.untagged() }
+    }
+}
+
+impl<'a, T> DerefMut for RefMutWith2Flags<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.untagged() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aligned_box::Align16;
+
+    #[test]
+    fn writes_through_with_the_flags() {
+        let mut counter_slot = Align16(1u16);
+        let mut exclusive = RefMutWith2Flags::new(&mut counter_slot, false, true);
+        exclusive.get_mut().0 += 1;
+        exclusive.set_flag_a(true);
+        exclusive.set_flag_b(false);
+        assert!(exclusive.get_flag_a() && !exclusive.get_flag_b());
+        assert_eq!(exclusive.get_ref().0, 2);
+        exclusive.0 += 3;
+        exclusive.into_mut().0 *= 10;
+        assert_eq!(counter_slot.0, 50);
+    }
+
+    #[test]
+    fn a_reborrow_per_call_keeps_the_flags() {
+        let mut values = [Align16(0u32), Align16(0)];
+        for (i, value) in values.iter_mut().enumerate() {
+            let mut tagged = RefMutWith2Flags::new(value, i == 0, true);
+            for _ in 0..3 {
+                tagged.get_mut().0 += 1;
+                let flag = tagged.get_flag_b();
+                tagged.set_flag_b(!flag);
+            }
+            assert_eq!((tagged.get_flag_a(), tagged.get_flag_b()), (i == 0, false));
+        }
+        assert_eq!(values.map(|v| v.0), [3, 3]);
+    }
+}
@@ -0,0 +1,87 @@
+// Name: RefWith1Flag - a reference and 1 flag in one word.
+//
+// Description: The original ref_with_flag of the book: a type aligned to 2
+//              bytes has 1 free low bit in its addresses, enough for one
+//              flag. So a &u16 or a &i16, that RefWith2Flags rejects, can
+//              carry e.g. a dirty bit without growing.
+//
+//              Like RefWith2Flags the word is a NonNull with the flag set by
+//              map_addr, so the provenance of the reference is kept.
+
+use core::marker::PhantomData;
+use core::ops::Deref;
+use core::ptr::NonNull;
+
+use crate::aligned::AlignedAtLeast;
+
+#[repr(transparent)]
+pub struct RefWith1Flag<'a, T> {
+    ptr_and_bit: NonNull<T>,
+    behaves_like: PhantomData<&'a T>,
+}
+
+// NonNull opts out of Send and Sync, this is a &T as far as threads go.
+unsafe impl<'a, T: Sync> Send for RefWith1Flag<'a, T> {}
+unsafe impl<'a, T: Sync> Sync for RefWith1Flag<'a, T> {}
+
+impl<'a, T: 'a> RefWith1Flag<'a, T> {
+
+    pub fn new(ptr: &'a T, flag: bool) -> RefWith1Flag<'a, T>
+    where
+        T: AlignedAtLeast<2>,
+    {
+        RefWith1Flag {
+            ptr_and_bit: NonNull::from(ptr).map_addr(|addr| addr | flag as usize),
+            behaves_like: PhantomData,
+        }
+    }
+
+    pub fn get_ref(&self) -> &'a T {
+        unsafe { &*self.ptr_and_bit.as_ptr().map_addr(|addr| addr & !1) }
+    }
+
+    pub fn get_flag(&self) -> bool {
+        self.ptr_and_bit.as_ptr().addr() & 1 != 0
+    }
+
+    pub fn set_flag(&mut self, flag: bool) {
+        // The address part is never null, clearing bit 0 keeps it non zero.
+        self.ptr_and_bit = unsafe { NonNull::new_unchecked(self.ptr_and_bit.as_ptr().map_addr(|addr| (addr & !1) | flag as usize)) };
+    }
+
+    pub fn toggle_flag(&mut self) {
+        self.set_flag(!self.get_flag());
+    }
+
+}
+
+impl<'a, T> Clone for RefWith1Flag<'a, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, T> Copy for RefWith1Flag<'a, T> {}
+
+impl<'a, T> Deref for RefWith1Flag<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.ptr_and_bit.as_ptr().map_addr(|addr| addr & !1) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn two_aligned_is_enough() {
+        let sample = 1234u16;
+        let mut dirty = RefWith1Flag::new(&sample, false);
+        dirty.set_flag(true);
+        assert!(dirty.get_flag() && *dirty.get_ref() == 1234);
+        dirty.toggle_flag();
+        assert!(!dirty.get_flag() && *dirty == 1234);
+    }
+}
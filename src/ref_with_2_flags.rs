@@ -75,42 +75,821 @@
 // Because this is a derived work the license is the same as the original code.                                 
 
 
-use std::marker::PhantomData;
-use std::mem::align_of;
+use core::cmp::Ordering;
+use core::ffi::c_void;
+use core::fmt;
+use core::hash::{Hash, Hasher};
+use core::marker::PhantomData;
+use core::mem::align_of;
+#[cfg(any(feature = "std", test))]
+use core::mem::ManuallyDrop;
+use core::num::NonZeroUsize;
+use core::ptr::NonNull;
+use core::ops::Deref;
 
+use crate::aligned::AlignedAtLeast;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum UserDataError {
+    /// The address part of the user data is null.
+    Null,
+    /// The address part isn't aligned for the referent type.
+    Misaligned,
+}
+
+/// What `validate` found wrong with the address part of a tagged word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TagCorruption {
+    /// The address part is null.
+    Null,
+    /// The address part isn't a multiple of the referent alignment.
+    Misaligned { addr: usize, align: usize },
+    /// The address isn't in the canonical form of a 48 bit virtual address
+    /// space, its high bits are not all copies of bit 47.
+    NonCanonical { addr: usize },
+}
+
+// repr(transparent) guarantees the same layout as a single pointer, and so the
+// same layout as &T, that is what makes the batch conversions below possible,
+// and lets it be a field of an extern "C" struct. The word is never null, the
+// address part of a reference isn't, and NonNull tells the compiler so:
+// Option<RefWith2Flags> is one word too, with None as null.
+//
+// The word is kept as a pointer, not a usize, and the flags are set and
+// cleared with map_addr, so it keeps the provenance of the reference it was
+// made from (strict provenance, Miri's -Zmiri-strict-provenance). Only the
+// flag tests and the comparisons look at the bare address.
+#[repr(transparent)]
 pub  struct RefWith2Flags<'a, T> {
-    ptr_and_bit: usize,
+    ptr_and_bit: NonNull<T>,
     behaves_like: PhantomData<&'a T> // occupies no space
 }
 
+// NonNull opts out of Send and Sync, this is a &T as far as threads go.
+unsafe impl<'a, T: Sync> Send for RefWith2Flags<'a, T> {}
+unsafe impl<'a, T: Sync> Sync for RefWith2Flags<'a, T> {}
+
 impl<'a, T: 'a> RefWith2Flags<'a, T> {
 
-    pub fn new(ptr: &'a T, flag_a: bool, flag_b: bool) -> RefWith2Flags<T> {
-        assert!(align_of::<T>() % 4 == 0);
+    /// The alignment is checked at compile time, by the AlignedAtLeast<4>
+    /// bound, an under aligned type doesn't compile:
+    ///
+    /// ```compile_fail
+    /// let half = 7u16;
+    /// let _ = ref_with_2_flags::RefWith2Flags::new(&half, true, false);
+    /// ```
+    pub fn new(ptr: &'a T, flag_a: bool, flag_b: bool) -> RefWith2Flags<'a, T>
+    where
+        T: AlignedAtLeast<4>,
+    {
         RefWith2Flags {
-            ptr_and_bit: ptr as *const T as usize | flag_a as usize | ((flag_b as usize) << 1),
+            ptr_and_bit: NonNull::from(ptr).map_addr(|addr| addr | flag_a as usize | ((flag_b as usize) << 1)),
             behaves_like: PhantomData
         }
     }
 
     pub fn get_ref(&self) -> &'a T {
         unsafe {
-            let ptr = (self.ptr_and_bit & !3) as *const T;
+            let ptr = self.untagged();
             &*ptr
             }
     }
     
     pub fn get_flag_a(&self) -> bool {
-        self.ptr_and_bit & 1 != 0
+        self.word() & 1 != 0
     }
 
     pub fn get_flag_b(&self) -> bool {
-        self.ptr_and_bit & 2 != 0
+        self.word() & 2 != 0
+    }
+
+    pub fn set_flag_a(&mut self, flag: bool) {
+        self.set_word((self.word() & !1) | flag as usize);
+    }
+
+    pub fn set_flag_b(&mut self, flag: bool) {
+        self.set_word((self.word() & !2) | ((flag as usize) << 1));
+    }
+
+    pub fn toggle_flag_a(&mut self) {
+        self.set_word(self.word() ^ 1);
+    }
+
+    pub fn toggle_flag_b(&mut self) {
+        self.set_word(self.word() ^ 2);
+    }
+
+    /// Overwrites both flags at once, the pointer part is untouched.
+    pub fn set_flags(&mut self, flag_a: bool, flag_b: bool) {
+        self.set_word((self.word() & !3) | flag_a as usize | ((flag_b as usize) << 1));
+    }
+
+    /// The reference and both flags, from a single read of the word: one
+    /// load, a mask and 2 bit tests, whatever the optimizer does with 3
+    /// separate accessor calls. codegen_check counts the loads on x86_64.
+    #[inline]
+    pub fn get_all(&self) -> (&'a T, bool, bool) {
+        let ptr = self.ptr_and_bit.as_ptr();
+        let word = ptr.addr();
+        (unsafe { &*ptr.with_addr(word & !3) }, word & 1 != 0, word & 2 != 0)
+    }
+
+    /// Splits it into the reference and the flags, e.g. to match on the
+    /// flag combination.
+    pub fn into_parts(self) -> (&'a T, bool, bool) {
+        self.get_all()
+    }
+
+    /// The inverse of into_parts.
+    pub fn from_parts((ptr, flag_a, flag_b): (&'a T, bool, bool)) -> RefWith2Flags<'a, T>
+    where
+        T: AlignedAtLeast<4>,
+    {
+        Self::new(ptr, flag_a, flag_b)
+    }
+
+    /// `f` applied to the referent if flag a is set.
+    pub fn if_flag_a<U>(&self, f: impl FnOnce(&'a T) -> U) -> Option<U> {
+        let (value, flag_a, _) = self.get_all();
+        if flag_a { Some(f(value)) } else { None }
+    }
+
+    /// `f` applied to the referent if flag b is set.
+    pub fn if_flag_b<U>(&self, f: impl FnOnce(&'a T) -> U) -> Option<U> {
+        let (value, _, flag_b) = self.get_all();
+        if flag_b { Some(f(value)) } else { None }
+    }
+
+    /// The referent if both flags pass their predicate.
+    pub fn filter_flags(&self, a_pred: impl FnOnce(bool) -> bool, b_pred: impl FnOnce(bool) -> bool) -> Option<&'a T> {
+        let (value, flag_a, flag_b) = self.get_all();
+        if a_pred(flag_a) && b_pred(flag_b) { Some(value) } else { None }
+    }
+
+    /// Reinterprets the referent as a `U`, keeping the address and both flags,
+    /// like `NonNull::cast`.
+    ///
+    /// # Safety
+    /// The referent must be a valid `U` for the lifetime `'a`, and so
+    /// aligned for `U` (checked in debug builds).
+    pub unsafe fn cast<U: AlignedAtLeast<4>>(self) -> RefWith2Flags<'a, U> {
+        debug_assert_eq!(self.ptr_and_bit.addr().get() & !3 & (align_of::<U>() - 1), 0, "referent not aligned for U");
+        RefWith2Flags {
+            ptr_and_bit: self.ptr_and_bit.cast(),
+            behaves_like: PhantomData
+        }
+    }
+
+    /// Checks the invariants of the address part: non null, aligned for `T`
+    /// and, on x86_64 and aarch64, canonical. Meant for assertion points
+    /// after unsafe interop, the flags themselves can't be wrong.
+    pub fn validate(&self) -> Result<(), TagCorruption> {
+        let addr = self.word() & !3;
+        if addr == 0 {
+            return Err(TagCorruption::Null);
+        }
+        if !addr.is_multiple_of(align_of::<T>()) {
+            return Err(TagCorruption::Misaligned { addr, align: align_of::<T>() });
+        }
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        if ((addr as isize) << 16 >> 16) as usize != addr {
+            return Err(TagCorruption::NonCanonical { addr });
+        }
+        Ok(())
+    }
+
+    /// Hints the CPU to bring the referent into the cache for a read, without
+    /// unpacking the pointer in the caller. A no-op without the `prefetch`
+    /// feature or on other targets than x86_64 and aarch64.
+    #[inline]
+    pub fn prefetch_read(&self) {
+        #[cfg(all(feature = "prefetch", target_arch = "x86_64"))]
+        unsafe {
+            use core::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+            _mm_prefetch::<_MM_HINT_T0>(self.untagged() as *const i8);
+        }
+        #[cfg(all(feature = "prefetch", target_arch = "aarch64"))]
+        unsafe {
+            core::arch::asm!("prfm pldl1keep, [{0}]", in(reg) self.untagged(), options(nostack, readonly));
+        }
+    }
+
+    /// Like `prefetch_read`, for a referent about to be written (through
+    /// interior mutability).
+    #[inline]
+    pub fn prefetch_write(&self) {
+        #[cfg(all(feature = "prefetch", target_arch = "x86_64"))]
+        unsafe {
+            use core::arch::x86_64::{_mm_prefetch, _MM_HINT_ET0};
+            _mm_prefetch::<_MM_HINT_ET0>(self.untagged() as *const i8);
+        }
+        #[cfg(all(feature = "prefetch", target_arch = "aarch64"))]
+        unsafe {
+            core::arch::asm!("prfm pstl1keep, [{0}]", in(reg) self.untagged(), options(nostack, readonly));
+        }
+    }
+
+    /// Returns `a` if `cond` is true and `b` otherwise, flags included,
+    /// without a branch: a conditional move, not mask arithmetic, that would
+    /// mix the provenances of both. codegen_check holds it to that on x86_64.
+    #[inline]
+    pub fn select(cond: bool, a: Self, b: Self) -> Self {
+        core::hint::select_unpredictable(cond, a, b)
+    }
+
+    /// The reference if `cond` is true, without a branch: the address is
+    /// masked to null, which is the None of `Option<&T>`.
+    #[inline]
+    pub fn get_ref_if(&self, cond: bool) -> Option<&'a T> {
+        let mask = (cond as usize).wrapping_neg();
+        unsafe { self.untagged().map_addr(|addr| addr & mask).as_ref() }
+    }
+
+    /// Tags every reference of the Vec with the same flags, in place, reusing
+    /// the allocation.
+    #[cfg(any(feature = "std", test))]
+    pub fn tag_vec(refs: Vec<&'a T>, flag_a: bool, flag_b: bool) -> Vec<RefWith2Flags<'a, T>>
+    where
+        T: AlignedAtLeast<4>,
+    {
+        let bits = flag_a as usize | ((flag_b as usize) << 1);
+        let mut refs = ManuallyDrop::new(refs);
+        let (ptr, len, cap) = (refs.as_mut_ptr(), refs.len(), refs.capacity());
+        unsafe {
+            let words = ptr as *mut *const T;
+            for i in 0..len {
+                *words.add(i) = (*words.add(i)).map_addr(|addr| addr | bits);
+            }
+            Vec::from_raw_parts(words as *mut RefWith2Flags<'a, T>, len, cap)
+        }
+    }
+
+    /// Strips the flags of every element of the Vec, in place, reusing the
+    /// allocation.
+    #[cfg(any(feature = "std", test))]
+    pub fn untag_vec(tagged: Vec<RefWith2Flags<'a, T>>) -> Vec<&'a T> {
+        let mut tagged = ManuallyDrop::new(tagged);
+        let (ptr, len, cap) = (tagged.as_mut_ptr(), tagged.len(), tagged.capacity());
+        unsafe {
+            let words = ptr as *mut *const T;
+            for i in 0..len {
+                *words.add(i) = (*words.add(i)).map_addr(|addr| addr & !3);
+            }
+            Vec::from_raw_parts(words as *mut &'a T, len, cap)
+        }
+    }
+
+    /// Overwrites the flags of every element of the slice.
+    pub fn retag_slice(tagged: &mut [RefWith2Flags<'a, T>], flag_a: bool, flag_b: bool) {
+        let bits = flag_a as usize | ((flag_b as usize) << 1);
+        for t in tagged.iter_mut() {
+            t.set_word((t.word() & !3) | bits);
+        }
+    }
+
+    /// Strips the flags of every element of the slice and views it as a slice
+    /// of plain references.
+    pub fn untag_slice<'s>(tagged: &'s mut [RefWith2Flags<'a, T>]) -> &'s mut [&'a T] {
+        Self::retag_slice(tagged, false, false);
+        // Every word is now a plain, aligned, non null address and any &'a T
+        // written through the returned slice is a valid untagged entry.
+        unsafe { &mut *(tagged as *mut [RefWith2Flags<'a, T>] as *mut [&'a T]) }
+    }
+
+    /// Same address and same flags, what == and Hash use.
+    pub fn ptr_and_flags_eq(&self, other: &Self) -> bool {
+        self.word() == other.word()
     }
 
+    /// Equal referents, whatever their addresses and the flags.
+    pub fn value_eq(&self, other: &Self) -> bool
+    where
+        T: PartialEq,
+    {
+        self.get_ref() == other.get_ref()
+    }
+
+    /// Compares the referent addresses, ignoring the flags.
+    pub fn cmp_by_addr(&self, other: &Self) -> Ordering {
+        (self.word() & !3).cmp(&(other.word() & !3))
+    }
+
+    /// Sorts the slice by referent address, to visit the referents in
+    /// memory order. Entries with the same address keep their order.
+    #[cfg(any(feature = "std", test))]
+    pub fn sort_by_address(tagged: &mut [RefWith2Flags<'a, T>]) {
+        tagged.sort_by(Self::cmp_by_addr);
+    }
+
+    /// Yields the tagged references in referent address order.
+    #[cfg(any(feature = "std", test))]
+    pub fn sorted_by_address<I>(tagged: I) -> std::vec::IntoIter<RefWith2Flags<'a, T>>
+    where
+        I: IntoIterator<Item = RefWith2Flags<'a, T>>,
+    {
+        let mut tagged: Vec<_> = tagged.into_iter().collect();
+        Self::sort_by_address(&mut tagged);
+        tagged.into_iter()
+    }
+
+    /// The whole word in binary, zero padded to the pointer width, with the
+    /// address bits and the flag bits (b then a) split by an underscore.
+    #[cfg(any(feature = "std", test))]
+    pub fn fmt_bits(&self) -> String {
+        let width = usize::BITS as usize - 2;
+        format!("{:0width$b}_{:02b}", self.word() >> 2, self.word() & 3, width = width)
+    }
+
+    /// The packed word, address and flags, without provenance, for bit tests
+    /// and comparisons.
+    pub(crate) fn word(&self) -> usize {
+        self.ptr_and_bit.as_ptr().addr()
+    }
+
+    /// The packed word as a pointer, with the provenance of the referent.
+    pub(crate) fn tagged_ptr(&self) -> *mut T {
+        self.ptr_and_bit.as_ptr()
+    }
+
+    /// Rebuilds a tagged reference from its packed pointer.
+    ///
+    /// # Safety
+    /// `ptr` must come from `tagged_ptr()` of a `RefWith2Flags<'a, T>`.
+    pub(crate) unsafe fn from_tagged_ptr(ptr: *mut T) -> RefWith2Flags<'a, T> {
+        debug_assert!(!ptr.is_null());
+        RefWith2Flags {
+            ptr_and_bit: NonNull::new_unchecked(ptr),
+            behaves_like: PhantomData
+        }
+    }
+
+    fn untagged(&self) -> *const T {
+        self.ptr_and_bit.as_ptr().map_addr(|addr| addr & !3)
+    }
+
+    // Only for new flags, the address part, non null, is kept.
+    fn set_word(&mut self, word: usize) {
+        debug_assert_eq!(word & !3, self.word() & !3);
+        self.ptr_and_bit = self.ptr_and_bit.map_addr(|addr| unsafe { NonZeroUsize::new_unchecked((addr.get() & !3) | (word & 3)) });
+    }
+
+    /// The whole tagged word as a raw pointer, flags included, e.g. for a C
+    /// callback `void*`. It is not a pointer to the referent while a flag is
+    /// set, only from_raw can use it.
+    pub fn into_raw(self) -> *const () {
+        self.tagged_ptr() as *const ()
+    }
+
+    /// Gets back the tagged reference, flags included, from into_raw.
+    ///
+    /// # Safety
+    /// `ptr` must come from `into_raw` of a `RefWith2Flags<'a, T>`, unchanged,
+    /// so its address is non null and aligned to at least 4 bytes, and the
+    /// referent must still be alive and not mutably borrowed for `'a`.
+    pub unsafe fn from_raw(ptr: *const ()) -> RefWith2Flags<'a, T> {
+        debug_assert!(ptr.addr() & !3 != 0, "from_raw of a null address");
+        Self::from_tagged_ptr(ptr as *mut T)
+    }
+
+    /// Stuffs the whole tagged word into a C callback user data pointer,
+    /// no allocation needed.
+    pub fn into_user_data(self) -> *mut c_void {
+        self.tagged_ptr() as *mut c_void
+    }
+
+    /// Gets back a tagged reference from a C callback user data pointer.
+    ///
+    /// # Safety
+    /// `data` must come from `into_user_data` of a `RefWith2Flags<'a, T>` and
+    /// the referent must still be alive for `'a`. Only the null and the
+    /// alignment errors can be detected.
+    pub unsafe fn from_user_data(data: *mut c_void) -> Result<RefWith2Flags<'a, T>, UserDataError> {
+        let addr = data.addr() & !3;
+        if addr == 0 {
+            return Err(UserDataError::Null);
+        }
+        if !addr.is_multiple_of(align_of::<T>()) {
+            return Err(UserDataError::Misaligned);
+        }
+        Ok(Self::from_tagged_ptr(data as *mut T))
+    }
+
+}
+
+// A pointer and a PhantomData<&T>, it copies like the &T, whatever T is, so no
+// derive, that would ask for T: Clone.
+impl<'a, T> Clone for RefWith2Flags<'a, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, T> Copy for RefWith2Flags<'a, T> {}
+
+// Equality and hashing are on the packed word, address and flags, like for
+// raw pointers, so they don't need T: Eq / Hash and a tagged reference can be
+// a HashMap key. value_eq() compares the referents, ByValue wraps them to key
+// by value.
+impl<'a, T> PartialEq for RefWith2Flags<'a, T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.ptr_and_flags_eq(other)
+    }
+}
+
+impl<'a, T> Eq for RefWith2Flags<'a, T> {}
+
+impl<'a, T> Hash for RefWith2Flags<'a, T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.word().hash(state);
+    }
+}
+
+// Method calls and indexing reach the referent directly, e.g. flagged[1],
+// get_ref() stays for when the full 'a lifetime is needed.
+impl<'a, T> Deref for RefWith2Flags<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.untagged() }
+    }
+}
+
+// Like the Debug of &T it needs T: Debug, and shows the referent next to the
+// decoded word.
+impl<'a, T: fmt::Debug> fmt::Debug for RefWith2Flags<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RefWith2Flags")
+            .field("addr", &self.untagged())
+            .field("flag_a", &self.get_flag_a())
+            .field("flag_b", &self.get_flag_b())
+            .field("value", self.get_ref())
+            .finish()
+    }
+}
+
+// The raw word with the 2 flag bits split off by an underscore, so the layout
+// is visible: {:b} prints the address bits above bit 2, then "_" and the flag
+// bits (b then a), e.g. 0b1111111111100_10 with {:#b}. {:x} and {:X} print
+// the untagged address in hex, then "_" and the same 2 flag bits in binary.
+impl<'a, T> fmt::Binary for RefWith2Flags<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let prefix = if f.alternate() { "0b" } else { "" };
+        write!(f, "{}{:b}_{:02b}", prefix, self.word() >> 2, self.word() & 3)
+    }
+}
+
+impl<'a, T> fmt::LowerHex for RefWith2Flags<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let prefix = if f.alternate() { "0x" } else { "" };
+        write!(f, "{}{:x}_{:02b}", prefix, self.word() & !3, self.word() & 3)
+    }
+}
+
+impl<'a, T> fmt::UpperHex for RefWith2Flags<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let prefix = if f.alternate() { "0x" } else { "" };
+        write!(f, "{}{:X}_{:02b}", prefix, self.word() & !3, self.word() & 3)
+    }
 }
 
         
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
 
+    // An 8 bytes aligned referent that isn't Copy, the hits of each slot
+    // are its index.
+    #[repr(align(8))]
+    struct Slot {
+        hits: u16,
+    }
+    crate::aligned_at_least!(Slot => 8);
+
+    fn slots(n: u16) -> Vec<Slot> {
+        (0..n).map(|hits| Slot { hits }).collect()
+    }
+
+    #[test]
+    fn debug_and_bits_decode_the_word() {
+        let value = 7u32;
+        let r = RefWith2Flags::new(&value, false, true);
+        let debug = format!("{:?}", r);
+        assert!(debug.contains("flag_a: false, flag_b: true, value: 7"), "{}", debug);
+        assert!(debug.contains(&format!("{:p}", &value)), "{}", debug);
+        let bits = r.fmt_bits();
+        assert_eq!(bits.len(), usize::BITS as usize + 1);
+        assert!(bits.ends_with("_10"));
+        let addr = usize::from_str_radix(&bits.replace('_', ""), 2).unwrap();
+        assert_eq!(addr, &value as *const u32 as usize | 2);
+    }
+
+    #[test]
+    fn layout_is_one_word() {
+        use std::mem::{size_of, transmute};
+        assert_eq!(size_of::<RefWith2Flags<u32>>(), size_of::<usize>());
+        assert_eq!(align_of::<RefWith2Flags<u32>>(), align_of::<usize>());
+        assert_eq!(size_of::<Option<RefWith2Flags<u32>>>(), size_of::<usize>());
+        let value = 9u32;
+        let r = RefWith2Flags::new(&value, true, false);
+        let word: *const u32 = unsafe { transmute(r) };
+        assert_eq!(word.addr(), (&value as *const u32).addr() | 1);
+        let back: RefWith2Flags<u32> = unsafe { transmute(word) };
+        assert_eq!(back.get_all(), (&value, true, false));
+        let none: Option<RefWith2Flags<u32>> = unsafe { transmute(std::ptr::null::<u32>()) };
+        assert!(none.is_none());
+    }
+
+    #[test]
+    fn setters_keep_the_reference() {
+        let values = [10u32, 20, 30];
+        for value in &values {
+            let mut r = RefWith2Flags::new(value, false, false);
+            r.set_flag_a(true);
+            assert!(std::ptr::eq(r.get_ref(), value));
+            assert_eq!((r.get_flag_a(), r.get_flag_b()), (true, false));
+            r.set_flag_b(true);
+            r.set_flag_a(false);
+            assert_eq!((r.get_flag_a(), r.get_flag_b()), (false, true));
+            r.set_flags(true, true);
+            assert_eq!((r.get_flag_a(), r.get_flag_b()), (true, true));
+            r.set_flags(false, false);
+            assert_eq!(r.get_ref(), value);
+            assert_eq!((r.get_flag_a(), r.get_flag_b()), (false, false));
+        }
+    }
 
+    #[test]
+    fn toggles_round_trip() {
+        let x = 1u64;
+        let mut r = RefWith2Flags::new(&x, true, false);
+        r.toggle_flag_a();
+        r.toggle_flag_b();
+        assert_eq!((r.get_flag_a(), r.get_flag_b()), (false, true));
+        for _ in 0..4 {
+            r.toggle_flag_a();
+            r.toggle_flag_b();
+        }
+        assert_eq!((r.get_flag_a(), r.get_flag_b()), (false, true));
+        assert!(std::ptr::eq(r.get_ref(), &x));
+    }
+
+    #[test]
+    fn select_picks_by_condition_with_flags() {
+        let (x, y) = (1u32, 2u32);
+        for (fa, fb) in [(false, false), (true, false), (false, true), (true, true)] {
+            let picked = RefWith2Flags::select(true, RefWith2Flags::new(&x, fa, fb), RefWith2Flags::new(&y, fb, fa));
+            assert_eq!((*picked.get_ref(), picked.get_flag_a(), picked.get_flag_b()), (1, fa, fb));
+            let picked = RefWith2Flags::select(false, RefWith2Flags::new(&x, fa, fb), RefWith2Flags::new(&y, fb, fa));
+            assert_eq!((*picked.get_ref(), picked.get_flag_a(), picked.get_flag_b()), (2, fb, fa));
+        }
+    }
+
+    #[test]
+    fn get_ref_if_ignores_the_flags() {
+        let x = 7u32;
+        for (fa, fb) in [(false, false), (true, false), (false, true), (true, true)] {
+            let r = RefWith2Flags::new(&x, fa, fb);
+            assert_eq!(r.get_ref_if(true), Some(&7));
+            assert_eq!(r.get_ref_if(false), None);
+            assert_eq!(r.get_ref_if(r.get_flag_a()).is_some(), fa);
+        }
+    }
+
+    #[test]
+    fn validate_diagnoses_corrupted_words() {
+        let x = 5u64;
+        let good = RefWith2Flags::new(&x, true, true);
+        assert_eq!(good.validate(), Ok(()));
+        let with_word = |word: usize| unsafe { RefWith2Flags::<u64>::from_tagged_ptr(std::ptr::without_provenance_mut(word)) };
+        assert_eq!(with_word(3).validate(), Err(TagCorruption::Null));
+        let addr = &x as *const u64 as usize;
+        if align_of::<u64>() == 8 {
+            assert_eq!(
+                with_word(addr + 4).validate(),
+                Err(TagCorruption::Misaligned { addr: addr + 4, align: 8 })
+            );
+        }
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        assert_eq!(
+            with_word(addr | 1 << 60).validate(),
+            Err(TagCorruption::NonCanonical { addr: addr | 1 << 60 })
+        );
+    }
+
+    #[test]
+    fn get_all_matches_the_accessors() {
+        let x = 9u32;
+        for (fa, fb) in [(false, false), (true, false), (false, true), (true, true)] {
+            let r = RefWith2Flags::new(&x, fa, fb);
+            let (value, a, b) = r.get_all();
+            assert!(std::ptr::eq(value, r.get_ref()));
+            assert_eq!((a, b), (r.get_flag_a(), r.get_flag_b()));
+        }
+    }
+
+    #[test]
+    fn select_and_get_ref_if() {
+        let (x, y) = (3u32, 4u32);
+        let picked = RefWith2Flags::select(false, RefWith2Flags::new(&x, true, true), RefWith2Flags::new(&y, false, true));
+        assert_eq!(*picked.get_ref(), 4);
+        assert_eq!(picked.get_ref_if(picked.get_flag_b()), Some(&4));
+        assert_eq!(picked.get_ref_if(false), None);
+        assert_eq!(std::mem::size_of::<Option<&u32>>(), std::mem::size_of::<usize>());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "referent not aligned for U")]
+    fn cast_checks_the_alignment_of_u() {
+        #[repr(align(8))]
+        struct Wide;
+        crate::aligned_at_least!(Wide => 8);
+
+        let halves = [0u32; 4];
+        // One of the 2 is 4 but not 8 aligned.
+        let odd = if (&halves[0] as *const u32 as usize).is_multiple_of(8) { &halves[1] } else { &halves[0] };
+        let _ = unsafe { RefWith2Flags::new(odd, false, false).cast::<Wide>() };
+    }
+
+    #[test]
+    fn cast_keeps_the_address_and_the_flags() {
+        let signed: i32 = -1;
+        let punned: RefWith2Flags<u32> = unsafe { RefWith2Flags::new(&signed, false, true).cast() };
+        assert_eq!(*punned.get_ref(), u32::MAX);
+        assert!(punned.get_flag_b() && !punned.get_flag_a());
+        let back: RefWith2Flags<i32> = unsafe { punned.cast() };
+        assert!(std::ptr::eq(back.get_ref(), &signed) && back.get_flag_b());
+    }
+
+    #[test]
+    fn whole_vecs_convert_without_reallocation() {
+        let numbers = [1_u64, 2, 3, 4];
+        let refs: Vec<&u64> = numbers.iter().collect();
+        let before = refs.as_ptr() as usize;
+        let mut tagged = RefWith2Flags::tag_vec(refs, true, false);
+        assert_eq!(tagged.as_ptr() as usize, before);
+        assert!(tagged.iter().all(|t| t.get_flag_a() && !t.get_flag_b()));
+        RefWith2Flags::retag_slice(&mut tagged, false, true);
+        assert!(tagged.iter().all(|t| !t.get_flag_a() && t.get_flag_b()));
+        assert_eq!(*RefWith2Flags::untag_slice(&mut tagged)[3], 4);
+        let refs = RefWith2Flags::untag_vec(tagged);
+        assert_eq!(refs.as_ptr() as usize, before);
+        assert_eq!(refs, vec![&1, &2, &3, &4]);
+        assert!(RefWith2Flags::untag_vec(RefWith2Flags::<u64>::tag_vec(Vec::new(), true, true)).is_empty());
+    }
+
+    #[test]
+    fn user_data_round_trip() {
+        let context = 99_u64;
+        let user_data = RefWith2Flags::new(&context, true, false).into_user_data();
+        let back = unsafe { RefWith2Flags::<u64>::from_user_data(user_data) }.unwrap();
+        assert_eq!(*back.get_ref(), 99);
+        assert!(back.get_flag_a() && !back.get_flag_b());
+        assert_eq!(back.validate(), Ok(()));
+        let null = unsafe { RefWith2Flags::<u64>::from_user_data(std::ptr::null_mut()) };
+        assert_eq!(null.err(), Some(UserDataError::Null));
+        let flags_only = unsafe { RefWith2Flags::<u64>::from_user_data(std::ptr::without_provenance_mut(3)) };
+        assert_eq!(flags_only.err(), Some(UserDataError::Null));
+        let misaligned = unsafe { RefWith2Flags::<u64>::from_user_data(std::ptr::without_provenance_mut(0x1004)) };
+        assert_eq!(misaligned.err(), Some(UserDataError::Misaligned));
+    }
+
+    #[test]
+    fn sorts_by_untagged_address() {
+        let slots = slots(3);
+        let mut handles = vec![
+            RefWith2Flags::new(&slots[2], true, false),
+            RefWith2Flags::new(&slots[0], false, true),
+            RefWith2Flags::new(&slots[1], true, true),
+        ];
+        assert_eq!(handles[1].cmp_by_addr(&handles[0]), std::cmp::Ordering::Less);
+        let in_order: Vec<u16> = RefWith2Flags::sorted_by_address(RefWith2Flags::tag_vec(slots.iter().rev().collect(), false, false))
+            .map(|r| r.get_ref().hits)
+            .collect();
+        assert_eq!(in_order, vec![0, 1, 2]);
+        RefWith2Flags::sort_by_address(&mut handles);
+        assert_eq!(handles.iter().map(|r| r.get_ref().hits).collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert!(handles[0].get_flag_b() && handles[2].get_flag_a());
+    }
+
+    #[test]
+    fn prefetch_and_get_all_in_a_traversal() {
+        let cells = slots(64);
+        let tagged = RefWith2Flags::tag_vec(cells.iter().collect(), false, false);
+        let mut total = 0;
+        for (i, r) in tagged.iter().enumerate() {
+            if let Some(ahead) = tagged.get(i + 8) {
+                ahead.prefetch_read();
+            }
+            total += r.get_ref().hits;
+        }
+        tagged[0].prefetch_write();
+        assert_eq!(total, (0..64).sum());
+        let hot_loop_hits: u16 = tagged
+            .iter()
+            .map(|r| match r.get_all() {
+                (slot, false, false) => slot.hits,
+                _ => 0,
+            })
+            .sum();
+        assert_eq!(hot_loop_hits, total);
+    }
+
+    #[test]
+    fn flag_combinators() {
+        let cells = slots(4);
+        let marked = [RefWith2Flags::new(&cells[1], true, false), RefWith2Flags::new(&cells[2], false, true), RefWith2Flags::new(&cells[3], true, true)];
+        let a_hits: Vec<u16> = marked.iter().filter_map(|r| r.if_flag_a(|slot| slot.hits)).collect();
+        assert_eq!(a_hits, vec![1, 3]);
+        assert_eq!(marked.iter().filter_map(|r| r.if_flag_b(|slot| slot.hits * 10)).sum::<u16>(), 50);
+        let only_b: Vec<u16> = marked.iter().filter_map(|r| r.filter_flags(|a| !a, |b| b)).map(|slot| slot.hits).collect();
+        assert_eq!(only_b, vec![2]);
+    }
+
+    #[test]
+    fn binary_and_hex_split_off_the_flags() {
+        let slot = Slot { hits: 0 };
+        let addr = &slot as *const Slot as usize;
+        let shown = RefWith2Flags::new(&slot, true, false);
+        assert_eq!(format!("{:#b}", shown), format!("0b{:b}_01", addr >> 2));
+        assert_eq!(format!("{:x}", shown), format!("{:x}_01", addr));
+        assert_eq!(format!("{:#X}", RefWith2Flags::new(&slot, true, true)), format!("0x{:X}_11", addr));
+    }
+
+    #[test]
+    fn flags_change_in_place() {
+        let slot = Slot { hits: 5 };
+        let mut mark = RefWith2Flags::new(&slot, false, false);
+        mark.set_flag_a(true);
+        mark.toggle_flag_b();
+        assert!(mark.get_flag_a() && mark.get_flag_b());
+        mark.toggle_flag_a();
+        mark.set_flag_b(false);
+        assert!(!mark.get_flag_a() && !mark.get_flag_b());
+        mark.set_flags(true, false);
+        assert_eq!((mark.get_ref().hits, mark.get_flag_a()), (5, true));
+    }
+
+    #[test]
+    fn reads_like_a_reference() {
+        let row = [10u32, 20, 30];
+        let flagged = RefWith2Flags::new(&row, true, false);
+        assert_eq!(flagged[1], 20);
+        assert_eq!(flagged.iter().sum::<u32>(), 60);
+        assert_eq!(flagged.len(), 3);
+    }
+
+    #[test]
+    fn copies_even_when_the_referent_does_not() {
+        let slot = Slot { hits: 12 };
+        let lonely_ref = RefWith2Flags::new(&slot, true, false);
+        let fan_out = [lonely_ref; 4];
+        assert!(fan_out.iter().all(|r| std::ptr::eq(r.get_ref(), &slot) && r.get_flag_a()));
+        #[allow(clippy::clone_on_copy)]
+        let cloned = lonely_ref.clone();
+        assert!(cloned.get_flag_a() && cloned.get_ref().hits == 12);
+    }
+
+    #[test]
+    fn eq_and_hash_on_the_word_value_eq_on_the_referents() {
+        let slot = Slot { hits: 13 };
+        let mut visits = HashMap::new();
+        *visits.entry(RefWith2Flags::new(&slot, true, false)).or_insert(0) += 1;
+        *visits.entry(RefWith2Flags::new(&slot, true, false)).or_insert(0) += 1;
+        *visits.entry(RefWith2Flags::new(&slot, false, false)).or_insert(0) += 1;
+        assert_eq!(visits.len(), 2);
+        assert_eq!(visits[&RefWith2Flags::new(&slot, true, false)], 2);
+        let (here, there) = (7u32, 7u32);
+        let (left, right) = (RefWith2Flags::new(&here, false, true), RefWith2Flags::new(&there, true, false));
+        assert!(left.value_eq(&right) && !left.ptr_and_flags_eq(&right) && left != right);
+    }
+
+    #[test]
+    fn into_and_from_parts() {
+        let cells = slots(16);
+        let describe = |r: RefWith2Flags<'_, Slot>| match r.into_parts() {
+            (slot, true, true) => format!("pinned dirty {}", slot.hits),
+            (slot, true, false) => format!("pinned {}", slot.hits),
+            (slot, false, _) => format!("free {}", slot.hits),
+        };
+        assert_eq!(describe(RefWith2Flags::new(&cells[14], true, false)), "pinned 14");
+        let (slot, a, b) = RefWith2Flags::new(&cells[15], false, true).into_parts();
+        let rebuilt = RefWith2Flags::from_parts((slot, !a, b));
+        assert_eq!(describe(rebuilt), "pinned dirty 15");
+    }
+
+    #[test]
+    fn raw_round_trip_through_a_callback() {
+        // Through a C callback void*, flags preserved.
+        extern "C" fn on_event(user: *const ()) -> u16 {
+            let back = unsafe { RefWith2Flags::<Slot>::from_raw(user) };
+            if back.get_flag_b() { back.hits * 2 } else { back.hits }
+        }
+        let cells = slots(17);
+        let raw = RefWith2Flags::new(&cells[16], false, true).into_raw();
+        assert_eq!(on_event(raw), 32);
+        assert_eq!(on_event(RefWith2Flags::new(&cells[3], true, false).into_raw()), 3);
+    }
+}
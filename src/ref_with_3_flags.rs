@@ -0,0 +1,112 @@
+// Name: RefWith3Flags - a reference and 3 flags in one word.
+//
+// Description: A type aligned to 8 bytes, u64, f64 and most structs with a
+//              pointer or a u64 in them on 64 bit targets, has 3 free low
+//              bits in its addresses, so it can carry a third flag next to
+//              the 2 of RefWith2Flags:
+//
+//                 bit 0 - flag a
+//                 bit 1 - flag b
+//                 bit 2 - flag c
+//
+//              TaggedRef<'a, T, 3> holds the same bits as a number, this is
+//              the named flags view.
+
+use core::marker::PhantomData;
+use core::ops::Deref;
+use core::ptr::NonNull;
+
+use crate::aligned::AlignedAtLeast;
+
+#[repr(transparent)]
+pub struct RefWith3Flags<'a, T> {
+    ptr_and_bit: NonNull<T>,
+    behaves_like: PhantomData<&'a T>,
+}
+
+// NonNull opts out of Send and Sync, this is a &T as far as threads go.
+unsafe impl<'a, T: Sync> Send for RefWith3Flags<'a, T> {}
+unsafe impl<'a, T: Sync> Sync for RefWith3Flags<'a, T> {}
+
+impl<'a, T: 'a> RefWith3Flags<'a, T> {
+
+    pub fn new(ptr: &'a T, flag_a: bool, flag_b: bool, flag_c: bool) -> RefWith3Flags<'a, T>
+    where
+        T: AlignedAtLeast<8>,
+    {
+        let bits = flag_a as usize | ((flag_b as usize) << 1) | ((flag_c as usize) << 2);
+        RefWith3Flags { ptr_and_bit: NonNull::from(ptr).map_addr(|addr| addr | bits), behaves_like: PhantomData }
+    }
+
+    pub fn get_ref(&self) -> &'a T {
+        unsafe { &*self.ptr_and_bit.as_ptr().map_addr(|addr| addr & !7) }
+    }
+
+    pub fn get_flag_a(&self) -> bool {
+        self.get_bit(0)
+    }
+
+    pub fn get_flag_b(&self) -> bool {
+        self.get_bit(1)
+    }
+
+    pub fn get_flag_c(&self) -> bool {
+        self.get_bit(2)
+    }
+
+    pub fn set_flag_a(&mut self, flag: bool) {
+        self.set_bit(0, flag);
+    }
+
+    pub fn set_flag_b(&mut self, flag: bool) {
+        self.set_bit(1, flag);
+    }
+
+    pub fn set_flag_c(&mut self, flag: bool) {
+        self.set_bit(2, flag);
+    }
+
+    fn get_bit(&self, bit: u32) -> bool {
+        self.ptr_and_bit.as_ptr().addr() & (1 << bit) != 0
+    }
+
+    fn set_bit(&mut self, bit: u32, flag: bool) {
+        let ptr = self.ptr_and_bit.as_ptr().map_addr(|addr| (addr & !(1 << bit)) | ((flag as usize) << bit));
+        // Only a flag bit changed, the address part is still non null.
+        self.ptr_and_bit = unsafe { NonNull::new_unchecked(ptr) };
+    }
+
+}
+
+impl<'a, T> Clone for RefWith3Flags<'a, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, T> Copy for RefWith3Flags<'a, T> {}
+
+impl<'a, T> Deref for RefWith3Flags<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.ptr_and_bit.as_ptr().map_addr(|addr| addr & !7) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aligned_box::Align16;
+
+    #[test]
+    fn third_flag_from_16_alignment() {
+        let slot = Align16(17u16);
+        let mut three = RefWith3Flags::new(&slot, true, false, true);
+        assert!(three.get_flag_a() && !three.get_flag_b() && three.get_flag_c());
+        three.set_flag_c(false);
+        three.set_flag_b(true);
+        assert!(three.get_flag_a() && three.get_flag_b() && !three.get_flag_c());
+        assert_eq!(three.0, 17);
+    }
+}
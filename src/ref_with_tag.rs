@@ -0,0 +1,121 @@
+// Name: RefWithTag - a reference and a typed tag, e.g. a user enum, in one
+//       word.
+//
+// Description: TagEnum says how a small type is stored in BITS bits, and
+//              RefWithTag<'a, T, E> keeps an E in the low bits of a &'a T. So
+//              a tri-color marking GC stores its enum Color { White, Gray,
+//              Black } next to the reference, and reads back a Color, not 2
+//              booleans to decode by hand.
+//
+//              E::BITS is checked against align_of::<T>() at compile time,
+//              like the width of TaggedRef, by the associated ALIGNED
+//              constant.
+
+use core::marker::PhantomData;
+use core::mem::align_of;
+use core::ptr::NonNull;
+
+use crate::bitpack;
+
+/// A type that can be stored in `BITS` bits.
+pub trait TagEnum: Copy {
+    const BITS: usize;
+
+    /// Must be below `1 << BITS`.
+    fn to_bits(self) -> usize;
+
+    /// Only ever called with a value returned by `to_bits`.
+    fn from_bits(bits: usize) -> Self;
+}
+
+impl TagEnum for bool {
+    const BITS: usize = 1;
+
+    fn to_bits(self) -> usize {
+        self as usize
+    }
+
+    fn from_bits(bits: usize) -> bool {
+        bits != 0
+    }
+}
+
+#[repr(transparent)]
+pub struct RefWithTag<'a, T, E: TagEnum> {
+    ptr_and_tag: NonNull<T>,
+    behaves_like: PhantomData<(&'a T, E)>,
+}
+
+impl<'a, T, E: TagEnum> RefWithTag<'a, T, E> {
+
+    const ALIGNED: () = assert!(
+        bitpack::align_supports(align_of::<T>(), E::BITS as u32),
+        "T is not aligned enough for E::BITS bits"
+    );
+
+    const MASK: usize = bitpack::mask_for(E::BITS as u32);
+
+    pub fn new(ptr: &'a T, tag: E) -> RefWithTag<'a, T, E> {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::ALIGNED;
+        let bits = Self::checked_bits(tag);
+        RefWithTag { ptr_and_tag: NonNull::from(ptr).map_addr(|addr| addr | bits), behaves_like: PhantomData }
+    }
+
+    pub fn get_ref(&self) -> &'a T {
+        unsafe { &*self.ptr_and_tag.as_ptr().map_addr(|addr| addr & !Self::MASK) }
+    }
+
+    pub fn get_tag(&self) -> E {
+        E::from_bits(self.ptr_and_tag.as_ptr().addr() & Self::MASK)
+    }
+
+    pub fn set_tag(&mut self, tag: E) {
+        let bits = Self::checked_bits(tag);
+        let ptr = self.ptr_and_tag.as_ptr().map_addr(|addr| (addr & !Self::MASK) | bits);
+        // The address part, non null, is kept.
+        self.ptr_and_tag = unsafe { NonNull::new_unchecked(ptr) };
+    }
+
+    // Panics if to_bits breaks its contract, rather than corrupt the address.
+    fn checked_bits(tag: E) -> usize {
+        let bits = tag.to_bits();
+        assert!(bits & !Self::MASK == 0, "TagEnum::to_bits returned more than BITS bits");
+        bits
+    }
+
+}
+
+impl<'a, T, E: TagEnum> Clone for RefWithTag<'a, T, E> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, T, E: TagEnum> Copy for RefWithTag<'a, T, E> {}
+
+// NonNull opts out of Send and Sync, this is a &T and an E as far as threads
+// go.
+unsafe impl<'a, T: Sync, E: TagEnum + Send> Send for RefWithTag<'a, T, E> {}
+unsafe impl<'a, T: Sync, E: TagEnum + Sync> Sync for RefWithTag<'a, T, E> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aligned_box::Align16;
+    use crate::gc::Color;
+
+    #[test]
+    fn a_user_enum_in_the_spare_bits() {
+        let slot = Align16(19u16);
+        let mut object = RefWithTag::new(&slot, Color::White);
+        assert_eq!(object.get_tag(), Color::White);
+        object.set_tag(Color::Gray);
+        let copy = object;
+        object.set_tag(Color::Black);
+        assert_eq!((copy.get_tag(), object.get_tag()), (Color::Gray, Color::Black));
+        assert_eq!(object.get_ref().0, 19);
+        let row = [10u32, 20, 30];
+        assert!(RefWithTag::new(&row, true).get_tag());
+    }
+}
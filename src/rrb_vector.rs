@@ -0,0 +1,481 @@
+// Name: RrbVector - a persistent vector (RRB-tree) with the node kind in the
+//       child pointers.
+//
+// Description: An immutable vector, every update returns a new version that
+//              shares all the untouched nodes with the old one. The tree has
+//              3 kinds of nodes:
+//
+//                 leaf    - up to 32 values.
+//                 strict  - up to 32 children, all full except the last, so
+//                           the child of an index is found by shifting.
+//                 relaxed - up to 32 children of any size, with a table of
+//                           the cumulative sizes, produced by concatenation.
+//
+//              Instead of an enum per child, the kind of a child is kept in
+//              the 2 low bits of the pointer to it (the nodes are Rc
+//              allocations, at least 4 bytes aligned):
+//
+//                 00 - strict, 01 - leaf, 10 - relaxed.
+//
+//              So a child costs a single word and the internal nodes are
+//              just arrays of words. The reference counts of the shared
+//              nodes are handled by Clone and Drop of the child words. A
+//              word is the pointer from Rc::into_raw with the kind or-ed in
+//              by map_addr, so the Rc counted down is the one counted up.
+
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+const BITS: usize = 5;
+const WIDTH: usize = 1 << BITS;
+
+const STRICT: usize = 0;
+const LEAF: usize = 1;
+const RELAXED: usize = 2;
+const KIND: usize = 3;
+
+struct Leaf<T> {
+    values: Vec<T>,
+}
+
+struct Strict<T> {
+    children: Vec<Child<T>>,
+}
+
+struct Relaxed<T> {
+    children: Vec<Child<T>>,
+    // sizes[i] is the number of values in children[0..=i].
+    sizes: Vec<usize>,
+}
+
+// One counted reference to a node, the kind is in the low bits.
+struct Child<T> {
+    word: *const (),
+    owns: PhantomData<Rc<Leaf<T>>>,
+}
+
+enum Node<'n, T> {
+    Leaf(&'n Leaf<T>),
+    Strict(&'n Strict<T>),
+    Relaxed(&'n Relaxed<T>),
+}
+
+impl<T> Child<T> {
+
+    fn leaf(values: Vec<T>) -> Child<T> {
+        Self::from_rc(Rc::new(Leaf { values }), LEAF)
+    }
+
+    fn strict(children: Vec<Child<T>>) -> Child<T> {
+        Self::from_rc(Rc::new(Strict { children }), STRICT)
+    }
+
+    fn relaxed(children: Vec<Child<T>>, sizes: Vec<usize>) -> Child<T> {
+        Self::from_rc(Rc::new(Relaxed { children, sizes }), RELAXED)
+    }
+
+    fn from_rc<N>(node: Rc<N>, kind: usize) -> Child<T> {
+        let ptr = Rc::into_raw(node).cast::<()>();
+        debug_assert_eq!(ptr.addr() & KIND, 0);
+        Child { word: ptr.map_addr(|addr| addr | kind), owns: PhantomData }
+    }
+
+    fn node(&self) -> Node<'_, T> {
+        let ptr = self.untagged();
+        unsafe {
+            match self.kind() {
+                LEAF => Node::Leaf(&*ptr.cast::<Leaf<T>>()),
+                RELAXED => Node::Relaxed(&*ptr.cast::<Relaxed<T>>()),
+                _ => Node::Strict(&*ptr.cast::<Strict<T>>()),
+            }
+        }
+    }
+
+    fn is_relaxed(&self) -> bool {
+        self.kind() == RELAXED
+    }
+
+    fn kind(&self) -> usize {
+        self.word.addr() & KIND
+    }
+
+    fn untagged(&self) -> *const () {
+        self.word.map_addr(|addr| addr & !KIND)
+    }
+
+    // Number of values below this node, a node of height h has children of
+    // height h - 1, leaves have height 0.
+    fn len(&self, height: usize) -> usize {
+        match self.node() {
+            Node::Leaf(leaf) => leaf.values.len(),
+            Node::Relaxed(relaxed) => *relaxed.sizes.last().unwrap(),
+            Node::Strict(strict) => {
+                let full = (strict.children.len() - 1) << (BITS * height);
+                full + strict.children.last().unwrap().len(height - 1)
+            }
+        }
+    }
+
+}
+
+impl<T> Clone for Child<T> {
+    fn clone(&self) -> Self {
+        let ptr = self.untagged();
+        unsafe {
+            match self.kind() {
+                LEAF => Rc::increment_strong_count(ptr.cast::<Leaf<T>>()),
+                RELAXED => Rc::increment_strong_count(ptr.cast::<Relaxed<T>>()),
+                _ => Rc::increment_strong_count(ptr.cast::<Strict<T>>()),
+            }
+        }
+        Child { word: self.word, owns: PhantomData }
+    }
+}
+
+impl<T> Drop for Child<T> {
+    fn drop(&mut self) {
+        let ptr = self.untagged();
+        unsafe {
+            match self.kind() {
+                LEAF => Rc::decrement_strong_count(ptr.cast::<Leaf<T>>()),
+                RELAXED => Rc::decrement_strong_count(ptr.cast::<Relaxed<T>>()),
+                _ => Rc::decrement_strong_count(ptr.cast::<Strict<T>>()),
+            }
+        }
+    }
+}
+
+pub struct RrbVector<T> {
+    root: Option<Child<T>>,
+    height: usize,
+    len: usize,
+}
+
+impl<T: Clone> RrbVector<T> {
+
+    pub fn new() -> RrbVector<T> {
+        RrbVector { root: None, height: 0, len: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, index: usize) -> Option<&T> {
+        if index >= self.len {
+            return None;
+        }
+        let mut node = self.root.as_ref()?;
+        let mut height = self.height;
+        let mut index = index;
+        loop {
+            match node.node() {
+                Node::Leaf(leaf) => return leaf.values.get(index),
+                Node::Strict(strict) => {
+                    let shift = BITS * height;
+                    node = &strict.children[index >> shift];
+                    index &= (1 << shift) - 1;
+                }
+                Node::Relaxed(relaxed) => {
+                    let slot = relaxed.sizes.partition_point(|&size| size <= index);
+                    if slot > 0 {
+                        index -= relaxed.sizes[slot - 1];
+                    }
+                    node = &relaxed.children[slot];
+                }
+            }
+            height -= 1;
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> + '_ {
+        (0..self.len).map(move |index| self.get(index).unwrap())
+    }
+
+    /// A new version with `value` appended.
+    pub fn push_back(&self, value: T) -> RrbVector<T> {
+        let root = match &self.root {
+            None => return RrbVector { root: Some(Child::leaf(vec![value])), height: 0, len: 1 },
+            Some(root) => root,
+        };
+        match Self::push(root, self.height, value) {
+            Ok(root) => RrbVector { root: Some(root), height: self.height, len: self.len + 1 },
+            Err(value) => {
+                // The root is full, grow the tree by one level.
+                let children = vec![root.clone(), Self::new_path(self.height, value)];
+                let root = if root.is_relaxed() {
+                    Child::relaxed(children, vec![self.len, self.len + 1])
+                } else {
+                    Child::strict(children)
+                };
+                RrbVector { root: Some(root), height: self.height + 1, len: self.len + 1 }
+            }
+        }
+    }
+
+    /// A new version with the value at `index` replaced.
+    pub fn set(&self, index: usize, value: T) -> RrbVector<T> {
+        assert!(index < self.len, "index out of bounds");
+        let root = Self::update(self.root.as_ref().unwrap(), self.height, index, value);
+        RrbVector { root: Some(root), height: self.height, len: self.len }
+    }
+
+    /// A new vector with the values of `self` followed by the ones of
+    /// `other`, sharing the nodes of both.
+    pub fn concat(&self, other: &RrbVector<T>) -> RrbVector<T> {
+        let (a, b) = match (&self.root, &other.root) {
+            (None, _) => return other.clone(),
+            (_, None) => return self.clone(),
+            (Some(a), Some(b)) => (a.clone(), b.clone()),
+        };
+        let height = self.height.max(other.height);
+        let a = Self::raise(a, self.height, height, self.len);
+        let b = Self::raise(b, other.height, height, other.len);
+        let len = self.len + other.len;
+        let merged = match (a.node(), b.node()) {
+            (Node::Leaf(left), Node::Leaf(right)) if len <= WIDTH => {
+                let values = left.values.iter().chain(right.values.iter()).cloned().collect();
+                Some(Child::leaf(values))
+            }
+            (Node::Leaf(_), _) | (_, Node::Leaf(_)) => None,
+            _ => {
+                let (left, right) = (Self::children(&a), Self::children(&b));
+                if left.len() + right.len() <= WIDTH {
+                    let children: Vec<Child<T>> = left.iter().chain(right.iter()).cloned().collect();
+                    let sizes = Self::sizes_of(&children, height - 1);
+                    Some(Child::relaxed(children, sizes))
+                } else {
+                    None
+                }
+            }
+        };
+        match merged {
+            Some(root) => RrbVector { root: Some(root), height, len },
+            None => RrbVector {
+                root: Some(Child::relaxed(vec![a, b], vec![self.len, len])),
+                height: height + 1,
+                len,
+            },
+        }
+    }
+
+    fn push(child: &Child<T>, height: usize, value: T) -> Result<Child<T>, T> {
+        match child.node() {
+            Node::Leaf(leaf) => {
+                if leaf.values.len() == WIDTH {
+                    return Err(value);
+                }
+                let mut values = leaf.values.clone();
+                values.push(value);
+                Ok(Child::leaf(values))
+            }
+            Node::Strict(strict) => {
+                let children = Self::push_children(&strict.children, height, value)?;
+                Ok(Child::strict(children))
+            }
+            Node::Relaxed(relaxed) => {
+                let children = Self::push_children(&relaxed.children, height, value)?;
+                let mut sizes = relaxed.sizes.clone();
+                if children.len() > sizes.len() {
+                    sizes.push(sizes.last().unwrap() + 1);
+                } else {
+                    *sizes.last_mut().unwrap() += 1;
+                }
+                Ok(Child::relaxed(children, sizes))
+            }
+        }
+    }
+
+    // Pushes into the last child, or appends a new child if it is full.
+    fn push_children(children: &[Child<T>], height: usize, value: T) -> Result<Vec<Child<T>>, T> {
+        let last = children.last().unwrap();
+        match Self::push(last, height - 1, value) {
+            Ok(new_last) => {
+                let mut children = children.to_vec();
+                *children.last_mut().unwrap() = new_last;
+                Ok(children)
+            }
+            Err(value) if children.len() < WIDTH => {
+                let mut children = children.to_vec();
+                children.push(Self::new_path(height - 1, value));
+                Ok(children)
+            }
+            Err(value) => Err(value),
+        }
+    }
+
+    fn new_path(height: usize, value: T) -> Child<T> {
+        if height == 0 {
+            Child::leaf(vec![value])
+        } else {
+            Child::strict(vec![Self::new_path(height - 1, value)])
+        }
+    }
+
+    fn update(child: &Child<T>, height: usize, index: usize, value: T) -> Child<T> {
+        match child.node() {
+            Node::Leaf(leaf) => {
+                let mut values = leaf.values.clone();
+                values[index] = value;
+                Child::leaf(values)
+            }
+            Node::Strict(strict) => {
+                let shift = BITS * height;
+                let slot = index >> shift;
+                let mut children = strict.children.clone();
+                children[slot] = Self::update(&children[slot], height - 1, index & ((1 << shift) - 1), value);
+                Child::strict(children)
+            }
+            Node::Relaxed(relaxed) => {
+                let slot = relaxed.sizes.partition_point(|&size| size <= index);
+                let offset = if slot > 0 { relaxed.sizes[slot - 1] } else { 0 };
+                let mut children = relaxed.children.clone();
+                children[slot] = Self::update(&children[slot], height - 1, index - offset, value);
+                Child::relaxed(children, relaxed.sizes.clone())
+            }
+        }
+    }
+
+    // Wraps a node in single child parents until it reaches `height`. A
+    // relaxed node is wrapped in relaxed parents, so that a strict parent
+    // never has a child that isn't full by the strict rules.
+    fn raise(mut child: Child<T>, mut height: usize, target: usize, len: usize) -> Child<T> {
+        while height < target {
+            child = if child.is_relaxed() {
+                Child::relaxed(vec![child], vec![len])
+            } else {
+                Child::strict(vec![child])
+            };
+            height += 1;
+        }
+        child
+    }
+
+    fn children(child: &Child<T>) -> &[Child<T>] {
+        match child.node() {
+            Node::Strict(strict) => &strict.children,
+            Node::Relaxed(relaxed) => &relaxed.children,
+            Node::Leaf(_) => &[],
+        }
+    }
+
+    fn sizes_of(children: &[Child<T>], height: usize) -> Vec<usize> {
+        children
+            .iter()
+            .scan(0, |total, child| {
+                *total += child.len(height);
+                Some(*total)
+            })
+            .collect()
+    }
+
+}
+
+impl<T> Clone for RrbVector<T> {
+    fn clone(&self) -> Self {
+        RrbVector { root: self.root.clone(), height: self.height, len: self.len }
+    }
+}
+
+impl<T: Clone> Default for RrbVector<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    #[test]
+    fn set_and_concat_share_the_old_versions() {
+        let mut evens = RrbVector::new();
+        for i in 0..100 {
+            evens = evens.push_back(i * 2);
+        }
+        let edited = evens.set(0, -1);
+        let joined = edited.concat(&evens);
+        assert_eq!(evens.get(0), Some(&0));
+        assert_eq!(joined.len(), 200);
+        assert_eq!(joined.get(0), Some(&-1));
+        assert_eq!(joined.get(150), Some(&100));
+        assert_eq!(joined.iter().skip(100).count(), 100);
+        assert!(!joined.is_empty() && RrbVector::<i32>::default().is_empty());
+    }
+
+    #[test]
+    fn random_versions_match_vecs() {
+        // The model: a Vec for every version kept.
+        let mut versions: Vec<(RrbVector<u32>, Vec<u32>)> = vec![(RrbVector::new(), Vec::new())];
+        let mut seed = 5150u32;
+        for _ in 0..1500 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let (vector, model) = &versions[(seed >> 16) as usize % versions.len()];
+            let mut model = model.clone();
+            let vector = match (seed >> 8) % 4 {
+                0 | 1 => {
+                    model.push(seed);
+                    vector.push_back(seed)
+                }
+                2 if !model.is_empty() => {
+                    let index = (seed >> 4) as usize % model.len();
+                    model[index] = seed;
+                    vector.set(index, seed)
+                }
+                _ => {
+                    let (other, other_model) = &versions[(seed >> 4) as usize % versions.len()];
+                    model.extend(other_model);
+                    vector.concat(other)
+                }
+            };
+            assert_eq!(vector.len(), model.len());
+            assert!(vector.iter().eq(model.iter()));
+            assert_eq!(vector.get(model.len()), None);
+            // Repeated concatenations double the length, keep it bounded.
+            if model.len() < 3000 {
+                versions.push((vector, model));
+            }
+        }
+        // The old versions were never changed by the new ones.
+        for (vector, model) in &versions {
+            assert!(vector.iter().eq(model.iter()));
+        }
+    }
+
+    #[test]
+    fn empty_and_single_value_edges() {
+        let empty: RrbVector<u8> = RrbVector::new();
+        assert_eq!(empty.get(0), None);
+        assert!(empty.concat(&empty).is_empty());
+        let one = empty.push_back(1);
+        assert_eq!(one.concat(&empty).iter().collect::<Vec<_>>(), vec![&1]);
+        assert_eq!(empty.concat(&one).get(0), Some(&1));
+        assert_eq!(one.set(0, 2).get(0), Some(&2));
+        assert_eq!(one.get(0), Some(&1));
+    }
+
+    #[test]
+    fn dropping_the_versions_frees_the_shared_nodes() {
+        let counted = Rc::new(());
+        let mut first = RrbVector::new();
+        for _ in 0..100 {
+            first = first.push_back(counted.clone());
+        }
+        let second = first.set(50, counted.clone()).concat(&first);
+        assert!(Rc::strong_count(&counted) > 100);
+        drop(first);
+        drop(second);
+        assert_eq!(Rc::strong_count(&counted), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "index out of bounds")]
+    fn set_past_the_end_panics() {
+        RrbVector::new().push_back(1).set(1, 2);
+    }
+}
@@ -0,0 +1,310 @@
+// Name: SceneGraph - scene nodes whose links carry dirty and visible bits.
+//
+// Description: A small game-dev style scene graph. Each node has a local
+//              transform and a cached world transform, and the link from a
+//              parent to each child is a tagged pointer with 2 flags:
+//
+//                 bit 0 - DIRTY   : something in the subtree below this link
+//                                   needs its world transform recomputed.
+//                 bit 1 - VISIBLE : the subtree is visible.
+//
+//              The link from a child to its parent uses bit 0 as SELF_DIRTY,
+//              the local transform of the node itself changed.
+//
+//              update_transforms() walks the links from the root and never
+//              follows a clean or hidden link, so the payload of the nodes
+//              in clean subtrees is not even touched, that is where the win
+//              is. Hidden subtrees keep their dirty bits and are brought up
+//              to date when they become visible again.
+//
+//              The links are pointers from Box::into_raw, the bits set and
+//              cleared with map_addr, so following one never turns an
+//              integer back into a node.
+
+use std::ptr;
+
+use crate::bitpack::untag_ptr;
+
+const DIRTY: usize = 1;
+const VISIBLE: usize = 2;
+const SELF_DIRTY: usize = 1;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Transform {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl Transform {
+    pub fn new(x: f32, y: f32) -> Transform {
+        Transform { x, y }
+    }
+
+    fn then(self, local: Transform) -> Transform {
+        Transform::new(self.x + local.x, self.y + local.y)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NodeId(usize);
+
+struct SceneNode {
+    local: Transform,
+    world: Transform,
+    // Tagged parent node, null for the root.
+    parent: *mut SceneNode,
+    // Index of the link to this node in the children of the parent.
+    slot: usize,
+    // Tagged children.
+    children: Vec<*mut SceneNode>,
+}
+
+pub struct SceneGraph {
+    // Owned nodes, from Box::into_raw, freed on drop.
+    nodes: Vec<*mut SceneNode>,
+    // Tagged root, the link with no parent node.
+    root_link: *mut SceneNode,
+}
+
+impl SceneGraph {
+
+    pub fn new() -> SceneGraph {
+        let mut graph = SceneGraph { nodes: Vec::new(), root_link: ptr::null_mut() };
+        let root = graph.alloc(Transform::default(), ptr::null_mut(), 0);
+        graph.root_link = root.map_addr(|addr| addr | DIRTY | VISIBLE);
+        graph
+    }
+
+    pub fn root(&self) -> NodeId {
+        NodeId(0)
+    }
+
+    pub fn add_child(&mut self, parent: NodeId, local: Transform) -> NodeId {
+        let parent = self.nodes[parent.0];
+        unsafe {
+            let slot = (*parent).children.len();
+            let child = self.alloc(local, parent, slot);
+            (*parent).children.push(child.map_addr(|addr| addr | VISIBLE));
+            self.mark_dirty(child);
+        }
+        NodeId(self.nodes.len() - 1)
+    }
+
+    pub fn set_local(&mut self, node: NodeId, local: Transform) {
+        let node = self.nodes[node.0];
+        unsafe {
+            (*node).local = local;
+            (*node).parent = (*node).parent.map_addr(|addr| addr | SELF_DIRTY);
+            self.mark_dirty(node);
+        }
+    }
+
+    /// The world transform as of the last update_transforms().
+    pub fn world(&self, node: NodeId) -> Transform {
+        unsafe { (*self.nodes[node.0]).world }
+    }
+
+    pub fn is_visible(&self, node: NodeId) -> bool {
+        let node = unsafe { &*self.nodes[node.0] };
+        let parent = untag_ptr(node.parent, 2);
+        let link = if parent.is_null() { self.root_link } else { unsafe { (&(*parent).children)[node.slot] } };
+        link.addr() & VISIBLE != 0
+    }
+
+    pub fn set_visible(&mut self, node: NodeId, visible: bool) {
+        let node = self.nodes[node.0];
+        unsafe {
+            let link = self.link(node);
+            if !visible {
+                *link = (*link).map_addr(|addr| addr & !VISIBLE);
+                return;
+            }
+            *link = (*link).map_addr(|addr| addr | VISIBLE);
+            // The subtree may have become dirty while hidden.
+            if (*link).addr() & DIRTY != 0 {
+                *link = (*link).map_addr(|addr| addr & !DIRTY);
+                self.mark_dirty(node);
+            }
+        }
+    }
+
+    /// Recomputes the stale world transforms of the visible nodes, returns
+    /// how many nodes were recomputed.
+    pub fn update_transforms(&mut self) -> usize {
+        unsafe { Self::update(&mut self.root_link, Transform::default(), false) }
+    }
+
+    fn alloc(&mut self, local: Transform, parent: *mut SceneNode, slot: usize) -> *mut SceneNode {
+        let node = Box::into_raw(Box::new(SceneNode {
+            local,
+            world: Transform::default(),
+            parent: parent.map_addr(|addr| addr | SELF_DIRTY),
+            slot,
+            children: Vec::new(),
+        }));
+        self.nodes.push(node);
+        node
+    }
+
+    // The link that points to `node`.
+    unsafe fn link(&mut self, node: *mut SceneNode) -> *mut *mut SceneNode {
+        let parent = untag_ptr((*node).parent, 2);
+        if parent.is_null() {
+            ptr::addr_of_mut!(self.root_link)
+        } else {
+            (*parent).children.as_mut_ptr().add((*node).slot)
+        }
+    }
+
+    // Sets DIRTY on the links from the node up to the root, stopping at the
+    // first link that is already dirty.
+    unsafe fn mark_dirty(&mut self, node: *mut SceneNode) {
+        let mut current = node;
+        loop {
+            let link = self.link(current);
+            if (*link).addr() & DIRTY != 0 {
+                return;
+            }
+            *link = (*link).map_addr(|addr| addr | DIRTY);
+            current = untag_ptr((*current).parent, 2);
+            if current.is_null() {
+                return;
+            }
+        }
+    }
+
+    unsafe fn update(link: *mut *mut SceneNode, parent_world: Transform, force: bool) -> usize {
+        let word = *link;
+        if word.addr() & DIRTY == 0 && !force {
+            return 0;
+        }
+        let node = &mut *untag_ptr(word, 2);
+        if word.addr() & VISIBLE == 0 {
+            if force {
+                // The parent moved, remember it for when it is shown again.
+                node.parent = node.parent.map_addr(|addr| addr | SELF_DIRTY);
+                *link = word.map_addr(|addr| addr | DIRTY);
+            }
+            return 0;
+        }
+        *link = word.map_addr(|addr| addr & !DIRTY);
+        let recompute = force || node.parent.addr() & SELF_DIRTY != 0;
+        let mut count = 0;
+        if recompute {
+            node.world = parent_world.then(node.local);
+            node.parent = node.parent.map_addr(|addr| addr & !SELF_DIRTY);
+            count += 1;
+        }
+        let world = node.world;
+        for child in node.children.iter_mut() {
+            count += Self::update(child, world, recompute);
+        }
+        count
+    }
+
+}
+
+impl Drop for SceneGraph {
+    fn drop(&mut self) {
+        for &node in self.nodes.iter() {
+            drop(unsafe { Box::from_raw(node) });
+        }
+    }
+}
+
+impl Default for SceneGraph {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prunes_clean_and_hidden_subtrees() {
+        let mut scene = SceneGraph::new();
+        let root = scene.root();
+        let player = scene.add_child(root, Transform::new(10.0, 0.0));
+        let weapon = scene.add_child(player, Transform::new(1.0, 2.0));
+        let tree = scene.add_child(root, Transform::new(-5.0, 3.0));
+        assert_eq!(scene.update_transforms(), 4);
+        assert_eq!(scene.world(weapon), Transform::new(11.0, 2.0));
+        scene.set_local(player, Transform::new(20.0, 0.0));
+        // Only the player subtree is recomputed, the tree isn't touched.
+        assert_eq!(scene.update_transforms(), 2);
+        assert_eq!(scene.world(weapon), Transform::new(21.0, 2.0));
+        assert_eq!(scene.update_transforms(), 0);
+        scene.set_visible(tree, false);
+        scene.set_local(tree, Transform::new(0.0, 0.0));
+        assert_eq!(scene.update_transforms(), 0);
+        assert!(!scene.is_visible(tree));
+        scene.set_visible(tree, true);
+        assert_eq!(scene.update_transforms(), 1);
+        assert_eq!(scene.world(tree), Transform::new(0.0, 0.0));
+    }
+
+    #[test]
+    fn a_lone_root_and_a_hidden_root() {
+        let mut scene = SceneGraph::default();
+        let root = scene.root();
+        assert_eq!(scene.update_transforms(), 1);
+        assert_eq!(scene.update_transforms(), 0);
+        scene.set_local(root, Transform::new(1.0, 1.0));
+        scene.set_visible(root, false);
+        let child = scene.add_child(root, Transform::new(2.0, 0.0));
+        assert_eq!(scene.update_transforms(), 0);
+        assert!(!scene.is_visible(root) && scene.is_visible(child));
+        scene.set_visible(root, true);
+        assert_eq!(scene.update_transforms(), 2);
+        assert_eq!(scene.world(child), Transform::new(3.0, 1.0));
+    }
+
+    #[test]
+    fn random_edits_match_the_recomputed_transforms() {
+        // The model: parent, local transform and own visibility of every node.
+        let mut scene = SceneGraph::new();
+        let mut model: Vec<(Option<usize>, Transform, bool)> = vec![(None, Transform::default(), true)];
+        let mut seed = 31337u32;
+        for _ in 0..2000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let node = (seed >> 16) as usize % model.len();
+            let local = Transform::new(((seed >> 4) % 16) as f32, ((seed >> 12) % 16) as f32);
+            match (seed >> 8) % 4 {
+                0 => {
+                    assert_eq!(scene.add_child(NodeId(node), local), NodeId(model.len()));
+                    model.push((Some(node), local, true));
+                }
+                1 => {
+                    scene.set_local(NodeId(node), local);
+                    model[node].1 = local;
+                }
+                2 => {
+                    let visible = (seed >> 3) & 1 == 0;
+                    scene.set_visible(NodeId(node), visible);
+                    model[node].2 = visible;
+                }
+                _ => {
+                    scene.update_transforms();
+                    for index in 0..model.len() {
+                        // Only the nodes with every link up to the root visible.
+                        let (mut world, mut shown, mut current) = (Transform::default(), true, Some(index));
+                        while let Some(at) = current {
+                            world = Transform::new(world.x + model[at].1.x, world.y + model[at].1.y);
+                            shown &= model[at].2;
+                            current = model[at].0;
+                        }
+                        if shown {
+                            assert_eq!(scene.world(NodeId(index)), world);
+                        }
+                    }
+                }
+            }
+            assert_eq!(scene.is_visible(NodeId(node)), model[node].2);
+        }
+        // Hidden subtrees stay dirty, but an update leaves nothing visible to redo.
+        scene.update_transforms();
+        assert_eq!(scene.update_transforms(), 0);
+    }
+}
@@ -0,0 +1,160 @@
+// Name: AtomicTaskPtr - the "scheduled" bit of a task, in the task pointer.
+//
+// Description: An executor must never push the same task twice onto its run
+//              queue, even when several wakers fire at the same time. The
+//              usual answer is a scheduled bit: the waker that flips it from
+//              0 to 1 pushes the task, every other waker does nothing, and
+//              the worker clears it just before running the task, so a wake
+//              up during the run schedules it again.
+//
+//              Here the bit lives in the same atomic word as the pointer to
+//              the task:
+//
+//                 bit 0 - SCHEDULED : the task is in a run queue.
+//                 bit 1 - CLOSED    : the task completed, never schedule it.
+//
+//              The word is an AtomicPtr and the bits are flipped with its
+//              fetch_or / fetch_and, so the task handed to the winning waker
+//              is the reference given to new(), provenance and all.
+
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::aligned::AlignedAtLeast;
+
+const SCHEDULED: usize = 1;
+const CLOSED: usize = 2;
+
+pub struct AtomicTaskPtr<'a, T> {
+    ptr_and_bits: AtomicPtr<T>,
+    behaves_like: PhantomData<&'a T>,
+}
+
+impl<'a, T: AlignedAtLeast<4> + Sync> AtomicTaskPtr<'a, T> {
+
+    pub fn new(task: &'a T) -> AtomicTaskPtr<'a, T> {
+        AtomicTaskPtr {
+            ptr_and_bits: AtomicPtr::new((task as *const T).cast_mut()),
+            behaves_like: PhantomData,
+        }
+    }
+
+    /// Sets the scheduled bit. Returns the task only to the caller that set
+    /// it, that caller is the one that must push the task onto a run queue.
+    pub fn try_mark_scheduled(&self) -> Option<&'a T> {
+        let mut word = self.ptr_and_bits.load(Ordering::Acquire);
+        loop {
+            if word.addr() & (SCHEDULED | CLOSED) != 0 {
+                return None;
+            }
+            match self.ptr_and_bits.compare_exchange_weak(
+                word,
+                word.map_addr(|addr| addr | SCHEDULED),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Some(Self::task(word)),
+                Err(current) => word = current,
+            }
+        }
+    }
+
+    /// Called by the worker just before running the task, so that a wake up
+    /// during the run schedules it again.
+    pub fn clear_on_run(&self) -> &'a T {
+        let word = self.ptr_and_bits.fetch_and(!SCHEDULED, Ordering::AcqRel);
+        Self::task(word)
+    }
+
+    /// Marks the task as completed, it can't be scheduled anymore.
+    pub fn close(&self) {
+        self.ptr_and_bits.fetch_or(CLOSED, Ordering::AcqRel);
+    }
+
+    pub fn is_scheduled(&self) -> bool {
+        self.ptr_and_bits.load(Ordering::Acquire).addr() & SCHEDULED != 0
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.ptr_and_bits.load(Ordering::Acquire).addr() & CLOSED != 0
+    }
+
+    fn task(word: *mut T) -> &'a T {
+        unsafe { &*word.map_addr(|addr| addr & !3) }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+    use std::thread;
+
+    #[test]
+    fn only_the_first_mark_enqueues() {
+        let task = 7_u32;
+        let ptr = AtomicTaskPtr::new(&task);
+        let mut queue = VecDeque::new();
+        for _ in 0..3 {
+            if let Some(task) = ptr.try_mark_scheduled() {
+                queue.push_back(task);
+            }
+        }
+        assert_eq!(queue.len(), 1);
+        assert!(ptr.is_scheduled());
+    }
+
+    #[test]
+    fn clear_on_run_allows_rescheduling() {
+        let task = 7_u32;
+        let ptr = AtomicTaskPtr::new(&task);
+        assert!(ptr.try_mark_scheduled().is_some());
+        assert_eq!(*ptr.clear_on_run(), 7);
+        assert!(!ptr.is_scheduled());
+        assert!(ptr.try_mark_scheduled().is_some());
+    }
+
+    #[test]
+    fn closed_task_is_never_scheduled() {
+        let task = 7_u32;
+        let ptr = AtomicTaskPtr::new(&task);
+        ptr.close();
+        assert!(ptr.is_closed());
+        assert!(ptr.try_mark_scheduled().is_none());
+    }
+
+    #[test]
+    fn concurrent_wakers_enqueue_once() {
+        let task = 7_u32;
+        let ptr = AtomicTaskPtr::new(&task);
+        let queue = Mutex::new(Vec::new());
+        for _ in 0..100 {
+            thread::scope(|s| {
+                for _ in 0..8 {
+                    s.spawn(|| {
+                        if let Some(task) = ptr.try_mark_scheduled() {
+                            queue.lock().unwrap().push(task);
+                        }
+                    });
+                }
+            });
+            assert_eq!(queue.lock().unwrap().len(), 1);
+            queue.lock().unwrap().clear();
+            ptr.clear_on_run();
+        }
+    }
+
+    #[test]
+    fn closing_a_scheduled_task_keeps_the_run() {
+        let task = 7_u32;
+        let ptr = AtomicTaskPtr::new(&task);
+        assert!(ptr.try_mark_scheduled().is_some());
+        ptr.close();
+        assert!(ptr.is_scheduled() && ptr.is_closed());
+        assert!(std::ptr::eq(ptr.clear_on_run(), &task));
+        assert!(!ptr.is_scheduled());
+        assert!(ptr.try_mark_scheduled().is_none());
+    }
+}
@@ -0,0 +1,160 @@
+// Name: ScopedTag - borrows the low bits of an existing &mut T for a scope.
+//
+// Description: Sometimes the flags are only needed for a while, e.g. marks
+//              during one traversal, and the reference lives in a structure
+//              whose definition can't be changed to hold a RefWith2Flags.
+//              ScopedTag::with() takes the slot holding the reference and
+//              uses its 2 low bits as flags while the closure runs, then
+//              writes the original reference back, whatever happened in
+//              between, panics included.
+//
+//              While the bits are in use the slot doesn't hold a valid
+//              reference, that is why the guard keeps the slot mutably
+//              borrowed: nothing else can read it until it is restored. The
+//              guard's Drop does the restoring, so a leaked guard would end
+//              the borrow with the tagged word still in the slot. with()
+//              never hands the guard out by value, only steal() does, and it
+//              is unsafe for that reason.
+
+use core::marker::PhantomData;
+
+use crate::aligned::AlignedAtLeast;
+
+pub struct ScopedTag<'s, 'a, T> {
+    slot: *mut *mut T,
+    original: *mut T,
+    borrows: PhantomData<&'s mut &'a mut T>,
+}
+
+impl<'s, 'a, T: AlignedAtLeast<4>> ScopedTag<'s, 'a, T> {
+
+    /// Starts using the low bits of the reference in `slot`, both flags
+    /// false, until the guard is dropped.
+    ///
+    /// # Safety
+    ///
+    /// The guard must be dropped, not leaked (mem::forget, a cycle of Rc):
+    /// only its Drop writes the reference back, and once the borrow of
+    /// `slot` ends the owner of the slot may read the tagged word.
+    pub unsafe fn steal(slot: &'s mut &'a mut T) -> ScopedTag<'s, 'a, T> {
+        let slot = slot as *mut &'a mut T as *mut *mut T;
+        let original = unsafe { *slot };
+        ScopedTag { slot, original, borrows: PhantomData }
+    }
+
+    /// Runs `f` with the bits of `slot` stolen, they are restored on return
+    /// and when `f` panics.
+    pub fn with<R>(slot: &'s mut &'a mut T, f: impl FnOnce(&mut ScopedTag<'s, 'a, T>) -> R) -> R {
+        // The guard lives in this frame, f only gets a &mut to it and can't
+        // leak it.
+        f(&mut unsafe { ScopedTag::steal(slot) })
+    }
+
+    pub fn get_ref(&self) -> &T {
+        unsafe { &*self.original }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.original }
+    }
+
+    pub fn get_flag_a(&self) -> bool {
+        self.word() & 1 != 0
+    }
+
+    pub fn get_flag_b(&self) -> bool {
+        self.word() & 2 != 0
+    }
+
+    pub fn set_flag_a(&mut self, flag: bool) {
+        self.set_bits((self.word() & !1) | flag as usize);
+    }
+
+    pub fn set_flag_b(&mut self, flag: bool) {
+        self.set_bits((self.word() & !2) | ((flag as usize) << 1));
+    }
+
+    fn word(&self) -> usize {
+        unsafe { self.slot.read() }.addr()
+    }
+
+    // Written as a raw pointer, never read back as a reference.
+    fn set_bits(&mut self, word: usize) {
+        unsafe { self.slot.write(self.original.with_addr(word)) };
+    }
+
+}
+
+impl<'s, 'a, T> Drop for ScopedTag<'s, 'a, T> {
+    fn drop(&mut self) {
+        unsafe { self.slot.write(self.original) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+
+    #[repr(align(4))]
+    struct Slot(u32);
+
+    crate::aligned_at_least!(Slot => 4);
+
+    #[test]
+    fn flags_round_trip_and_the_slot_is_restored() {
+        let mut target = Slot(7);
+        let mut slot = &mut target;
+        let original = slot as *const Slot;
+        let flags = ScopedTag::with(&mut slot, |tag| {
+            assert!(!tag.get_flag_a() && !tag.get_flag_b());
+            tag.set_flag_a(true);
+            tag.set_flag_b(true);
+            tag.set_flag_a(false);
+            tag.get_mut().0 += 1;
+            (tag.get_flag_a(), tag.get_flag_b(), tag.get_ref().0)
+        });
+        assert_eq!(flags, (false, true, 8));
+        assert_eq!(slot as *const Slot, original);
+        assert_eq!(slot.0, 8);
+    }
+
+    #[test]
+    fn the_slot_is_restored_after_a_panic() {
+        let mut target = Slot(7);
+        let mut slot = &mut target;
+        let original = slot as *const Slot;
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            ScopedTag::with(&mut slot, |tag| {
+                tag.set_flag_a(true);
+                tag.set_flag_b(true);
+                panic!("in the closure");
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(slot as *const Slot, original);
+        assert_eq!(slot.0, 7);
+    }
+
+    #[test]
+    fn marks_a_reference_owned_by_someone_else() {
+        struct Visitor<'v> {
+            current: &'v mut Slot,
+        }
+        let mut target = Slot(0);
+        let mut visitor = Visitor { current: &mut target };
+        let seen = ScopedTag::with(&mut visitor.current, |tag| {
+            tag.set_flag_a(true);
+            tag.get_mut().0 += 1;
+            tag.set_flag_b(tag.get_flag_a());
+            (tag.get_flag_a(), tag.get_flag_b(), tag.get_ref().0)
+        });
+        assert_eq!(seen, (true, true, 1));
+        ScopedTag::with(&mut visitor.current, |tag| {
+            tag.set_flag_b(true);
+            assert!(!tag.get_flag_a() && tag.get_flag_b());
+        });
+        visitor.current.0 += 1;
+        assert_eq!(target.0, 2);
+    }
+}
@@ -0,0 +1,211 @@
+// Name: SharedSliceWith2Flags - Arc<[T]>, Arc<str>, Rc<[T]> and Rc<str> with
+//       2 flags.
+//
+// Description: A shared slice is a fat pointer, a data pointer plus a length.
+//              The flags go into the low bits of the data pointer and the
+//              length travels alongside, so the tagged version has the same
+//              size as the untagged one.
+//
+//              The data pointer given by Arc::into_raw / Rc::into_raw points
+//              just after the reference counts, that are usize's, so even for
+//              a str, or a slice of u8, it is in practice aligned to at least
+//              4 bytes. This is not a documented guarantee of std, so it is
+//              checked at construction. The flags are or-ed into the
+//              pointer from into_raw with map_addr and masked off the same
+//              way before from_raw, it never goes through a usize, so the
+//              count decremented is the one of the original allocation.
+//
+//              Clone increments the reference count and Drop decrements it,
+//              exactly like the shared pointer that was tagged.
+
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+use std::ptr;
+use std::rc::Rc;
+use std::sync::Arc;
+
+pub type ArcSliceWith2Flags<T> = SharedSliceWith2Flags<Arc<[T]>>;
+pub type ArcStrWith2Flags = SharedSliceWith2Flags<Arc<str>>;
+pub type RcSliceWith2Flags<T> = SharedSliceWith2Flags<Rc<[T]>>;
+pub type RcStrWith2Flags = SharedSliceWith2Flags<Rc<str>>;
+
+/// A shared pointer to a slice like type that can be split into a data
+/// pointer and a length and rebuilt from them.
+///
+/// # Safety
+/// `from_raw_parts` and `target` must accept exactly what `into_raw_parts`
+/// returned.
+pub unsafe trait SharedSlice: Clone + Deref {
+    fn into_raw_parts(self) -> (*const (), usize);
+
+    /// # Safety
+    /// The parts must come from `into_raw_parts` and be used only once.
+    unsafe fn from_raw_parts(data: *const (), len: usize) -> Self;
+
+    /// # Safety
+    /// The parts must come from `into_raw_parts` of a still alive pointer.
+    unsafe fn target<'s>(data: *const (), len: usize) -> &'s Self::Target;
+}
+
+macro_rules! impl_shared_slice {
+    ($shared:ident) => {
+        unsafe impl<T> SharedSlice for $shared<[T]> {
+            fn into_raw_parts(self) -> (*const (), usize) {
+                let raw = $shared::into_raw(self);
+                (raw as *const (), raw.len())
+            }
+
+            unsafe fn from_raw_parts(data: *const (), len: usize) -> Self {
+                $shared::from_raw(ptr::slice_from_raw_parts(data as *const T, len))
+            }
+
+            unsafe fn target<'s>(data: *const (), len: usize) -> &'s [T] {
+                &*ptr::slice_from_raw_parts(data as *const T, len)
+            }
+        }
+
+        unsafe impl SharedSlice for $shared<str> {
+            fn into_raw_parts(self) -> (*const (), usize) {
+                let raw = $shared::into_raw(self);
+                (raw as *const (), (raw as *const [u8]).len())
+            }
+
+            unsafe fn from_raw_parts(data: *const (), len: usize) -> Self {
+                $shared::from_raw(ptr::slice_from_raw_parts(data as *const u8, len) as *const str)
+            }
+
+            unsafe fn target<'s>(data: *const (), len: usize) -> &'s str {
+                &*(ptr::slice_from_raw_parts(data as *const u8, len) as *const str)
+            }
+        }
+    };
+}
+
+impl_shared_slice!(Arc);
+impl_shared_slice!(Rc);
+
+pub struct SharedSliceWith2Flags<P: SharedSlice> {
+    ptr_and_bit: *const (),
+    len: usize,
+    behaves_like: PhantomData<P>
+}
+
+// The raw pointer opts out of Send and Sync, this is a P as far as threads
+// go.
+unsafe impl<P: SharedSlice + Send> Send for SharedSliceWith2Flags<P> {}
+unsafe impl<P: SharedSlice + Sync> Sync for SharedSliceWith2Flags<P> {}
+
+impl<P: SharedSlice> SharedSliceWith2Flags<P> {
+
+    pub fn new(shared: P, flag_a: bool, flag_b: bool) -> SharedSliceWith2Flags<P> {
+        let (data, len) = shared.into_raw_parts();
+        if data.addr() & 3 != 0 {
+            // Give the reference back before panicking, so it isn't leaked.
+            drop(unsafe { P::from_raw_parts(data, len) });
+            panic!("shared slice data pointer is not 4 bytes aligned");
+        }
+        SharedSliceWith2Flags {
+            ptr_and_bit: data.map_addr(|addr| addr | flag_a as usize | ((flag_b as usize) << 1)),
+            len,
+            behaves_like: PhantomData
+        }
+    }
+
+    pub fn get_ref(&self) -> &P::Target {
+        unsafe { P::target(self.data(), self.len) }
+    }
+
+    pub fn get_flag_a(&self) -> bool {
+        self.ptr_and_bit.addr() & 1 != 0
+    }
+
+    pub fn get_flag_b(&self) -> bool {
+        self.ptr_and_bit.addr() & 2 != 0
+    }
+
+    pub fn set_flag_a(&mut self, flag_a: bool) {
+        self.ptr_and_bit = self.ptr_and_bit.map_addr(|addr| (addr & !1) | flag_a as usize);
+    }
+
+    pub fn set_flag_b(&mut self, flag_b: bool) {
+        self.ptr_and_bit = self.ptr_and_bit.map_addr(|addr| (addr & !2) | ((flag_b as usize) << 1));
+    }
+
+    /// Gives back the shared pointer, dropping the flags.
+    pub fn into_inner(self) -> P {
+        let this = ManuallyDrop::new(self);
+        unsafe { P::from_raw_parts(this.data(), this.len) }
+    }
+
+    fn data(&self) -> *const () {
+        self.ptr_and_bit.map_addr(|addr| addr & !3)
+    }
+
+}
+
+impl<P: SharedSlice> Clone for SharedSliceWith2Flags<P> {
+    fn clone(&self) -> Self {
+        let shared = ManuallyDrop::new(unsafe { P::from_raw_parts(self.data(), self.len) });
+        let (data, len) = P::clone(&shared).into_raw_parts();
+        debug_assert_eq!(data, self.data());
+        SharedSliceWith2Flags {
+            ptr_and_bit: data.map_addr(|addr| addr | (self.ptr_and_bit.addr() & 3)),
+            len,
+            behaves_like: PhantomData
+        }
+    }
+}
+
+impl<P: SharedSlice> Drop for SharedSliceWith2Flags<P> {
+    fn drop(&mut self) {
+        drop(unsafe { P::from_raw_parts(self.data(), self.len) });
+    }
+}
+
+impl<P: SharedSlice> Deref for SharedSliceWith2Flags<P> {
+    type Target = P::Target;
+
+    fn deref(&self) -> &P::Target {
+        self.get_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clones_share_the_slice_with_their_own_flags() {
+        let shared: Arc<[u8]> = Arc::from(&b"tagged"[..]);
+        let mut slice: ArcSliceWith2Flags<u8> = ArcSliceWith2Flags::new(shared.clone(), true, false);
+        let copy = slice.clone();
+        assert_eq!(Arc::strong_count(&shared), 3);
+        slice.set_flag_b(true);
+        slice.set_flag_a(false);
+        assert_eq!(&slice[..3], b"tag");
+        assert!(copy.get_flag_a() && !copy.get_flag_b());
+        drop(copy);
+        assert!(Arc::ptr_eq(&slice.into_inner(), &shared));
+        assert_eq!(Arc::strong_count(&shared), 1);
+        let name = ArcStrWith2Flags::new(Arc::from("name"), false, true);
+        assert_eq!(name.get_ref(), "name");
+        let local = RcSliceWith2Flags::new(Rc::from(vec![1_i32, 2, 3]), true, true);
+        assert_eq!(local.len(), 3);
+        let text = RcStrWith2Flags::new(Rc::from("text"), true, false);
+        assert!(text.starts_with("te") && text.get_flag_a());
+    }
+
+    #[test]
+    fn empty_slices_and_strings() {
+        let empty: Arc<[u64]> = Arc::from(Vec::new());
+        let mut slice = ArcSliceWith2Flags::new(empty.clone(), true, true);
+        assert!(slice.is_empty() && slice.get_flag_a() && slice.get_flag_b());
+        slice.set_flag_a(false);
+        assert!(!slice.get_flag_a() && slice.get_flag_b());
+        assert!(Arc::ptr_eq(&slice.into_inner(), &empty));
+        let text = RcStrWith2Flags::new(Rc::from(""), false, true);
+        assert_eq!(text.get_ref(), "");
+        assert!(text.clone().get_flag_b());
+    }
+}
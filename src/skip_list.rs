@@ -0,0 +1,398 @@
+// Name: SkipList - a lock free skip list, the mark bit in every forward
+//       pointer.
+//
+// Description: The lock free skip list of Herlihy and Shavit (after Fraser):
+//              a HarrisList at each level, each node in the bottom level and
+//              in the levels above up to its random height. Every forward
+//              pointer is an AtomicTaggedPtr, and flag a of a node's forward
+//              pointer at a level is its deletion mark at that level:
+//
+//                 flag a - MARKED : the node is being deleted at this level.
+//
+//              remove() marks the node from its top level down, the mark at
+//              the bottom is the point where the value is gone, and then
+//              find() unlinks it, every find() that walks over a marked node
+//              unlinks it. insert() links the node at the bottom first, the
+//              point where the value is in, and then level by level upwards,
+//              giving up on the levels left if the node gets marked meanwhile.
+//              contains() is wait free, it skips the marked nodes without
+//              unlinking them.
+//
+//              As in HarrisList, the head and the tail are sentinels of full
+//              height, the forward pointers of the tail point to the tail
+//              itself, the nodes are &'static to the AtomicTaggedPtr so T
+//              must be 'static, and the nodes removed while the list is
+//              shared go on a retired list. A removed node may still be
+//              linked at some level (an insert racing with the remove can
+//              even link it again, marked, above), so collect(), with
+//              &mut self, first unlinks the marked nodes left at every level,
+//              and only then frees the retired ones.
+
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+
+use crate::aligned::AlignedAtLeast;
+use crate::atomic_tagged_ptr::AtomicTaggedPtr;
+use crate::ref_with_2_flags::RefWith2Flags;
+
+const MAX_HEIGHT: usize = 16;
+
+#[repr(align(4))]
+struct Node<T: 'static> {
+    // Uninit in the sentinels, never written once the node is linked.
+    value: MaybeUninit<T>,
+    // One forward pointer per level, the bottom one first.
+    next: Box<[AtomicTaggedPtr<'static, Node<T>>]>,
+    // Link of the retired list, written by the remove() that marked the
+    // bottom level.
+    retired_next: AtomicPtr<Node<T>>,
+    // The pointer from Box::into_raw, to free the node with its own
+    // provenance, not the one of a shared reference.
+    raw: *mut Node<T>,
+}
+
+const _: () = assert!(core::mem::align_of::<Node<()>>() >= 4);
+unsafe impl<T: 'static> AlignedAtLeast<4> for Node<T> {}
+
+type Path<T> = [&'static Node<T>; MAX_HEIGHT];
+
+pub struct SkipList<T: 'static> {
+    head: *mut Node<T>,
+    tail: *mut Node<T>,
+    retired: AtomicPtr<Node<T>>,
+    // State of the random heights, a splitmix64 sequence.
+    seed: AtomicU64,
+}
+
+// The values are shared between the threads that read the list and moved
+// in by the ones that insert.
+unsafe impl<T: Send + Sync> Send for SkipList<T> {}
+unsafe impl<T: Send + Sync> Sync for SkipList<T> {}
+
+impl<T: Ord + 'static> SkipList<T> {
+
+    pub fn new() -> SkipList<T> {
+        let tail = Self::alloc_node(MaybeUninit::uninit(), MAX_HEIGHT, None);
+        let head = Self::alloc_node(MaybeUninit::uninit(), MAX_HEIGHT, Some(tail));
+        SkipList { head, tail, retired: AtomicPtr::new(ptr::null_mut()), seed: AtomicU64::new(0) }
+    }
+
+    /// Returns false, and drops `value`, if it was already in the list.
+    pub fn insert(&self, value: T) -> bool {
+        let height = self.random_height();
+        let raw = Self::alloc_node(MaybeUninit::new(value), height, Some(self.tail));
+        let node = unsafe { &*raw };
+        let value = unsafe { node.value.assume_init_ref() };
+        let (mut preds, mut succs) = ([self.head_node(); MAX_HEIGHT], [self.head_node(); MAX_HEIGHT]);
+        loop {
+            if self.find(value, &mut preds, &mut succs) {
+                drop(unsafe { Box::from_raw(raw).value.assume_init() });
+                return false;
+            }
+            // The node isn't shared until the CAS at the bottom publishes it.
+            for (level, succ) in succs.iter().enumerate().take(height) {
+                node.next[level].store(RefWith2Flags::new(*succ, false, false), Ordering::Relaxed);
+            }
+            let expected = RefWith2Flags::new(succs[0], false, false);
+            let linked = RefWith2Flags::new(node, false, false);
+            if preds[0].next[0].compare_exchange(&expected, linked, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+                break;
+            }
+        }
+        for level in 1..height {
+            loop {
+                let next = node.next[level].load(Ordering::Acquire);
+                if next.get_flag_a() {
+                    // Removed already, the levels left don't matter.
+                    return true;
+                }
+                if !ptr::eq(next.get_ref(), succs[level]) {
+                    let succ = RefWith2Flags::new(succs[level], false, false);
+                    if node.next[level].compare_exchange(&next, succ, Ordering::AcqRel, Ordering::Acquire).is_err() {
+                        continue;
+                    }
+                }
+                let expected = RefWith2Flags::new(succs[level], false, false);
+                let linked = RefWith2Flags::new(node, false, false);
+                if preds[level].next[level].compare_exchange(&expected, linked, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+                    break;
+                }
+                self.find(value, &mut preds, &mut succs);
+            }
+        }
+        true
+    }
+
+    /// Returns false if `value` wasn't in the list, or another remove() got
+    /// it first.
+    pub fn remove(&self, value: &T) -> bool {
+        let (mut preds, mut succs) = ([self.head_node(); MAX_HEIGHT], [self.head_node(); MAX_HEIGHT]);
+        if !self.find(value, &mut preds, &mut succs) {
+            return false;
+        }
+        let node = succs[0];
+        for level in (1..node.next.len()).rev() {
+            let mut next = node.next[level].load(Ordering::Acquire);
+            while !next.get_flag_a() {
+                let mut marked = next;
+                marked.set_flag_a(true);
+                match node.next[level].compare_exchange(&next, marked, Ordering::AcqRel, Ordering::Acquire) {
+                    Ok(_) => break,
+                    Err(actual) => next = actual,
+                }
+            }
+        }
+        let mut next = node.next[0].load(Ordering::Acquire);
+        loop {
+            if next.get_flag_a() {
+                return false;
+            }
+            let mut marked = next;
+            marked.set_flag_a(true);
+            match node.next[0].compare_exchange(&next, marked, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => break,
+                Err(actual) => next = actual,
+            }
+        }
+        self.retire(node);
+        self.find(value, &mut preds, &mut succs);
+        true
+    }
+
+    /// Wait free, it doesn't help unlink.
+    pub fn contains(&self, value: &T) -> bool {
+        let mut pred = self.head_node();
+        let mut curr = pred;
+        for level in (0..MAX_HEIGHT).rev() {
+            curr = pred.next[level].load(Ordering::Acquire).get_ref();
+            loop {
+                let mut next = curr.next[level].load(Ordering::Acquire);
+                while next.get_flag_a() {
+                    curr = next.get_ref();
+                    next = curr.next[level].load(Ordering::Acquire);
+                }
+                if self.is_tail(curr) || unsafe { curr.value.assume_init_ref() } >= value {
+                    break;
+                }
+                pred = curr;
+                curr = next.get_ref();
+            }
+        }
+        !self.is_tail(curr) && unsafe { curr.value.assume_init_ref() } == value
+    }
+
+    /// The values not marked for deletion, in order, from the bottom level.
+    /// Under concurrent updates it is not a snapshot.
+    pub fn iter(&self) -> impl Iterator<Item = &T> + '_ {
+        let mut node = self.head_node();
+        std::iter::from_fn(move || loop {
+            node = node.next[0].load(Ordering::Acquire).get_ref();
+            if self.is_tail(node) {
+                return None;
+            }
+            if !node.next[0].load(Ordering::Acquire).get_flag_a() {
+                return Some(unsafe { node.value.assume_init_ref() });
+            }
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    // Fills, at every level, the last node below `value` and the one after
+    // it, unlinking the marked nodes on the way. Returns whether the one
+    // after it at the bottom holds `value`.
+    fn find(&self, value: &T, preds: &mut Path<T>, succs: &mut Path<T>) -> bool {
+        'retry: loop {
+            let mut pred = self.head_node();
+            for level in (0..MAX_HEIGHT).rev() {
+                let mut curr = pred.next[level].load(Ordering::Acquire).get_ref();
+                loop {
+                    let next = curr.next[level].load(Ordering::Acquire);
+                    if next.get_flag_a() {
+                        let expected = RefWith2Flags::new(curr, false, false);
+                        let succ = RefWith2Flags::new(next.get_ref(), false, false);
+                        if pred.next[level].compare_exchange(&expected, succ, Ordering::AcqRel, Ordering::Acquire).is_err() {
+                            // pred is marked itself, or changed.
+                            continue 'retry;
+                        }
+                        curr = next.get_ref();
+                        continue;
+                    }
+                    if self.is_tail(curr) || unsafe { curr.value.assume_init_ref() } >= value {
+                        break;
+                    }
+                    pred = curr;
+                    curr = next.get_ref();
+                }
+                preds[level] = pred;
+                succs[level] = curr;
+            }
+            return !self.is_tail(succs[0]) && unsafe { succs[0].value.assume_init_ref() } == value;
+        }
+    }
+
+    // 1 with probability 1/2, 2 with 1/4, and so on, up to MAX_HEIGHT.
+    fn random_height(&self) -> usize {
+        let mut z = self.seed.fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed).wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        (z.trailing_ones() as usize + 1).min(MAX_HEIGHT)
+    }
+
+    fn retire(&self, node: &Node<T>) {
+        let mut top = self.retired.load(Ordering::Relaxed);
+        loop {
+            node.retired_next.store(top, Ordering::Relaxed);
+            match self.retired.compare_exchange_weak(top, node.raw, Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => return,
+                Err(actual) => top = actual,
+            }
+        }
+    }
+
+}
+
+impl<T: 'static> SkipList<T> {
+
+    /// Unlinks the removed nodes still linked at some level, and frees them.
+    pub fn collect(&mut self) {
+        let head = self.head_node();
+        for level in 0..MAX_HEIGHT {
+            // No other thread is in the list, plain stores are enough.
+            let mut pred = head;
+            loop {
+                let curr = pred.next[level].load(Ordering::Relaxed).get_ref();
+                if self.is_tail(curr) {
+                    break;
+                }
+                if curr.next[0].load(Ordering::Relaxed).get_flag_a() {
+                    let succ = curr.next[level].load(Ordering::Relaxed).get_ref();
+                    pred.next[level].store(RefWith2Flags::new(succ, false, false), Ordering::Relaxed);
+                } else {
+                    pred = curr;
+                }
+            }
+        }
+        let mut node = self.retired.swap(ptr::null_mut(), Ordering::Acquire);
+        while !node.is_null() {
+            let boxed = unsafe { Box::from_raw(node) };
+            node = boxed.retired_next.load(Ordering::Relaxed);
+            drop(unsafe { boxed.value.assume_init() });
+        }
+    }
+
+    fn head_node(&self) -> &'static Node<T> {
+        unsafe { &*self.head }
+    }
+
+    fn is_tail(&self, node: &Node<T>) -> bool {
+        ptr::eq(node, self.tail)
+    }
+
+    // `next` None makes the node point to itself at every level.
+    fn alloc_node(value: MaybeUninit<T>, height: usize, next: Option<*mut Node<T>>) -> *mut Node<T> {
+        let raw = Box::into_raw(Box::<Node<T>>::new_uninit()).cast::<Node<T>>();
+        let next = (0..height)
+            .map(|_| AtomicTaggedPtr::new(unsafe { RefWith2Flags::from_tagged_ptr(next.unwrap_or(raw)) }))
+            .collect();
+        let node = Node { value, next, retired_next: AtomicPtr::new(ptr::null_mut()), raw };
+        unsafe { raw.write(node) };
+        raw
+    }
+
+}
+
+impl<T: Ord + 'static> Default for SkipList<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: 'static> Drop for SkipList<T> {
+    fn drop(&mut self) {
+        self.collect();
+        // What is left at the bottom is not marked.
+        let mut node = self.head_node().next[0].load(Ordering::Relaxed).get_ref().raw;
+        while node != self.tail {
+            let boxed = unsafe { Box::from_raw(node) };
+            node = boxed.next[0].load(Ordering::Relaxed).get_ref().raw;
+            drop(unsafe { boxed.value.assume_init() });
+        }
+        drop(unsafe { Box::from_raw(self.head) });
+        drop(unsafe { Box::from_raw(self.tail) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // The values at each level, after a collect().
+    fn levels(list: &SkipList<u32>) -> Vec<Vec<u32>> {
+        (0..MAX_HEIGHT)
+            .map(|level| {
+                let mut values = Vec::new();
+                let mut node = list.head_node().next[level].load(Ordering::Relaxed);
+                while !list.is_tail(node.get_ref()) {
+                    assert!(!node.get_flag_a());
+                    values.push(unsafe { *node.get_ref().value.assume_init_ref() });
+                    node = node.get_ref().next[level].load(Ordering::Relaxed);
+                }
+                values
+            })
+            .collect()
+    }
+
+    #[test]
+    fn concurrent_inserts_and_removes_keep_every_level_sorted() {
+        let mut list = SkipList::new();
+        thread::scope(|s| {
+            for t in 0..4 {
+                let list = &list;
+                s.spawn(move || {
+                    for i in (t..2000).step_by(4) {
+                        assert!(list.insert(i));
+                    }
+                    for i in (t..2000).step_by(4).filter(|i| i % 3 == 0) {
+                        assert!(list.remove(&i));
+                    }
+                });
+            }
+        });
+        let expected: Vec<u32> = (0..2000).filter(|i| i % 3 != 0).collect();
+        assert_eq!(list.iter().copied().collect::<Vec<_>>(), expected);
+        assert!(list.contains(&1) && !list.contains(&3) && !list.contains(&2000));
+        list.collect();
+        let levels = levels(&list);
+        assert_eq!(levels[0], expected);
+        for level in &levels[1..] {
+            assert!(level.windows(2).all(|pair| pair[0] < pair[1]));
+            assert!(level.iter().all(|value| expected.binary_search(value).is_ok()));
+        }
+        assert!(levels[1].len() > expected.len() / 4);
+    }
+
+    #[test]
+    fn racing_inserts_and_removes_of_one_value() {
+        let list = SkipList::new();
+        for _ in 0..100 {
+            let (inserted, removed) = thread::scope(|s| {
+                let inserts: Vec<_> = (0..2).map(|_| s.spawn(|| list.insert(7) as u32)).collect();
+                let removes: Vec<_> = (0..2).map(|_| s.spawn(|| list.remove(&7) as u32)).collect();
+                let inserted: u32 = inserts.into_iter().map(|h| h.join().unwrap()).sum();
+                (inserted, removes.into_iter().map(|h| h.join().unwrap()).sum::<u32>())
+            });
+            assert_eq!(inserted - removed, list.contains(&7) as u32);
+            list.remove(&7);
+        }
+    }
+}
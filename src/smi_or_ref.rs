@@ -0,0 +1,131 @@
+// Name: SmiOrRef - a small integer or a reference in one word, V8 style
+//       SMI tagging.
+//
+// Description: The value representation of V8 and of many other VMs: bit 0
+//              tells an inline integer, a SMI, from a reference,
+//
+//                 bit 0 = 0 - a SMI, the integer is the word shifted right
+//                             by 1, 63 bits on 64 bit targets, 31 on 32 bit
+//                             ones.
+//                 bit 0 = 1 - a &T, the word is its address plus 1.
+//
+//              The integers get the 0 tag so that adding or comparing 2 SMIs
+//              works on the words directly, the tag of the sum is 0 again.
+//              The reference pays for it, its tag must be taken off before a
+//              load, which is free in an addressing mode on real hardware. T
+//              must be at least 2 bytes aligned, for the tag bit.
+//
+//              int() panics on an integer outside MIN_INT..=MAX_INT,
+//              try_int() returns None instead.
+
+use core::marker::PhantomData;
+use core::ptr;
+
+use crate::aligned::AlignedAtLeast;
+
+const REF: usize = 1;
+
+#[repr(transparent)]
+pub struct SmiOrRef<'a, T> {
+    // A pointer, with the provenance of the reference or none for a SMI.
+    word: *const T,
+    behaves_like: PhantomData<&'a T>,
+}
+
+// The raw pointer opts out of Send and Sync, this is an isize or a &T as far
+// as threads go.
+unsafe impl<'a, T: Sync> Send for SmiOrRef<'a, T> {}
+unsafe impl<'a, T: Sync> Sync for SmiOrRef<'a, T> {}
+
+impl<'a, T: 'a> SmiOrRef<'a, T> {
+
+    pub const MIN_INT: isize = isize::MIN >> 1;
+    pub const MAX_INT: isize = isize::MAX >> 1;
+
+    /// Panics if `value` is outside MIN_INT..=MAX_INT.
+    pub fn int(value: isize) -> SmiOrRef<'a, T> {
+        Self::try_int(value).expect("integer doesn't fit in a SMI")
+    }
+
+    /// None if `value` is outside MIN_INT..=MAX_INT.
+    pub fn try_int(value: isize) -> Option<SmiOrRef<'a, T>> {
+        if !(Self::MIN_INT..=Self::MAX_INT).contains(&value) {
+            return None;
+        }
+        Some(SmiOrRef { word: ptr::without_provenance((value << 1) as usize), behaves_like: PhantomData })
+    }
+
+    pub fn from_ref(reference: &'a T) -> SmiOrRef<'a, T>
+    where
+        T: AlignedAtLeast<2>,
+    {
+        SmiOrRef { word: ptr::from_ref(reference).map_addr(|addr| addr | REF), behaves_like: PhantomData }
+    }
+
+    pub fn is_int(&self) -> bool {
+        self.word.addr() & REF == 0
+    }
+
+    pub fn is_ref(&self) -> bool {
+        !self.is_int()
+    }
+
+    pub fn as_int(&self) -> Option<isize> {
+        self.is_int().then(|| self.word.addr() as isize >> 1)
+    }
+
+    pub fn as_ref(&self) -> Option<&'a T> {
+        // Only from_ref() sets REF, with the address of a &'a T.
+        self.is_ref().then(|| unsafe { &*self.word.map_addr(|addr| addr & !REF) })
+    }
+
+    /// The sum of 2 SMIs, on the words. None if one of them is a reference
+    /// or the sum doesn't fit in a SMI.
+    pub fn checked_add(&self, other: &SmiOrRef<'a, T>) -> Option<SmiOrRef<'a, T>> {
+        if !(self.is_int() && other.is_int()) {
+            return None;
+        }
+        let sum = (self.word.addr() as isize).checked_add(other.word.addr() as isize)?;
+        Some(SmiOrRef { word: ptr::without_provenance(sum as usize), behaves_like: PhantomData })
+    }
+
+}
+
+impl<'a, T> Clone for SmiOrRef<'a, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, T> Copy for SmiOrRef<'a, T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aligned_box::Align16;
+
+    #[test]
+    fn smis_add_on_the_words() {
+        let closure = Align16(9u16);
+        let stack = [SmiOrRef::int(40), SmiOrRef::int(2), SmiOrRef::from_ref(&closure)];
+        assert_eq!(stack[0].checked_add(&stack[1]).and_then(|sum| sum.as_int()), Some(42));
+        assert!(stack[0].checked_add(&stack[2]).is_none() && stack[2].as_int().is_none());
+        assert_eq!(stack[2].as_ref().map(|slot| slot.0), Some(9));
+        let max = SmiOrRef::<Align16<u16>>::int(SmiOrRef::<Align16<u16>>::MAX_INT);
+        assert!(max.checked_add(&SmiOrRef::int(1)).is_none() && SmiOrRef::<Align16<u16>>::try_int(isize::MIN).is_none());
+        assert_eq!(SmiOrRef::<Align16<u16>>::int(-7).as_int(), Some(-7));
+    }
+
+    #[test]
+    fn the_int_range_edges() {
+        type Smi<'a> = SmiOrRef<'a, Align16<u16>>;
+        for int in [Smi::MIN_INT, -1, 0, 1, Smi::MAX_INT] {
+            assert_eq!(Smi::int(int).as_int(), Some(int));
+            assert!(Smi::int(int).is_int() && !Smi::int(int).is_ref());
+        }
+        assert!(Smi::try_int(Smi::MAX_INT + 1).is_none() && Smi::try_int(Smi::MIN_INT - 1).is_none());
+        let min = Smi::int(Smi::MIN_INT);
+        assert!(min.checked_add(&Smi::int(-1)).is_none());
+        assert_eq!(min.checked_add(&Smi::int(Smi::MAX_INT)).and_then(|sum| sum.as_int()), Some(-1));
+    }
+}
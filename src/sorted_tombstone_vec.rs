@@ -0,0 +1,182 @@
+// Name: SortedTombstoneVec - a sorted Vec of tagged references with lazy
+//       deletion.
+//
+// Description: Every entry is a RefWith2Flags and flag_a marks the entry
+//              as a tombstone (logically deleted). Removing a value only
+//              sets that bit, so there is no shifting of the Vec and no
+//              separate bitmap. Lookups are binary searches that skip the
+//              tombstones, and the dead entries are physically removed by
+//              compact(), that is called automatically when more than half
+//              of the entries are tombstones, so the cost is amortized.
+//
+//              Tombstoned entries still point to valid values, so they keep
+//              the Vec sorted and the binary search can still use them as
+//              pivots.
+//
+//              flag_b is not used by the collection and is kept as is.
+
+use crate::aligned::AlignedAtLeast;
+use crate::ref_with_2_flags::RefWith2Flags;
+
+pub struct SortedTombstoneVec<'a, T> {
+    entries: Vec<RefWith2Flags<'a, T>>,
+    tombstones: usize,
+}
+
+impl<'a, T: Ord + AlignedAtLeast<4> + 'a> SortedTombstoneVec<'a, T> {
+
+    pub fn new() -> SortedTombstoneVec<'a, T> {
+        SortedTombstoneVec {
+            entries: Vec::new(),
+            tombstones: 0,
+        }
+    }
+
+    /// Number of live (not tombstoned) entries.
+    pub fn len(&self) -> usize {
+        self.entries.len() - self.tombstones
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of tombstoned entries still occupying a slot.
+    pub fn tombstones(&self) -> usize {
+        self.tombstones
+    }
+
+    /// Inserts after any equal values, so equal values keep insertion order.
+    pub fn insert(&mut self, value: &'a T) {
+        let pos = self.entries.partition_point(|e| e.get_ref() <= value);
+        self.entries.insert(pos, RefWith2Flags::new(value, false, false));
+    }
+
+    /// Returns the first live entry equal to `value`.
+    pub fn find(&self, value: &T) -> Option<&'a T> {
+        self.find_index(value).map(|i| self.entries[i].get_ref())
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        self.find_index(value).is_some()
+    }
+
+    /// Marks the first live entry equal to `value` as a tombstone.
+    /// Returns false if there was no such entry.
+    pub fn remove(&mut self, value: &T) -> bool {
+        let index = match self.find_index(value) {
+            Some(index) => index,
+            None => return false,
+        };
+        let entry = &self.entries[index];
+        self.entries[index] = RefWith2Flags::new(entry.get_ref(), true, entry.get_flag_b());
+        self.tombstones += 1;
+        if self.tombstones * 2 > self.entries.len() {
+            self.compact();
+        }
+        true
+    }
+
+    /// Physically removes all the tombstoned entries.
+    pub fn compact(&mut self) {
+        if self.tombstones == 0 {
+            return;
+        }
+        self.entries.retain(|e| !e.get_flag_a());
+        self.tombstones = 0;
+    }
+
+    /// Iterates over the live entries in sorted order.
+    pub fn iter(&self) -> impl Iterator<Item = &'a T> + '_ {
+        self.entries
+            .iter()
+            .filter(|e| !e.get_flag_a())
+            .map(|e| e.get_ref())
+    }
+
+    fn find_index(&self, value: &T) -> Option<usize> {
+        let start = self.entries.partition_point(|e| e.get_ref() < value);
+        self.entries[start..]
+            .iter()
+            .take_while(|e| e.get_ref() == value)
+            .position(|e| !e.get_flag_a())
+            .map(|offset| start + offset)
+    }
+
+}
+
+impl<'a, T: Ord + AlignedAtLeast<4> + 'a> Default for SortedTombstoneVec<'a, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn lazy_deletion_and_compaction() {
+        let values = [50, 10, 40, 20, 30];
+        let mut index = SortedTombstoneVec::new();
+        for v in values.iter() {
+            index.insert(v);
+        }
+        assert!(index.remove(&40));
+        assert!(!index.contains(&40));
+        assert_eq!(index.find(&20), Some(&20));
+        assert_eq!(index.len(), 4);
+        assert_eq!(index.tombstones(), 1);
+        index.compact();
+        assert_eq!(index.tombstones(), 0);
+        assert!(!index.is_empty());
+        assert_eq!(index.iter().copied().collect::<Vec<_>>(), vec![10, 20, 30, 50]);
+    }
+
+    #[test]
+    fn matches_a_counted_btree_map() {
+        let keys: Vec<u32> = (0..200).collect();
+        let mut index = SortedTombstoneVec::new();
+        let mut model: BTreeMap<u32, usize> = BTreeMap::new();
+        let mut seed = 2024u32;
+        for _ in 0..4000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let key = (seed >> 16) % 200;
+            if (seed >> 8) % 3 < 2 {
+                index.insert(&keys[key as usize]);
+                *model.entry(key).or_insert(0) += 1;
+            } else {
+                let count = model.get(&key).copied().unwrap_or(0);
+                assert_eq!(index.remove(&key), count > 0);
+                match count {
+                    0 => {}
+                    1 => { model.remove(&key); }
+                    _ => { *model.get_mut(&key).unwrap() -= 1; }
+                }
+            }
+            // compact() runs before the tombstones outnumber the live entries.
+            assert!(index.tombstones() <= index.len() + 1);
+            assert_eq!(index.contains(&key), model.contains_key(&key));
+        }
+        assert_eq!(index.len(), model.values().sum::<usize>());
+        let expected = model.iter().flat_map(|(k, n)| std::iter::repeat_n(*k, *n));
+        assert!(index.iter().copied().eq(expected));
+    }
+
+    #[test]
+    fn empty_and_single_entry() {
+        let mut index: SortedTombstoneVec<u32> = SortedTombstoneVec::default();
+        assert!(index.is_empty());
+        assert!(!index.remove(&1));
+        assert_eq!(index.find(&1), None);
+        index.compact();
+        let one = 1;
+        index.insert(&one);
+        assert_eq!(index.find(&1), Some(&1));
+        // One tombstone out of one entry is more than half, so it compacts at once.
+        assert!(index.remove(&1));
+        assert_eq!((index.len(), index.tombstones()), (0, 0));
+        assert_eq!(index.iter().next(), None);
+    }
+}
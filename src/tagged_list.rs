@@ -0,0 +1,372 @@
+// Name: List - an intrusive singly linked list, 2 user flags in each next
+//       link.
+//
+// Description: Intrusive: the list doesn't allocate nodes, each element
+//              embeds its own Link, and the list only borrows the elements,
+//              for 'a. The element type is at least 4 bytes aligned, so the
+//              next address in a Link has 2 free low bits, and they are 2
+//              per element flags, free for the user (e.g. "pinned" and
+//              "dirty"):
+//
+//                 bit 0 - flag a
+//                 bit 1 - flag b
+//
+//              The flags belong to the element, not to its position, they
+//              stay as they are when it's linked, moved or unlinked, and the
+//              last element keeps its flags next to a null address, like an
+//              OptionRefWith2Flags None. The Link is a Cell, so the flags can
+//              be set through a shared reference, from a Cursor while walking
+//              the list, and CursorMut inserts and removes at its position.
+//
+//              An element is in at most one list at a time: pushing one that
+//              is already linked rewrites its next link and corrupts the
+//              other list (a cycle or a lost tail, no undefined behaviour).
+//
+//              The word of a Link is a pointer, made from the &'a T of the
+//              next element with the flags ORed in by map_addr, so next()
+//              hands back a reference with that element's provenance. A
+//              Link with no next element holds only the flags, a pointer
+//              without provenance.
+
+use core::cell::Cell;
+use core::marker::PhantomData;
+use core::ptr;
+
+use crate::aligned::AlignedAtLeast;
+
+const FLAG_A: usize = 1;
+const FLAG_B: usize = 2;
+
+pub struct Link<'a, T> {
+    // The next element, plus the flags.
+    word: Cell<*const T>,
+    behaves_like: PhantomData<Cell<Option<&'a T>>>,
+}
+
+// The raw pointer opts out of Send, this is a Cell<Option<&T>> as far as
+// threads go.
+unsafe impl<'a, T: Sync> Send for Link<'a, T> {}
+
+/// An element type that embeds a Link to its own type.
+pub trait Linked<'a>: AlignedAtLeast<4> + Sized + 'a {
+    fn link(&self) -> &Link<'a, Self>;
+}
+
+impl<'a, T> Link<'a, T> {
+
+    pub const fn new(flag_a: bool, flag_b: bool) -> Link<'a, T> {
+        Link { word: Cell::new(ptr::without_provenance(flag_a as usize | ((flag_b as usize) << 1))), behaves_like: PhantomData }
+    }
+
+    pub fn get_flag_a(&self) -> bool {
+        self.word.get().addr() & FLAG_A != 0
+    }
+
+    pub fn get_flag_b(&self) -> bool {
+        self.word.get().addr() & FLAG_B != 0
+    }
+
+    pub fn set_flag_a(&self, flag: bool) {
+        self.word.set(self.word.get().map_addr(|addr| (addr & !FLAG_A) | flag as usize));
+    }
+
+    pub fn set_flag_b(&self, flag: bool) {
+        self.word.set(self.word.get().map_addr(|addr| (addr & !FLAG_B) | ((flag as usize) << 1)));
+    }
+
+    fn next(&self) -> Option<&'a T> {
+        // Only ever set by set_next() from a &'a T.
+        unsafe { self.word.get().map_addr(|addr| addr & !3).as_ref() }
+    }
+
+    // Keeps the flags.
+    fn set_next(&self, next: Option<&'a T>) {
+        let flags = self.word.get().addr() & 3;
+        self.word.set(next.map_or(ptr::null(), ptr::from_ref).map_addr(|addr| addr | flags));
+    }
+
+}
+
+impl<'a, T> Default for Link<'a, T> {
+    fn default() -> Self {
+        Self::new(false, false)
+    }
+}
+
+pub struct List<'a, T> {
+    head: Option<&'a T>,
+    len: usize,
+}
+
+pub struct Cursor<'l, 'a, T> {
+    current: Option<&'a T>,
+    list: PhantomData<&'l List<'a, T>>,
+}
+
+pub struct CursorMut<'l, 'a, T> {
+    list: &'l mut List<'a, T>,
+    prev: Option<&'a T>,
+    current: Option<&'a T>,
+}
+
+impl<'a, T: Linked<'a>> List<'a, T> {
+
+    pub const fn new() -> List<'a, T> {
+        List { head: None, len: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.is_none()
+    }
+
+    pub fn front(&self) -> Option<&'a T> {
+        self.head
+    }
+
+    pub fn push_front(&mut self, element: &'a T) {
+        element.link().set_next(self.head);
+        self.head = Some(element);
+        self.len += 1;
+    }
+
+    /// Unlinks the first element, its flags are kept.
+    pub fn pop_front(&mut self) -> Option<&'a T> {
+        let head = self.head?;
+        self.head = head.link().next();
+        head.link().set_next(None);
+        self.len -= 1;
+        Some(head)
+    }
+
+    pub fn cursor(&self) -> Cursor<'_, 'a, T> {
+        Cursor { current: self.head, list: PhantomData }
+    }
+
+    pub fn cursor_mut(&mut self) -> CursorMut<'_, 'a, T> {
+        let current = self.head;
+        CursorMut { list: self, prev: None, current }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &'a T> + '_ {
+        let mut cursor = self.cursor();
+        core::iter::from_fn(move || {
+            let current = cursor.current()?;
+            cursor.move_next();
+            Some(current)
+        })
+    }
+
+}
+
+impl<'a, T: Linked<'a>> Default for List<'a, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'l, 'a, T: Linked<'a>> Cursor<'l, 'a, T> {
+
+    /// None past the end.
+    pub fn current(&self) -> Option<&'a T> {
+        self.current
+    }
+
+    /// The flags of the current element, None past the end.
+    pub fn flags(&self) -> Option<(bool, bool)> {
+        let link = self.current?.link();
+        Some((link.get_flag_a(), link.get_flag_b()))
+    }
+
+    /// Does nothing past the end.
+    pub fn set_flag_a(&self, flag: bool) {
+        if let Some(current) = self.current {
+            current.link().set_flag_a(flag);
+        }
+    }
+
+    /// Does nothing past the end.
+    pub fn set_flag_b(&self, flag: bool) {
+        if let Some(current) = self.current {
+            current.link().set_flag_b(flag);
+        }
+    }
+
+    /// Does nothing past the end.
+    pub fn move_next(&mut self) {
+        self.current = self.current.and_then(|current| current.link().next());
+    }
+
+}
+
+impl<'l, 'a, T: Linked<'a>> CursorMut<'l, 'a, T> {
+
+    /// None past the end.
+    pub fn current(&self) -> Option<&'a T> {
+        self.current
+    }
+
+    /// The flags of the current element, None past the end.
+    pub fn flags(&self) -> Option<(bool, bool)> {
+        let link = self.current?.link();
+        Some((link.get_flag_a(), link.get_flag_b()))
+    }
+
+    /// Does nothing past the end.
+    pub fn move_next(&mut self) {
+        if let Some(current) = self.current {
+            self.prev = Some(current);
+            self.current = current.link().next();
+        }
+    }
+
+    /// Unlinks the current element, keeping its flags, and moves to the
+    /// next one. None past the end.
+    pub fn remove_current(&mut self) -> Option<&'a T> {
+        let current = self.current?;
+        let next = current.link().next();
+        match self.prev {
+            Some(prev) => prev.link().set_next(next),
+            None => self.list.head = next,
+        }
+        current.link().set_next(None);
+        self.current = next;
+        self.list.len -= 1;
+        Some(current)
+    }
+
+    /// Links `element` before the current one (at the end past the end),
+    /// it becomes the current element.
+    pub fn insert(&mut self, element: &'a T) {
+        element.link().set_next(self.current);
+        match self.prev {
+            Some(prev) => prev.link().set_next(Some(element)),
+            None => self.list.head = Some(element),
+        }
+        self.current = Some(element);
+        self.list.len += 1;
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A cache page, linked through its own link.
+    #[repr(align(4))]
+    struct Page<'a> {
+        link: Link<'a, Page<'a>>,
+        number: u32,
+    }
+
+    crate::aligned_at_least!(Page<'_> => 4);
+
+    impl<'a> Linked<'a> for Page<'a> {
+        fn link(&self) -> &Link<'a, Page<'a>> {
+            &self.link
+        }
+    }
+
+    #[test]
+    fn evicts_by_the_flags_in_the_links() {
+        let pages: Vec<Page> = (0..5).map(|number| Page { link: Link::default(), number }).collect();
+        let mut list = List::new();
+        for page in pages.iter().rev() {
+            list.push_front(page);
+        }
+        pages[1].link.set_flag_a(true);
+        let mut cursor = list.cursor();
+        while let Some(page) = cursor.current() {
+            if page.number % 2 == 0 {
+                cursor.set_flag_b(true);
+            }
+            cursor.move_next();
+        }
+        // Evicts the unpinned dirty pages, the flags stay with them.
+        let mut cursor = list.cursor_mut();
+        let mut evicted = Vec::new();
+        while let Some((pinned, dirty)) = cursor.flags() {
+            if dirty && !pinned {
+                evicted.push(cursor.remove_current().unwrap().number);
+            } else {
+                cursor.move_next();
+            }
+        }
+        cursor.insert(&pages[4]);
+        assert_eq!(evicted, [0, 2, 4]);
+        assert!(list.iter().map(|page| page.number).eq([1, 3, 4]));
+        assert!(pages[4].link.get_flag_b() && pages[1].link.get_flag_a() && list.len() == 3);
+    }
+
+    #[test]
+    fn random_edits_match_a_vec() {
+        let pages: Vec<Page> = (0..32).map(|number| Page { link: Link::default(), number }).collect();
+        let mut list = List::new();
+        // The model: the numbers in list order, and every page's flags.
+        let mut model: Vec<u32> = Vec::new();
+        let mut flags = [(false, false); 32];
+        let mut seed = 2940u32;
+        for _ in 0..3000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let number = (seed >> 16) % 32;
+            let at = if model.is_empty() { 0 } else { (seed >> 24) as usize % (model.len() + 1) };
+            match (seed >> 8) % 5 {
+                0 if !model.contains(&number) => {
+                    list.push_front(&pages[number as usize]);
+                    model.insert(0, number);
+                }
+                1 if !model.contains(&number) => {
+                    let mut cursor = list.cursor_mut();
+                    (0..at).for_each(|_| cursor.move_next());
+                    cursor.insert(&pages[number as usize]);
+                    model.insert(at, number);
+                }
+                2 => {
+                    let popped = list.pop_front().map(|page| page.number);
+                    assert_eq!(popped, (!model.is_empty()).then(|| model.remove(0)));
+                }
+                3 => {
+                    let mut cursor = list.cursor_mut();
+                    (0..at).for_each(|_| cursor.move_next());
+                    let removed = cursor.remove_current().map(|page| page.number);
+                    assert_eq!(removed, (at < model.len()).then(|| model.remove(at)));
+                }
+                _ => {
+                    let (a, b) = (seed & 1 != 0, seed & 2 != 0);
+                    pages[number as usize].link.set_flag_a(a);
+                    pages[number as usize].link.set_flag_b(b);
+                    flags[number as usize] = (a, b);
+                }
+            }
+            assert!(list.iter().map(|page| page.number).eq(model.iter().copied()));
+            assert_eq!((list.len(), list.is_empty()), (model.len(), model.is_empty()));
+        }
+        let mut cursor = list.cursor();
+        while let Some(page) = cursor.current() {
+            assert_eq!(cursor.flags(), Some(flags[page.number as usize]));
+            cursor.move_next();
+        }
+        assert!(pages.iter().all(|page| (page.link.get_flag_a(), page.link.get_flag_b()) == flags[page.number as usize]));
+    }
+
+    #[test]
+    fn an_empty_list_and_a_single_page() {
+        let mut list: List<Page> = List::new();
+        assert!(list.pop_front().is_none() && list.front().is_none() && list.iter().next().is_none());
+        let mut cursor = list.cursor_mut();
+        assert!(cursor.remove_current().is_none() && cursor.flags().is_none());
+        cursor.move_next();
+        let only = Page { link: Link::new(true, false), number: 7 };
+        list.cursor_mut().insert(&only);
+        assert!(list.front().is_some_and(|page| page.number == 7));
+        let cursor = list.cursor();
+        cursor.set_flag_b(true);
+        assert_eq!(cursor.flags(), Some((true, true)));
+        assert!(std::ptr::eq(list.pop_front().unwrap(), &only) && list.is_empty());
+        assert!(only.link.get_flag_a() && only.link.get_flag_b());
+    }
+}
@@ -0,0 +1,216 @@
+// Name: TaggedMutex - a mutex whose lock word is the tagged data pointer,
+//       with poisoning.
+//
+// Description: Like std::sync::Mutex, lock() returns a guard and a panic
+//              while holding it poisons the mutex, later lock() calls return
+//              the guard inside a PoisonError. The whole state is one atomic
+//              word, the address of the boxed data plus 2 bits:
+//
+//                 bit 0 - LOCKED   : a guard exists.
+//                 bit 1 - POISONED : a guard was dropped during a panic.
+//
+//              So a mutex per node costs one word. There is no room left for
+//              a contended bit, a waiting thread spins a little and then
+//              yields, which suits short critical sections. For parking, see
+//              WordMutex.
+//
+//              The data is boxed in the same Align8 cell as WordMutex's, and
+//              the word is an AtomicPtr to it: the bits come and go with its
+//              fetch_or and fetch_and, which keep the pointer's provenance.
+
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::{LockResult, PoisonError, TryLockError, TryLockResult};
+use std::thread;
+
+use crate::aligned_box::Align8;
+use crate::bitpack::untag_ptr;
+
+const LOCKED: usize = 1;
+const POISONED: usize = 2;
+const BITS: usize = LOCKED | POISONED;
+
+const SPINS: u32 = 64;
+
+pub struct TaggedMutex<T> {
+    word: AtomicPtr<Align8<UnsafeCell<T>>>,
+    owns: PhantomData<Box<Align8<UnsafeCell<T>>>>,
+}
+
+pub struct TaggedMutexGuard<'m, T> {
+    mutex: &'m TaggedMutex<T>,
+    // A panic that was already unwinding at lock time doesn't poison.
+    panicking: bool,
+    // Sync only if T is, like a &mut T.
+    behaves_like: PhantomData<&'m mut T>,
+}
+
+unsafe impl<T: Send> Send for TaggedMutex<T> {}
+unsafe impl<T: Send> Sync for TaggedMutex<T> {}
+
+impl<T> TaggedMutex<T> {
+
+    pub fn new(value: T) -> TaggedMutex<T> {
+        let data = Box::into_raw(Box::new(Align8(UnsafeCell::new(value))));
+        debug_assert_eq!(data.addr() & BITS, 0);
+        TaggedMutex { word: AtomicPtr::new(data), owns: PhantomData }
+    }
+
+    pub fn lock(&self) -> LockResult<TaggedMutexGuard<'_, T>> {
+        let mut spins = 0;
+        loop {
+            match self.try_lock() {
+                Ok(guard) => return Ok(guard),
+                Err(TryLockError::Poisoned(error)) => return Err(error),
+                Err(TryLockError::WouldBlock) if spins < SPINS => {
+                    spins += 1;
+                    std::hint::spin_loop();
+                }
+                Err(TryLockError::WouldBlock) => thread::yield_now(),
+            }
+        }
+    }
+
+    pub fn try_lock(&self) -> TryLockResult<TaggedMutexGuard<'_, T>> {
+        let word = self.word.fetch_or(LOCKED, Ordering::Acquire);
+        if word.addr() & LOCKED != 0 {
+            return Err(TryLockError::WouldBlock);
+        }
+        let guard = TaggedMutexGuard { mutex: self, panicking: thread::panicking(), behaves_like: PhantomData };
+        if word.addr() & POISONED != 0 {
+            Err(TryLockError::Poisoned(PoisonError::new(guard)))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.word.load(Ordering::Relaxed).addr() & POISONED != 0
+    }
+
+    pub fn clear_poison(&self) {
+        self.word.fetch_and(!POISONED, Ordering::Relaxed);
+    }
+
+    pub fn into_inner(self) -> LockResult<T> {
+        let word = self.word.load(Ordering::Relaxed);
+        std::mem::forget(self);
+        let value = unsafe { Box::from_raw(untag_ptr(word, 2)).0.into_inner() };
+        if word.addr() & POISONED != 0 { Err(PoisonError::new(value)) } else { Ok(value) }
+    }
+
+    fn data(&self) -> *mut T {
+        let data = untag_ptr(self.word.load(Ordering::Relaxed), 2);
+        unsafe { (*data).0.get() }
+    }
+
+}
+
+impl<T> Drop for TaggedMutex<T> {
+    fn drop(&mut self) {
+        drop(unsafe { Box::from_raw(untag_ptr(*self.word.get_mut(), 2)) });
+    }
+}
+
+impl<T: Default> Default for TaggedMutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<'m, T> Deref for TaggedMutexGuard<'m, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data() }
+    }
+}
+
+impl<'m, T> DerefMut for TaggedMutexGuard<'m, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data() }
+    }
+}
+
+impl<'m, T: fmt::Debug> fmt::Debug for TaggedMutexGuard<'m, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'m, T> Drop for TaggedMutexGuard<'m, T> {
+    fn drop(&mut self) {
+        let poison = if !self.panicking && thread::panicking() { POISONED } else { 0 };
+        if poison != 0 {
+            self.mutex.word.fetch_or(poison, Ordering::Relaxed);
+        }
+        self.mutex.word.fetch_and(!LOCKED, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::TryLockError;
+    use std::thread;
+
+    #[test]
+    fn locks_and_poisons() {
+        let shared = TaggedMutex::new(vec![1, 2, 3]);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| shared.lock().unwrap().push(4));
+            }
+        });
+        assert_eq!(shared.lock().unwrap().len(), 7);
+        assert!(shared.try_lock().is_ok());
+        let held = shared.lock().unwrap();
+        assert!(matches!(shared.try_lock(), Err(TryLockError::WouldBlock)));
+        drop(held);
+        let panicked = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _guard = shared.lock().unwrap();
+            panic!("poison the mutex");
+        }));
+        assert!(panicked.is_err() && shared.is_poisoned());
+        assert_eq!(shared.lock().unwrap_err().into_inner().len(), 7);
+        shared.clear_poison();
+        assert_eq!(shared.into_inner().unwrap().len(), 7);
+        assert!(!TaggedMutex::<u8>::default().is_poisoned());
+    }
+
+    #[test]
+    fn a_lock_taken_while_unwinding_does_not_poison() {
+        // A lock taken in a destructor that runs during an unwind isn't what
+        // panicked, so it leaves the mutex clean.
+        struct Logger<'m>(&'m TaggedMutex<Vec<&'static str>>);
+        impl Drop for Logger<'_> {
+            fn drop(&mut self) {
+                self.0.lock().unwrap().push("unwound");
+            }
+        }
+        let log = TaggedMutex::new(Vec::new());
+        let panicked = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _logger = Logger(&log);
+            panic!("unrelated");
+        }));
+        assert!(panicked.is_err() && !log.is_poisoned());
+        assert_eq!(log.into_inner().unwrap(), vec!["unwound"]);
+    }
+
+    #[test]
+    fn one_word_whatever_the_data_and_poisoned_into_inner() {
+        assert_eq!(size_of::<TaggedMutex<u8>>(), size_of::<usize>());
+        assert_eq!(size_of::<TaggedMutex<[u64; 16]>>(), size_of::<usize>());
+        let poisoned = TaggedMutex::new(String::from("kept"));
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut guard = poisoned.lock().unwrap();
+            guard.push('!');
+            panic!("poison");
+        }));
+        assert_eq!(poisoned.into_inner().unwrap_err().into_inner(), "kept!");
+    }
+}
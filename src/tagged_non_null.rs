@@ -0,0 +1,205 @@
+// Name: TaggedNonNull - a raw NonNull<T> with 2 flags in its low bits.
+//
+// Description: The unsafe, lifetime free variant of RefWith2Flags, for code
+//              that manages the memory itself (arenas, intrusive structures,
+//              FFI). It is Copy like NonNull, and the address arithmetic
+//              carries the 2 tag bits through unchanged, so walking an array
+//              of elements doesn't need a strip, offset, re-tag at each step,
+//              and the distance between 2 tagged pointers ignores the flags.
+//
+//              The tagged pointer is kept as a NonNull and changed with
+//              map_addr, so it keeps the provenance of the original pointer.
+
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::num::NonZeroUsize;
+use core::ptr::NonNull;
+
+use crate::aligned::AlignedAtLeast;
+
+const BITS: usize = 3;
+
+pub struct TaggedNonNull<T> {
+    ptr: NonNull<T>,
+    behaves_like: PhantomData<NonNull<T>>,
+}
+
+impl<T> TaggedNonNull<T> {
+
+    pub fn new(ptr: NonNull<T>, flag_a: bool, flag_b: bool) -> TaggedNonNull<T>
+    where
+        T: AlignedAtLeast<4>,
+    {
+        debug_assert_eq!(ptr.as_ptr() as usize & BITS, 0, "pointer not aligned to 4");
+        let bits = flag_a as usize | ((flag_b as usize) << 1);
+        TaggedNonNull { ptr: ptr.map_addr(|addr| addr | bits), behaves_like: PhantomData }
+    }
+
+    /// The untagged pointer.
+    pub fn as_ptr(self) -> NonNull<T> {
+        // The address part is non null, so clearing the bits keeps it non null.
+        unsafe { self.ptr.map_addr(|addr| NonZeroUsize::new_unchecked(addr.get() & !BITS)) }
+    }
+
+    pub fn get_flag_a(self) -> bool {
+        self.ptr.addr().get() & 1 != 0
+    }
+
+    pub fn get_flag_b(self) -> bool {
+        self.ptr.addr().get() & 2 != 0
+    }
+
+    pub fn set_flag_a(&mut self, flag: bool) {
+        self.set_bits((self.ptr.addr().get() & 2) | flag as usize);
+    }
+
+    pub fn set_flag_b(&mut self, flag: bool) {
+        self.set_bits((self.ptr.addr().get() & 1) | ((flag as usize) << 1));
+    }
+
+    /// Moves the address by `bytes`, keeping the flags.
+    ///
+    /// # Safety
+    /// Same as `NonNull::byte_add` on the untagged pointer, and the result
+    /// must stay at least 4 bytes aligned (checked in debug builds).
+    pub unsafe fn byte_add(self, bytes: usize) -> TaggedNonNull<T> {
+        debug_assert_eq!(bytes & BITS, 0, "byte offset would clobber the tag bits");
+        self.retag(self.as_ptr().byte_add(bytes))
+    }
+
+    /// Moves the address by `count` elements of T, keeping the flags.
+    ///
+    /// # Safety
+    /// Same as `NonNull::add` on the untagged pointer.
+    pub unsafe fn add(self, count: usize) -> TaggedNonNull<T> {
+        debug_assert!(align_of::<T>() > BITS, "T must be at least 4 bytes aligned");
+        self.retag(self.as_ptr().add(count))
+    }
+
+    /// Rounds the address up to a multiple of `align`, a power of 2 of at
+    /// least 4, keeping the flags. The result is only a valid pointer if it
+    /// stays inside the same allocation.
+    pub fn align_up(self, align: usize) -> TaggedNonNull<T> {
+        assert!(align.is_power_of_two() && align > BITS, "align must be a power of 2, at least 4");
+        let addr = self.as_ptr().addr().get();
+        let bits = self.ptr.addr().get() & BITS;
+        let aligned = addr.checked_next_multiple_of(align).expect("address overflow") | bits;
+        let ptr = self.ptr.map_addr(|_| NonZeroUsize::new(aligned).unwrap());
+        TaggedNonNull { ptr, behaves_like: PhantomData }
+    }
+
+    /// Distance from `origin` in elements of T, on the untagged addresses.
+    ///
+    /// # Safety
+    /// Same as `NonNull::offset_from`: both pointers in the same allocation
+    /// and the distance a multiple of the size of T (checked in debug
+    /// builds, the allocation can't be).
+    pub unsafe fn offset_from(self, origin: TaggedNonNull<T>) -> isize {
+        debug_assert!(size_of::<T>() != 0, "offset_from on a zero sized type");
+        debug_assert_eq!(
+            self.byte_offset_from(origin) % size_of::<T>() as isize,
+            0,
+            "pointers are not a whole number of elements apart"
+        );
+        self.as_ptr().offset_from(origin.as_ptr())
+    }
+
+    /// Distance from `origin` in bytes, on the untagged addresses.
+    ///
+    /// # Safety
+    /// Same as `NonNull::byte_offset_from`: both pointers in the same
+    /// allocation.
+    pub unsafe fn byte_offset_from(self, origin: TaggedNonNull<T>) -> isize {
+        self.as_ptr().byte_offset_from(origin.as_ptr())
+    }
+
+    // The offsets are done on the untagged pointer, a tagged one may point
+    // past the end of its allocation, and the flags put back on the result.
+    fn retag(self, ptr: NonNull<T>) -> TaggedNonNull<T> {
+        let bits = self.ptr.addr().get() & BITS;
+        TaggedNonNull { ptr: ptr.map_addr(|addr| addr | bits), behaves_like: PhantomData }
+    }
+
+    fn set_bits(&mut self, bits: usize) {
+        // The address part is non null, whatever the bits.
+        self.ptr = self.ptr.map_addr(|addr| unsafe { NonZeroUsize::new_unchecked((addr.get() & !BITS) | bits) });
+    }
+
+}
+
+impl<T> Clone for TaggedNonNull<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for TaggedNonNull<T> {}
+
+impl<T> PartialEq for TaggedNonNull<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.ptr == other.ptr
+    }
+}
+
+impl<T> Eq for TaggedNonNull<T> {}
+
+impl<T> fmt::Debug for TaggedNonNull<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TaggedNonNull")
+            .field("ptr", &self.as_ptr())
+            .field("flag_a", &self.get_flag_a())
+            .field("flag_b", &self.get_flag_b())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aligned_box::Align16;
+
+    #[test]
+    fn offsets_are_on_the_untagged_address() {
+        let words = [0u32, 1, 2, 3, 4, 5, 6, 7];
+        let origin = TaggedNonNull::new(NonNull::from(&words[0]), true, true);
+        for (i, word) in words.iter().enumerate() {
+            let element = unsafe { origin.add(i) };
+            let by_bytes = unsafe { origin.byte_add(i * size_of::<u32>()) };
+            assert_eq!(element, by_bytes);
+            assert_eq!(element.as_ptr(), NonNull::from(word));
+            assert!(element.get_flag_a() && element.get_flag_b());
+            assert_eq!(unsafe { *element.as_ptr().as_ref() }, i as u32);
+            // Index recovery, whatever the flags on either side.
+            let mut plain = element;
+            plain.set_flag_a(false);
+            assert_eq!(unsafe { plain.offset_from(origin) }, i as isize);
+            assert_eq!(unsafe { origin.offset_from(plain) }, -(i as isize));
+            assert_eq!(unsafe { plain.byte_offset_from(origin) }, (i * size_of::<u32>()) as isize);
+        }
+    }
+
+    #[test]
+    fn walks_an_array_with_the_flags() {
+        let cells: Vec<Align16<u16>> = (0..4).map(Align16).collect();
+        let base = NonNull::from(&cells[0]);
+        let mut cursor = TaggedNonNull::new(base, true, false);
+        cursor.set_flag_b(true);
+        let mut walked = 0;
+        for i in 0..4 {
+            let element = unsafe { cursor.add(i) };
+            assert!(element.get_flag_a() && element.get_flag_b());
+            walked += unsafe { element.as_ptr().as_ref() }.0;
+        }
+        assert_eq!(walked, 6);
+        let second = unsafe { cursor.byte_add(size_of::<Align16<u16>>()) };
+        assert_eq!(second, unsafe { cursor.add(1) });
+        let words_u32 = [0u32; 4];
+        let unaligned = TaggedNonNull::new(NonNull::from(&words_u32[1]), false, true);
+        let rounded = unaligned.align_up(8);
+        assert!((rounded.as_ptr().as_ptr() as usize).is_multiple_of(8));
+        assert!(rounded.get_flag_b() && !rounded.get_flag_a());
+        cursor.set_flag_a(false);
+        assert!(format!("{:?}", cursor).contains("flag_a: false"));
+    }
+}
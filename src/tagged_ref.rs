@@ -0,0 +1,127 @@
+// Name: TaggedRef - a reference with a tag of BITS bits in its low bits.
+//
+// Description: The generalization of RefWith2Flags to any tag width: a type
+//              aligned to 2^n bytes has n free low bits in its addresses, so
+//              TaggedRef<'a, T, 3> holds a tag of 0..8 next to a reference
+//              to an 8 bytes aligned T.
+//
+//              The width is checked against align_of::<T>() at compile time:
+//              a TaggedRef::new for a type that isn't aligned enough fails to
+//              build (the error is reported when the function is
+//              instantiated, by the associated ALIGNED constant).
+//
+//              With BITS = bitpack::spare_bits::<T>() the tag takes all the
+//              bits the alignment frees, e.g. a 6 bit counter next to a
+//              reference to a 64 bytes aligned cache line, read and written
+//              as a u8 by get_tag_u8() and set_tag_u8().
+//
+//              The word is kept as a pointer and the tag is written with
+//              map_addr, so it keeps the provenance of the reference.
+
+use core::marker::PhantomData;
+use core::mem::align_of;
+use core::ptr;
+
+use crate::bitpack;
+
+/// A tag above the maximum of the TaggedRef it was given to.
+#[derive(Debug, PartialEq, Eq)]
+pub struct TagOverflow {
+    pub tag: u8,
+    pub max: u8,
+}
+
+#[repr(transparent)]
+pub struct TaggedRef<'a, T, const BITS: usize> {
+    ptr_and_tag: *const T,
+    behaves_like: PhantomData<&'a T>,
+}
+
+// The raw pointer opts out of Send and Sync, this is a &T as far as threads
+// go.
+unsafe impl<'a, T: Sync, const BITS: usize> Send for TaggedRef<'a, T, BITS> {}
+unsafe impl<'a, T: Sync, const BITS: usize> Sync for TaggedRef<'a, T, BITS> {}
+
+impl<'a, T, const BITS: usize> TaggedRef<'a, T, BITS> {
+
+    const ALIGNED: () = assert!(
+        bitpack::align_supports(align_of::<T>(), BITS as u32),
+        "T is not aligned enough for a tag of BITS bits"
+    );
+
+    /// Mask of the tag bits.
+    pub const MASK: usize = bitpack::mask_for(BITS as u32);
+
+    /// The largest tag, for the u8 accessors, that need BITS <= 8.
+    pub const MAX_TAG_U8: u8 = {
+        assert!(BITS <= 8, "a tag of BITS bits doesn't fit in a u8");
+        Self::MASK as u8
+    };
+
+    /// Panics if `tag` doesn't fit in BITS bits.
+    pub fn new(ptr: &'a T, tag: usize) -> TaggedRef<'a, T, BITS> {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::ALIGNED;
+        TaggedRef {
+            ptr_and_tag: ptr::from_ref(ptr).map_addr(|addr| bitpack::pack(addr, tag, BITS as u32)),
+            behaves_like: PhantomData,
+        }
+    }
+
+    pub fn get_ref(&self) -> &'a T {
+        unsafe { &*self.ptr_and_tag.map_addr(|addr| bitpack::unpack(addr, BITS as u32).0) }
+    }
+
+    pub fn get_tag(&self) -> usize {
+        self.ptr_and_tag.addr() & Self::MASK
+    }
+
+    /// Panics if `tag` doesn't fit in BITS bits.
+    pub fn set_tag(&mut self, tag: usize) {
+        self.ptr_and_tag = self.ptr_and_tag.map_addr(|addr| bitpack::pack(addr & !Self::MASK, tag, BITS as u32));
+    }
+
+    pub fn get_tag_u8(&self) -> u8 {
+        self.get_tag() as u8 & Self::MAX_TAG_U8
+    }
+
+    /// Leaves the tag as it was if `tag` is above MAX_TAG_U8.
+    pub fn set_tag_u8(&mut self, tag: u8) -> Result<(), TagOverflow> {
+        if tag > Self::MAX_TAG_U8 {
+            return Err(TagOverflow { tag, max: Self::MAX_TAG_U8 });
+        }
+        self.ptr_and_tag = self.ptr_and_tag.map_addr(|addr| (addr & !Self::MASK) | tag as usize);
+        Ok(())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aligned_box::Align16;
+
+    #[test]
+    fn any_width_the_alignment_allows() {
+        let slot = Align16(7u16);
+        let mut state = TaggedRef::<Align16<u16>, 4>::new(&slot, 0b1010);
+        assert_eq!((state.get_ref().0, state.get_tag()), (7, 0b1010));
+        state.set_tag(TaggedRef::<Align16<u16>, 4>::MASK);
+        assert_eq!(state.get_tag(), 15);
+        assert_eq!(state.get_ref().0, 7);
+    }
+
+    #[test]
+    fn counts_up_to_the_spare_bits() {
+        type CountedSlot<'a> = TaggedRef<'a, Align16<u16>, { bitpack::spare_bits::<Align16<u16>>() }>;
+        let slot = Align16(18u16);
+        let mut counted = CountedSlot::new(&slot, 0);
+        for _ in 0..CountedSlot::MAX_TAG_U8 {
+            let next = counted.get_tag_u8() + 1;
+            counted.set_tag_u8(next).unwrap();
+        }
+        assert_eq!(counted.get_tag_u8(), 15);
+        assert_eq!(counted.set_tag_u8(16), Err(TagOverflow { tag: 16, max: 15 }));
+        assert_eq!((counted.get_tag_u8(), counted.get_ref().0), (15, 18));
+    }
+}
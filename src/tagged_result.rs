@@ -0,0 +1,133 @@
+// Name: TaggedResult - a Result<&T, E> in one word, for a small error E.
+//
+// Description: Result<&'a Node, ParseErrKind> takes 2 words, returned in 2
+//              registers. Here it is one:
+//
+//                 Ok  - the address of the T, its low bits 0.
+//                 Err - the pointer part (the bits above E::BITS) 0 and the
+//                       error code, E::to_bits(), in the low E::BITS bits.
+//
+//              No T lives at an address below its alignment, so a word whose
+//              pointer part is 0 can't be an Ok. E::BITS is checked against
+//              align_of::<T>() at compile time, like in RefWithTag.
+//
+//              An Ok word is the reference as a pointer, so the &T handed
+//              back keeps its provenance. An Err word is an error code and
+//              carries none, ptr::without_provenance.
+
+use core::marker::PhantomData;
+use core::mem::align_of;
+use core::ptr;
+
+use crate::bitpack;
+use crate::ref_with_tag::TagEnum;
+
+#[repr(transparent)]
+pub struct TaggedResult<'a, T, E: TagEnum> {
+    ptr_or_code: *const T,
+    behaves_like: PhantomData<Result<&'a T, E>>,
+}
+
+// The raw pointer opts out of Send and Sync, this is a Result<&T, E> as far
+// as threads go.
+unsafe impl<'a, T: Sync, E: TagEnum + Send> Send for TaggedResult<'a, T, E> {}
+unsafe impl<'a, T: Sync, E: TagEnum + Sync> Sync for TaggedResult<'a, T, E> {}
+
+impl<'a, T, E: TagEnum> TaggedResult<'a, T, E> {
+
+    const ALIGNED: () = assert!(
+        bitpack::align_supports(align_of::<T>(), E::BITS as u32),
+        "T is not aligned enough for E::BITS bits"
+    );
+
+    const MASK: usize = bitpack::mask_for(E::BITS as u32);
+
+    pub fn new(result: Result<&'a T, E>) -> TaggedResult<'a, T, E> {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::ALIGNED;
+        let ptr_or_code = match result {
+            Ok(ptr) => ptr::from_ref(ptr),
+            Err(code) => {
+                let bits = code.to_bits();
+                assert!(bits & !Self::MASK == 0, "TagEnum::to_bits returned more than BITS bits");
+                ptr::without_provenance(bits)
+            }
+        };
+        TaggedResult { ptr_or_code, behaves_like: PhantomData }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.ptr_or_code.addr() & !Self::MASK != 0
+    }
+
+    pub fn is_err(&self) -> bool {
+        !self.is_ok()
+    }
+
+    pub fn into_result(self) -> Result<&'a T, E> {
+        if self.is_ok() {
+            Ok(unsafe { &*self.ptr_or_code })
+        } else {
+            Err(E::from_bits(self.ptr_or_code.addr()))
+        }
+    }
+
+}
+
+impl<'a, T, E: TagEnum> From<Result<&'a T, E>> for TaggedResult<'a, T, E> {
+    fn from(result: Result<&'a T, E>) -> Self {
+        TaggedResult::new(result)
+    }
+}
+
+impl<'a, T, E: TagEnum> From<TaggedResult<'a, T, E>> for Result<&'a T, E> {
+    fn from(result: TaggedResult<'a, T, E>) -> Self {
+        result.into_result()
+    }
+}
+
+impl<'a, T, E: TagEnum> Clone for TaggedResult<'a, T, E> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, T, E: TagEnum> Copy for TaggedResult<'a, T, E> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aligned_box::Align16;
+    use crate::gc::Color;
+
+    #[test]
+    fn ok_and_err_in_one_word() {
+        let cells: Vec<Align16<u16>> = (0..64).map(Align16).collect();
+        let lookup = |index: usize| -> TaggedResult<Align16<u16>, Color> {
+            match index {
+                0..=63 => TaggedResult::new(Ok(&cells[index])),
+                64..=127 => TaggedResult::new(Err(Color::Gray)),
+                _ => TaggedResult::new(Err(Color::White)),
+            }
+        };
+        assert!(lookup(20).is_ok() && std::ptr::eq(lookup(20).into_result().unwrap(), &cells[20]));
+        assert_eq!(lookup(100).into_result().err(), Some(Color::Gray));
+        assert_eq!(Result::from(lookup(500)).err(), Some(Color::White));
+        assert_eq!(std::mem::size_of::<TaggedResult<Align16<u16>, Color>>(), std::mem::size_of::<usize>());
+    }
+
+    #[test]
+    fn every_error_code_and_every_element() {
+        for color in [Color::White, Color::Gray, Color::Black] {
+            let err: TaggedResult<u64, Color> = TaggedResult::new(Err(color));
+            assert!(err.is_err() && !err.is_ok());
+            assert_eq!(err.into_result(), Err(color));
+        }
+        let values = [1u64, 2, 3];
+        for value in &values {
+            let ok: TaggedResult<u64, Color> = TaggedResult::new(Ok(value));
+            assert!(ok.is_ok());
+            assert!(std::ptr::eq(ok.into_result().unwrap(), value));
+        }
+    }
+}
@@ -0,0 +1,175 @@
+// Name: TaggedSmallString - an owned string of one word, inline when it is
+//       short.
+//
+// Description: A String is 3 words. Here the string is 1 word, and bit 0 of
+//              it says where the bytes are:
+//
+//                 bit 0 = 1, INLINE - the string is in the word itself: the
+//                                     low byte is len << 1 | INLINE, and the
+//                                     other bytes of the word are the UTF-8,
+//                                     up to INLINE_CAPACITY, 7 on 64 bit.
+//                 bit 0 = 0         - a heap block, its length (a usize) and
+//                                     then the bytes. The block is usize
+//                                     aligned, so bit 0 of its address is
+//                                     free for the tag.
+//
+//              The low byte of the word is the first one in memory on little
+//              endian targets and the last one on big endian ones, either way
+//              the other bytes are contiguous and as_str() borrows them in
+//              place. Both forms are non null, so an Option of it is 1 word
+//              too. The heap block is a single allocation, the length lives
+//              in it rather than in a fat pointer.
+
+use std::alloc::{self, Layout};
+use std::fmt;
+use std::mem::size_of;
+use std::ops::Deref;
+use std::ptr::{self, NonNull};
+
+const INLINE: usize = 1;
+// Index of the low byte of the word, and of the inline bytes.
+const TAG_BYTE: usize = if cfg!(target_endian = "little") { 0 } else { size_of::<usize>() - 1 };
+const INLINE_START: usize = if cfg!(target_endian = "little") { 1 } else { 0 };
+
+pub struct TaggedSmallString {
+    // Inline bytes, or the address of the heap block.
+    word: NonNull<u8>,
+}
+
+// It owns its bytes, like a String.
+unsafe impl Send for TaggedSmallString {}
+unsafe impl Sync for TaggedSmallString {}
+
+impl TaggedSmallString {
+
+    pub const INLINE_CAPACITY: usize = size_of::<usize>() - 1;
+
+    pub fn new(string: &str) -> TaggedSmallString {
+        let len = string.len();
+        if len <= Self::INLINE_CAPACITY {
+            let mut bytes = [0u8; size_of::<usize>()];
+            bytes[TAG_BYTE] = ((len << 1) | INLINE) as u8;
+            bytes[INLINE_START..INLINE_START + len].copy_from_slice(string.as_bytes());
+            // The word has INLINE set, it isn't null.
+            let word = unsafe { NonNull::new_unchecked(ptr::without_provenance_mut(usize::from_ne_bytes(bytes))) };
+            return TaggedSmallString { word };
+        }
+        let layout = heap_layout(len);
+        unsafe {
+            let block = alloc::alloc(layout);
+            let Some(block) = NonNull::new(block) else {
+                alloc::handle_alloc_error(layout);
+            };
+            block.cast::<usize>().write(len);
+            ptr::copy_nonoverlapping(string.as_ptr(), block.as_ptr().add(size_of::<usize>()), len);
+            TaggedSmallString { word: block }
+        }
+    }
+
+    /// True if the string fits the word, no heap block behind it.
+    pub fn is_inline(&self) -> bool {
+        self.word.as_ptr().addr() & INLINE != 0
+    }
+
+    pub fn as_str(&self) -> &str {
+        unsafe {
+            let bytes = if self.is_inline() {
+                let len = (self.word.as_ptr().addr() & 0xFF) >> 1;
+                let start = ptr::from_ref(&self.word).cast::<u8>().add(INLINE_START);
+                std::slice::from_raw_parts(start, len)
+            } else {
+                let len = self.word.cast::<usize>().read();
+                std::slice::from_raw_parts(self.word.as_ptr().add(size_of::<usize>()), len)
+            };
+            // Copied from a &str by new().
+            std::str::from_utf8_unchecked(bytes)
+        }
+    }
+
+}
+
+fn heap_layout(len: usize) -> Layout {
+    let (layout, _) = Layout::new::<usize>().extend(Layout::array::<u8>(len).unwrap()).unwrap();
+    layout.pad_to_align()
+}
+
+impl Deref for TaggedSmallString {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl Clone for TaggedSmallString {
+    fn clone(&self) -> Self {
+        TaggedSmallString::new(self.as_str())
+    }
+}
+
+impl Drop for TaggedSmallString {
+    fn drop(&mut self) {
+        if !self.is_inline() {
+            let len = unsafe { self.word.cast::<usize>().read() };
+            unsafe { alloc::dealloc(self.word.as_ptr(), heap_layout(len)) };
+        }
+    }
+}
+
+impl From<&str> for TaggedSmallString {
+    fn from(string: &str) -> Self {
+        TaggedSmallString::new(string)
+    }
+}
+
+impl PartialEq for TaggedSmallString {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for TaggedSmallString {}
+
+impl fmt::Debug for TaggedSmallString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for TaggedSmallString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.as_str(), f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_strings_are_inline() {
+        let short = TaggedSmallString::new("x_pos");
+        let long = TaggedSmallString::from("a_much_longer_identifier");
+        assert!(short.is_inline() && !long.is_inline());
+        assert_eq!((short.as_str(), &*long), ("x_pos", "a_much_longer_identifier"));
+        assert_eq!(std::mem::size_of::<Option<TaggedSmallString>>(), std::mem::size_of::<usize>());
+        let copy = long.clone();
+        assert!(copy == long && copy.len() == 24 && short.starts_with("x_"));
+        assert!(TaggedSmallString::new("").is_empty() && TaggedSmallString::new("1234567").is_inline());
+        assert_eq!(format!("{short}/{long:?}"), "x_pos/\"a_much_longer_identifier\"");
+    }
+
+    #[test]
+    fn every_length_around_the_inline_capacity() {
+        let text = "0123456789abcdef";
+        for len in 0..=text.len() {
+            let string = TaggedSmallString::new(&text[..len]);
+            assert_eq!(string.as_str(), &text[..len]);
+            assert_eq!(string.is_inline(), len <= TaggedSmallString::INLINE_CAPACITY);
+            assert_eq!(string.clone(), string);
+        }
+        let unicode = TaggedSmallString::new("é€");
+        assert_eq!(unicode.is_inline(), unicode.len() <= TaggedSmallString::INLINE_CAPACITY);
+        assert_eq!(unicode.chars().count(), 2);
+    }
+}
@@ -0,0 +1,276 @@
+// Name: TaggedStack - a lock free Treiber stack whose head is an
+//       AtomicTaggedPtr.
+//
+// Description: The textbook lock free stack: push and pop are a CAS loop on
+//              the head pointer. The head is an AtomicTaggedPtr to the top
+//              node, and flag a of that word is the CLOSED bit:
+//
+//                 flag a - CLOSED : close() was called, push() fails and
+//                                   gives the value back, pop() still
+//                                   drains what is left.
+//
+//              Because the bit is in the same word as the head, a push that
+//              raced with close() can't slip in: its CAS expected the head
+//              without the bit and fails, and the retry sees it.
+//
+//              An AtomicTaggedPtr always holds a reference, so the bottom of
+//              the stack is a sentinel node without value, the stack is
+//              empty when the head points to it.
+//
+//              The ABA problem (a pop that read the head A and its next B,
+//              stalled, and then CASed A -> B after A was popped, freed,
+//              reallocated and pushed again) is avoided by never freeing or
+//              reusing a popped node while the stack is shared: popped nodes
+//              go on a retired list that is freed by collect() and by Drop,
+//              which take &mut self. So a node address is never seen twice
+//              and a pop can always read the next of a node it loaded.
+//
+//              The nodes are handed to the AtomicTaggedPtr as &'static
+//              references, they live until the stack frees them, so T must
+//              be 'static.
+
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::aligned::AlignedAtLeast;
+use crate::atomic_tagged_ptr::AtomicTaggedPtr;
+use crate::ref_with_2_flags::RefWith2Flags;
+
+#[repr(align(4))]
+struct Node<T> {
+    // Uninit in the sentinel and once popped.
+    value: UnsafeCell<MaybeUninit<T>>,
+    // Null only in the sentinel, never written once the node is pushed.
+    next: *const Node<T>,
+    // Link of the retired list, written by the one pop that unlinked it.
+    retired_next: AtomicPtr<Node<T>>,
+    // The pointer from Box::into_raw, to free the node with its own
+    // provenance, not the one of a shared reference.
+    raw: *mut Node<T>,
+}
+
+const _: () = assert!(core::mem::align_of::<Node<()>>() >= 4);
+unsafe impl<T> AlignedAtLeast<4> for Node<T> {}
+
+pub struct TaggedStack<T: 'static> {
+    head: AtomicTaggedPtr<'static, Node<T>>,
+    retired: AtomicPtr<Node<T>>,
+}
+
+// The values are only moved in and out, never shared, like in a
+// Mutex<Vec<T>>.
+unsafe impl<T: Send> Send for TaggedStack<T> {}
+unsafe impl<T: Send> Sync for TaggedStack<T> {}
+
+impl<T: 'static> TaggedStack<T> {
+
+    pub fn new() -> TaggedStack<T> {
+        let sentinel = Self::alloc_node(MaybeUninit::uninit());
+        TaggedStack {
+            head: AtomicTaggedPtr::new(RefWith2Flags::new(unsafe { &*sentinel }, false, false)),
+            retired: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Gives the value back if the stack is closed.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let node = Self::alloc_node(MaybeUninit::new(value));
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            if head.get_flag_a() {
+                let node = unsafe { Box::from_raw(node) };
+                return Err(unsafe { node.value.into_inner().assume_init() });
+            }
+            // The node isn't shared until the CAS publishes it.
+            unsafe { (*node).next = head.get_ref() };
+            let new = RefWith2Flags::new(unsafe { &*node }, false, false);
+            match self.head.compare_exchange_weak(&head, new, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return Ok(()),
+                Err(actual) => head = actual,
+            }
+        }
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            let node = head.get_ref();
+            if node.next.is_null() {
+                return None;
+            }
+            // A popped node stays allocated, so its next can still be read.
+            let next = RefWith2Flags::new(unsafe { &*node.next }, head.get_flag_a(), false);
+            match self.head.compare_exchange_weak(&head, next, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => {
+                    // Only the pop whose CAS unlinked the node gets here.
+                    let value = unsafe { (*node.value.get()).assume_init_read() };
+                    self.retire(node);
+                    return Some(value);
+                }
+                Err(actual) => head = actual,
+            }
+        }
+    }
+
+    /// Makes every later push fail. Returns false if it was already closed.
+    pub fn close(&self) -> bool {
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            if head.get_flag_a() {
+                return false;
+            }
+            let mut closed = head;
+            closed.set_flag_a(true);
+            match self.head.compare_exchange_weak(&head, closed, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return true,
+                Err(actual) => head = actual,
+            }
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.head.load(Ordering::Acquire).get_flag_a()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Acquire).get_ref().next.is_null()
+    }
+
+    /// Frees the nodes of the values popped so far.
+    pub fn collect(&mut self) {
+        let mut node = self.retired.swap(ptr::null_mut(), Ordering::Acquire);
+        while !node.is_null() {
+            let boxed = unsafe { Box::from_raw(node) };
+            node = boxed.retired_next.into_inner();
+        }
+    }
+
+    fn alloc_node(value: MaybeUninit<T>) -> *mut Node<T> {
+        let node = Node {
+            value: UnsafeCell::new(value),
+            next: ptr::null(),
+            retired_next: AtomicPtr::new(ptr::null_mut()),
+            raw: ptr::null_mut(),
+        };
+        let raw = Box::into_raw(Box::new(node));
+        unsafe { (*raw).raw = raw };
+        raw
+    }
+
+    fn retire(&self, node: &Node<T>) {
+        let mut top = self.retired.load(Ordering::Relaxed);
+        loop {
+            node.retired_next.store(top, Ordering::Relaxed);
+            match self.retired.compare_exchange_weak(top, node.raw, Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => return,
+                Err(actual) => top = actual,
+            }
+        }
+    }
+
+}
+
+impl<T: 'static> Default for TaggedStack<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: 'static> Drop for TaggedStack<T> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+        self.collect();
+        let sentinel = self.head.load(Ordering::Relaxed).get_ref().raw;
+        drop(unsafe { Box::from_raw(sentinel) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::thread;
+
+    #[test]
+    fn concurrent_push_pop_loses_nothing() {
+        let stack = TaggedStack::new();
+        let popped = Mutex::new(Vec::new());
+        thread::scope(|s| {
+            for t in 0..4 {
+                let stack = &stack;
+                s.spawn(move || {
+                    for i in 0..1000 {
+                        stack.push(t * 1000 + i).unwrap();
+                    }
+                });
+                s.spawn(|| {
+                    let mut mine = Vec::new();
+                    for _ in 0..1000 {
+                        mine.extend(stack.pop());
+                    }
+                    popped.lock().unwrap().extend(mine);
+                });
+            }
+        });
+        let mut all = popped.into_inner().unwrap();
+        all.extend(std::iter::from_fn(|| stack.pop()));
+        all.sort();
+        assert_eq!(all, (0..4000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn push_fails_once_closed() {
+        let stack = TaggedStack::new();
+        stack.push(1).unwrap();
+        assert!(stack.close() && !stack.close());
+        assert_eq!(stack.push(2), Err(2));
+        assert_eq!((stack.pop(), stack.pop()), (Some(1), None));
+        assert!(stack.is_closed() && stack.is_empty());
+    }
+
+    #[test]
+    fn close_racing_pushes_loses_nothing() {
+        for _ in 0..20 {
+            let stack = TaggedStack::new();
+            let (pushed, refused) = (Mutex::new(Vec::new()), Mutex::new(Vec::new()));
+            let opened = Mutex::new(0);
+            thread::scope(|s| {
+                for t in 0..4 {
+                    let (stack, pushed, refused) = (&stack, &pushed, &refused);
+                    s.spawn(move || {
+                        let (mut ok, mut err) = (Vec::new(), Vec::new());
+                        for i in 0..500 {
+                            match stack.push(t * 500 + i) {
+                                Ok(()) => {
+                                    // Nothing gets in after a refusal.
+                                    assert!(err.is_empty(), "a push got in after the close");
+                                    ok.push(t * 500 + i);
+                                }
+                                Err(value) => err.push(value),
+                            }
+                        }
+                        pushed.lock().unwrap().extend(ok);
+                        refused.lock().unwrap().extend(err);
+                    });
+                }
+                for _ in 0..2 {
+                    s.spawn(|| {
+                        thread::yield_now();
+                        *opened.lock().unwrap() += stack.close() as u32;
+                    });
+                }
+            });
+            assert_eq!(opened.into_inner().unwrap(), 1);
+            let mut popped: Vec<_> = std::iter::from_fn(|| stack.pop()).collect();
+            let mut pushed = pushed.into_inner().unwrap();
+            let refused = refused.into_inner().unwrap();
+            popped.sort();
+            pushed.sort();
+            assert_eq!(popped, pushed);
+            assert_eq!(pushed.len() + refused.len(), 2000);
+            assert!(stack.is_closed() && stack.push(0) == Err(0));
+        }
+    }
+}
@@ -0,0 +1,221 @@
+// Name: TaggedVec - a growable Vec of tagged references.
+//
+// Description: A thin wrapper over Vec<RefWith2Flags> so that a list of
+//              references with 2 flags each costs one word per element.
+//              It can be built from iterator pipelines with collect() or
+//              extend(), from (reference, flag_a, flag_b) triples or from
+//              plain references (both flags false).
+//
+//              The par_ bulk operations split the elements into one chunk
+//              per available core and run them on scoped threads, for mark
+//              phases over many words. Below PAR_MIN_CHUNK elements per
+//              chunk they run on the calling thread, a spawn costs more.
+
+use std::iter::FromIterator;
+use std::thread;
+
+use crate::aligned::AlignedAtLeast;
+use crate::ref_with_2_flags::RefWith2Flags;
+
+const PAR_MIN_CHUNK: usize = 16 * 1024;
+
+pub struct TaggedVec<'a, T> {
+    entries: Vec<RefWith2Flags<'a, T>>,
+}
+
+impl<'a, T: AlignedAtLeast<4> + 'a> TaggedVec<'a, T> {
+
+    pub fn new() -> TaggedVec<'a, T> {
+        TaggedVec { entries: Vec::new() }
+    }
+
+    pub fn push(&mut self, value: &'a T, flag_a: bool, flag_b: bool) {
+        self.entries.push(RefWith2Flags::new(value, flag_a, flag_b));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&RefWith2Flags<'a, T>> {
+        self.entries.get(index)
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, RefWith2Flags<'a, T>> {
+        self.entries.iter()
+    }
+
+    /// Sets flag a of every element, in parallel.
+    pub fn par_set_all_flag_a(&mut self, flag: bool)
+    where
+        T: Sync,
+    {
+        let chunk = chunk_len(self.entries.len());
+        thread::scope(|s| {
+            for part in self.entries.chunks_mut(chunk) {
+                s.spawn(move || part.iter_mut().for_each(|r| r.set_flag_a(flag)));
+            }
+        });
+    }
+
+    /// The number of elements with flag a set and with flag b set, counted
+    /// in parallel.
+    pub fn par_count_flags(&self) -> (usize, usize)
+    where
+        T: Sync,
+    {
+        let chunk = chunk_len(self.entries.len());
+        thread::scope(|s| {
+            let counts: Vec<_> = self
+                .entries
+                .chunks(chunk)
+                .map(|part| {
+                    s.spawn(move || {
+                        let a = part.iter().filter(|r| r.get_flag_a()).count();
+                        let b = part.iter().filter(|r| r.get_flag_b()).count();
+                        (a, b)
+                    })
+                })
+                .collect();
+            counts
+                .into_iter()
+                .map(|count| count.join().unwrap())
+                .fold((0, 0), |(a, b), (part_a, part_b)| (a + part_a, b + part_b))
+        })
+    }
+
+    /// Keeps the elements for which `keep(flag_a, flag_b)` is true, in their
+    /// order. The chunks are filtered in parallel and then joined.
+    pub fn par_retain_by_flag<F>(&mut self, keep: F)
+    where
+        T: Sync,
+        F: Fn(bool, bool) -> bool + Sync,
+    {
+        let chunk = chunk_len(self.entries.len());
+        let keep = &keep;
+        let kept: Vec<Vec<RefWith2Flags<'a, T>>> = thread::scope(|s| {
+            let parts: Vec<_> = self
+                .entries
+                .chunks(chunk)
+                .map(|part| {
+                    s.spawn(move || {
+                        part.iter()
+                            .copied()
+                            .filter(|r| keep(r.get_flag_a(), r.get_flag_b()))
+                            .collect()
+                    })
+                })
+                .collect();
+            parts.into_iter().map(|part| part.join().unwrap()).collect()
+        });
+        self.entries.clear();
+        kept.into_iter().for_each(|part| self.entries.extend(part));
+    }
+
+}
+
+// One chunk per core, none smaller than PAR_MIN_CHUNK, so a short Vec is a
+// single chunk on a single thread.
+fn chunk_len(len: usize) -> usize {
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    len.div_ceil(threads).max(PAR_MIN_CHUNK)
+}
+
+impl<'a, T: AlignedAtLeast<4> + 'a> Default for TaggedVec<'a, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a, T: AlignedAtLeast<4> + 'a> FromIterator<(&'a T, bool, bool)> for TaggedVec<'a, T> {
+    fn from_iter<I: IntoIterator<Item = (&'a T, bool, bool)>>(iter: I) -> Self {
+        let mut tagged = TaggedVec::new();
+        tagged.extend(iter);
+        tagged
+    }
+}
+
+impl<'a, T: AlignedAtLeast<4> + 'a> FromIterator<&'a T> for TaggedVec<'a, T> {
+    fn from_iter<I: IntoIterator<Item = &'a T>>(iter: I) -> Self {
+        let mut tagged = TaggedVec::new();
+        tagged.extend(iter);
+        tagged
+    }
+}
+
+impl<'a, T: AlignedAtLeast<4> + 'a> Extend<(&'a T, bool, bool)> for TaggedVec<'a, T> {
+    fn extend<I: IntoIterator<Item = (&'a T, bool, bool)>>(&mut self, iter: I) {
+        self.entries.extend(
+            iter.into_iter()
+                .map(|(value, flag_a, flag_b)| RefWith2Flags::new(value, flag_a, flag_b)),
+        );
+    }
+}
+
+impl<'a, T: AlignedAtLeast<4> + 'a> Extend<&'a T> for TaggedVec<'a, T> {
+    fn extend<I: IntoIterator<Item = &'a T>>(&mut self, iter: I) {
+        self.entries.extend(iter.into_iter().map(|value| RefWith2Flags::new(value, false, false)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parallel_operations_match_the_sequential_ones() {
+        // Several chunks, so the work really is split across threads.
+        let numbers: Vec<u32> = (0..5 * PAR_MIN_CHUNK as u32 + 7).collect();
+        let mut list: TaggedVec<u32> = numbers.iter().map(|n| (n, n % 3 == 0, n % 5 == 0)).collect();
+        let a = numbers.iter().filter(|n| *n % 3 == 0).count();
+        let b = numbers.iter().filter(|n| *n % 5 == 0).count();
+        assert_eq!(list.par_count_flags(), (a, b));
+
+        list.par_retain_by_flag(|flag_a, flag_b| flag_a || flag_b);
+        let kept: Vec<u32> = numbers.iter().copied().filter(|n| n % 3 == 0 || n % 5 == 0).collect();
+        assert_eq!(list.iter().map(|r| *r.get_ref()).collect::<Vec<_>>(), kept);
+
+        list.par_set_all_flag_a(true);
+        assert_eq!(list.par_count_flags(), (kept.len(), b));
+        list.par_set_all_flag_a(false);
+        assert_eq!(list.par_count_flags(), (0, b));
+        assert!(list.iter().all(|r| *r.get_ref() % 5 == 0 || !r.get_flag_b()));
+    }
+
+    #[test]
+    fn parallel_operations_on_short_and_empty_vecs() {
+        let numbers = [1_u32, 2, 3];
+        let mut list: TaggedVec<u32> = numbers.iter().map(|n| (n, false, *n == 2)).collect();
+        assert_eq!(list.par_count_flags(), (0, 1));
+        list.par_retain_by_flag(|_, flag_b| flag_b);
+        assert_eq!(list.len(), 1);
+        assert_eq!(*list.get(0).unwrap().get_ref(), 2);
+
+        let mut empty: TaggedVec<u32> = TaggedVec::new();
+        empty.par_set_all_flag_a(true);
+        empty.par_retain_by_flag(|_, _| false);
+        assert_eq!(empty.par_count_flags(), (0, 0));
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn collects_from_iterators() {
+        let numbers = [1_u64, 2, 3, 4];
+        let mut list: TaggedVec<u64> = numbers.iter().map(|n| (n, n % 2 == 0, false)).collect();
+        list.extend(numbers.iter().take(1));
+        list.push(&numbers[3], false, true);
+        assert_eq!(list.len(), 6);
+        assert!(!list.is_empty());
+        assert!(list.get(1).unwrap().get_flag_a());
+        assert_eq!(list.iter().filter(|t| t.get_flag_a()).count(), 2);
+        let plain: TaggedVec<u64> = numbers.iter().collect();
+        assert!(plain.iter().all(|t| !t.get_flag_a() && !t.get_flag_b()));
+        assert!(plain.get(4).is_none());
+        let none: TaggedVec<u64> = std::iter::empty::<&u64>().collect();
+        assert!(none.is_empty() && none.get(0).is_none());
+    }
+}
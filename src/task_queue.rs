@@ -0,0 +1,198 @@
+// Name: TaskQueue - a queue of closures with a "cancelled" bit on each entry.
+//
+// Description: Every queued closure gets one atomic word, the pointer to the
+//              boxed closure plus 2 bits, shared between the queue and the
+//              TaskHandle returned by push():
+//
+//                 bit 0 - CANCELLED : the handle cancelled the task.
+//                 bit 1 - TAKEN     : a worker took the task, to run it or to
+//                                     drop it.
+//
+//              Cancelling sets CANCELLED with a CAS on that word, from any
+//              thread, unless TAKEN is already there. The worker that pops
+//              the entry sets TAKEN with a fetch_or, and the bits it gets
+//              back decide: if CANCELLED was already there the closure is
+//              dropped without running, otherwise it runs and a later
+//              cancel() reports that it was too late.
+//
+//              A Box<dyn FnOnce()> is a fat pointer, 2 words, so the closure
+//              is boxed twice, the outer box is a thin pointer that fits the
+//              word next to the bits.
+//
+//              The word is an AtomicPtr to that outer box, so the pointer
+//              handed back to Box::from_raw is the one Box::into_raw gave,
+//              with the bits masked off by map_addr.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::Arc;
+
+const CANCELLED: usize = 1;
+const TAKEN: usize = 2;
+const BITS: usize = CANCELLED | TAKEN;
+
+type Task = Box<dyn FnOnce() + Send>;
+
+struct Entry {
+    word: AtomicPtr<Task>,
+}
+
+pub struct TaskHandle {
+    entry: Arc<Entry>,
+}
+
+pub struct TaskQueue {
+    entries: VecDeque<Arc<Entry>>,
+}
+
+impl TaskQueue {
+
+    pub fn new() -> TaskQueue {
+        TaskQueue { entries: VecDeque::new() }
+    }
+
+    /// Entries still queued, cancelled ones included until a worker pops
+    /// them.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn push<F: FnOnce() + Send + 'static>(&mut self, task: F) -> TaskHandle {
+        let task: Box<Task> = Box::new(Box::new(task));
+        let task = Box::into_raw(task);
+        debug_assert_eq!(task.addr() & BITS, 0);
+        let entry = Arc::new(Entry { word: AtomicPtr::new(task) });
+        self.entries.push_back(entry.clone());
+        TaskHandle { entry }
+    }
+
+    /// Runs the first task that isn't cancelled, dropping the cancelled ones
+    /// in front of it. Returns false if there was nothing to run.
+    pub fn run_next(&mut self) -> bool {
+        while let Some(entry) = self.entries.pop_front() {
+            if let Some(task) = Self::take(&entry) {
+                task();
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Runs every task that isn't cancelled, returns how many ran.
+    pub fn run_all(&mut self) -> usize {
+        let mut ran = 0;
+        while self.run_next() {
+            ran += 1;
+        }
+        ran
+    }
+
+    // Sets TAKEN and frees the closure, handing it back only if it wasn't
+    // cancelled.
+    fn take(entry: &Entry) -> Option<Task> {
+        let word = entry.word.fetch_or(TAKEN, Ordering::AcqRel);
+        let task = unsafe { Box::from_raw(word.map_addr(|addr| addr & !BITS)) };
+        if word.addr() & CANCELLED == 0 { Some(*task) } else { None }
+    }
+
+}
+
+impl Default for TaskQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for TaskQueue {
+    fn drop(&mut self) {
+        for entry in self.entries.drain(..) {
+            drop(Self::take(&entry));
+        }
+    }
+}
+
+impl TaskHandle {
+
+    /// Cancels the task, returns false if a worker already took it or it was
+    /// already cancelled.
+    pub fn cancel(&self) -> bool {
+        self.entry
+            .word
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |word| {
+                if word.addr() & BITS == 0 { Some(word.map_addr(|addr| addr | CANCELLED)) } else { None }
+            })
+            .is_ok()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.entry.word.load(Ordering::Acquire).addr() & CANCELLED != 0
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    #[test]
+    fn cancelled_closures_are_skipped() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut tasks = TaskQueue::new();
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let log = log.clone();
+                tasks.push(move || log.lock().unwrap().push(i))
+            })
+            .collect();
+        assert!(handles[1].cancel() && handles[1].is_cancelled());
+        assert!(tasks.run_next());
+        assert!(!handles[0].cancel() && !handles[0].is_cancelled());
+        assert!(handles[3].cancel());
+        assert_eq!(tasks.len(), 3);
+        assert_eq!(tasks.run_all(), 1);
+        assert!(tasks.is_empty() && TaskQueue::default().is_empty());
+        assert_eq!(*log.lock().unwrap(), vec![0, 2]);
+    }
+
+    #[test]
+    fn dropping_the_queue_drops_the_closures_unrun() {
+        let counted = Arc::new(());
+        let mut tasks = TaskQueue::new();
+        let held = counted.clone();
+        let kept = tasks.push(move || drop(held));
+        let held = counted.clone();
+        let cancelled = tasks.push(move || drop(held));
+        assert!(cancelled.cancel() && !cancelled.cancel());
+        assert_eq!(Arc::strong_count(&counted), 3);
+        drop(tasks);
+        assert!(!kept.cancel() && !kept.is_cancelled());
+        assert_eq!(Arc::strong_count(&counted), 1);
+        assert!(!TaskQueue::new().run_next());
+    }
+
+    #[test]
+    fn cancels_race_the_worker() {
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        let mut tasks = TaskQueue::new();
+        let handles: Vec<TaskHandle> = (0..64)
+            .map(|i| {
+                let ran = ran.clone();
+                tasks.push(move || ran.lock().unwrap().push(i))
+            })
+            .collect();
+        let cancelled: Vec<usize> = thread::scope(|s| {
+            let canceller = s.spawn(|| (0..64).filter(|&i| handles[i].cancel()).collect());
+            tasks.run_all();
+            canceller.join().unwrap()
+        });
+        let ran = ran.lock().unwrap();
+        assert_eq!(ran.len() + cancelled.len(), 64);
+        assert!(cancelled.iter().all(|i| !ran.contains(i) && handles[*i].is_cancelled()));
+    }
+}
@@ -0,0 +1,233 @@
+// Name: TimerWheel - a hashed timer wheel with "expired" and "cancelled" bits
+//       on the entry pointers.
+//
+// Description: The wheel has a ring of slots and a hand that moves one slot
+//              per tick. A timer due in d ticks goes to the slot d ahead of
+//              the hand, with the number of full turns to wait when d is
+//              larger than the ring.
+//
+//              Each slot entry is one atomic word, the pointer to the boxed
+//              timer plus 2 bits:
+//
+//                 bit 0 - EXPIRED   : the wheel reached the timer.
+//                 bit 1 - CANCELLED : the handle cancelled the timer.
+//
+//              The word is shared with the TimerHandle, so cancelling is a
+//              single fetch_or from any thread, no lock and no unlinking from
+//              the slot. The wheel drops a cancelled timer the next time the
+//              hand passes its slot. Only the wheel ever follows the pointer,
+//              the handle only looks at the bits.
+//
+//              The word is an AtomicPtr holding the pointer from
+//              Box::into_raw, type erased so the handle needs no T. The bits
+//              are set with its fetch_or and masked off with map_addr, so the
+//              Box rebuilt is the one that was leaked.
+
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::Arc;
+
+const EXPIRED: usize = 1;
+const CANCELLED: usize = 2;
+const BITS: usize = EXPIRED | CANCELLED;
+
+struct Timer<T> {
+    // Full turns of the wheel left before the timer is due.
+    rounds: usize,
+    payload: T,
+}
+
+struct Entry {
+    // A tagged Box<Timer<T>>, the T is the wheel's.
+    word: AtomicPtr<()>,
+}
+
+pub struct TimerHandle {
+    entry: Arc<Entry>,
+}
+
+pub struct TimerWheel<T> {
+    slots: Vec<Vec<Arc<Entry>>>,
+    hand: usize,
+    pending: usize,
+    owns: PhantomData<Box<Timer<T>>>,
+}
+
+impl<T> TimerWheel<T> {
+
+    pub fn new(slots: usize) -> TimerWheel<T> {
+        assert!(slots > 0, "a wheel needs at least one slot");
+        TimerWheel {
+            slots: (0..slots).map(|_| Vec::new()).collect(),
+            hand: 0,
+            pending: 0,
+            owns: PhantomData,
+        }
+    }
+
+    /// Number of timers in the wheel, cancelled ones included until the
+    /// hand passes them.
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    /// Schedules `payload` to expire after `delay` ticks (at least 1).
+    pub fn schedule(&mut self, delay: usize, payload: T) -> TimerHandle {
+        let delay = delay.max(1);
+        let n = self.slots.len();
+        let timer = Box::into_raw(Box::new(Timer { rounds: (delay - 1) / n, payload })).cast::<()>();
+        debug_assert_eq!(timer.addr() & BITS, 0);
+        let entry = Arc::new(Entry { word: AtomicPtr::new(timer) });
+        self.slots[(self.hand + delay) % n].push(entry.clone());
+        self.pending += 1;
+        TimerHandle { entry }
+    }
+
+    /// Moves the hand one slot and returns the payloads of the timers that
+    /// expired, the cancelled ones met on the way are dropped.
+    pub fn tick(&mut self) -> Vec<T> {
+        self.hand = (self.hand + 1) % self.slots.len();
+        let mut expired = Vec::new();
+        let slot = &mut self.slots[self.hand];
+        let before = slot.len();
+        slot.retain(|entry| {
+            let word = entry.word.load(Ordering::Acquire);
+            let timer = unsafe { &mut *timer_of::<T>(word) };
+            if word.addr() & CANCELLED == 0 && timer.rounds > 0 {
+                timer.rounds -= 1;
+                return true;
+            }
+            let word = entry.word.fetch_or(EXPIRED, Ordering::AcqRel);
+            let timer = unsafe { Box::from_raw(timer_of::<T>(word)) };
+            if word.addr() & CANCELLED == 0 {
+                expired.push(timer.payload);
+            }
+            false
+        });
+        self.pending -= before - slot.len();
+        expired
+    }
+
+}
+
+impl<T> Drop for TimerWheel<T> {
+    fn drop(&mut self) {
+        for entry in self.slots.iter().flatten() {
+            let word = entry.word.fetch_or(EXPIRED, Ordering::AcqRel);
+            drop(unsafe { Box::from_raw(timer_of::<T>(word)) });
+        }
+    }
+}
+
+fn timer_of<T>(word: *mut ()) -> *mut Timer<T> {
+    word.map_addr(|addr| addr & !BITS).cast()
+}
+
+impl TimerHandle {
+
+    /// Cancels the timer, returns false if it had already expired or been
+    /// cancelled.
+    pub fn cancel(&self) -> bool {
+        self.entry.word.fetch_or(CANCELLED, Ordering::AcqRel).addr() & BITS == 0
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.entry.word.load(Ordering::Acquire).addr() & CANCELLED != 0
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.entry.word.load(Ordering::Acquire).addr() & EXPIRED != 0
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::thread;
+
+    #[test]
+    fn cancelled_timers_never_fire() {
+        let mut wheel = TimerWheel::new(8);
+        let soon = wheel.schedule(2, "soon");
+        let cancelled = wheel.schedule(3, "cancelled");
+        let _late = wheel.schedule(11, "late");
+        assert!(cancelled.cancel());
+        assert!(!cancelled.cancel() && cancelled.is_cancelled());
+        assert_eq!(wheel.tick(), Vec::<&str>::new());
+        assert_eq!(wheel.tick(), vec!["soon"]);
+        assert!(soon.is_expired() && !soon.cancel());
+        assert_eq!(wheel.tick(), Vec::<&str>::new());
+        assert_eq!(wheel.pending(), 1);
+        let fired: Vec<&str> = (0..8).flat_map(|_| wheel.tick()).collect();
+        assert_eq!(fired, vec!["late"]);
+        assert_eq!(wheel.pending(), 0);
+    }
+
+    #[test]
+    fn random_schedules_fire_on_their_tick() {
+        // The model: the due tick of every timer not cancelled.
+        let mut wheel = TimerWheel::new(5);
+        let mut due: Vec<(usize, u32)> = Vec::new();
+        let mut handles = Vec::new();
+        let mut now = 0;
+        let mut seed = 6060u32;
+        for _ in 0..2000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            match (seed >> 8) % 4 {
+                0 | 1 => {
+                    let delay = (seed >> 16) as usize % 23;
+                    handles.push((wheel.schedule(delay, seed), seed));
+                    due.push((now + delay.max(1), seed));
+                }
+                2 if !handles.is_empty() => {
+                    let (handle, payload) = &handles[(seed >> 16) as usize % handles.len()];
+                    let pending = due.iter().any(|&(_, p)| p == *payload);
+                    assert_eq!(handle.cancel(), pending);
+                    due.retain(|&(_, p)| p != *payload);
+                }
+                _ => {
+                    now += 1;
+                    let mut fired = wheel.tick();
+                    let mut expected: Vec<u32> = due.iter().filter(|&&(at, _)| at == now).map(|&(_, p)| p).collect();
+                    fired.sort();
+                    expected.sort();
+                    assert_eq!(fired, expected);
+                    due.retain(|&(at, _)| at != now);
+                }
+            }
+        }
+        assert!(wheel.pending() >= due.len());
+    }
+
+    #[test]
+    fn dropping_the_wheel_drops_the_pending_payloads() {
+        let counted = Rc::new(());
+        let mut wheel = TimerWheel::new(1);
+        let handle = wheel.schedule(0, counted.clone());
+        let cancelled = wheel.schedule(5, counted.clone());
+        assert!(cancelled.cancel());
+        assert_eq!(wheel.pending(), 2);
+        assert_eq!(wheel.tick().len(), 1);
+        assert!(handle.is_expired() && !handle.is_cancelled());
+        drop(wheel);
+        assert!(cancelled.is_expired() && !cancelled.cancel());
+        assert_eq!(Rc::strong_count(&counted), 1);
+    }
+
+    #[test]
+    fn handles_cancel_from_other_threads() {
+        let mut wheel = TimerWheel::new(4);
+        let handles: Vec<TimerHandle> = (0..8).map(|i| wheel.schedule(1 + i % 2, i)).collect();
+        thread::scope(|s| {
+            for handle in handles.iter().step_by(2) {
+                s.spawn(move || assert!(handle.cancel()));
+            }
+        });
+        let mut fired = wheel.tick();
+        fired.extend(wheel.tick());
+        fired.sort();
+        assert_eq!(fired, vec![1, 3, 5, 7]);
+    }
+}
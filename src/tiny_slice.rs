@@ -0,0 +1,138 @@
+// Name: TinySlice - a slice reference of one word when it is short.
+//
+// Description: A &[T] is 2 words, pointer and length. When T is at least 4
+//              bytes aligned the 2 low bits of the pointer are free, and they
+//              are enough for a length of 1 to 3. So:
+//
+//                 0               - the empty slice.
+//                 ptr | len       - len in 1..=3, the slice is inline.
+//                 box ptr | 00    - a longer slice, the word points to a
+//                                   boxed (ptr, len) pair.
+//
+//              Graphs where most nodes have at most 3 edges pay a single
+//              word per edge list, and only the long lists pay for a box.
+//
+//              The word is a raw pointer, to the elements or to the box, the
+//              length set and cleared with map_addr. The empty slice is the
+//              null pointer.
+
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::ptr;
+
+use crate::aligned::AlignedAtLeast;
+
+const LEN_MASK: usize = 3;
+
+pub struct TinySlice<'a, T> {
+    word: *const (),
+    behaves_like: PhantomData<&'a [T]>,
+}
+
+// The raw pointer opts out of Send and Sync, this is a &[T] as far as
+// threads go.
+unsafe impl<'a, T: Sync> Send for TinySlice<'a, T> {}
+unsafe impl<'a, T: Sync> Sync for TinySlice<'a, T> {}
+
+impl<'a, T: AlignedAtLeast<4>> TinySlice<'a, T> {
+
+    pub fn new(slice: &'a [T]) -> TinySlice<'a, T> {
+        let word = match slice.len() {
+            0 => ptr::null(),
+            len @ 1..=3 => slice.as_ptr().map_addr(|addr| addr | len).cast(),
+            _ => Box::into_raw(Box::new(slice)).cast_const().cast(),
+        };
+        TinySlice { word, behaves_like: PhantomData }
+    }
+
+    pub fn as_slice(&self) -> &'a [T] {
+        match self.word.addr() & LEN_MASK {
+            _ if self.word.is_null() => &[],
+            0 => unsafe { *self.word.cast::<&'a [T]>() },
+            len => unsafe { std::slice::from_raw_parts(self.word.map_addr(|addr| addr & !LEN_MASK).cast::<T>(), len) },
+        }
+    }
+
+    /// True if the slice fits the word, no box behind it.
+    pub fn is_inline(&self) -> bool {
+        self.word.is_null() || self.word.addr() & LEN_MASK != 0
+    }
+
+}
+
+impl<'a, T: AlignedAtLeast<4>> Deref for TinySlice<'a, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        self.as_slice()
+    }
+}
+
+impl<'a, T: AlignedAtLeast<4>> Clone for TinySlice<'a, T> {
+    fn clone(&self) -> Self {
+        TinySlice::new(self.as_slice())
+    }
+}
+
+impl<'a, T> Drop for TinySlice<'a, T> {
+    fn drop(&mut self) {
+        if !self.word.is_null() && self.word.addr() & LEN_MASK == 0 {
+            drop(unsafe { Box::from_raw(self.word.cast_mut().cast::<&'a [T]>()) });
+        }
+    }
+}
+
+impl<'a, T: AlignedAtLeast<4>> From<&'a [T]> for TinySlice<'a, T> {
+    fn from(slice: &'a [T]) -> Self {
+        TinySlice::new(slice)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aligned_box::Align16;
+
+    #[test]
+    fn short_lengths_are_inline() {
+        let targets: [u32; 5] = [1, 2, 3, 4, 5];
+        let edges: Vec<TinySlice<u32>> = vec![
+            TinySlice::new(&targets[..0]),
+            TinySlice::new(&targets[..2]),
+            TinySlice::from(&targets[2..5]),
+            TinySlice::new(&targets[..]),
+        ];
+        assert_eq!(std::mem::size_of::<TinySlice<u32>>(), std::mem::size_of::<usize>());
+        assert!(edges[..3].iter().all(|e| e.is_inline()) && !edges[3].is_inline());
+        assert_eq!(edges[1].as_slice(), &[1, 2]);
+        assert_eq!(&*edges[2], &[3, 4, 5]);
+        assert_eq!(edges[3].clone().len(), 5);
+        assert!(edges[0].is_empty());
+    }
+
+    #[test]
+    fn every_subslice_round_trips() {
+        let values: Vec<u64> = (0..9).map(|i| i * 7).collect();
+        for start in 0..=values.len() {
+            for end in start..=values.len() {
+                let tiny = TinySlice::new(&values[start..end]);
+                assert_eq!(tiny.as_slice(), &values[start..end]);
+                assert!(start == end || tiny.as_slice().as_ptr() == values[start..end].as_ptr());
+                assert_eq!(tiny.is_inline(), end - start <= 3);
+                assert_eq!(&*tiny.clone(), &values[start..end]);
+            }
+        }
+    }
+
+    #[test]
+    fn over_aligned_elements_and_threads() {
+        let wide = [Align16(1u8), Align16(2), Align16(3), Align16(4)];
+        let inline = TinySlice::new(&wide[1..]);
+        let boxed = TinySlice::new(&wide[..]);
+        assert!(inline.is_inline() && !boxed.is_inline());
+        std::thread::scope(|s| {
+            s.spawn(|| assert_eq!(inline.iter().map(|a| a.0).sum::<u8>(), 9));
+            s.spawn(|| assert_eq!(boxed.len(), 4));
+        });
+    }
+}
@@ -0,0 +1,393 @@
+// Name: ToyVm - a stack based toy VM whose values are tagged words.
+//
+// Description: Every value on the operand stack is a NanBox, one 64 bit
+//              word that says what it is:
+//
+//                 an i32     - an integer, inline in the word.
+//                 a boolean  - inline, the value is the low bit of the word.
+//                 null       - nil.
+//                 a &String  - a string of the VM heap.
+//
+//              So pushing, popping and copying values never allocates or
+//              follows a pointer, only the string operations do. Integers
+//              that don't fit in an i32 are a VmError::Overflow, never a
+//              silently wrapped word.
+//
+//              The strings live in a VmHeap, an arena the VM borrows, and a
+//              Value<'h> borrows the heap too. So a value can't outlive the
+//              strings it points to, the borrow checker refuses to drop the
+//              heap while one is still around.
+
+use std::cell::RefCell;
+use std::fmt;
+
+use crate::nan_box::{self, NanBox};
+
+/// A value of the VM, valid for as long as the heap `'h` it points into.
+#[derive(Clone, Copy)]
+pub struct Value<'h>(NanBox<'h, String>);
+
+impl<'h> Value<'h> {
+
+    /// None if `value` doesn't fit in the i32 of a value.
+    pub fn int(value: isize) -> Option<Value<'h>> {
+        i32::try_from(value).ok().map(|value| Value(NanBox::int(value)))
+    }
+
+    pub fn bool(value: bool) -> Value<'h> {
+        Value(NanBox::bool(value))
+    }
+
+    pub fn nil() -> Value<'h> {
+        Value(NanBox::null())
+    }
+
+    pub fn as_int(self) -> Option<isize> {
+        self.0.as_int().map(|value| value as isize)
+    }
+
+    pub fn as_bool(self) -> Option<bool> {
+        self.0.as_bool()
+    }
+
+    pub fn is_nil(self) -> bool {
+        self.0.is_null()
+    }
+
+    /// The text of a string value.
+    pub fn as_str(self) -> Option<&'h str> {
+        self.0.as_ref().map(String::as_str)
+    }
+
+}
+
+// The same word: equal immediates, or the same string object.
+impl<'h> PartialEq for Value<'h> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.to_bits() == other.0.to_bits()
+    }
+}
+
+impl<'h> Eq for Value<'h> {}
+
+impl<'h> fmt::Debug for Value<'h> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0.get() {
+            nan_box::Value::Int(i) => write!(f, "{}", i),
+            nan_box::Value::Bool(b) => write!(f, "{}", b),
+            nan_box::Value::Null => write!(f, "nil"),
+            nan_box::Value::Ref(s) => write!(f, "{:?}", s),
+            nan_box::Value::Float(_) => unreachable!("the VM has no floats"),
+        }
+    }
+}
+
+/// The strings of a VM. They are only added, never moved or freed before
+/// the heap, so the values can borrow them for the life of the heap.
+#[derive(Default)]
+pub struct VmHeap {
+    #[allow(clippy::vec_box)]
+    strings: RefCell<Vec<Box<String>>>,
+}
+
+impl VmHeap {
+
+    pub fn new() -> VmHeap {
+        VmHeap::default()
+    }
+
+    /// Number of strings allocated so far.
+    pub fn len(&self) -> usize {
+        self.strings.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn alloc(&self, text: String) -> &String {
+        let boxed = Box::new(text);
+        let string: *const String = &*boxed;
+        self.strings.borrow_mut().push(boxed);
+        // The Box keeps the String at its address and is dropped only with
+        // the heap, the Vec growing moves the Box, not the String.
+        unsafe { &*string }
+    }
+
+}
+
+#[derive(Clone, Debug)]
+pub enum Op {
+    PushInt(isize),
+    PushBool(bool),
+    PushNil,
+    PushStr(String),
+    Dup,
+    Pop,
+    Swap,
+    Over,
+    Add,
+    Sub,
+    Lt,
+    Eq,
+    Not,
+    Concat,
+    Jump(usize),
+    JumpIfFalse(usize),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum VmError {
+    StackUnderflow,
+    TypeError(&'static str),
+    BadJump(usize),
+    /// An integer literal or result outside the i32 range of the values.
+    Overflow,
+}
+
+pub struct ToyVm<'h> {
+    heap: &'h VmHeap,
+    stack: Vec<Value<'h>>,
+}
+
+impl<'h> ToyVm<'h> {
+
+    pub fn new(heap: &'h VmHeap) -> ToyVm<'h> {
+        ToyVm { heap, stack: Vec::new() }
+    }
+
+    /// Runs the program and returns the value left on the top of the stack,
+    /// nil if the stack is empty.
+    pub fn run(&mut self, program: &[Op]) -> Result<Value<'h>, VmError> {
+        let mut pc = 0;
+        while pc < program.len() {
+            let op = &program[pc];
+            pc += 1;
+            match op {
+                Op::PushInt(i) => self.stack.push(Value::int(*i).ok_or(VmError::Overflow)?),
+                Op::PushBool(b) => self.stack.push(Value::bool(*b)),
+                Op::PushNil => self.stack.push(Value::nil()),
+                Op::PushStr(s) => {
+                    let value = self.alloc_str(s.clone());
+                    self.stack.push(value);
+                }
+                Op::Dup => {
+                    let top = self.pop()?;
+                    self.stack.extend([top, top]);
+                }
+                Op::Pop => {
+                    self.pop()?;
+                }
+                Op::Swap => {
+                    let (a, b) = (self.pop()?, self.pop()?);
+                    self.stack.extend([a, b]);
+                }
+                Op::Over => {
+                    let (b, a) = (self.pop()?, self.pop()?);
+                    self.stack.extend([a, b, a]);
+                }
+                Op::Add => {
+                    let (b, a) = (self.pop_int()?, self.pop_int()?);
+                    self.push_int(a.checked_add(b))?;
+                }
+                Op::Sub => {
+                    let (b, a) = (self.pop_int()?, self.pop_int()?);
+                    self.push_int(a.checked_sub(b))?;
+                }
+                Op::Lt => {
+                    let (b, a) = (self.pop_int()?, self.pop_int()?);
+                    self.stack.push(Value::bool(a < b));
+                }
+                Op::Eq => {
+                    let (b, a) = (self.pop()?, self.pop()?);
+                    let equal = match (a.as_str(), b.as_str()) {
+                        (Some(a), Some(b)) => a == b,
+                        _ => a == b,
+                    };
+                    self.stack.push(Value::bool(equal));
+                }
+                Op::Not => {
+                    let b = self.pop_bool()?;
+                    self.stack.push(Value::bool(!b));
+                }
+                Op::Concat => {
+                    let (b, a) = (self.pop()?, self.pop()?);
+                    let text = match (a.as_str(), b.as_str()) {
+                        (Some(a), Some(b)) => format!("{}{}", a, b),
+                        _ => return Err(VmError::TypeError("concat expects strings")),
+                    };
+                    let value = self.alloc_str(text);
+                    self.stack.push(value);
+                }
+                Op::Jump(target) => pc = Self::check_jump(*target, program)?,
+                Op::JumpIfFalse(target) => {
+                    if !self.pop_bool()? {
+                        pc = Self::check_jump(*target, program)?;
+                    }
+                }
+            }
+        }
+        Ok(self.stack.last().copied().unwrap_or(Value::nil()))
+    }
+
+    fn alloc_str(&mut self, text: String) -> Value<'h> {
+        Value(NanBox::from_ref(self.heap.alloc(text)))
+    }
+
+    fn pop(&mut self) -> Result<Value<'h>, VmError> {
+        self.stack.pop().ok_or(VmError::StackUnderflow)
+    }
+
+    fn pop_int(&mut self) -> Result<isize, VmError> {
+        self.pop()?.as_int().ok_or(VmError::TypeError("expected an integer"))
+    }
+
+    fn pop_bool(&mut self) -> Result<bool, VmError> {
+        self.pop()?.as_bool().ok_or(VmError::TypeError("expected a boolean"))
+    }
+
+    fn push_int(&mut self, value: Option<isize>) -> Result<(), VmError> {
+        let value = value.and_then(Value::int).ok_or(VmError::Overflow)?;
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn check_jump(target: usize, program: &[Op]) -> Result<usize, VmError> {
+        if target <= program.len() { Ok(target) } else { Err(VmError::BadJump(target)) }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn value_words_round_trip() {
+        for i in [-5, 0, 1, i32::MAX as isize, i32::MIN as isize] {
+            assert_eq!(Value::int(i).unwrap().as_int(), Some(i));
+        }
+        assert!(Value::int(i32::MAX as isize + 1).is_none());
+        assert!(Value::int(isize::MIN).is_none());
+        assert_eq!(Value::bool(true).as_bool(), Some(true));
+        assert_eq!(Value::bool(false).as_bool(), Some(false));
+        assert!(Value::nil().is_nil());
+        assert_eq!(Value::nil().as_int(), None);
+        assert_eq!(Value::int(3).unwrap().as_bool(), None);
+        assert_ne!(Value::bool(false), Value::nil());
+        assert_ne!(Value::int(0).unwrap(), Value::bool(false));
+    }
+
+    #[test]
+    fn arithmetic() {
+        let heap = VmHeap::new();
+        let mut vm = ToyVm::new(&heap);
+        let result = vm.run(&[Op::PushInt(40), Op::PushInt(5), Op::Sub, Op::PushInt(7), Op::Add]);
+        assert_eq!(result.unwrap().as_int(), Some(42));
+    }
+
+    #[test]
+    fn loop_sums_one_to_ten() {
+        let program = [
+            Op::PushInt(0),
+            Op::PushInt(1),
+            // 2: stack is [sum, i], loop while i < 11
+            Op::Dup,
+            Op::PushInt(11),
+            Op::Lt,
+            Op::JumpIfFalse(13),
+            Op::Swap,
+            Op::Over,
+            Op::Add,
+            Op::Swap,
+            Op::PushInt(1),
+            Op::Add,
+            Op::Jump(2),
+            // 13
+            Op::Pop,
+        ];
+        let heap = VmHeap::new();
+        let mut vm = ToyVm::new(&heap);
+        assert_eq!(vm.run(&program).unwrap().as_int(), Some(55));
+    }
+
+    #[test]
+    fn branches_on_booleans() {
+        let heap = VmHeap::new();
+        let mut vm = ToyVm::new(&heap);
+        let program = [
+            Op::PushInt(1),
+            Op::PushInt(2),
+            Op::Lt,
+            Op::Not,
+            Op::JumpIfFalse(6),
+            Op::PushStr("no".to_string()),
+            // 6
+            Op::PushStr("yes".to_string()),
+        ];
+        let top = vm.run(&program).unwrap();
+        assert_eq!(top.as_str(), Some("yes"));
+    }
+
+    #[test]
+    fn strings_live_in_the_heap() {
+        let heap = VmHeap::new();
+        let mut vm = ToyVm::new(&heap);
+        let program = [
+            Op::PushStr("tagged ".to_string()),
+            Op::PushStr("words".to_string()),
+            Op::Concat,
+            Op::Dup,
+            Op::PushStr("tagged words".to_string()),
+            Op::Eq,
+        ];
+        assert_eq!(vm.run(&program).unwrap().as_bool(), Some(true));
+        vm.stack.pop();
+        let top = *vm.stack.last().unwrap();
+        assert_eq!(format!("{:?}", top), "\"tagged words\"");
+        assert_eq!(heap.len(), 4);
+        // The value outlives the VM, the heap keeps its string alive.
+        drop(vm);
+        assert_eq!(top.as_str(), Some("tagged words"));
+    }
+
+    #[test]
+    fn an_empty_program_leaves_nil() {
+        let heap = VmHeap::new();
+        let mut vm = ToyVm::new(&heap);
+        assert!(vm.run(&[]).unwrap().is_nil());
+        assert!(heap.is_empty());
+        assert_eq!(format!("{:?}", vm.run(&[Op::PushBool(true), Op::Not]).unwrap()), "false");
+    }
+
+    #[test]
+    fn errors() {
+        let heap = VmHeap::new();
+        let mut vm = ToyVm::new(&heap);
+        assert_eq!(vm.run(&[Op::Add]), Err(VmError::StackUnderflow));
+        let heap = VmHeap::new();
+        let mut vm = ToyVm::new(&heap);
+        assert_eq!(
+            vm.run(&[Op::PushBool(true), Op::PushInt(1), Op::Add]),
+            Err(VmError::TypeError("expected an integer"))
+        );
+        let heap = VmHeap::new();
+        let mut vm = ToyVm::new(&heap);
+        assert_eq!(vm.run(&[Op::Jump(9)]), Err(VmError::BadJump(9)));
+        let heap = VmHeap::new();
+        let mut vm = ToyVm::new(&heap);
+        assert_eq!(vm.run(&[Op::PushInt(i32::MAX as isize + 1)]), Err(VmError::Overflow));
+        let heap = VmHeap::new();
+        let mut vm = ToyVm::new(&heap);
+        assert_eq!(vm.run(&[Op::PushInt(i32::MAX as isize), Op::PushInt(1), Op::Add]), Err(VmError::Overflow));
+        let heap = VmHeap::new();
+        let mut vm = ToyVm::new(&heap);
+        assert_eq!(vm.run(&[Op::PushInt(i32::MIN as isize), Op::PushInt(1), Op::Sub]), Err(VmError::Overflow));
+        let heap = VmHeap::new();
+        let mut vm = ToyVm::new(&heap);
+        assert_eq!(
+            vm.run(&[Op::PushNil, Op::PushInt(1), Op::Concat]),
+            Err(VmError::TypeError("concat expects strings"))
+        );
+    }
+}
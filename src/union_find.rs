@@ -0,0 +1,151 @@
+// Name: UnionFindNode - a disjoint set forest, the root flag and the rank in
+//       the parent word.
+//
+// Description: In a union-find forest only the roots need a rank and only the
+//              other nodes need a parent, so one word holds either, told
+//              apart by bit 0. The node embeds a usize, so it is at least 2
+//              bytes aligned and a parent address always has bit 0 at zero:
+//
+//                 bit 0 = 0 : a child, the word is the parent address.
+//                 bit 0 = 1 : a root, the rest of the word is the rank.
+//
+//              The nodes are borrowed for 'a and the word is a Cell, so the
+//              forest is built and compressed through shared references:
+//              find() halves the path as it walks up, union() links the root
+//              of lower rank under the other one. Both are O(α(n)) amortized.
+//
+//              The parent of a child is kept as a pointer made from the
+//              &'a UnionFindNode, so parent() hands back a reference with its
+//              provenance. The rank of a root is a pointer without
+//              provenance, never dereferenced.
+
+use core::cell::Cell;
+use core::marker::PhantomData;
+use core::ptr;
+
+const ROOT: usize = 1;
+
+pub struct UnionFindNode<'a, T> {
+    // The parent, or the rank and ROOT.
+    parent_or_rank: Cell<*const UnionFindNode<'a, T>>,
+    value: T,
+    behaves_like: PhantomData<Cell<Option<&'a UnionFindNode<'a, T>>>>,
+}
+
+impl<'a, T> UnionFindNode<'a, T> {
+
+    /// A set of its own, of rank 0.
+    pub const fn new(value: T) -> UnionFindNode<'a, T> {
+        UnionFindNode { parent_or_rank: Cell::new(ptr::without_provenance(ROOT)), value, behaves_like: PhantomData }
+    }
+
+    pub fn get(&self) -> &T {
+        &self.value
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.parent_or_rank.get().addr() & ROOT != 0
+    }
+
+    /// None for a node that isn't a root.
+    pub fn rank(&self) -> Option<usize> {
+        let word = self.parent_or_rank.get().addr();
+        (word & ROOT != 0).then_some(word >> 1)
+    }
+
+    /// The root of the set of this node, the representative of the set.
+    pub fn find(&'a self) -> &'a UnionFindNode<'a, T> {
+        let mut node = self;
+        while let Some(parent) = node.parent() {
+            let Some(grandparent) = parent.parent() else {
+                return parent;
+            };
+            // Path halving: skips the parent, for the next finds.
+            node.set_parent(grandparent);
+            node = grandparent;
+        }
+        node
+    }
+
+    pub fn same_set(&'a self, other: &'a UnionFindNode<'a, T>) -> bool {
+        ptr::eq(self.find(), other.find())
+    }
+
+    /// Merges the sets of the 2 nodes, returns the root of the union.
+    pub fn union(&'a self, other: &'a UnionFindNode<'a, T>) -> &'a UnionFindNode<'a, T> {
+        let (a, b) = (self.find(), other.find());
+        if ptr::eq(a, b) {
+            return a;
+        }
+        let (rank_a, rank_b) = (a.rank().unwrap(), b.rank().unwrap());
+        let (root, child) = if rank_a < rank_b { (b, a) } else { (a, b) };
+        if rank_a == rank_b {
+            root.parent_or_rank.set(ptr::without_provenance(((rank_a + 1) << 1) | ROOT));
+        }
+        child.set_parent(root);
+        root
+    }
+
+    fn parent(&self) -> Option<&'a UnionFindNode<'a, T>> {
+        let word = self.parent_or_rank.get();
+        // Only ever set by set_parent() from a &'a UnionFindNode.
+        (word.addr() & ROOT == 0).then(|| unsafe { &*word })
+    }
+
+    fn set_parent(&self, parent: &'a UnionFindNode<'a, T>) {
+        self.parent_or_rank.set(ptr::from_ref(parent));
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connected_components() {
+        let nodes: Vec<UnionFindNode<char>> = "abcdef".chars().map(UnionFindNode::new).collect();
+        for (a, b) in [(0, 1), (2, 3), (1, 3), (4, 5)] {
+            nodes[a].union(&nodes[b]);
+        }
+        assert!(nodes[0].same_set(&nodes[2]) && !nodes[0].same_set(&nodes[4]));
+        let root = nodes[3].find();
+        assert_eq!(root.rank(), Some(2));
+        assert!(nodes.iter().filter(|node| node.is_root()).count() == 2 && nodes[1].rank().is_none());
+        assert_eq!(*nodes[5].find().get(), 'e');
+    }
+
+    #[test]
+    fn random_unions_match_a_labeling() {
+        let nodes: Vec<UnionFindNode<usize>> = (0..64).map(UnionFindNode::new).collect();
+        // The model: a component label per node, relabeled on every union.
+        let mut label: Vec<usize> = (0..64).collect();
+        let mut seed: u32 = 11;
+        for _ in 0..400 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let (a, b) = ((seed >> 8) as usize % 64, (seed >> 20) as usize % 64);
+            let root = nodes[a].union(&nodes[b]);
+            let (old, new) = (label[b], label[a]);
+            label.iter_mut().filter(|l| **l == old).for_each(|l| *l = new);
+            assert!(root.is_root() && ptr::eq(root, nodes[b].find()));
+            for i in 0..64 {
+                assert_eq!(nodes[i].same_set(&nodes[a]), label[i] == label[a]);
+            }
+            let roots = nodes.iter().filter(|node| node.is_root()).count();
+            let mut labels = label.clone();
+            labels.sort_unstable();
+            labels.dedup();
+            assert_eq!(roots, labels.len());
+        }
+        // Union by rank keeps the trees at most log2(64) high.
+        assert!(nodes.iter().filter_map(UnionFindNode::rank).all(|rank| rank <= 6));
+    }
+
+    #[test]
+    fn a_single_node_and_a_self_union() {
+        let node = UnionFindNode::new(7);
+        assert!(node.is_root() && node.rank() == Some(0) && ptr::eq(node.find(), &node));
+        assert!(ptr::eq(node.union(&node), &node) && node.same_set(&node));
+        assert_eq!((node.rank(), *node.get()), (Some(0), 7));
+    }
+}
@@ -0,0 +1,174 @@
+// Name: WordMutex - a mutex whose state is one tagged word.
+//
+// Description: The classic futex word layout: the lock state fits in a single
+//              atomic word, and locking or unlocking without contention is a
+//              single atomic operation on it. Here the word is the address of the
+//              protected data plus 2 bits:
+//
+//                 bit 0 - LOCKED    : a thread holds the lock.
+//                 bit 1 - CONTENDED : threads may be parked waiting for it.
+//
+//              A thread that finds the lock taken sets CONTENDED and parks.
+//              The unlock clears both bits and, only if CONTENDED was set,
+//              unparks the waiters, which race for the lock again. They are
+//              parked in the table of ParkingTaggedPtr, keyed by the address
+//              of the word, as the kernel keys its futex queues, so the mutex
+//              is that one word. The table is only touched on the contended
+//              path.
+
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::aligned_box::Align8;
+use crate::bitpack::untag_ptr;
+use crate::parking_tagged_ptr::{park, unpark_all};
+
+const LOCKED: usize = 1;
+const CONTENDED: usize = 2;
+const BITS: usize = LOCKED | CONTENDED;
+
+pub struct WordMutex<T> {
+    word: AtomicPtr<Align8<UnsafeCell<T>>>,
+    owns: PhantomData<Box<Align8<UnsafeCell<T>>>>,
+}
+pub struct WordMutexGuard<'m, T> {
+    mutex: &'m WordMutex<T>,
+    // Sync only if T is, like a &mut T.
+    behaves_like: PhantomData<&'m mut T>,
+}
+
+unsafe impl<T: Send> Send for WordMutex<T> {}
+unsafe impl<T: Send> Sync for WordMutex<T> {}
+
+impl<T> WordMutex<T> {
+
+    pub fn new(value: T) -> WordMutex<T> {
+        let data = Box::into_raw(Box::new(Align8(UnsafeCell::new(value))));
+        WordMutex { word: AtomicPtr::new(data), owns: PhantomData }
+    }
+
+    pub fn lock(&self) -> WordMutexGuard<'_, T> {
+        if let Some(guard) = self.try_lock() {
+            return guard;
+        }
+        loop {
+            let word = self.word.load(Ordering::Relaxed);
+            if word.addr() & LOCKED == 0 {
+                // Keep CONTENDED, other threads may still be parked.
+                if self
+                    .word
+                    .compare_exchange_weak(word, word.map_addr(|addr| addr | LOCKED), Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    return WordMutexGuard { mutex: self, behaves_like: PhantomData };
+                }
+                continue;
+            }
+            // CONTENDED is set with the bucket locked, so the unlock that
+            // sees it finds this thread parked.
+            park(self.key(), || {
+                let contended = word.map_addr(|addr| addr | CONTENDED);
+                self.word.compare_exchange(word, contended, Ordering::Relaxed, Ordering::Relaxed).is_ok()
+            });
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<WordMutexGuard<'_, T>> {
+        let word = self.word.load(Ordering::Relaxed);
+        if word.addr() & LOCKED != 0 {
+            return None;
+        }
+        self.word
+            .compare_exchange(word, word.map_addr(|addr| addr | LOCKED), Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| WordMutexGuard { mutex: self, behaves_like: PhantomData })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.word.load(Ordering::Relaxed).addr() & LOCKED != 0
+    }
+
+    pub fn into_inner(self) -> T {
+        let this = std::mem::ManuallyDrop::new(self);
+        let data = untag_ptr(this.word.load(Ordering::Relaxed), 2);
+        unsafe { Box::from_raw(data).0.into_inner() }
+    }
+
+    fn data(&self) -> *mut T {
+        let data = untag_ptr(self.word.load(Ordering::Relaxed), 2);
+        unsafe { (*data).0.get() }
+    }
+
+    fn unlock(&self) {
+        let word = self.word.fetch_and(!BITS, Ordering::Release);
+        if word.addr() & CONTENDED != 0 {
+            unpark_all(self.key());
+        }
+    }
+
+    // Where the waiters park.
+    fn key(&self) -> usize {
+        ptr::from_ref(&self.word).addr()
+    }
+
+}
+
+impl<T> Drop for WordMutex<T> {
+    fn drop(&mut self) {
+        let data = untag_ptr(*self.word.get_mut(), 2);
+        drop(unsafe { Box::from_raw(data) });
+    }
+}
+
+impl<T: Default> Default for WordMutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<'m, T> Deref for WordMutexGuard<'m, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data() }
+    }
+}
+
+impl<'m, T> DerefMut for WordMutexGuard<'m, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data() }
+    }
+}
+
+impl<'m, T> Drop for WordMutexGuard<'m, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn is_one_word_and_excludes() {
+        assert_eq!(size_of::<WordMutex<u64>>(), size_of::<usize>());
+        assert_eq!(size_of::<WordMutex<[u8; 100]>>(), size_of::<usize>());
+        let counter = WordMutex::new(0u64);
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..1000 {
+                        *counter.lock() += 1;
+                    }
+                });
+            }
+        });
+        assert!(!counter.is_locked());
+        assert_eq!(counter.into_inner(), 8000);
+    }
+}
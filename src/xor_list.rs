@@ -0,0 +1,407 @@
+// Name: XorList - a doubly linked list with one link word per node.
+//
+// Description: Each node stores prev XOR next instead of the 2 pointers.
+//              Knowing the address of one neighbour, the other one is the
+//              link XOR that address, so the list can be walked in both
+//              directions with a single word per node.
+//
+//              The nodes are at least 2 bytes aligned, so both addresses
+//              have bit 0 at zero and so does their XOR. That spare bit of
+//              the link is a per node flag, free for the user.
+//
+//              A XOR of 2 addresses is an integer that no pointer can carry,
+//              so this is the one place where provenance goes through a
+//              usize: every node is exposed (expose_provenance) when it is
+//              allocated, and a decoded neighbour is turned back into a
+//              pointer with with_exposed_provenance_mut. The ends and the
+//              cursor positions are pointers.
+//
+//              Traversal goes through cursors, that carry the (prev, current)
+//              pair needed to decode the links, so no raw pointer is exposed.
+//              As with the cursors of std's LinkedList, past either end there
+//              is a "ghost" position, where current() is None: moving next
+//              from it reaches the head, and moving prev the tail.
+
+use std::marker::PhantomData;
+use std::ptr;
+
+const FLAG: usize = 1;
+
+struct Node<T> {
+    // Exposed addresses prev ^ next, plus the FLAG bit.
+    link: usize,
+    value: T,
+}
+
+pub struct XorList<T> {
+    head: *mut Node<T>,
+    tail: *mut Node<T>,
+    len: usize,
+    owns: PhantomData<Box<Node<T>>>,
+}
+
+// The raw pointers opt out of Send and Sync, this is a LinkedList<T> as far
+// as threads go.
+unsafe impl<T: Send> Send for XorList<T> {}
+unsafe impl<T: Sync> Sync for XorList<T> {}
+
+// Where a cursor is, both null on the ghost. The list is borrowed for as
+// long as the cursor lives, so its ends are copied in.
+struct Position<T> {
+    prev: *mut Node<T>,
+    current: *mut Node<T>,
+    head: *mut Node<T>,
+    tail: *mut Node<T>,
+}
+
+impl<T> Clone for Position<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Position<T> {}
+
+pub struct Cursor<'l, T> {
+    at: Position<T>,
+    list: PhantomData<&'l XorList<T>>,
+}
+
+pub struct CursorMut<'l, T> {
+    at: Position<T>,
+    list: PhantomData<&'l mut XorList<T>>,
+}
+
+// The positions are raw pointers, the cursors go between threads like the
+// borrows of the list they stand for.
+unsafe impl<'l, T: Sync> Send for Cursor<'l, T> {}
+unsafe impl<'l, T: Sync> Sync for Cursor<'l, T> {}
+unsafe impl<'l, T: Send> Send for CursorMut<'l, T> {}
+unsafe impl<'l, T: Sync> Sync for CursorMut<'l, T> {}
+
+impl<T> XorList<T> {
+
+    pub fn new() -> XorList<T> {
+        XorList { head: ptr::null_mut(), tail: ptr::null_mut(), len: 0, owns: PhantomData }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn push_front(&mut self, value: T) {
+        let node = Self::alloc(self.head, value);
+        if !self.head.is_null() {
+            unsafe { (*self.head).link ^= node.addr() };
+        } else {
+            self.tail = node;
+        }
+        self.head = node;
+        self.len += 1;
+    }
+
+    pub fn push_back(&mut self, value: T) {
+        let node = Self::alloc(self.tail, value);
+        if !self.tail.is_null() {
+            unsafe { (*self.tail).link ^= node.addr() };
+        } else {
+            self.head = node;
+        }
+        self.tail = node;
+        self.len += 1;
+    }
+
+    pub fn pop_front(&mut self) -> Option<T> {
+        let value = unsafe { Self::unlink_end(&mut self.head, &mut self.tail)? };
+        self.len -= 1;
+        Some(value)
+    }
+
+    pub fn pop_back(&mut self) -> Option<T> {
+        let value = unsafe { Self::unlink_end(&mut self.tail, &mut self.head)? };
+        self.len -= 1;
+        Some(value)
+    }
+
+    /// A cursor on the first node.
+    pub fn cursor_front(&self) -> Cursor<'_, T> {
+        Cursor { at: self.ghost().next(), list: PhantomData }
+    }
+
+    /// A cursor on the last node, moving next from it reaches the ghost.
+    pub fn cursor_back(&self) -> Cursor<'_, T> {
+        Cursor { at: self.ghost().prev(), list: PhantomData }
+    }
+
+    pub fn cursor_front_mut(&mut self) -> CursorMut<'_, T> {
+        CursorMut { at: self.ghost().next(), list: PhantomData }
+    }
+
+    pub fn cursor_back_mut(&mut self) -> CursorMut<'_, T> {
+        CursorMut { at: self.ghost().prev(), list: PhantomData }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> + '_ {
+        let mut cursor = self.cursor_front();
+        std::iter::from_fn(move || {
+            let value = cursor.current()?;
+            cursor.move_next();
+            Some(value)
+        })
+    }
+
+    fn ghost(&self) -> Position<T> {
+        Position { prev: ptr::null_mut(), current: ptr::null_mut(), head: self.head, tail: self.tail }
+    }
+
+    // A new node whose only neighbour is `neighbour`, exposed so that it can
+    // be found again from the links.
+    fn alloc(neighbour: *mut Node<T>, value: T) -> *mut Node<T> {
+        let node = Box::into_raw(Box::new(Node { link: neighbour.addr(), value }));
+        node.expose_provenance();
+        node
+    }
+
+    // Removes the node at one end, `end` is that end and `other` the other.
+    unsafe fn unlink_end(end: &mut *mut Node<T>, other: &mut *mut Node<T>) -> Option<T> {
+        if end.is_null() {
+            return None;
+        }
+        let node = Box::from_raw(*end);
+        // At an end one of the neighbours is null, so the link is the other.
+        let neighbour = decode(node.link, ptr::null_mut());
+        if !neighbour.is_null() {
+            (*neighbour).link ^= end.addr();
+        }
+        if *other == *end {
+            *other = ptr::null_mut();
+        }
+        *end = neighbour;
+        Some(node.value)
+    }
+
+}
+
+// The neighbour of a node that isn't `known`, from the node's link.
+fn decode<T>(link: usize, known: *mut Node<T>) -> *mut Node<T> {
+    match (link & !FLAG) ^ known.addr() {
+        0 => ptr::null_mut(),
+        addr => ptr::with_exposed_provenance_mut(addr),
+    }
+}
+
+impl<T> Position<T> {
+
+    fn next(self) -> Position<T> {
+        if self.current.is_null() {
+            return Position { prev: ptr::null_mut(), current: self.head, ..self };
+        }
+        let next = decode(unsafe { (*self.current).link }, self.prev);
+        if next.is_null() {
+            Position { prev: ptr::null_mut(), current: ptr::null_mut(), ..self }
+        } else {
+            Position { prev: self.current, current: next, ..self }
+        }
+    }
+
+    fn prev(self) -> Position<T> {
+        if self.current.is_null() {
+            // The tail's next is null, so its link is its prev.
+            let prev = if self.tail.is_null() { ptr::null_mut() } else { decode(unsafe { (*self.tail).link }, ptr::null_mut()) };
+            return Position { prev, current: self.tail, ..self };
+        }
+        if self.prev.is_null() {
+            return Position { prev: ptr::null_mut(), current: ptr::null_mut(), ..self };
+        }
+        let prev = decode(unsafe { (*self.prev).link }, self.current);
+        Position { prev, current: self.prev, ..self }
+    }
+
+    fn flag(&self) -> bool {
+        !self.current.is_null() && unsafe { (*self.current).link & FLAG != 0 }
+    }
+
+}
+
+impl<'l, T> Cursor<'l, T> {
+
+    pub fn current(&self) -> Option<&'l T> {
+        if self.at.current.is_null() {
+            return None;
+        }
+        Some(unsafe { &(*self.at.current).value })
+    }
+
+    pub fn flag(&self) -> bool {
+        self.at.flag()
+    }
+
+    pub fn move_next(&mut self) {
+        self.at = self.at.next();
+    }
+
+    pub fn move_prev(&mut self) {
+        self.at = self.at.prev();
+    }
+
+}
+
+impl<'l, T> CursorMut<'l, T> {
+
+    pub fn current(&mut self) -> Option<&mut T> {
+        if self.at.current.is_null() {
+            return None;
+        }
+        Some(unsafe { &mut (*self.at.current).value })
+    }
+
+    pub fn flag(&self) -> bool {
+        self.at.flag()
+    }
+
+    pub fn set_flag(&mut self, flag: bool) {
+        if !self.at.current.is_null() {
+            let node = unsafe { &mut *self.at.current };
+            node.link = (node.link & !FLAG) | flag as usize;
+        }
+    }
+
+    pub fn move_next(&mut self) {
+        self.at = self.at.next();
+    }
+
+    pub fn move_prev(&mut self) {
+        self.at = self.at.prev();
+    }
+
+}
+
+impl<T> Default for XorList<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for XorList<T> {
+    fn drop(&mut self) {
+        while self.pop_front().is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    fn backwards(list: &XorList<u32>) -> Vec<u32> {
+        let mut cursor = list.cursor_back();
+        let mut values = Vec::new();
+        while let Some(&value) = cursor.current() {
+            values.push(value);
+            cursor.move_prev();
+        }
+        values
+    }
+
+    #[test]
+    fn walks_both_ways_through_the_ghost() {
+        let mut list = XorList::new();
+        assert!(list.cursor_front().current().is_none());
+        assert!(backwards(&list).is_empty());
+        let mut cursor = list.cursor_back_mut();
+        cursor.move_prev();
+        cursor.move_next();
+        assert!(cursor.current().is_none());
+
+        list.push_back(1);
+        let mut cursor = list.cursor_front();
+        cursor.move_prev();
+        assert!(cursor.current().is_none());
+        cursor.move_next();
+        assert_eq!(cursor.current(), Some(&1));
+        cursor.move_next();
+        assert!(cursor.current().is_none());
+        cursor.move_prev();
+        assert_eq!(cursor.current(), Some(&1));
+        assert_eq!(backwards(&list), vec![1]);
+
+        for value in 2..=5 {
+            list.push_back(value);
+        }
+        assert_eq!(backwards(&list), vec![5, 4, 3, 2, 1]);
+        let mut cursor = list.cursor_front();
+        cursor.move_prev();
+        cursor.move_prev();
+        assert_eq!(cursor.current(), Some(&5));
+        cursor.move_next();
+        cursor.move_next();
+        assert_eq!(cursor.current(), Some(&1));
+    }
+
+    #[test]
+    fn the_flag_survives_the_walk() {
+        let mut list: XorList<u32> = (0..6).fold(XorList::new(), |mut list, value| {
+            list.push_back(value);
+            list
+        });
+        let mut cursor = list.cursor_back_mut();
+        while let Some(&mut value) = cursor.current() {
+            cursor.set_flag(value % 2 == 0);
+            cursor.move_prev();
+        }
+        let mut cursor = list.cursor_front();
+        while let Some(&value) = cursor.current() {
+            assert_eq!(cursor.flag(), value % 2 == 0);
+            cursor.move_next();
+        }
+        assert_eq!(backwards(&list), vec![5, 4, 3, 2, 1, 0]);
+        assert_eq!(list.iter().copied().collect::<Vec<_>>(), vec![0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn random_pushes_and_pops_match_a_vec_deque() {
+        let mut list = XorList::new();
+        let mut model = VecDeque::new();
+        let mut seed = 8080u32;
+        for _ in 0..4000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            match (seed >> 8) % 4 {
+                0 => {
+                    list.push_front(seed);
+                    model.push_front(seed);
+                }
+                1 => {
+                    list.push_back(seed);
+                    model.push_back(seed);
+                }
+                2 => assert_eq!(list.pop_front(), model.pop_front()),
+                _ => assert_eq!(list.pop_back(), model.pop_back()),
+            }
+            assert_eq!(list.len(), model.len());
+            assert_eq!(list.is_empty(), model.is_empty());
+        }
+        assert!(list.iter().eq(model.iter()));
+        let mut reversed: Vec<u32> = model.iter().copied().collect();
+        reversed.reverse();
+        assert_eq!(backwards(&list), reversed);
+    }
+
+    #[test]
+    fn a_single_node_from_both_ends() {
+        let mut list = XorList::new();
+        list.push_front(1_u32);
+        list.cursor_front_mut().set_flag(true);
+        assert!(list.cursor_back().flag());
+        assert_eq!(list.pop_back(), Some(1));
+        assert!(list.is_empty() && list.pop_front().is_none() && list.pop_back().is_none());
+        assert!(!list.cursor_front().flag());
+        list.push_back(2);
+        assert_eq!(list.pop_front(), Some(2));
+        list.push_back(3);
+        assert_eq!(list.iter().collect::<Vec<_>>(), vec![&3]);
+    }
+}